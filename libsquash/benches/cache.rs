@@ -0,0 +1,48 @@
+// Resolving paths in a few hot directories of an image file, with and
+// without the inode and entry caches.
+//
+// Run with `cargo bench --bench cache`.
+
+use std::time::{Duration, Instant};
+
+use libsquash::fs::{FsOptions, FS};
+use libsquash::{write_image_file, EncryptionType};
+
+const DIRS: usize = 16;
+const FILES: usize = 512;
+// Lookups per measurement, spread over all the paths
+const LOOKUPS: usize = 50000;
+
+fn measure(fs: &FS, paths: &[String]) -> Duration {
+    let start = Instant::now();
+    for i in 0..LOOKUPS {
+        let path = &paths[(i * 7919) % paths.len()];
+        assert!(fs.resolve(path).unwrap().is_some());
+    }
+    start.elapsed() / LOOKUPS as u32
+}
+
+fn main() {
+    let dir = tempfile::tempdir().unwrap();
+    let src = dir.path().join("src");
+    let paths: Vec<_> = (0..DIRS * FILES)
+        .map(|i| format!("a/b/d{:02}/file-{:06}", i % DIRS, i))
+        .collect();
+    for path in &paths {
+        let path = src.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, "").unwrap();
+    }
+    let image = dir.path().join("image.sqh");
+    write_image_file(&src, &image, None, EncryptionType::None).unwrap();
+    println!("{:>10} {:>12}", "capacity", "resolve");
+    for capacity in [0, 256, 4096, 65536] {
+        let opts = FsOptions {
+            cache_capacity: capacity,
+            ..Default::default()
+        };
+        let file = std::fs::File::open(&image).unwrap();
+        let fs = FS::open_with(file, None, &opts).unwrap();
+        println!("{:>10} {:>12?}", capacity, measure(&fs, &paths));
+    }
+}
@@ -0,0 +1,56 @@
+// Lookup time by directory size, with and without hash indexes.
+//
+// Run with `cargo bench --bench lookup`. The default of
+// WriteOptions::hash_index_min_entries is about where the index starts
+// to be faster than a binary search.
+
+use std::io::Cursor;
+use std::time::{Duration, Instant};
+
+use libsquash::fs::FS;
+use libsquash::{write_image_with, EncryptionType, WriteOptions};
+
+const SIZES: &[usize] = &[4, 8, 16, 32, 64, 128, 256, 1024, 4096, 16384];
+// Lookups per measurement, spread over all the names
+const LOOKUPS: usize = 20000;
+
+fn build(entries: usize, index: bool) -> (FS, Vec<String>) {
+    let dir = tempfile::tempdir().unwrap();
+    let names: Vec<_> =
+        (0..entries).map(|i| format!("file-{:08}", i)).collect();
+    for name in &names {
+        std::fs::write(dir.path().join(name), "").unwrap();
+    }
+    let opts = WriteOptions {
+        hash_index_min_entries: if index { Some(0) } else { None },
+        ..Default::default()
+    };
+    let mut out = Cursor::new(Vec::new());
+    write_image_with(dir.path(), &mut out, None, EncryptionType::None, &opts)
+        .unwrap();
+    (FS::open(out, None).unwrap(), names)
+}
+
+fn measure(fs: &FS, names: &[String]) -> Duration {
+    let root = fs.get_root().unwrap();
+    let start = Instant::now();
+    for i in 0..LOOKUPS {
+        let name = &names[(i * 7919) % names.len()];
+        assert!(root.resolve_entry(name).unwrap().is_some());
+    }
+    start.elapsed() / LOOKUPS as u32
+}
+
+fn main() {
+    println!("{:>8} {:>12} {:>12}", "entries", "binary", "hash index");
+    for &size in SIZES {
+        let (plain, names) = build(size, false);
+        let (indexed, _) = build(size, true);
+        println!(
+            "{:>8} {:>12?} {:>12?}",
+            size,
+            measure(&plain, &names),
+            measure(&indexed, &names)
+        );
+    }
+}
@@ -0,0 +1,56 @@
+// Resolving and reading many small files from an image file, with a
+// system call per read or from a memory map.
+//
+// Run with `cargo bench --bench mmap --features mmap`.
+
+use std::time::{Duration, Instant};
+
+use libsquash::fs::{FSItem, FS};
+use libsquash::{write_image_file, EncryptionType, MmapImage};
+
+const FILES: usize = 4096;
+const ROUNDS: usize = 5;
+
+fn measure(fs: &FS, names: &[String]) -> Duration {
+    let mut buf = [0; 64];
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        for name in names {
+            match fs.resolve(name).unwrap() {
+                Some(FSItem::File(f)) => f.read_at(&mut buf, 0).unwrap(),
+                _ => panic!("{} is not a file", name),
+            };
+        }
+    }
+    start.elapsed() / (ROUNDS * names.len()) as u32
+}
+
+fn main() {
+    let dir = tempfile::tempdir().unwrap();
+    let src = dir.path().join("src");
+    let names: Vec<_> = (0..FILES)
+        .map(|i| format!("d{:02}/file-{:06}", i % 64, i))
+        .collect();
+    for name in &names {
+        let path = src.join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, name).unwrap();
+    }
+    let image = dir.path().join("image.sqh");
+    let key = [1; libsquash::CHACHA20_KEY_LEN];
+    println!("{:>10} {:>12} {:>12}", "encryption", "read_at", "mmap");
+    for (enc, key) in [
+        (EncryptionType::None, None),
+        (EncryptionType::ChaCha20, Some(&key[..])),
+    ] {
+        write_image_file(&src, &image, key, enc).unwrap();
+        let file = FS::open(std::fs::File::open(&image).unwrap(), key).unwrap();
+        let mmap = FS::open(MmapImage::open(&image).unwrap(), key).unwrap();
+        println!(
+            "{:>10} {:>12?} {:>12?}",
+            format!("{:?}", enc),
+            measure(&file, &names),
+            measure(&mmap, &names)
+        );
+    }
+}
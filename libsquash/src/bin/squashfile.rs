@@ -1,36 +1,176 @@
 use clap::{Args, Parser, Subcommand};
 
-use libsquash::{extract_image_file, write_image_file, EncryptionType, Result};
+use libsquash::fs::{FSItem, FileType, FS};
+use libsquash::{
+    dedup_report, derive_key, extract_image_file_with, extract_subtree_with,
+    new_salt, open_image_file, probe_image_file, read_header_file, verify,
+    verify_integrity, write_image_file_with, Collation, CompressionType,
+    EncryptionType, Error, ExtractOptions, ImageHeader, OverwritePolicy,
+    Result, WriteOptions,
+};
 
-use std::path::PathBuf;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use serde_json::{json, Map, Value};
+
+use std::ffi::OsString;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
 
 extern crate hex;
 
 fn enc_parse(s: &str) -> std::result::Result<EncryptionType, String> {
+    s.parse().map_err(|_| "Invalid encryption type".into())
+}
+
+fn comp_parse(s: &str) -> std::result::Result<CompressionType, String> {
+    s.parse().map_err(|_| "Invalid compression type".into())
+}
+
+fn coll_parse(s: &str) -> std::result::Result<Collation, String> {
+    Ok(match s {
+        "bytes" => Collation::Bytes,
+        "case-insensitive" => Collation::CaseInsensitive,
+        "natural" => Collation::Natural,
+        _ => return Err("Invalid collation".into()),
+    })
+}
+
+fn idmap_parse(s: &str) -> std::result::Result<(u32, u32, u32), String> {
+    let ids: Vec<_> = s.split(':').map(|i| i.parse::<u32>()).collect();
+    match ids[..] {
+        [Ok(inner), Ok(outer), Ok(count)] => Ok((inner, outer, count)),
+        _ => Err("Invalid id map, expected IMAGE_ID:HOST_ID:COUNT".into()),
+    }
+}
+
+fn overwrite_parse(s: &str) -> std::result::Result<OverwritePolicy, String> {
+    Ok(match s {
+        "overwrite" => OverwritePolicy::Overwrite,
+        "skip" => OverwritePolicy::Skip,
+        "error" => OverwritePolicy::Error,
+        _ => return Err("Invalid overwrite policy".into()),
+    })
+}
+
+fn type_parse(s: &str) -> std::result::Result<char, String> {
+    match s {
+        "f" | "d" | "l" => Ok(s.chars().next().unwrap()),
+        _ => Err("Invalid type, must be one of f, d or l".into()),
+    }
+}
+
+fn type_matches(ty: char, ft: FileType) -> bool {
+    match ty {
+        'f' => ft.is_file(),
+        'd' => ft.is_dir(),
+        _ => ft.is_symlink(),
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    Plain,
+    Json,
+}
+
+fn format_parse(s: &str) -> std::result::Result<OutputFormat, String> {
     Ok(match s {
-        "chacha20" => EncryptionType::ChaCha20,
-        "none" => EncryptionType::None,
-        _ => return Err("Invalid encryption type".into()),
+        "plain" => OutputFormat::Plain,
+        "json" => OutputFormat::Json,
+        _ => return Err("Invalid format, must be plain or json".into()),
     })
 }
 
+/// Adds `value` to `obj` under `key`. Names that are not UTF-8 are
+/// written in base64, with a "<key>_base64" member set to true.
+fn insert_bytes(obj: &mut Map<String, Value>, key: &str, value: &[u8]) {
+    match std::str::from_utf8(value) {
+        Ok(s) => {
+            obj.insert(key.into(), s.into());
+        }
+        Err(_) => {
+            obj.insert(key.into(), BASE64_STANDARD.encode(value).into());
+            obj.insert(format!("{}_base64", key), true.into());
+        }
+    }
+}
+
 #[derive(Parser)]
 #[clap(rename_all = "lower")]
 struct Cli {
+    /// Output of list, info and verify (plain or json)
+    #[clap(long, global = true, value_parser = format_parse, default_value = "plain")]
+    format: OutputFormat,
     #[clap(subcommand)]
     command: Command,
 }
 
+/// Key of an image, given in hex or derived from a passphrase
+#[derive(Args)]
+struct KeyArgs {
+    #[clap(short, long, value_parser)]
+    key: Option<String>,
+    /// Passphrase to derive the key from, instead of --key
+    #[clap(long, value_parser, conflicts_with = "key")]
+    passphrase: Option<String>,
+}
+
 #[derive(Args)]
 struct CreateArgs {
     #[clap(short, long, value_parser)]
     source: PathBuf,
     #[clap(short, long, value_parser)]
     image: PathBuf,
-    #[clap(short, long, value_parser)]
-    key: Option<String>,
+    #[clap(flatten)]
+    key: KeyArgs,
     #[clap(short, long, value_parser = enc_parse, default_value = "none")]
     enc_type: EncryptionType,
+    /// Warn about names in a directory that only differ in case
+    #[clap(long)]
+    warn_case_collisions: bool,
+    /// Order of directory entries (bytes, case-insensitive or natural)
+    #[clap(long, value_parser = coll_parse, default_value = "bytes")]
+    collation: Collation,
+    /// Compression of file contents (none or zstd)
+    #[clap(long, value_parser = comp_parse, default_value = "none")]
+    compression: CompressionType,
+    /// Compression level
+    #[clap(long, value_parser, default_value_t = 3)]
+    compression_level: i32,
+    /// Store the total size of the files below each directory
+    #[clap(long)]
+    subtree_sizes: bool,
+    /// Only depend on the source tree (derive the UUID from contents)
+    #[clap(long)]
+    deterministic: bool,
+    /// Store hash trees to check file contents on every read
+    #[clap(long)]
+    merkle: bool,
+    /// Store symlinks with the same target once
+    #[clap(long)]
+    dedup_symlinks: bool,
+    /// Store identical file contents once
+    #[clap(long)]
+    dedup: bool,
+    /// Store extended attributes
+    #[clap(long)]
+    xattrs: bool,
+    /// Store the CRC-32 of each file, checked by verify
+    #[clap(long)]
+    checksums: bool,
+    /// Store a digest of the whole image, checked by verify --integrity
+    #[clap(long)]
+    integrity: bool,
+    /// Leave out files smaller than this many bytes
+    #[clap(long, value_parser)]
+    min_file_size: Option<u64>,
+    /// Leave out files larger than this many bytes
+    #[clap(long, value_parser)]
+    max_file_size: Option<u64>,
+    /// Size of the blocks xchacha20poly1305 encrypts (a power of two)
+    #[clap(long, value_parser, default_value_t = 4096)]
+    encryption_block_size: u64,
 }
 
 #[derive(Args)]
@@ -39,30 +179,661 @@ struct ExtractArgs {
     target: PathBuf,
     #[clap(short, long, value_parser)]
     image: PathBuf,
+    #[clap(flatten)]
+    key: KeyArgs,
+    /// Only extract this path of the image
+    #[clap(long, value_parser)]
+    path: Option<OsString>,
+    /// What to do with existing entries (overwrite, skip or error)
+    #[clap(long, value_parser = overwrite_parse, default_value = "error")]
+    overwrite: OverwritePolicy,
+    /// Restore the owner of entries (needs root)
+    #[clap(long)]
+    preserve_owner: bool,
+    /// Map restored user ids, as IMAGE_ID:HOST_ID:COUNT
+    #[clap(long, value_parser = idmap_parse, requires = "preserve-owner")]
+    uid_map: Vec<(u32, u32, u32)>,
+    /// Map restored group ids, as IMAGE_ID:HOST_ID:COUNT
+    #[clap(long, value_parser = idmap_parse, requires = "preserve-owner")]
+    gid_map: Vec<(u32, u32, u32)>,
+    /// Owner of ids outside of the maps, instead of failing
+    #[clap(long, value_parser, requires = "preserve-owner")]
+    unmapped_id: Option<u32>,
+}
+
+#[derive(Args)]
+struct DedupArgs {
+    #[clap(short, long, value_parser, required = true)]
+    image: Vec<PathBuf>,
+    #[clap(flatten)]
+    key: KeyArgs,
+}
+
+#[derive(Args)]
+struct ListArgs {
     #[clap(short, long, value_parser)]
-    key: Option<String>,
+    image: PathBuf,
+    #[clap(flatten)]
+    key: KeyArgs,
+    /// Directory in the image to list, paths are relative to it
+    #[clap(short, long, value_parser)]
+    start: Option<String>,
+    /// Only list entries of this type (f, d or l)
+    #[clap(short, long = "type", value_parser = type_parse)]
+    ty: Option<char>,
+    /// Separate paths with NUL instead of newlines. Paths are then
+    /// printed as is, without the decorations of the other modes.
+    #[clap(short = '0', long)]
+    null: bool,
+    /// Also print the size of files
+    #[clap(short, long)]
+    long: bool,
+    /// Also print the guessed content type of files
+    #[clap(long)]
+    detect_type: bool,
+}
+
+#[derive(Args)]
+struct CatArgs {
+    #[clap(short, long, value_parser)]
+    image: PathBuf,
+    #[clap(flatten)]
+    key: KeyArgs,
+    /// Path of the file in the image
+    #[clap(short, long, value_parser)]
+    path: OsString,
+}
+
+#[cfg(feature = "fuse")]
+#[derive(Args)]
+struct MountArgs {
+    #[clap(short, long, value_parser)]
+    image: PathBuf,
+    #[clap(flatten)]
+    key: KeyArgs,
+    /// Directory to mount the image on, until it is unmounted
+    #[clap(short, long, value_parser)]
+    target: PathBuf,
+}
+
+#[derive(Args)]
+struct VerifyArgs {
+    #[clap(short, long, value_parser)]
+    image: PathBuf,
+    #[clap(flatten)]
+    key: KeyArgs,
+    /// Only check the contents of this file against --hash
+    #[clap(long, value_parser, requires = "hash")]
+    path: Option<String>,
+    /// Expected BLAKE3 hash of --path, in hex
+    #[clap(long, value_parser, requires = "path")]
+    hash: Option<String>,
+    /// Only check the digest of the whole image, no key needed
+    #[clap(long, conflicts_with = "path")]
+    integrity: bool,
+}
+
+#[derive(Args)]
+struct InfoArgs {
+    #[clap(short, long, value_parser)]
+    image: PathBuf,
+    /// Show what is needed to open the image
+    #[clap(long)]
+    requirements: bool,
+    /// Key of the image, only needed to dump encrypted images
+    #[cfg(feature = "internals")]
+    #[clap(flatten)]
+    key: KeyArgs,
+    /// Dump the decrypted bytes of the image from this offset
+    #[cfg(feature = "internals")]
+    #[clap(long)]
+    hexdump: Option<u64>,
+    /// Number of bytes to dump
+    #[cfg(feature = "internals")]
+    #[clap(long, default_value = "256")]
+    length: usize,
 }
 
 #[derive(Subcommand)]
 enum Command {
     Create(CreateArgs),
     Extract(ExtractArgs),
+    /// Report how much file data is duplicated across images
+    Dedup(DedupArgs),
+    /// List the paths in an image
+    List(ListArgs),
+    /// Show the header of an image, without needing its key
+    Info(InfoArgs),
+    /// Write the contents of a file in an image to stdout
+    Cat(CatArgs),
+    /// Read a whole image and report what can't be read
+    Verify(VerifyArgs),
+    /// Mount an image read-only with FUSE
+    #[cfg(feature = "fuse")]
+    Mount(MountArgs),
 }
 
-fn create(args: &CreateArgs) -> Result<()> {
-    let key = match args.key {
+fn decode_key(key: &Option<String>) -> Result<Option<Vec<u8>>> {
+    Ok(match key {
         Some(ref s) => Some(hex::decode(s)?),
         None => None,
+    })
+}
+
+// The key of the image with `header`
+fn image_key(header: &ImageHeader, args: &KeyArgs) -> Result<Option<Vec<u8>>> {
+    match &args.passphrase {
+        Some(p) => header.passphrase_key(p.as_bytes()).map(Some),
+        None => decode_key(&args.key),
+    }
+}
+
+fn open_image(image: &Path, key: &KeyArgs) -> Result<FS> {
+    let header = read_header_file(image)?;
+    let key = image_key(&header, key)?;
+    header.encryption_type()?.validate_key(key.as_deref())?;
+    open_image_file(image, key.as_deref())
+}
+
+// Reproducible builds convention to cap stored timestamps
+fn source_date_epoch() -> Option<u64> {
+    std::env::var("SOURCE_DATE_EPOCH").ok()?.trim().parse().ok()
+}
+
+fn create(args: &CreateArgs) -> Result<()> {
+    // A new salt for each image
+    let (key, key_salt) = match &args.key.passphrase {
+        Some(p) => {
+            let salt = new_salt()?;
+            let mut key = derive_key(p.as_bytes(), &salt)?;
+            key.truncate(args.enc_type.key_len());
+            (Some(key), Some(salt))
+        }
+        None => (decode_key(&args.key.key)?, None),
     };
-    write_image_file(&args.source, &args.image, key.as_deref(), args.enc_type)
+    args.enc_type.validate_key(key.as_deref())?;
+    let opts = WriteOptions {
+        warn_case_collisions: args.warn_case_collisions,
+        collation: args.collation,
+        compression: args.compression,
+        compression_level: args.compression_level,
+        subtree_sizes: args.subtree_sizes,
+        deterministic: args.deterministic,
+        clamp_mtime: source_date_epoch(),
+        merkle: args.merkle,
+        dedup_symlinks: args.dedup_symlinks,
+        dedup: args.dedup,
+        xattrs: args.xattrs,
+        checksums: args.checksums,
+        integrity: args.integrity,
+        min_file_size: args.min_file_size,
+        max_file_size: args.max_file_size,
+        encryption_block_size: args.encryption_block_size,
+        key_salt,
+        ..Default::default()
+    };
+    let summary = write_image_file_with(
+        &args.source,
+        &args.image,
+        key.as_deref(),
+        args.enc_type,
+        &opts,
+    )?;
+    for p in &summary.vanished {
+        eprintln!("warning: {} was removed while writing", p.display());
+    }
+    for (a, b) in &summary.case_collisions {
+        eprintln!(
+            "warning: {} and {} differ only in case",
+            a.display(),
+            b.display()
+        );
+    }
+    println!(
+        "{} files, {} directories, {} symlinks, {} bytes of file data, \
+         image is {} bytes",
+        summary.files,
+        summary.dirs,
+        summary.symlinks,
+        summary.total_data_bytes,
+        summary.image_size
+    );
+    if summary.hardlinks != 0 {
+        println!("{} hard links share file data", summary.hardlinks);
+    }
+    if summary.shared_symlinks != 0 {
+        println!("{} symlinks share their target", summary.shared_symlinks);
+    }
+    if summary.dedup_bytes != 0 {
+        println!("{} bytes of duplicate file data", summary.dedup_bytes);
+    }
+    if !summary.size_excluded.is_empty() {
+        println!(
+            "{} files left out because of their size",
+            summary.size_excluded.len()
+        );
+    }
+    Ok(())
 }
 
 fn extract(args: &ExtractArgs) -> Result<()> {
-    let key = match args.key {
-        Some(ref s) => Some(hex::decode(s)?),
-        None => None,
+    let header = read_header_file(&args.image)?;
+    let key = image_key(&header, &args.key)?;
+    header.encryption_type()?.validate_key(key.as_deref())?;
+    let opts = ExtractOptions {
+        overwrite: args.overwrite,
+        preserve_owner: args.preserve_owner,
+        uid_map: args.uid_map.clone(),
+        gid_map: args.gid_map.clone(),
+        unmapped_id: args.unmapped_id,
+        ..Default::default()
+    };
+    let summary = match &args.path {
+        Some(path) => extract_subtree_with(
+            &args.image,
+            path.as_bytes(),
+            &args.target,
+            key.as_deref(),
+            &opts,
+        )?,
+        None => extract_image_file_with(
+            &args.image,
+            &args.target,
+            key.as_deref(),
+            &opts,
+        )?,
+    };
+    if summary.unowned != 0 {
+        eprintln!(
+            "warning: not running as root, the owner of {} entries was not \
+             restored",
+            summary.unowned
+        );
+    }
+    if summary.unset_xattrs != 0 {
+        eprintln!(
+            "warning: {} extended attributes could not be set",
+            summary.unset_xattrs
+        );
+    }
+    if summary.skipped != 0 {
+        println!("skipped {} existing entries", summary.skipped);
+    }
+    Ok(())
+}
+
+fn dedup(args: &DedupArgs) -> Result<()> {
+    let images = args
+        .image
+        .iter()
+        .map(|p| open_image(p, &args.key))
+        .collect::<Result<Vec<_>>>()?;
+    let report = dedup_report(&images.iter().collect::<Vec<_>>())?;
+    println!("{:>10} {:>14} {:>14}  image", "files", "bytes", "shared");
+    for (path, stats) in args.image.iter().zip(&report.images) {
+        println!(
+            "{:>10} {:>14} {:>14}  {}",
+            stats.files,
+            stats.total_bytes,
+            stats.shared_bytes,
+            path.display()
+        );
+    }
+    println!(
+        "total: {} bytes, {} unique, {} could be saved",
+        report.total_bytes,
+        report.unique_bytes,
+        report.savings()
+    );
+    Ok(())
+}
+
+fn type_name(ft: FileType) -> &'static str {
+    if ft.is_file() {
+        "file"
+    } else if ft.is_dir() {
+        "dir"
+    } else if ft.is_symlink() {
+        "symlink"
+    } else if ft.is_char_device() {
+        "char_device"
+    } else if ft.is_block_device() {
+        "block_device"
+    } else if ft.is_fifo() {
+        "fifo"
+    } else {
+        "socket"
+    }
+}
+
+fn list_entry_json(
+    path: &[u8],
+    item: &FSItem,
+    ft: FileType,
+    args: &ListArgs,
+) -> Result<Value> {
+    let mut obj = Map::new();
+    insert_bytes(&mut obj, "path", path);
+    obj.insert("type".into(), type_name(ft).into());
+    match item {
+        FSItem::File(f) => {
+            obj.insert("size".into(), f.size().into());
+            if args.detect_type {
+                obj.insert("content_type".into(), f.detect_type()?.into());
+            }
+        }
+        FSItem::Symlink(s) => insert_bytes(&mut obj, "target", &s.get_link()?),
+        FSItem::Special(s) => {
+            if let Some((major, minor)) = s.rdev() {
+                obj.insert("major".into(), major.into());
+                obj.insert("minor".into(), minor.into());
+            }
+        }
+        FSItem::Directory(_) => (),
+    }
+    Ok(obj.into())
+}
+
+fn list(args: &ListArgs, format: OutputFormat) -> Result<()> {
+    if args.null && format == OutputFormat::Json {
+        return Err(Error::InvalidOperation("--null is only for plain output"));
+    }
+    let fs = open_image(&args.image, &args.key)?;
+    let dir = match args.start {
+        None => fs.get_root()?,
+        Some(ref p) => match fs.resolve(p)? {
+            Some(FSItem::Directory(d)) => d,
+            Some(_) => {
+                return Err(Error::InvalidOperation("start is not a directory"))
+            }
+            None => return Err(Error::InvalidOperation("start not found")),
+        },
+    };
+    let mut out = std::io::stdout().lock();
+    let mut first = true;
+    if format == OutputFormat::Json {
+        out.write_all(b"[")?;
+    }
+    for e in dir.walk() {
+        let (path, ent) = e?;
+        if let Some(ty) = args.ty {
+            if !type_matches(ty, ent.file_type()?) {
+                continue;
+            }
+        }
+        // One entry per line, written as we go
+        if format == OutputFormat::Json {
+            let json =
+                list_entry_json(&path, &ent.item()?, ent.file_type()?, args)?;
+            write!(out, "{}\n{}", if first { "" } else { "," }, json)?;
+            first = false;
+            continue;
+        }
+        if args.null {
+            out.write_all(&path)?;
+            out.write_all(b"\0")?;
+            continue;
+        }
+        let item = ent.item()?;
+        if args.long {
+            match item {
+                FSItem::File(ref f) => write!(out, "{:>12} ", f.size())?,
+                _ => write!(out, "{:>12} ", "-")?,
+            }
+        }
+        if args.detect_type {
+            let ty = match item {
+                FSItem::File(ref f) => f.detect_type()?,
+                _ => None,
+            };
+            write!(out, "{:<24} ", ty.unwrap_or("-"))?;
+        }
+        out.write_all(&path)?;
+        match item {
+            FSItem::Directory(_) => out.write_all(b"/")?,
+            FSItem::Symlink(s) => {
+                out.write_all(b" -> ")?;
+                out.write_all(&s.get_link()?)?;
+            }
+            FSItem::File(_) | FSItem::Special(_) => (),
+        }
+        out.write_all(b"\n")?;
+    }
+    if format == OutputFormat::Json {
+        out.write_all(b"\n]\n")?;
+    }
+    Ok(())
+}
+
+fn format_uuid(uuid: &[u8; 16]) -> String {
+    let h = hex::encode(uuid);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &h[..8],
+        &h[8..12],
+        &h[12..16],
+        &h[16..20],
+        &h[20..]
+    )
+}
+
+fn info_json(args: &InfoArgs) -> Result<()> {
+    let header = read_header_file(&args.image)?;
+    let compression = match header.compression_type() {
+        Ok(ty) => Some(ty.name()),
+        Err(_) => None,
+    };
+    let encryption = match header.encryption_type() {
+        Ok(ty) => Some(ty.name()),
+        Err(_) => None,
     };
-    extract_image_file(&args.image, &args.target, key.as_deref())
+    let mut obj = json!({
+        "magic": String::from_utf8_lossy(&header.magic()),
+        "magic_valid": header.magic_valid(),
+        "version": {
+            "major": header.version_major(),
+            "minor": header.version_minor(),
+        },
+        "compression": compression,
+        "encryption": encryption,
+        "root_inode": header.root_inode(),
+        "incompatible_features": header.incompat_features(),
+        "compatible_features": header.compat_features(),
+        "uuid": header.uuid().map(|u| format_uuid(&u)),
+        "root_hash": header.root_hash().map(hex::encode),
+        "digest": header.digest().map(hex::encode),
+        "key_salt": header.key_salt().map(hex::encode),
+    });
+    if args.requirements {
+        let reqs: Vec<_> = probe_image_file(&args.image)?
+            .into_iter()
+            .map(|r| json!({"name": r.name, "available": r.available}))
+            .collect();
+        obj["requirements"] = reqs.into();
+    }
+    println!("{}", obj);
+    Ok(())
+}
+
+fn info(args: &InfoArgs, format: OutputFormat) -> Result<()> {
+    if format == OutputFormat::Json {
+        return info_json(args);
+    }
+    let header = read_header_file(&args.image)?;
+    let magic = header.magic();
+    println!(
+        "magic: {}{}",
+        String::from_utf8_lossy(&magic),
+        if header.magic_valid() {
+            ""
+        } else {
+            " (invalid)"
+        }
+    );
+    println!(
+        "version: {}.{}",
+        header.version_major(),
+        header.version_minor()
+    );
+    // The header is never encrypted so all of this works without a key
+    match header.compression_type() {
+        Ok(ty) => println!("compression: {}", ty),
+        Err(_) => println!("compression: unknown"),
+    }
+    match header.encryption_type() {
+        Ok(ty) => println!("encryption: {}", ty),
+        Err(_) => println!("encryption: unknown"),
+    }
+    println!("root inode: {}", header.root_inode());
+    println!("incompatible features: {:#x}", header.incompat_features());
+    println!("compatible features: {:#x}", header.compat_features());
+    match header.uuid() {
+        Some(uuid) => println!("uuid: {}", format_uuid(&uuid)),
+        None => println!("uuid: none"),
+    }
+    match header.root_hash() {
+        Some(hash) => println!("root hash: {}", hex::encode(hash)),
+        None => println!("root hash: none"),
+    }
+    match header.digest() {
+        Some(digest) => println!("digest: {}", hex::encode(digest)),
+        None => println!("digest: none"),
+    }
+    match header.key_salt() {
+        Some(salt) => println!("key salt: {}", hex::encode(salt)),
+        None => println!("key salt: none"),
+    }
+    if args.requirements {
+        let reqs = probe_image_file(&args.image)?;
+        if reqs.is_empty() {
+            println!("requires: nothing");
+        } else {
+            let reqs: Vec<_> = reqs.iter().map(|r| r.to_string()).collect();
+            println!("requires: {}", reqs.join(", "));
+        }
+    }
+    #[cfg(feature = "internals")]
+    if let Some(offset) = args.hexdump {
+        let fs = open_image(&args.image, &args.key)?;
+        hexdump(offset, &fs.read_raw(offset, args.length)?);
+    }
+    Ok(())
+}
+
+#[cfg(feature = "internals")]
+fn hexdump(offset: u64, data: &[u8]) {
+    for (i, line) in data.chunks(16).enumerate() {
+        let hex: Vec<_> = line.iter().map(|b| format!("{:02x}", b)).collect();
+        let text: String = line
+            .iter()
+            .map(|&b| if b.is_ascii_graphic() { b as char } else { '.' })
+            .collect();
+        println!(
+            "{:08x}  {:<47}  |{}|",
+            offset + 16 * i as u64,
+            hex.join(" "),
+            text
+        );
+    }
+}
+
+#[cfg(feature = "fuse")]
+fn mount(args: &MountArgs) -> Result<()> {
+    let fs = open_image(&args.image, &args.key)?;
+    libsquash::mount(&fs, &args.target)
+}
+
+fn cat(args: &CatArgs) -> Result<()> {
+    let fs = open_image(&args.image, &args.key)?;
+    let file = match fs.resolve(args.path.as_bytes())? {
+        Some(FSItem::File(f)) => f,
+        Some(FSItem::Directory(_)) => {
+            return Err(Error::InvalidOperation("path is a directory"))
+        }
+        Some(_) => return Err(Error::InvalidOperation("path is not a file")),
+        None => return Err(Error::InvalidOperation("path not found")),
+    };
+    std::io::copy(&mut file.reader(), &mut std::io::stdout().lock())?;
+    Ok(())
+}
+
+// How many failures verify prints
+const VERIFY_MAX_SHOWN: usize = 10;
+
+fn verify_path(
+    fs: &FS,
+    path: &str,
+    hash: &str,
+    format: OutputFormat,
+) -> Result<()> {
+    let ok = fs.verify_file(path.as_bytes(), &hex::decode(hash)?)?;
+    if format == OutputFormat::Json {
+        println!("{}", json!({"path": path, "ok": ok}));
+    } else if ok {
+        println!("{}: OK", path);
+    }
+    if !ok {
+        return Err(Error::Format("the file does not match the hash"));
+    }
+    Ok(())
+}
+
+fn verify_digest(image: &Path, format: OutputFormat) -> Result<()> {
+    let ok = verify_integrity(&std::fs::File::open(image)?)?;
+    if format == OutputFormat::Json {
+        println!("{}", json!({ "ok": ok }));
+    } else if ok {
+        println!("digest OK");
+    }
+    if !ok {
+        return Err(Error::Format("the image does not match its digest"));
+    }
+    Ok(())
+}
+
+fn verify_image(args: &VerifyArgs, format: OutputFormat) -> Result<()> {
+    if args.integrity {
+        return verify_digest(&args.image, format);
+    }
+    let fs = open_image(&args.image, &args.key)?;
+    if let (Some(path), Some(hash)) = (&args.path, &args.hash) {
+        return verify_path(&fs, path, hash, format);
+    }
+    let report = verify(&fs)?;
+    if format == OutputFormat::Json {
+        // All the failures, unlike plain output
+        let failures: Vec<_> = report
+            .failures
+            .iter()
+            .map(|(path, e)| {
+                let mut obj = Map::new();
+                insert_bytes(&mut obj, "path", path);
+                obj.insert("error".into(), e.to_string().into());
+                Value::from(obj)
+            })
+            .collect();
+        let obj = json!({
+            "ok": report.is_ok(),
+            "entries": report.entries,
+            "failures": failures,
+        });
+        println!("{}", obj);
+        if !report.is_ok() {
+            return Err(Error::Format("the image is corrupt"));
+        }
+        return Ok(());
+    }
+    for (path, e) in report.failures.iter().take(VERIFY_MAX_SHOWN) {
+        eprintln!("{}: {}", String::from_utf8_lossy(path), e);
+    }
+    if report.failures.len() > VERIFY_MAX_SHOWN {
+        eprintln!("... and {} more", report.failures.len() - VERIFY_MAX_SHOWN);
+    }
+    if !report.is_ok() {
+        return Err(Error::Format("the image is corrupt"));
+    }
+    println!("{} entries OK", report.entries);
+    Ok(())
 }
 
 fn main() -> Result<()> {
@@ -71,5 +842,12 @@ fn main() -> Result<()> {
     match &cli.command {
         Command::Create(args) => create(args),
         Command::Extract(args) => extract(args),
+        Command::Dedup(args) => dedup(args),
+        Command::List(args) => list(args, cli.format),
+        Command::Info(args) => info(args, cli.format),
+        Command::Cat(args) => cat(args),
+        Command::Verify(args) => verify_image(args, cli.format),
+        #[cfg(feature = "fuse")]
+        Command::Mount(args) => mount(args),
     }
 }
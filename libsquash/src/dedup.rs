@@ -0,0 +1,85 @@
+// Statistics about duplicated file contents across images
+
+use crate::error::Error;
+use crate::fs;
+
+use std::collections::HashMap;
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Duplication statistics for one image.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct ImageDedup {
+    /// Number of regular files
+    pub files: u64,
+    /// Total size of the contents of regular files
+    pub total_bytes: u64,
+    /// Size of the contents of files whose data is also present in
+    /// another file, in this image or another.
+    pub shared_bytes: u64,
+}
+
+/// Duplication statistics across a set of images.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct DedupReport {
+    /// Total size of the contents of all regular files
+    pub total_bytes: u64,
+    /// Size of the distinct file contents, which is what would be
+    /// stored if data was shared.
+    pub unique_bytes: u64,
+    /// Per-image statistics, in the order the images were given
+    pub images: Vec<ImageDedup>,
+}
+
+impl DedupReport {
+    /// Number of bytes that sharing data would save.
+    pub fn savings(&self) -> u64 {
+        self.total_bytes - self.unique_bytes
+    }
+}
+
+fn collect_digests(
+    dir: &fs::Directory,
+    out: &mut Vec<([u8; 32], u64)>,
+) -> Result<()> {
+    for e in dir.walk() {
+        if let fs::FSItem::File(f) = e?.1.item()? {
+            out.push((f.digest()?, f.size()));
+        }
+    }
+    Ok(())
+}
+
+/// Hash every file of `images` and report how much of their data is
+/// duplicated.
+pub fn dedup_report(images: &[&fs::FS]) -> Result<DedupReport> {
+    let mut per_image = Vec::with_capacity(images.len());
+    // Number of files with each content
+    let mut counts: HashMap<[u8; 32], (u64, u64)> = HashMap::new();
+    for img in images {
+        let mut digests = Vec::new();
+        collect_digests(&img.get_root()?, &mut digests)?;
+        for (digest, size) in &digests {
+            counts.entry(*digest).or_insert((0, *size)).0 += 1;
+        }
+        per_image.push(digests);
+    }
+
+    let mut report = DedupReport {
+        unique_bytes: counts.values().map(|(_, size)| size).sum(),
+        ..Default::default()
+    };
+    for digests in per_image {
+        let mut stats = ImageDedup::default();
+        for (digest, size) in digests {
+            stats.files += 1;
+            stats.total_bytes += size;
+            if counts[&digest].0 > 1 {
+                stats.shared_bytes += size;
+            }
+        }
+        report.total_bytes += stats.total_bytes;
+        report.images.push(stats);
+    }
+    Ok(report)
+}
@@ -0,0 +1,599 @@
+// Authenticated encryption with XChaCha20-Poly1305
+//
+// Everything after the header is split in blocks of the block size of
+// the image, BLOCK_SIZE by default (the last one can be shorter), that
+// are encrypted independently. Each block is stored as its 24 bytes
+// nonce, the ciphertext and the 16 bytes tag, so offsets in the image
+// are those of the plaintext and are mapped to stored blocks here. The associated data is the stream
+// (metadata or the data of split images) and the number of the block,
+// so that blocks can't be moved around.
+//
+// The nonce is a keyed hash of the same and of the plaintext, which
+// makes images as deterministic as without encryption and gives a new
+// nonce whenever a block is rewritten with other contents.
+
+use crate::disk::ReadAt;
+use crate::error::Error;
+use crate::Result;
+
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use chacha20poly1305::{Tag, XChaCha20Poly1305, XNonce};
+
+use std::cmp::{max, min};
+use std::io::{self, Seek, SeekFrom, Write};
+
+/// Length of an XChaCha20-Poly1305 key
+pub const XCHACHA20_POLY1305_KEY_LEN: usize = 32;
+
+/// Default plaintext size of a block
+pub const BLOCK_SIZE: u64 = 4096;
+/// Smallest plaintext size of a block
+pub const MIN_BLOCK_SIZE: u64 = 512;
+/// Largest plaintext size of a block
+pub const MAX_BLOCK_SIZE: u64 = 1 << 20;
+
+const NONCE_SIZE: u64 = 24;
+const TAG_SIZE: u64 = 16;
+
+/// Whether blocks can have `size` bytes of plaintext: a power of two
+/// from MIN_BLOCK_SIZE to MAX_BLOCK_SIZE.
+pub fn valid_block_size(size: u64) -> bool {
+    size.is_power_of_two() && (MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&size)
+}
+
+// See crypto::CHACHA20_DATA_STREAM
+const DATA_STREAM: u64 = 1;
+
+const NONCE_CONTEXT: &str = "squashfile 2024 xchacha20poly1305 nonce";
+
+struct Blocks {
+    cipher: XChaCha20Poly1305,
+    nonce_key: [u8; 32],
+    stream: u64,
+    // Offset of the first block, the size of the header
+    base: u64,
+    // Plaintext size of a block
+    size: u64,
+}
+
+impl Blocks {
+    fn new(
+        key: Option<&[u8]>,
+        stream: u64,
+        base: u64,
+        size: u64,
+    ) -> Result<Self> {
+        if !valid_block_size(size) {
+            return Err(Error::Format("invalid encryption block size"));
+        }
+        let key = key.ok_or(Error::KeyRequired)?;
+        if key.len() != XCHACHA20_POLY1305_KEY_LEN {
+            return Err(Error::InvalidKeyLength {
+                cipher: "xchacha20poly1305",
+                expected: XCHACHA20_POLY1305_KEY_LEN,
+                found: key.len(),
+            });
+        }
+        Ok(Blocks {
+            cipher: XChaCha20Poly1305::new_from_slice(key)
+                .map_err(|_| Error::Crypto("Invalid key"))?,
+            nonce_key: blake3::derive_key(NONCE_CONTEXT, key),
+            stream,
+            base,
+            size,
+        })
+    }
+
+    // Stored size of a full block
+    fn stored_size(&self) -> u64 {
+        NONCE_SIZE + self.size + TAG_SIZE
+    }
+
+    fn stored_offset(&self, block: u64) -> u64 {
+        self.base + block * self.stored_size()
+    }
+
+    fn associated_data(&self, block: u64) -> [u8; 16] {
+        let mut res = [0; 16];
+        res[..8].copy_from_slice(&self.stream.to_le_bytes());
+        res[8..].copy_from_slice(&block.to_le_bytes());
+        res
+    }
+
+    // Stored form of `plain`
+    fn seal(&self, block: u64, plain: &[u8]) -> Result<Vec<u8>> {
+        let ad = self.associated_data(block);
+        let mut hasher = blake3::Hasher::new_keyed(&self.nonce_key);
+        hasher.update(&ad);
+        hasher.update(plain);
+        let nonce = *XNonce::from_slice(
+            &hasher.finalize().as_bytes()[..NONCE_SIZE as usize],
+        );
+        let mut res = nonce.to_vec();
+        res.extend_from_slice(plain);
+        let tag = self
+            .cipher
+            .encrypt_in_place_detached(
+                &nonce,
+                &ad,
+                &mut res[NONCE_SIZE as usize..],
+            )
+            .map_err(|_| Error::Crypto("Encrypting error"))?;
+        res.extend_from_slice(&tag);
+        Ok(res)
+    }
+
+    // Plaintext of the stored `data`
+    fn open(&self, block: u64, data: &[u8]) -> Result<Vec<u8>> {
+        if (data.len() as u64) < NONCE_SIZE + TAG_SIZE {
+            return Err(Error::Crypto("truncated block"));
+        }
+        let (nonce, rest) = data.split_at(NONCE_SIZE as usize);
+        let (ciphertext, tag) = rest.split_at(rest.len() - TAG_SIZE as usize);
+        let mut plain = ciphertext.to_vec();
+        self.cipher
+            .decrypt_in_place_detached(
+                XNonce::from_slice(nonce),
+                &self.associated_data(block),
+                &mut plain,
+                Tag::from_slice(tag),
+            )
+            .map_err(|_| Error::Crypto("authentication failed"))?;
+        Ok(plain)
+    }
+
+    // Plaintext of a block, empty past the end
+    fn read(&self, file: &dyn ReadAt, block: u64) -> Result<Vec<u8>> {
+        let mut buf = vec![0; self.stored_size() as usize];
+        let offset = self.stored_offset(block);
+        let mut sz = 0;
+        while sz < buf.len() {
+            match file.read_at(&mut buf[sz..], offset + sz as u64) {
+                Ok(0) => break,
+                Ok(n) => sz += n,
+                Err(Error::IO(ref e))
+                    if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if sz == 0 {
+            return Ok(Vec::new());
+        }
+        self.open(block, &buf[..sz])
+    }
+}
+
+/// Decrypts and checks an image on reads.
+pub struct DecryptXChaCha20Poly1305<F> {
+    f: F,
+    blocks: Blocks,
+}
+
+impl<F> DecryptXChaCha20Poly1305<F> {
+    /// Blocks of `size` bytes of plaintext start at `base`, the size
+    /// of the header.
+    pub fn new(f: F, key: Option<&[u8]>, base: u64, size: u64) -> Result<Self> {
+        Ok(DecryptXChaCha20Poly1305 {
+            f,
+            blocks: Blocks::new(key, 0, base, size)?,
+        })
+    }
+
+    /// Like `new` but for the data stream of a split image.
+    pub fn new_data(
+        f: F,
+        key: Option<&[u8]>,
+        base: u64,
+        size: u64,
+    ) -> Result<Self> {
+        Ok(DecryptXChaCha20Poly1305 {
+            f,
+            blocks: Blocks::new(key, DATA_STREAM, base, size)?,
+        })
+    }
+}
+
+impl<F: ReadAt> ReadAt for DecryptXChaCha20Poly1305<F> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        let base = self.blocks.base;
+        if offset < base {
+            return Err(Error::Bounds("offset inside the header"));
+        }
+        let mut sz = 0;
+        while sz < buf.len() {
+            let pos = offset - base + sz as u64;
+            let within = (pos % self.blocks.size) as usize;
+            let plain = self.blocks.read(&self.f, pos / self.blocks.size)?;
+            if within >= plain.len() {
+                break;
+            }
+            let n = min(buf.len() - sz, plain.len() - within);
+            buf[sz..sz + n].copy_from_slice(&plain[within..within + n]);
+            sz += n;
+        }
+        Ok(sz)
+    }
+
+    fn stream_len(&self) -> Option<u64> {
+        let stored = self.f.stream_len()?;
+        let base = self.blocks.base;
+        let rest = stored.saturating_sub(base);
+        let full = self.blocks.stored_size();
+        let last = (rest % full).saturating_sub(NONCE_SIZE + TAG_SIZE);
+        Some(base + rest / full * self.blocks.size + last)
+    }
+}
+
+/// Encrypts what is written to it, block by block.
+///
+/// Blocks are written once left, so writing again in a block that was
+/// written before reads it back from `readback`, which must read what
+/// was written to the output. Call `flush` once done.
+pub struct EncryptXChaCha20Poly1305<'a, F> {
+    f: F,
+    blocks: Blocks,
+    readback: Option<&'a dyn ReadAt>,
+    pos: u64,
+    // End of what was written
+    len: u64,
+    // The block being written, its plaintext and whether it changed
+    current: Option<(u64, Vec<u8>, bool)>,
+}
+
+impl<'a, F: Write + Seek> EncryptXChaCha20Poly1305<'a, F> {
+    /// Blocks of `size` bytes of plaintext start at the current
+    /// position of `f`.
+    pub fn new(
+        mut f: F,
+        key: Option<&[u8]>,
+        readback: Option<&'a dyn ReadAt>,
+        size: u64,
+    ) -> Result<Self> {
+        let base = f.stream_position()?;
+        Ok(EncryptXChaCha20Poly1305 {
+            f,
+            blocks: Blocks::new(key, 0, base, size)?,
+            readback,
+            pos: base,
+            len: base,
+            current: None,
+        })
+    }
+
+    /// Like `new` for the data stream of a split image, which is only
+    /// ever appended to.
+    pub fn new_data(mut f: F, key: Option<&[u8]>, size: u64) -> Result<Self> {
+        let base = f.stream_position()?;
+        Ok(EncryptXChaCha20Poly1305 {
+            f,
+            blocks: Blocks::new(key, DATA_STREAM, base, size)?,
+            readback: None,
+            pos: base,
+            len: base,
+            current: None,
+        })
+    }
+
+    fn write_current(&mut self) -> Result<()> {
+        if let Some((block, plain, true)) = &self.current {
+            let stored = self.blocks.seal(*block, plain)?;
+            self.f
+                .seek(SeekFrom::Start(self.blocks.stored_offset(*block)))?;
+            self.f.write_all(&stored)?;
+        }
+        if let Some(current) = self.current.as_mut() {
+            current.2 = false;
+        }
+        Ok(())
+    }
+
+    // Make `block` the current one
+    fn load(&mut self, block: u64) -> Result<()> {
+        if self.current.as_ref().map(|c| c.0) == Some(block) {
+            return Ok(());
+        }
+        self.write_current()?;
+        let start = self.blocks.base + block * self.blocks.size;
+        let plain = if start < self.len {
+            let readback = self.readback.ok_or(Error::InvalidOperation(
+                "can't read back encrypted blocks",
+            ))?;
+            self.f.flush()?;
+            self.blocks.read(readback, block)?
+        } else {
+            Vec::new()
+        };
+        self.current = Some((block, plain, false));
+        Ok(())
+    }
+
+    fn write_block(&mut self, buf: &[u8]) -> Result<usize> {
+        let base = self.blocks.base;
+        if self.pos < base {
+            return Err(Error::Bounds("offset inside the header"));
+        }
+        let size = self.blocks.size;
+        let block = (self.pos - base) / size;
+        let within = ((self.pos - base) % size) as usize;
+        self.load(block)?;
+        let (_, plain, dirty) = self.current.as_mut().unwrap();
+        let n = min(buf.len(), size as usize - within);
+        if plain.len() < within + n {
+            plain.resize(within + n, 0);
+        }
+        plain[within..within + n].copy_from_slice(&buf[..n]);
+        *dirty = true;
+        self.pos += n as u64;
+        self.len = max(self.len, self.pos);
+        Ok(n)
+    }
+}
+
+fn io_error(e: Error) -> io::Error {
+    match e {
+        Error::IO(e) => e,
+        e => io::Error::other(e),
+    }
+}
+
+impl<'a, F: Write + Seek> Write for EncryptXChaCha20Poly1305<'a, F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Fill gaps with zeros so that every block before is stored
+        let target = self.pos;
+        self.pos = min(self.pos, self.len);
+        while self.pos < target {
+            let n = min(target - self.pos, self.blocks.size) as usize;
+            self.write_block(&vec![0; n]).map_err(io_error)?;
+        }
+        self.write_block(buf).map_err(io_error)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_current().map_err(io_error)?;
+        self.f.flush()
+    }
+}
+
+impl<'a, F: Write + Seek> Seek for EncryptXChaCha20Poly1305<'a, F> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::Current(d) => self.pos.checked_add_signed(d),
+            SeekFrom::End(d) => self.len.checked_add_signed(d),
+        };
+        self.pos = new.ok_or(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid seek",
+        ))?;
+        Ok(self.pos)
+    }
+}
+
+// Stored size of a full block of the default size
+#[cfg(test)]
+const STORED_SIZE: u64 = NONCE_SIZE + BLOCK_SIZE + TAG_SIZE;
+
+#[cfg(test)]
+const TEST_KEY: [u8; XCHACHA20_POLY1305_KEY_LEN] = [7; 32];
+
+#[cfg(test)]
+fn test_data(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+#[test]
+fn test_aead_roundtrip() {
+    use std::io::Cursor;
+
+    let data = test_data(3 * BLOCK_SIZE as usize + 100);
+    let mut out = Cursor::new(vec![0; 10]);
+    out.seek(SeekFrom::Start(10)).unwrap();
+    let mut enc = EncryptXChaCha20Poly1305::new(
+        &mut out,
+        Some(&TEST_KEY),
+        None,
+        BLOCK_SIZE,
+    )
+    .unwrap();
+    enc.write_all(&data).unwrap();
+    enc.flush().unwrap();
+    drop(enc);
+    let stored = out.into_inner();
+    assert_eq!(stored.len() as u64, 10 + 3 * STORED_SIZE + 140);
+
+    let dec = DecryptXChaCha20Poly1305::new(
+        Cursor::new(&stored),
+        Some(&TEST_KEY),
+        10,
+        BLOCK_SIZE,
+    )
+    .unwrap();
+    assert_eq!(dec.stream_len(), Some(10 + data.len() as u64));
+    let mut buf = vec![0; data.len()];
+    dec.read_exact_at(&mut buf, 10).unwrap();
+    assert_eq!(buf, data);
+    let mut buf = vec![0; 200];
+    assert_eq!(dec.read_at(&mut buf, 10 + 3 * BLOCK_SIZE).unwrap(), 100);
+    assert_eq!(buf[..100], data[3 * BLOCK_SIZE as usize..]);
+
+    // Same plaintext, same ciphertext
+    let mut again = Cursor::new(vec![0; 10]);
+    again.seek(SeekFrom::Start(10)).unwrap();
+    let mut enc = EncryptXChaCha20Poly1305::new(
+        &mut again,
+        Some(&TEST_KEY),
+        None,
+        BLOCK_SIZE,
+    )
+    .unwrap();
+    enc.write_all(&data).unwrap();
+    enc.flush().unwrap();
+    drop(enc);
+    assert_eq!(again.into_inner(), stored);
+}
+
+#[test]
+fn test_aead_rewrite() {
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+
+    // Output that can be read while it is written
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Cursor<Vec<u8>>>>);
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    impl Seek for Shared {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.0.lock().unwrap().seek(pos)
+        }
+    }
+    impl ReadAt for Shared {
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+            self.0.lock().unwrap().read_at(buf, offset)
+        }
+    }
+
+    let out = Shared::default();
+    let mut data = test_data(2 * BLOCK_SIZE as usize + 10);
+    let mut enc = EncryptXChaCha20Poly1305::new(
+        out.clone(),
+        Some(&TEST_KEY),
+        Some(&out),
+        BLOCK_SIZE,
+    )
+    .unwrap();
+    enc.write_all(&data).unwrap();
+    // Back in the first block, then past the end
+    enc.seek(SeekFrom::Start(5)).unwrap();
+    enc.write_all(b"patch").unwrap();
+    data[5..10].copy_from_slice(b"patch");
+    enc.seek(SeekFrom::Start(3 * BLOCK_SIZE)).unwrap();
+    enc.write_all(b"end").unwrap();
+    data.resize(3 * BLOCK_SIZE as usize, 0);
+    data.extend_from_slice(b"end");
+    enc.flush().unwrap();
+    drop(enc);
+
+    let stored = out.0.lock().unwrap().get_ref().clone();
+    let dec = DecryptXChaCha20Poly1305::new(
+        Cursor::new(&stored),
+        Some(&TEST_KEY),
+        0,
+        BLOCK_SIZE,
+    )
+    .unwrap();
+    let mut buf = vec![0; data.len()];
+    dec.read_exact_at(&mut buf, 0).unwrap();
+    assert_eq!(buf, data);
+
+    // Without a way to read back
+    let mut enc = EncryptXChaCha20Poly1305::new(
+        Cursor::new(Vec::new()),
+        Some(&TEST_KEY),
+        None,
+        BLOCK_SIZE,
+    )
+    .unwrap();
+    enc.write_all(&data).unwrap();
+    enc.seek(SeekFrom::Start(0)).unwrap();
+    assert!(enc.write_all(b"x").is_err());
+}
+
+#[test]
+fn test_aead_tampering() {
+    use std::io::Cursor;
+
+    let data = test_data(2 * BLOCK_SIZE as usize);
+    let mut out = Cursor::new(Vec::new());
+    let mut enc = EncryptXChaCha20Poly1305::new(
+        &mut out,
+        Some(&TEST_KEY),
+        None,
+        BLOCK_SIZE,
+    )
+    .unwrap();
+    enc.write_all(&data).unwrap();
+    enc.flush().unwrap();
+    drop(enc);
+    let stored = out.into_inner();
+    let read = |stored: &[u8], key: &[u8]| {
+        let dec = DecryptXChaCha20Poly1305::new(
+            Cursor::new(stored),
+            Some(key),
+            0,
+            BLOCK_SIZE,
+        )
+        .unwrap();
+        let mut buf = vec![0; 10];
+        dec.read_exact_at(&mut buf, BLOCK_SIZE)
+    };
+    assert!(read(&stored, &TEST_KEY).is_ok());
+    assert!(matches!(read(&stored, &[8; 32]), Err(Error::Crypto(_))));
+
+    // Flipped bit in the ciphertext
+    let mut bad = stored.clone();
+    bad[(STORED_SIZE + NONCE_SIZE + 1) as usize] ^= 1;
+    assert!(matches!(read(&bad, &TEST_KEY), Err(Error::Crypto(_))));
+    // Swapped blocks
+    let (first, second) = stored.split_at(STORED_SIZE as usize);
+    let swapped = [second, first].concat();
+    assert!(matches!(read(&swapped, &TEST_KEY), Err(Error::Crypto(_))));
+}
+
+#[test]
+fn test_aead_block_size() {
+    use std::io::Cursor;
+
+    assert!(valid_block_size(BLOCK_SIZE));
+    for size in [0, 256, 1000, 2 * MAX_BLOCK_SIZE] {
+        assert!(!valid_block_size(size));
+        assert!(matches!(
+            DecryptXChaCha20Poly1305::new((), Some(&TEST_KEY), 0, size),
+            Err(Error::Format(_))
+        ));
+    }
+
+    let data = test_data(3 * MIN_BLOCK_SIZE as usize + 7);
+    let mut out = Cursor::new(Vec::new());
+    let mut enc = EncryptXChaCha20Poly1305::new(
+        &mut out,
+        Some(&TEST_KEY),
+        None,
+        MIN_BLOCK_SIZE,
+    )
+    .unwrap();
+    enc.write_all(&data).unwrap();
+    enc.flush().unwrap();
+    drop(enc);
+    let stored = out.into_inner();
+    assert_eq!(stored.len() as u64, data.len() as u64 + 4 * 40);
+    let dec = DecryptXChaCha20Poly1305::new(
+        Cursor::new(&stored),
+        Some(&TEST_KEY),
+        0,
+        MIN_BLOCK_SIZE,
+    )
+    .unwrap();
+    assert_eq!(dec.stream_len(), Some(data.len() as u64));
+    let mut buf = vec![0; data.len()];
+    dec.read_exact_at(&mut buf, 0).unwrap();
+    assert_eq!(buf, data);
+    // Read as blocks of another size
+    let dec = DecryptXChaCha20Poly1305::new(
+        Cursor::new(&stored),
+        Some(&TEST_KEY),
+        0,
+        BLOCK_SIZE,
+    )
+    .unwrap();
+    assert!(matches!(
+        dec.read_exact_at(&mut buf[..10], 0),
+        Err(Error::Crypto(_))
+    ));
+}
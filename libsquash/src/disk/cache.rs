@@ -0,0 +1,55 @@
+// Least recently used caches of what was read from an image
+//
+// Images never change once written so entries never go stale, they
+// are only evicted to stay within the capacity.
+
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+/// Default number of entries of each cache of an image.
+pub const CACHE_CAPACITY: usize = 4096;
+
+pub struct Lru<K, V> {
+    capacity: usize,
+    // Values with the time they were last used
+    map: HashMap<K, (V, u64)>,
+    // Keys by the time they were last used, oldest first
+    order: BTreeMap<u64, K>,
+    clock: u64,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> Lru<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        Lru {
+            capacity,
+            map: HashMap::new(),
+            order: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<V> {
+        let (value, used) = self.map.get_mut(key)?;
+        self.order.remove(used);
+        self.clock += 1;
+        *used = self.clock;
+        self.order.insert(self.clock, key.clone());
+        Some(value.clone())
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.clock += 1;
+        if let Some((_, used)) =
+            self.map.insert(key.clone(), (value, self.clock))
+        {
+            self.order.remove(&used);
+        } else if self.map.len() > self.capacity {
+            let (_, oldest) = self.order.pop_first().unwrap();
+            self.map.remove(&oldest);
+        }
+        self.order.insert(self.clock, key);
+    }
+}
@@ -0,0 +1,257 @@
+// Compression of file data
+//
+// Compressed file data is split in blocks of BLOCK_SIZE bytes (the last
+// one can be shorter) that are compressed independently so that a read
+// only has to decompress the blocks it overlaps. The blocks are
+// followed by a table of the n + 1 image offsets of their boundaries
+// (as u64le) and the inode points to that table.
+//
+// Uncompressed data is a single block spanning the whole file, with the
+// same table of its boundaries. Images without INCOMPAT_BLOCK_TABLES
+// have no table for uncompressed data and are read directly.
+
+use crate::disk::{add_offset, check_end, CompressionType, ReadAt};
+use crate::error::Error;
+use crate::Result;
+
+use std::cmp::min;
+use std::io::{self, Read, Seek, Write};
+use std::ops::Range;
+
+pub const DEFAULT_LEVEL: i32 = 3;
+
+/// Uncompressed size of a block
+pub const BLOCK_SIZE: u64 = 128 * 1024;
+
+const ENTRY_SIZE: u64 = std::mem::size_of::<u64>() as u64;
+
+pub fn check_supported(ty: CompressionType) -> Result<()> {
+    match ty {
+        CompressionType::None => Ok(()),
+        CompressionType::Zstd if cfg!(feature = "zstd") => Ok(()),
+        CompressionType::Zstd => {
+            Err(Error::Compression("zstd support is not enabled"))
+        }
+    }
+}
+
+fn compress_block(
+    ty: CompressionType,
+    level: i32,
+    data: &[u8],
+) -> Result<Vec<u8>> {
+    match ty {
+        CompressionType::None => Ok(data.to_vec()),
+        #[cfg(feature = "zstd")]
+        CompressionType::Zstd => Ok(zstd::bulk::compress(data, level)?),
+        #[cfg(not(feature = "zstd"))]
+        CompressionType::Zstd => {
+            let _ = level;
+            check_supported(ty).map(|_| Vec::new())
+        }
+    }
+}
+
+fn decompress_block(
+    ty: CompressionType,
+    data: &[u8],
+    size: usize,
+) -> Result<Vec<u8>> {
+    let res = match ty {
+        CompressionType::None => data.to_vec(),
+        // Leave room for one more byte to tell a block that is too
+        // large apart from a corrupt one
+        #[cfg(feature = "zstd")]
+        CompressionType::Zstd => zstd::bulk::decompress(data, size + 1)
+            .map_err(|_| Error::Compression("invalid compressed data"))?,
+        #[cfg(not(feature = "zstd"))]
+        CompressionType::Zstd => {
+            return check_supported(ty).map(|_| Vec::new())
+        }
+    };
+    if res.len() != size {
+        return Err(Error::Compression("size mismatch"));
+    }
+    Ok(res)
+}
+
+// Largest valid compressed size of a block, to avoid allocating
+// whatever a corrupt table says.
+fn max_stored_block(ty: CompressionType) -> u64 {
+    match ty {
+        CompressionType::None => BLOCK_SIZE,
+        #[cfg(feature = "zstd")]
+        CompressionType::Zstd => {
+            zstd::zstd_safe::compress_bound(BLOCK_SIZE as usize) as u64
+        }
+        #[cfg(not(feature = "zstd"))]
+        CompressionType::Zstd => BLOCK_SIZE,
+    }
+}
+
+// Uncompressed size of the blocks of data of `size` bytes
+fn block_len(ty: CompressionType, size: u64) -> u64 {
+    match ty {
+        CompressionType::None => size.max(1),
+        _ => BLOCK_SIZE,
+    }
+}
+
+fn num_blocks(ty: CompressionType, size: u64) -> u64 {
+    size.div_ceil(block_len(ty, size))
+}
+
+/// Compress all of `src` to `out`.
+///
+/// Returns the offset of the block table, which is what the inode
+/// should point to, and the uncompressed size.
+pub fn compress<R: Read, W: Write + Seek>(
+    ty: CompressionType,
+    level: i32,
+    src: &mut R,
+    out: &mut W,
+) -> Result<(u64, u64)> {
+    let mut table = vec![out.stream_position()?];
+    let mut size = 0;
+    let mut block = Vec::with_capacity(BLOCK_SIZE as usize);
+    loop {
+        if ty == CompressionType::None {
+            // A single block, copied as it is
+            size = io::copy(src, out)?;
+            if size > 0 {
+                table.push(out.stream_position()?);
+            }
+            break;
+        }
+        block.clear();
+        src.by_ref().take(BLOCK_SIZE).read_to_end(&mut block)?;
+        if block.is_empty() {
+            break;
+        }
+        out.write_all(&compress_block(ty, level, &block)?)?;
+        table.push(out.stream_position()?);
+        size += block.len() as u64;
+    }
+    let offset = out.stream_position()?;
+    for pos in table {
+        out.write_all(&pos.to_le_bytes())?;
+    }
+    Ok((offset, size))
+}
+
+/// Shift the table written by `compress` at `offset` of `buf`, for data
+/// of `size` bytes, for `buf` to be written `delta` bytes further.
+pub fn relocate(
+    ty: CompressionType,
+    buf: &mut [u8],
+    offset: u64,
+    size: u64,
+    delta: u64,
+) {
+    let start = offset as usize;
+    let end = start + ((num_blocks(ty, size) + 1) * ENTRY_SIZE) as usize;
+    for entry in buf[start..end].chunks_exact_mut(ENTRY_SIZE as usize) {
+        let pos = u64::from_le_bytes((&*entry).try_into().unwrap());
+        entry.copy_from_slice(&(pos + delta).to_le_bytes());
+    }
+}
+
+fn read_entries(
+    file: &dyn ReadAt,
+    offset: u64,
+    first: u64,
+    count: u64,
+) -> Result<Vec<u64>> {
+    let mut buf = vec![0; (count * ENTRY_SIZE) as usize];
+    let pos = add_offset(offset, first * ENTRY_SIZE)?;
+    check_end(file.stream_len(), pos, buf.len())?;
+    file.read_exact_at(&mut buf, pos)?;
+    Ok(buf
+        .chunks_exact(ENTRY_SIZE as usize)
+        .map(|c| u64::from_le_bytes(c.try_into().unwrap()))
+        .collect())
+}
+
+/// Number of bytes taken by the compressed data of `size` bytes with
+/// its table at `offset`.
+pub fn stored_size(
+    ty: CompressionType,
+    file: &dyn ReadAt,
+    offset: u64,
+    size: u64,
+) -> Result<u64> {
+    let n = num_blocks(ty, size);
+    let start = read_entries(file, offset, 0, 1)?[0];
+    let end = read_entries(file, offset, n, 1)?[0];
+    end.checked_sub(start)
+        .and_then(|s| s.checked_add((n + 1) * ENTRY_SIZE))
+        .ok_or(Error::Compression("invalid block table"))
+}
+
+/// Range of the image taken by the compressed data of `size` bytes
+/// with its table at `offset`, the table included.
+pub fn extent(
+    ty: CompressionType,
+    file: &dyn ReadAt,
+    offset: u64,
+    size: u64,
+) -> Result<Range<u64>> {
+    let n = num_blocks(ty, size);
+    let start = read_entries(file, offset, 0, 1)?[0];
+    if start > offset {
+        return Err(Error::Compression("invalid block table"));
+    }
+    Ok(start..add_offset(offset, (n + 1) * ENTRY_SIZE)?)
+}
+
+/// Fill `buf` with the uncompressed data starting at `off` of the
+/// `size` bytes with their table at `offset`.
+///
+/// Only the blocks overlapping the requested range are read.
+pub fn read_at(
+    ty: CompressionType,
+    file: &dyn ReadAt,
+    offset: u64,
+    size: u64,
+    buf: &mut [u8],
+    off: u64,
+) -> Result<()> {
+    if buf.is_empty() {
+        return Ok(());
+    }
+    let end = off
+        .checked_add(buf.len() as u64)
+        .filter(|&e| e <= size)
+        .ok_or(Error::Bounds("read past the end of the file"))?;
+    let block = block_len(ty, size);
+    let first = off / block;
+    let last = (end - 1) / block;
+    let bounds = read_entries(file, offset, first, last - first + 2)?;
+    let mut pos = 0;
+    for (i, b) in bounds.windows(2).enumerate() {
+        let start = (first + i as u64) * block;
+        let block_size = min(block, size - start);
+        let from = (off + pos as u64 - start) as usize;
+        let sz = min(buf.len() - pos, block_size as usize - from);
+        let len = b[1].checked_sub(b[0]);
+        if ty == CompressionType::None {
+            // Only the requested part of the block
+            if len != Some(block_size) {
+                return Err(Error::Compression("invalid block table"));
+            }
+            let at = add_offset(b[0], from as u64)?;
+            check_end(file.stream_len(), at, sz)?;
+            file.read_exact_at(&mut buf[pos..pos + sz], at)?;
+        } else {
+            let len = len
+                .filter(|&l| l <= max_stored_block(ty))
+                .ok_or(Error::Compression("invalid block table"))?;
+            let mut data = vec![0; len as usize];
+            file.read_exact_at(&mut data, b[0])?;
+            let data = decompress_block(ty, &data, block_size as usize)?;
+            buf[pos..pos + sz].copy_from_slice(&data[from..from + sz]);
+        }
+        pos += sz;
+    }
+    Ok(())
+}
@@ -10,22 +10,122 @@ use std::io::{Seek, Write};
 use std::io::Cursor;
 
 extern crate chacha20;
+#[cfg(test)]
 use chacha20::cipher::IvSizeUser;
 use chacha20::cipher::KeyIvInit;
 use chacha20::cipher::KeySizeUser;
 use chacha20::cipher::StreamCipher;
 use chacha20::cipher::StreamCipherSeek;
-use chacha20::ChaCha20;
+use chacha20::{ChaCha20, XChaCha20};
+
+/// Length of a ChaCha20 key: 32 bytes of key and a 4 bytes nonce prefix
+pub const CHACHA20_KEY_LEN: usize = 36;
 
 const CHACHA20_REKEY_PERIOD: u64 = 4_294_967_296; // 2**32
 const CHACHA20_BUFFER_SIZE: usize = 4096;
+// Set in the block counter part of the nonce for the separate data
+// stream of split images, so it never reuses the keystream of the
+// metadata stream. Offsets stay well below 2**95.
+const CHACHA20_DATA_STREAM: u64 = 1 << 63;
 
 pub type Key<'a> = Option<&'a [u8]>;
 
+/// Length of the nonce stored in the header.
+pub const NONCE_LEN: usize = 16;
+// Length of the random part of the nonce before minor version 16
+pub(crate) const SHORT_NONCE_LEN: usize = 4;
+// Context of the nonce of deterministic images
+const NONCE_CONTEXT: &str = "squashfile 2026 xchacha20 nonce";
+
+/// Length of the salt keys are derived from passphrases with.
+pub const SALT_LEN: usize = 16;
+
+// Argon2id costs, part of the format since they aren't stored: 19 MiB
+// of memory, 2 passes and 1 lane (the minimum recommended by OWASP)
+const ARGON2_M_COST: u32 = 19 * 1024;
+const ARGON2_T_COST: u32 = 2;
+const ARGON2_P_COST: u32 = 1;
+
+/// Derive a key from `passphrase` with Argon2id. `salt` should come
+/// from `new_salt` and be stored with `WriteOptions::key_salt`.
+pub fn derive_key(passphrase: &[u8], salt: &[u8; SALT_LEN]) -> Result<Vec<u8>> {
+    let params = argon2::Params::new(
+        ARGON2_M_COST,
+        ARGON2_T_COST,
+        ARGON2_P_COST,
+        Some(CHACHA20_KEY_LEN),
+    )
+    .map_err(|_| Error::Crypto("Invalid key derivation parameters"))?;
+    let argon = argon2::Argon2::new(
+        argon2::Algorithm::Argon2id,
+        argon2::Version::V0x13,
+        params,
+    );
+    let mut key = vec![0; CHACHA20_KEY_LEN];
+    argon
+        .hash_password_into(passphrase, salt, &mut key)
+        .map_err(|_| Error::Crypto("Key derivation error"))?;
+    Ok(key)
+}
+
+/// A random nonce for `EncryptChaCha20::with_nonce`.
+pub fn new_nonce() -> Result<[u8; NONCE_LEN]> {
+    let mut nonce = [0; NONCE_LEN];
+    getrandom::getrandom(&mut nonce).map_err(io::Error::from)?;
+    Ok(nonce)
+}
+
+/// The nonce for `EncryptChaCha20::with_nonce` of a deterministic
+/// image, from the key and `contents`, the hash of its plaintext. It is
+/// only shared by images with the same key and contents.
+pub fn derive_nonce(key: &[u8], contents: &[u8; 32]) -> [u8; NONCE_LEN] {
+    let mut hasher = blake3::Hasher::new_derive_key(NONCE_CONTEXT);
+    hasher.update(key);
+    hasher.update(contents);
+    hasher.finalize().as_bytes()[..NONCE_LEN]
+        .try_into()
+        .unwrap()
+}
+
+/// A random salt for `derive_key`.
+pub fn new_salt() -> Result<[u8; SALT_LEN]> {
+    let mut salt = [0; SALT_LEN];
+    getrandom::getrandom(&mut salt).map_err(io::Error::from)?;
+    Ok(salt)
+}
+
+// The cipher for one rekey period
+enum Cipher {
+    ChaCha20(ChaCha20),
+    XChaCha20(XChaCha20),
+}
+
+impl Cipher {
+    fn apply_keystream(
+        &mut self,
+        pos: u64,
+        buf: &mut [u8],
+    ) -> std::result::Result<(), chacha20::cipher::StreamCipherError> {
+        match self {
+            Cipher::ChaCha20(c) => {
+                c.try_seek(pos)?;
+                c.try_apply_keystream(buf)
+            }
+            Cipher::XChaCha20(c) => {
+                c.try_seek(pos)?;
+                c.try_apply_keystream(buf)
+            }
+        }
+    }
+}
+
 pub struct EncryptChaCha20<F> {
     f: F,
     nonce_prefix: [u8; 4],
+    // With XChaCha20, since minor version 16
+    nonce: Option<[u8; NONCE_LEN]>,
     key: chacha20::Key,
+    stream: u64,
     pos: u64,
     buf: [u8; CHACHA20_BUFFER_SIZE],
 }
@@ -33,35 +133,91 @@ pub struct EncryptChaCha20<F> {
 impl<F> EncryptChaCha20<F> {
     pub fn new(f: F, k: Key) -> Result<Self> {
         let key = match k {
-            None => return Err(Error::Crypto("No key provided")),
+            None => return Err(Error::KeyRequired),
             Some(k) => k,
         };
-        if key.len() != ChaCha20::key_size() + ChaCha20::iv_size() - 8 {
-            return Err(Error::Crypto("Invalid key length"));
+        if key.len() != CHACHA20_KEY_LEN {
+            return Err(Error::InvalidKeyLength {
+                cipher: "chacha20",
+                expected: CHACHA20_KEY_LEN,
+                found: key.len(),
+            });
         }
         let key_sz = ChaCha20::key_size();
         Ok(EncryptChaCha20 {
-            f: f,
+            f,
             nonce_prefix: key[key_sz..].try_into().unwrap(),
+            nonce: None,
             key: *chacha20::Key::from_slice(&key[..key_sz]),
+            stream: 0,
             pos: 0,
             buf: [0; CHACHA20_BUFFER_SIZE],
         })
     }
 
-    fn block_nonce(&self, n: &mut chacha20::Nonce, pos: u64) {
-        let nonce = n.as_mut_slice();
-        let block_pos = pos / CHACHA20_REKEY_PERIOD;
-        nonce[..4].copy_from_slice(&self.nonce_prefix);
-        nonce[4..].copy_from_slice(&block_pos.to_be_bytes());
+    /// Like `new` but for the data stream of a split image.
+    pub fn new_data(f: F, k: Key) -> Result<Self> {
+        let mut res = Self::new(f, k)?;
+        res.stream = CHACHA20_DATA_STREAM;
+        Ok(res)
+    }
+
+    /// Use XChaCha20 with `nonce`, stored with the image, in place of
+    /// the nonce prefix of the key, so that images with the same key
+    /// use different keystreams.
+    pub fn with_nonce(mut self, nonce: [u8; NONCE_LEN]) -> Self {
+        self.nonce = Some(nonce);
+        self
+    }
+
+    // Mix `nonce` into the nonce prefix of the key, for images of
+    // minor versions 14 and 15
+    pub(crate) fn with_short_nonce(
+        mut self,
+        nonce: [u8; SHORT_NONCE_LEN],
+    ) -> Self {
+        for (p, n) in self.nonce_prefix.iter_mut().zip(nonce) {
+            *p ^= n;
+        }
+        self
+    }
+
+    // The cipher for the rekey period of `pos`
+    fn cipher(&self, pos: u64) -> Cipher {
+        let block_pos = (pos / CHACHA20_REKEY_PERIOD) | self.stream;
+        match self.nonce {
+            None => {
+                let mut nonce = [0; 12];
+                nonce[..4].copy_from_slice(&self.nonce_prefix);
+                nonce[4..].copy_from_slice(&block_pos.to_be_bytes());
+                Cipher::ChaCha20(ChaCha20::new(&self.key, &nonce.into()))
+            }
+            Some(n) => {
+                let mut nonce = [0; 24];
+                nonce[..NONCE_LEN].copy_from_slice(&n);
+                nonce[NONCE_LEN..].copy_from_slice(&block_pos.to_be_bytes());
+                Cipher::XChaCha20(XChaCha20::new(&self.key, &nonce.into()))
+            }
+        }
     }
 }
 
 impl<F: ReadAt> ReadAt for EncryptChaCha20<F> {
     fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
         let mut pos = 0;
-        let mut nonce = *chacha20::Nonce::from_slice(&[0; 12]);
-        let sz = self.f.read_at(buf, offset)?;
+        // The underlying reader may return short reads, fill as much of
+        // the buffer as we can so that we only stop at the end of the
+        // file.
+        let mut sz = 0;
+        while sz < buf.len() {
+            match self.f.read_at(&mut buf[sz..], offset + sz as u64) {
+                Ok(0) => break,
+                Ok(n) => sz += n,
+                Err(Error::IO(ref e))
+                    if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
         let mut len = sz;
         let mut off = offset;
         while len > 0 {
@@ -71,13 +227,8 @@ impl<F: ReadAt> ReadAt for EncryptChaCha20<F> {
             let l = min(len, (CHACHA20_REKEY_PERIOD - p) as usize);
             // current buffer (within the limits of the block)
             let b = &mut buf[pos..pos + l];
-            self.block_nonce(&mut nonce, off);
-            let mut crypto = ChaCha20::new(&self.key, &nonce);
-            crypto
-                .try_seek(p)
-                .map_err(|_| Error::Crypto("Decrypting error"))?;
-            crypto
-                .try_apply_keystream(b)
+            self.cipher(off)
+                .apply_keystream(p, b)
                 .map_err(|_| Error::Crypto("Decrypting error"))?;
             len -= l;
             pos += l;
@@ -85,12 +236,15 @@ impl<F: ReadAt> ReadAt for EncryptChaCha20<F> {
         }
         Ok(sz)
     }
+
+    fn stream_len(&self) -> Option<u64> {
+        self.f.stream_len()
+    }
 }
 
 impl<W: Write> Write for EncryptChaCha20<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let mut pos = 0;
-        let mut nonce = *chacha20::Nonce::from_slice(&[0; 12]);
         let mut len = buf.len();
         let mut off = self.pos;
         while len > 0 {
@@ -99,15 +253,10 @@ impl<W: Write> Write for EncryptChaCha20<W> {
                 len,
                 min(CHACHA20_BUFFER_SIZE, (CHACHA20_REKEY_PERIOD - p) as usize),
             );
-            let b = &buf[pos..pos + l];
-            self.block_nonce(&mut nonce, off);
-            let mut crypto = ChaCha20::new(&self.key, &nonce);
-            crypto
-                .try_seek(p)
-                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-            crypto
-                .apply_keystream_b2b(b, &mut self.buf[..l])
-                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            self.buf[..l].copy_from_slice(&buf[pos..pos + l]);
+            self.cipher(off)
+                .apply_keystream(p, &mut self.buf[..l])
+                .map_err(io::Error::other)?;
             let sz = self.f.write(&self.buf[..l])?;
             self.pos += sz as u64;
             if sz == 0 {
@@ -144,14 +293,33 @@ const TEST_DATA_1: [u8; 32] = [
     21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31,
 ];
 
+#[test]
+fn test_key_len() {
+    assert_eq!(
+        CHACHA20_KEY_LEN,
+        ChaCha20::key_size() + ChaCha20::iv_size() - 8
+    );
+}
+
+#[test]
+fn test_derive_key() {
+    let salt = [7; SALT_LEN];
+    let key = derive_key(b"hunter2", &salt).unwrap();
+    assert_eq!(key.len(), CHACHA20_KEY_LEN);
+    assert_eq!(derive_key(b"hunter2", &salt).unwrap(), key);
+    assert_ne!(derive_key(b"hunter3", &salt).unwrap(), key);
+    assert_ne!(derive_key(b"hunter2", &[8; SALT_LEN]).unwrap(), key);
+    assert_ne!(new_salt().unwrap(), new_salt().unwrap());
+}
+
 #[test]
 fn test_crypto_init() {
     let crypto = EncryptChaCha20::new((), Some(&TEST_KEY));
-    assert!(matches!(crypto, Ok(_)));
+    assert!(crypto.is_ok());
     let crypto = EncryptChaCha20::new((), Some(&[]));
-    assert!(matches!(crypto, Err(_)));
+    assert!(crypto.is_err());
     let crypto = EncryptChaCha20::new((), None);
-    assert!(matches!(crypto, Err(_)));
+    assert!(crypto.is_err());
 }
 
 #[test]
@@ -173,6 +341,38 @@ fn test_crypto_roundtrip_1() {
     assert!(b == TEST_DATA_1[16..]);
 }
 
+#[cfg(test)]
+struct OneByteReader(Cursor<Vec<u8>>);
+
+#[cfg(test)]
+impl ReadAt for OneByteReader {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        let len = min(buf.len(), 1);
+        self.0.read_at(&mut buf[..len], offset)
+    }
+}
+
+#[test]
+fn test_crypto_short_reads() {
+    let buf = Cursor::new(vec![0; 32]);
+    let mut crypto = EncryptChaCha20::new(buf, Some(&TEST_KEY)).unwrap();
+    crypto.write_all(&TEST_DATA_1).unwrap();
+
+    let inner = OneByteReader(Cursor::new(crypto.f.into_inner()));
+    let crypto = EncryptChaCha20::new(inner, Some(&TEST_KEY)).unwrap();
+
+    let mut b = vec![33; 20];
+    let r = crypto.read_at(b.as_mut_slice(), 5);
+    assert!(matches!(r, Ok(20)));
+    assert!(b == TEST_DATA_1[5..25]);
+
+    // Reads still stop at the end of the data
+    let mut b = vec![33; 20];
+    let r = crypto.read_at(b.as_mut_slice(), 24);
+    assert!(matches!(r, Ok(8)));
+    assert!(b[..8] == TEST_DATA_1[24..]);
+}
+
 #[test]
 fn test_crypto_roundtrip_2() {
     let buf = Cursor::new(vec![0; 32]);
@@ -193,3 +393,48 @@ fn test_crypto_roundtrip_2() {
     assert!(matches!(r, Ok(())));
     assert!(b == TEST_DATA_1);
 }
+
+#[test]
+fn test_crypto_nonce() {
+    let new = || {
+        EncryptChaCha20::new(Cursor::new(vec![0; 32]), Some(&TEST_KEY)).unwrap()
+    };
+    let mut plain = new();
+    let mut short = new().with_short_nonce([1, 2, 3, 4]);
+    let mut wide = new().with_nonce([5; NONCE_LEN]);
+    let mut other = new().with_nonce([6; NONCE_LEN]);
+    for crypto in [&mut plain, &mut short, &mut wide, &mut other] {
+        crypto.write_all(&TEST_DATA_1).unwrap();
+    }
+    let streams = [&plain, &short, &wide, &other].map(|c| c.f.get_ref());
+    for (i, a) in streams.iter().enumerate() {
+        assert!(streams[i + 1..].iter().all(|b| a != b));
+    }
+
+    for crypto in [short, wide] {
+        let mut b = vec![33; 32];
+        crypto.read_exact_at(b.as_mut_slice(), 0).unwrap();
+        assert!(b == TEST_DATA_1);
+    }
+    let nonce = derive_nonce(&TEST_KEY, &[0; 32]);
+    assert_eq!(derive_nonce(&TEST_KEY, &[0; 32]), nonce);
+    assert_ne!(derive_nonce(&TEST_KEY, &[1; 32]), nonce);
+    assert_ne!(derive_nonce(&TEST_KEY[1..], &[0; 32]), nonce);
+}
+
+#[test]
+fn test_crypto_data_stream() {
+    let mut meta =
+        EncryptChaCha20::new(Cursor::new(vec![0; 32]), Some(&TEST_KEY))
+            .unwrap();
+    let mut data =
+        EncryptChaCha20::new_data(Cursor::new(vec![0; 32]), Some(&TEST_KEY))
+            .unwrap();
+    meta.write_all(&TEST_DATA_1).unwrap();
+    data.write_all(&TEST_DATA_1).unwrap();
+    assert!(meta.f.get_ref() != data.f.get_ref());
+
+    let mut b = vec![33; 32];
+    data.read_exact_at(b.as_mut_slice(), 0).unwrap();
+    assert!(b == TEST_DATA_1);
+}
@@ -0,0 +1,89 @@
+// Hash indexes of directory entries
+//
+// Large directories can have an index to find an entry by name without
+// a binary search over the entries. The index starts with the number of
+// buckets n (a power of two) and the n + 1 boundaries of the buckets in
+// the slots that follow, all u32le. Each slot is the position of an
+// entry, as u32le, and the entries of a bucket are those whose name
+// hash modulo n is the bucket number.
+
+use crate::disk::{add_offset, ReadAt};
+use crate::error::Error;
+use crate::Result;
+
+const ENTRY_SIZE: u64 = std::mem::size_of::<u32>() as u64;
+
+/// Most entries an index can have, so that the number of buckets fits
+pub const INDEX_ENTRIES_MAX: usize = 1 << 31;
+
+// 64-bit FNV-1a
+fn name_hash(name: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for &b in name {
+        h ^= b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h
+}
+
+/// Serialized index of the entries with `names`, in entry order. There
+/// can't be more than INDEX_ENTRIES_MAX of them.
+pub fn build<N: AsRef<[u8]>>(names: &[N]) -> Vec<u8> {
+    let buckets = std::cmp::max(1, names.len().next_power_of_two());
+    let mask = buckets as u64 - 1;
+    let mut slots: Vec<(u64, u32)> = names
+        .iter()
+        .enumerate()
+        .map(|(i, n)| (name_hash(n.as_ref()) & mask, i as u32))
+        .collect();
+    slots.sort();
+    let mut res =
+        Vec::with_capacity((2 + buckets + slots.len()) * ENTRY_SIZE as usize);
+    res.extend_from_slice(&(buckets as u32).to_le_bytes());
+    let mut pos = 0;
+    for b in 0..=buckets as u64 {
+        while pos < slots.len() && slots[pos].0 < b {
+            pos += 1;
+        }
+        res.extend_from_slice(&(pos as u32).to_le_bytes());
+    }
+    for (_, i) in slots {
+        res.extend_from_slice(&i.to_le_bytes());
+    }
+    res
+}
+
+fn read_u32s(file: &dyn ReadAt, offset: u64, count: u64) -> Result<Vec<u32>> {
+    let mut buf = vec![0; (count * ENTRY_SIZE) as usize];
+    file.read_exact_at(&mut buf, offset)?;
+    Ok(buf
+        .chunks_exact(ENTRY_SIZE as usize)
+        .map(|c| u32::from_le_bytes(c.try_into().unwrap()))
+        .collect())
+}
+
+/// Positions of the entries that can be named `name` in the index at
+/// `offset` of a directory with `entries` entries.
+pub fn candidates(
+    file: &dyn ReadAt,
+    offset: u64,
+    entries: u64,
+    name: &[u8],
+) -> Result<Vec<u64>> {
+    let buckets = read_u32s(file, offset, 1)?[0] as u64;
+    if !buckets.is_power_of_two() {
+        return Err(Error::Format("invalid hash index"));
+    }
+    let bucket = name_hash(name) & (buckets - 1);
+    let bounds =
+        read_u32s(file, add_offset(offset, (1 + bucket) * ENTRY_SIZE)?, 2)?;
+    let (start, end) = (bounds[0] as u64, bounds[1] as u64);
+    if start > end || end > entries {
+        return Err(Error::Format("invalid hash index"));
+    }
+    let slots = add_offset(offset, (2 + buckets + start) * ENTRY_SIZE)?;
+    Ok(read_u32s(file, slots, end - start)?
+        .into_iter()
+        .map(u64::from)
+        .collect())
+}
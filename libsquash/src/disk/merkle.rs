@@ -0,0 +1,210 @@
+// Hash trees over file contents
+//
+// The uncompressed contents of a file are split in blocks of BLOCK_SIZE
+// bytes (the last one can be shorter, an empty file has one empty
+// block). The leaves of the tree are the hashes of the blocks, each
+// level above hashes pairs of nodes of the level below, and the last
+// level is the root. The tree is stored level by level, starting with
+// the leaves, as 32 bytes nodes so that a block can be checked by
+// reading only the nodes on its path to the root.
+//
+// Directories, symlinks and special files are hashed too, from their
+// entries, target or inode, so that the hash of the root directory
+// covers the whole tree.
+
+use crate::disk::{add_offset, InodeType, ReadAt};
+use crate::error::Error;
+use crate::Result;
+
+use std::io::{self, Read};
+
+pub type Hash = [u8; 32];
+
+/// Uncompressed size of a block
+pub const BLOCK_SIZE: u64 = 4096;
+
+const NODE_SIZE: u64 = std::mem::size_of::<Hash>() as u64;
+
+// Prefixes so that the different kinds of hashes can't be confused
+const LEAF: u8 = 0;
+const NODE: u8 = 1;
+const DIRECTORY: u8 = 2;
+const SYMLINK: u8 = 3;
+const SPECIAL: u8 = 4;
+
+fn leaf_hash(index: u64, data: &[u8]) -> Hash {
+    let mut h = blake3::Hasher::new();
+    h.update(&[LEAF]);
+    h.update(&index.to_le_bytes());
+    h.update(data);
+    *h.finalize().as_bytes()
+}
+
+// A node without a right child only hashes its left one
+fn node_hash(left: &Hash, right: Option<&Hash>) -> Hash {
+    let mut h = blake3::Hasher::new();
+    h.update(&[NODE]);
+    h.update(left);
+    if let Some(r) = right {
+        h.update(r);
+    }
+    *h.finalize().as_bytes()
+}
+
+// Number of nodes in each level, from the leaves to the root
+fn level_sizes(size: u64) -> Vec<u64> {
+    let mut res = vec![std::cmp::max(1, size.div_ceil(BLOCK_SIZE))];
+    while res[res.len() - 1] > 1 {
+        res.push(res[res.len() - 1].div_ceil(2));
+    }
+    res
+}
+
+/// Hashes everything read through it to build the tree of the data.
+pub struct TreeReader<R> {
+    inner: R,
+    block: Vec<u8>,
+    leaves: Vec<Hash>,
+}
+
+impl<R: Read> TreeReader<R> {
+    pub fn new(inner: R) -> Self {
+        TreeReader {
+            inner,
+            block: Vec::with_capacity(BLOCK_SIZE as usize),
+            leaves: Vec::new(),
+        }
+    }
+
+    /// All the nodes of the tree of what was read, in storage order.
+    /// The root is the last one.
+    pub fn finish(mut self) -> Vec<Hash> {
+        if !self.block.is_empty() || self.leaves.is_empty() {
+            self.leaves
+                .push(leaf_hash(self.leaves.len() as u64, &self.block));
+        }
+        let mut nodes = self.leaves;
+        let mut start = 0;
+        while nodes.len() - start > 1 {
+            let end = nodes.len();
+            for i in (start..end).step_by(2) {
+                let right = if i + 1 < end {
+                    Some(nodes[i + 1])
+                } else {
+                    None
+                };
+                nodes.push(node_hash(&nodes[i], right.as_ref()));
+            }
+            start = end;
+        }
+        nodes
+    }
+}
+
+impl<R: Read> Read for TreeReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let sz = self.inner.read(buf)?;
+        let mut data = &buf[..sz];
+        while !data.is_empty() {
+            let n = std::cmp::min(
+                data.len(),
+                BLOCK_SIZE as usize - self.block.len(),
+            );
+            self.block.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if self.block.len() == BLOCK_SIZE as usize {
+                self.leaves
+                    .push(leaf_hash(self.leaves.len() as u64, &self.block));
+                self.block.clear();
+            }
+        }
+        Ok(sz)
+    }
+}
+
+fn read_node(file: &dyn ReadAt, offset: u64, index: u64) -> Result<Hash> {
+    let pos = index
+        .checked_mul(NODE_SIZE)
+        .ok_or(Error::Bounds("offset overflow"))?;
+    let mut buf = [0; NODE_SIZE as usize];
+    file.read_exact_at(&mut buf, add_offset(offset, pos)?)?;
+    Ok(buf)
+}
+
+/// Root of the tree at `offset` for `size` bytes of data.
+pub fn root(file: &dyn ReadAt, offset: u64, size: u64) -> Result<Hash> {
+    let total: u64 = level_sizes(size).iter().sum();
+    read_node(file, offset, total - 1)
+}
+
+/// Check block number `index` of the `size` bytes of data whose tree
+/// is at `offset` against the root of that tree.
+pub fn verify_block(
+    file: &dyn ReadAt,
+    offset: u64,
+    size: u64,
+    index: u64,
+    data: &[u8],
+) -> Result<()> {
+    let levels = level_sizes(size);
+    let mut hash = leaf_hash(index, data);
+    let mut idx = index;
+    let mut level_start = 0;
+    for &n in &levels[..levels.len() - 1] {
+        let sibling = idx ^ 1;
+        hash = if sibling >= n {
+            node_hash(&hash, None)
+        } else {
+            let other = read_node(file, offset, level_start + sibling)?;
+            if sibling > idx {
+                node_hash(&hash, Some(&other))
+            } else {
+                node_hash(&other, Some(&hash))
+            }
+        };
+        level_start += n;
+        idx /= 2;
+    }
+    if read_node(file, offset, level_start)? != hash {
+        return Err(Error::Crypto("block verification failed"));
+    }
+    Ok(())
+}
+
+/// Combines the names and hashes of the entries of a directory.
+pub struct DirHasher(blake3::Hasher);
+
+impl DirHasher {
+    pub fn new() -> Self {
+        let mut h = blake3::Hasher::new();
+        h.update(&[DIRECTORY]);
+        DirHasher(h)
+    }
+
+    pub fn add(&mut self, name: &[u8], hash: &Hash) {
+        self.0.update(&(name.len() as u64).to_le_bytes());
+        self.0.update(name);
+        self.0.update(hash);
+    }
+
+    pub fn finish(&self) -> Hash {
+        *self.0.finalize().as_bytes()
+    }
+}
+
+pub fn symlink_hash(target: &[u8]) -> Hash {
+    let mut h = blake3::Hasher::new();
+    h.update(&[SYMLINK]);
+    h.update(target);
+    *h.finalize().as_bytes()
+}
+
+/// Hash of a device, FIFO or socket from its inode type and its offset
+/// and size fields.
+pub fn special_hash(inode_type: InodeType, (offset, size): (u64, u64)) -> Hash {
+    let mut h = blake3::Hasher::new();
+    h.update(&[SPECIAL, inode_type.into()]);
+    h.update(&offset.to_le_bytes());
+    h.update(&size.to_le_bytes());
+    *h.finalize().as_bytes()
+}
@@ -5,26 +5,143 @@ pub mod write;
 #[cfg(test)]
 mod tests;
 
+mod aead;
+mod cache;
+mod compress;
+pub(crate) mod crc32;
 mod crypto;
-pub use crypto::Key;
+mod index;
+pub(crate) mod merkle;
+pub(crate) mod xattr;
+pub use aead::XCHACHA20_POLY1305_KEY_LEN;
+pub use cache::CACHE_CAPACITY;
+use crypto::SHORT_NONCE_LEN;
+pub use crypto::{
+    derive_key, new_salt, Key, CHACHA20_KEY_LEN, NONCE_LEN, SALT_LEN,
+};
 
 // This is for read_at/read_exact_at
 use std::os::unix::fs::FileExt;
 
 use memchr::memchr;
 
+use cache::Lru;
+
 use crate::error::Error;
-use std::cmp::min;
+use std::cmp::{min, Ordering};
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
 use std::ffi::CString;
+use std::fmt;
 use std::io;
 use std::io::Cursor;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
 
 type Result<T> = std::result::Result<T, Error>;
 
 pub static MAGIC: [u8; 8] = *b"SQUASHFL";
 pub static VERSION_MAJOR: u8 = 0;
-pub static VERSION_MINOR: u8 = 0;
+pub static VERSION_MINOR: u8 = 18;
+// First minor version storing the mode in inodes
+const MINOR_MODE: u8 = 2;
+// First minor version with a UUID after the base header
+const MINOR_UUID: u8 = 3;
+// First minor version with 64-byte inodes, starting with the mtime
+const MINOR_INODE_EXT: u8 = 4;
+// First minor version storing the owner in inodes
+const MINOR_OWNER: u8 = 5;
+// Minor version 6 added INCOMPAT_SPLIT_DATA, which needs no gating
+// First minor version with hash trees (with COMPAT_MERKLE, replaced by
+// INCOMPAT_MERKLE in minor version 15)
+const MINOR_MERKLE: u8 = 7;
+// First minor version with directory hash indexes
+const MINOR_HASH_INDEX: u8 = 8;
+// Minor version 9 added the inode types of special files, which older
+// readers refuse when they meet them
+// First minor version with 72-byte inodes, ending with the offset of
+// extended attributes (with COMPAT_XATTR)
+const MINOR_XATTR: u8 = 10;
+// First minor version with the integrity digest after the root hash
+// (with COMPAT_DIGEST)
+const MINOR_DIGEST: u8 = 11;
+// First minor version with 80-byte inodes, with the CRC-32 of files
+// after the offset of extended attributes (with COMPAT_CRC32)
+const MINOR_CRC32: u8 = 12;
+// First minor version with the salt of passphrase keys after the
+// digest (with COMPAT_KEY_SALT)
+const MINOR_KEY_SALT: u8 = 13;
+// First minor version with a random nonce in the padding of the base
+// header (with INCOMPAT_NONCE)
+const MINOR_NONCE: u8 = 14;
+// Minor version 15 added INCOMPAT_MERKLE, which older readers refuse
+// First minor version with a 16 bytes nonce after the key salt (with
+// INCOMPAT_WIDE_NONCE)
+const MINOR_WIDE_NONCE: u8 = 16;
+// First minor version with the block size of XChaCha20-Poly1305 after
+// the nonce (with INCOMPAT_BLOCK_SIZE)
+const MINOR_BLOCK_SIZE: u8 = 17;
+// Minor version 18 added INCOMPAT_BLOCK_TABLES, which older readers
+// refuse
+
+/// Default limit on the length of symlink targets, for both writing and
+/// reading. This is PATH_MAX on Linux.
+pub const LINK_TARGET_MAX: usize = 4096;
+/// The limit on the length of symlink targets can't be raised past this.
+pub const LINK_TARGET_HARD_MAX: usize = 65536;
+/// Longest name of a directory entry, without its NUL. This is well
+/// above NAME_MAX on Linux.
+pub const NAME_MAX: usize = 4096;
+
+/// Incompatible features, stored in the header. A reader must refuse
+/// to open an image that uses a feature it doesn't know about.
+pub const INCOMPAT_COLLATION: u32 = 0x1;
+/// File contents are in a separate data stream, see `open_split`.
+pub const INCOMPAT_SPLIT_DATA: u32 = 0x2;
+/// The nonce of the cipher mixes the key with 4 random bytes from the
+/// header, as written before minor version 16, see INCOMPAT_WIDE_NONCE.
+pub const INCOMPAT_NONCE: u32 = 0x4;
+/// Files have a hash tree checked on every read, directories store
+/// their hash and the header has the root hash of the whole tree. The
+/// root of each file is checked against it through its directories.
+pub const INCOMPAT_MERKLE: u32 = 0x8;
+/// The cipher is XChaCha20 with the 16 bytes nonce of the header,
+/// random or derived from the contents of deterministic images, so that
+/// images with the same key never share keystream.
+pub const INCOMPAT_WIDE_NONCE: u32 = 0x10;
+/// XChaCha20-Poly1305 encrypts blocks of the size in the header rather
+/// than of the default size.
+pub const INCOMPAT_BLOCK_SIZE: u32 = 0x20;
+/// Uncompressed file contents are a single block with a block table,
+/// like compressed ones. Without it, they are stored alone and file
+/// inodes point to them directly.
+pub const INCOMPAT_BLOCK_TABLES: u32 = 0x40;
+const INCOMPAT_SUPPORTED: u32 = INCOMPAT_COLLATION
+    | INCOMPAT_SPLIT_DATA
+    | INCOMPAT_NONCE
+    | INCOMPAT_MERKLE
+    | INCOMPAT_WIDE_NONCE
+    | INCOMPAT_BLOCK_SIZE
+    | INCOMPAT_BLOCK_TABLES;
+
+/// Compatible features, stored in the header. Readers can ignore the
+/// ones they don't know about.
+pub const COMPAT_SUBTREE_SIZE: u32 = 0x1;
+/// Hash trees as written before minor version 15, see INCOMPAT_MERKLE.
+/// Blocks are checked against the root of their file but file roots
+/// are not checked against the root hash of the header.
+pub const COMPAT_MERKLE: u32 = 0x2;
+/// Some directories have a hash index of their entries.
+pub const COMPAT_HASH_INDEX: u32 = 0x4;
+/// Some inodes have extended attributes.
+pub const COMPAT_XATTR: u32 = 0x8;
+/// The header has a digest of the whole image, see `seal_image`.
+pub const COMPAT_DIGEST: u32 = 0x10;
+/// Files have the CRC-32 of their contents in their inode.
+pub const COMPAT_CRC32: u32 = 0x20;
+/// The key was derived from a passphrase with the salt in the header,
+/// see `derive_key`.
+pub const COMPAT_KEY_SALT: u32 = 0x40;
 
 #[derive(Copy, Clone, Debug, Default)]
 #[repr(C, packed(8))]
@@ -66,6 +183,58 @@ impl From<u32> for u32le {
 pub enum EncryptionType {
     None,
     ChaCha20,
+    XChaCha20Poly1305,
+}
+
+impl EncryptionType {
+    /// Every encryption type, in the order of their values.
+    pub fn all() -> impl Iterator<Item = EncryptionType> {
+        [
+            EncryptionType::None,
+            EncryptionType::ChaCha20,
+            EncryptionType::XChaCha20Poly1305,
+        ]
+        .into_iter()
+    }
+
+    /// Length of the key needed for this encryption type.
+    pub fn key_len(&self) -> usize {
+        match self {
+            EncryptionType::None => 0,
+            EncryptionType::ChaCha20 => CHACHA20_KEY_LEN,
+            EncryptionType::XChaCha20Poly1305 => XCHACHA20_POLY1305_KEY_LEN,
+        }
+    }
+
+    /// Name of the cipher, as used by the command line.
+    pub fn name(&self) -> &'static str {
+        match self {
+            EncryptionType::None => "none",
+            EncryptionType::ChaCha20 => "chacha20",
+            EncryptionType::XChaCha20Poly1305 => "xchacha20poly1305",
+        }
+    }
+
+    /// Check that `key` can be used with this encryption type.
+    ///
+    /// Keys passed for EncryptionType::None are ignored.
+    pub fn validate_key(&self, key: Key) -> Result<()> {
+        match (self, key) {
+            (EncryptionType::None, _) => Ok(()),
+            (_, None) => Err(Error::KeyRequired),
+            (_, Some(k)) => {
+                if k.len() != self.key_len() {
+                    Err(Error::InvalidKeyLength {
+                        cipher: self.name(),
+                        expected: self.key_len(),
+                        found: k.len(),
+                    })
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
 }
 
 impl TryFrom<u8> for EncryptionType {
@@ -75,6 +244,7 @@ impl TryFrom<u8> for EncryptionType {
         match val {
             0 => Ok(EncryptionType::None),
             1 => Ok(EncryptionType::ChaCha20),
+            2 => Ok(EncryptionType::XChaCha20Poly1305),
             _ => Err(Error::Format("EncryptionType")),
         }
     }
@@ -85,12 +255,48 @@ impl From<EncryptionType> for u8 {
         match val {
             EncryptionType::None => 0,
             EncryptionType::ChaCha20 => 1,
+            EncryptionType::XChaCha20Poly1305 => 2,
         }
     }
 }
-#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+
+impl FromStr for EncryptionType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        EncryptionType::all()
+            .find(|ty| ty.name() == s)
+            .ok_or(Error::Format("EncryptionType"))
+    }
+}
+
+impl fmt::Display for EncryptionType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
 pub enum CompressionType {
+    #[default]
     None,
+    Zstd,
+}
+
+impl CompressionType {
+    /// Every compression type, in the order of their values. Some may
+    /// not be enabled in this build.
+    pub fn all() -> impl Iterator<Item = CompressionType> {
+        [CompressionType::None, CompressionType::Zstd].into_iter()
+    }
+
+    /// Name of the compression, as used by the command line.
+    pub fn name(&self) -> &'static str {
+        match self {
+            CompressionType::None => "none",
+            CompressionType::Zstd => "zstd",
+        }
+    }
 }
 
 impl TryFrom<u8> for CompressionType {
@@ -99,6 +305,7 @@ impl TryFrom<u8> for CompressionType {
     fn try_from(val: u8) -> Result<Self> {
         match val {
             0 => Ok(CompressionType::None),
+            1 => Ok(CompressionType::Zstd),
             _ => Err(Error::Format("CompressionType")),
         }
     }
@@ -108,10 +315,27 @@ impl From<CompressionType> for u8 {
     fn from(val: CompressionType) -> u8 {
         match val {
             CompressionType::None => 0,
+            CompressionType::Zstd => 1,
         }
     }
 }
 
+impl FromStr for CompressionType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        CompressionType::all()
+            .find(|ty| ty.name() == s)
+            .ok_or(Error::Format("CompressionType"))
+    }
+}
+
+impl fmt::Display for CompressionType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
 #[derive(Copy, Clone, Debug, Default)]
 #[repr(C, packed)]
 struct Header {
@@ -121,17 +345,160 @@ struct Header {
     version_minor: u8,
     compression_type: u8,
     encryption_type: u8,
-    _pad1: u32,
-    _pad2: u64,
+    incompat: u32le,
+    compat: u32le,
+    // Since MINOR_NONCE
+    short_nonce: [u8; SHORT_NONCE_LEN],
+    // Since MINOR_UUID
+    uuid: [u8; 16],
+    // Since MINOR_MERKLE
+    root_hash: [u8; 32],
+    // Since MINOR_DIGEST
+    digest: [u8; 32],
+    // Since MINOR_KEY_SALT
+    key_salt: [u8; SALT_LEN],
+    // Since MINOR_WIDE_NONCE
+    nonce: [u8; NONCE_LEN],
+    // Since MINOR_BLOCK_SIZE
+    block_size: u32le,
+}
+
+assert_eq_size!(Header, [u8; 148]);
+
+// Size of the header written by the first versions
+const HEADER_BASE_SIZE: usize = 32;
+
+// Size of the header for a minor version. Images can store anything
+// right after it.
+fn header_size(version_minor: u8) -> u64 {
+    if version_minor < MINOR_UUID {
+        HEADER_BASE_SIZE as u64
+    } else if version_minor < MINOR_MERKLE {
+        48
+    } else if version_minor < MINOR_DIGEST {
+        80
+    } else if version_minor < MINOR_KEY_SALT {
+        112
+    } else if version_minor < MINOR_WIDE_NONCE {
+        128
+    } else if version_minor < MINOR_BLOCK_SIZE {
+        144
+    } else {
+        std::mem::size_of::<Header>() as u64
+    }
+}
+
+/// Order of the entries of a directory.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum Collation {
+    /// Byte-wise order of the names
+    #[default]
+    Bytes,
+    /// ASCII case-insensitive order
+    CaseInsensitive,
+    /// Like Bytes, but runs of digits are compared by numeric value so
+    /// that "img2" comes before "img10"
+    Natural,
 }
 
-assert_eq_size!(Header, [u8; 32]);
+impl TryFrom<u8> for Collation {
+    type Error = Error;
+
+    fn try_from(val: u8) -> Result<Self> {
+        match val {
+            0 => Ok(Collation::Bytes),
+            1 => Ok(Collation::CaseInsensitive),
+            2 => Ok(Collation::Natural),
+            _ => Err(Error::Format("Collation")),
+        }
+    }
+}
+
+impl From<Collation> for u8 {
+    fn from(val: Collation) -> u8 {
+        match val {
+            Collation::Bytes => 0,
+            Collation::CaseInsensitive => 1,
+            Collation::Natural => 2,
+        }
+    }
+}
+
+fn skip_digits(s: &[u8], mut i: usize) -> usize {
+    while i < s.len() && s[i].is_ascii_digit() {
+        i += 1;
+    }
+    i
+}
+
+fn trim_zeros(s: &[u8]) -> &[u8] {
+    let start = s.iter().position(|&c| c != b'0').unwrap_or(s.len());
+    &s[start..]
+}
+
+fn natural_cmp(a: &[u8], b: &[u8]) -> Ordering {
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i].is_ascii_digit() && b[j].is_ascii_digit() {
+            let (ei, ej) = (skip_digits(a, i), skip_digits(b, j));
+            let (na, nb) = (trim_zeros(&a[i..ei]), trim_zeros(&b[j..ej]));
+            let ord = na.len().cmp(&nb.len()).then_with(|| na.cmp(nb));
+            if ord != Ordering::Equal {
+                return ord;
+            }
+            i = ei;
+            j = ej;
+        } else {
+            if a[i] != b[j] {
+                return a[i].cmp(&b[j]);
+            }
+            i += 1;
+            j += 1;
+        }
+    }
+    (i < a.len()).cmp(&(j < b.len()))
+}
+
+impl Collation {
+    /// Compare two names according to this collation.
+    ///
+    /// Names that only compare equal for the collation are ordered
+    /// byte-wise so that distinct names are never equal.
+    pub fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        let ord = match self {
+            Collation::Bytes => Ordering::Equal,
+            Collation::CaseInsensitive => a
+                .iter()
+                .map(u8::to_ascii_lowercase)
+                .cmp(b.iter().map(u8::to_ascii_lowercase)),
+            Collation::Natural => natural_cmp(a, b),
+        };
+        ord.then_with(|| a.cmp(b))
+    }
+}
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub enum InodeType {
     Directory,
     File,
     Symlink,
+    // The offset and size of devices are their major and minor numbers
+    CharDevice,
+    BlockDevice,
+    Fifo,
+    Socket,
+}
+
+impl InodeType {
+    pub fn is_special(&self) -> bool {
+        matches!(
+            self,
+            InodeType::CharDevice
+                | InodeType::BlockDevice
+                | InodeType::Fifo
+                | InodeType::Socket
+        )
+    }
 }
 
 impl TryFrom<u8> for InodeType {
@@ -142,6 +509,10 @@ impl TryFrom<u8> for InodeType {
             0 => Ok(InodeType::Directory),
             1 => Ok(InodeType::File),
             2 => Ok(InodeType::Symlink),
+            3 => Ok(InodeType::CharDevice),
+            4 => Ok(InodeType::BlockDevice),
+            5 => Ok(InodeType::Fifo),
+            6 => Ok(InodeType::Socket),
             _ => Err(Error::Format("InodeType")),
         }
     }
@@ -153,6 +524,10 @@ impl From<InodeType> for u8 {
             InodeType::Directory => 0,
             InodeType::File => 1,
             InodeType::Symlink => 2,
+            InodeType::CharDevice => 3,
+            InodeType::BlockDevice => 4,
+            InodeType::Fifo => 5,
+            InodeType::Socket => 6,
         }
     }
 }
@@ -164,10 +539,47 @@ pub struct Inode {
     offset: u64le,
     size: u64le,
     inode_type: u8,
-    _pad: [u8; 7],
+    collation: u8,
+    mode: u32le,
+    _pad: [u8; 2],
+    // Since MINOR_INODE_EXT
+    mtime: u64le,
+    // Since MINOR_OWNER
+    uid: u32le,
+    gid: u32le,
+    // Since MINOR_MERKLE, offset of the hash tree of files
+    merkle: u64le,
+    // Since MINOR_HASH_INDEX, offset of the hash index of directories
+    // that have one, 0 otherwise
+    hash_index: u64le,
+    // Since MINOR_XATTR, offset of the extended attributes, 0 if none
+    xattrs: u64le,
+    // Since MINOR_CRC32, CRC-32 of the uncompressed contents of files
+    crc32: u32le,
+    _pad2: [u8; 4],
 }
 
-assert_eq_size!(Inode, [u8; 32]);
+assert_eq_size!(Inode, [u8; 80]);
+
+// Size of the inodes written by the first versions
+const INODE_BASE_SIZE: usize = 32;
+// Size of the inodes from MINOR_INODE_EXT to MINOR_XATTR
+const INODE_EXT_SIZE: usize = 64;
+// Size of the inodes from MINOR_XATTR to MINOR_CRC32
+const INODE_XATTR_SIZE: usize = 72;
+
+// Size of the inodes of images of minor version `minor`
+fn inode_size(minor: u8) -> usize {
+    if minor < MINOR_INODE_EXT {
+        INODE_BASE_SIZE
+    } else if minor < MINOR_XATTR {
+        INODE_EXT_SIZE
+    } else if minor < MINOR_CRC32 {
+        INODE_XATTR_SIZE
+    } else {
+        std::mem::size_of::<Inode>()
+    }
+}
 
 #[derive(Copy, Clone, Debug, Default)]
 #[repr(C, packed)]
@@ -178,11 +590,68 @@ pub struct Dirent {
 
 assert_eq_size!(Dirent, [u8; 16]);
 
+// Largest Image::read_raw
+#[cfg(feature = "internals")]
+const RAW_READ_MAX: usize = 1 << 20;
+
 pub struct Image {
     file: Box<dyn ReadAt>,
+    // The stored bytes of `file`, before decryption
+    raw: Arc<dyn ReadAt>,
+    // File contents of split images
+    data: Option<Box<dyn ReadAt>>,
     header: Header,
-    // we only have None for the compression and encryption for now
-    // later there will be fields here to deal with those
+    compression: CompressionType,
+    // Longest symlink target that is read
+    link_target_max: usize,
+    // Check the CRC-32 of files read to the end
+    verify_checksums: bool,
+    pinned: RwLock<Pinned>,
+    cache: Mutex<Cache>,
+    // With INCOMPAT_MERKLE
+    authentic: Mutex<Authentic>,
+    // Lengths of `file` and `data`, when known when opening
+    len: Option<u64>,
+    data_len: Option<u64>,
+}
+
+// What was read recently, see FsOptions::cache_capacity
+struct Cache {
+    // By offset
+    inodes: Lru<u64, Inode>,
+    // Directory entries with their name, by offset
+    dirents: Lru<u64, (Dirent, CString)>,
+}
+
+impl Cache {
+    fn new(capacity: usize) -> Self {
+        Cache {
+            inodes: Lru::new(capacity),
+            dirents: Lru::new(capacity),
+        }
+    }
+}
+
+// Hashes of the entries of the directories checked against the root
+// hash of the header so far, by offset of the directory inode
+type Authentic = HashMap<u64, HashSet<merkle::Hash>>;
+
+// Inodes and directory entries kept in memory by FS::precache
+#[derive(Default)]
+struct Pinned {
+    // By offset
+    inodes: HashMap<u64, Inode>,
+    // By offset of the entries of the directory, then name
+    entries: HashMap<u64, HashMap<Vec<u8>, Dirent>>,
+}
+
+fn struct_to_slice<T>(ptr: &T) -> &[u8] {
+    unsafe {
+        std::slice::from_raw_parts(
+            (ptr as *const T) as *const u8,
+            std::mem::size_of::<T>(),
+        )
+    }
 }
 
 fn struct_to_mut_slice<T>(ptr: &mut T) -> &mut [u8] {
@@ -194,15 +663,319 @@ fn struct_to_mut_slice<T>(ptr: &mut T) -> &mut [u8] {
     }
 }
 
-fn read_header<T: ReadAt>(file: &T) -> Result<Header> {
+/// Read-only view of the header of an image.
+///
+/// The header is never encrypted so this is available without a key.
+#[derive(Copy, Clone, Debug)]
+pub struct ImageHeader {
+    header: Header,
+}
+
+impl ImageHeader {
+    pub fn magic(&self) -> [u8; 8] {
+        self.header.magic
+    }
+
+    pub fn magic_valid(&self) -> bool {
+        self.header.magic == MAGIC
+    }
+
+    pub fn version_major(&self) -> u8 {
+        self.header.version_major
+    }
+
+    pub fn version_minor(&self) -> u8 {
+        self.header.version_minor
+    }
+
+    pub fn compression_type(&self) -> Result<CompressionType> {
+        CompressionType::try_from(self.header.compression_type)
+    }
+
+    pub fn encryption_type(&self) -> Result<EncryptionType> {
+        EncryptionType::try_from(self.header.encryption_type)
+    }
+
+    pub fn root_inode(&self) -> u64 {
+        self.header.root_inode.into()
+    }
+
+    pub fn incompat_features(&self) -> u32 {
+        self.header.incompat.into()
+    }
+
+    pub fn compat_features(&self) -> u32 {
+        self.header.compat.into()
+    }
+
+    /// UUID of the image, None for images older than the minor
+    /// version that added it.
+    pub fn uuid(&self) -> Option<[u8; 16]> {
+        if self.header.version_minor < MINOR_UUID {
+            None
+        } else {
+            Some(self.header.uuid)
+        }
+    }
+
+    /// Hash of the whole tree of the image, if it has hash trees.
+    pub fn root_hash(&self) -> Option<[u8; 32]> {
+        if self.has_merkle() {
+            Some(self.header.root_hash)
+        } else {
+            None
+        }
+    }
+
+    /// Digest of the whole image, for images sealed by `seal_image`.
+    pub fn digest(&self) -> Option<[u8; 32]> {
+        if self.header.version_minor >= MINOR_DIGEST
+            && u32::from(self.header.compat) & COMPAT_DIGEST != 0
+        {
+            Some(self.header.digest)
+        } else {
+            None
+        }
+    }
+
+    /// Nonce of the cipher, None for images whose nonce comes from the
+    /// key, if only in part.
+    pub fn nonce(&self) -> Option<[u8; NONCE_LEN]> {
+        if self.header.version_minor >= MINOR_WIDE_NONCE
+            && u32::from(self.header.incompat) & INCOMPAT_WIDE_NONCE != 0
+        {
+            Some(self.header.nonce)
+        } else {
+            None
+        }
+    }
+
+    /// Plaintext size of the blocks of XChaCha20-Poly1305 images.
+    pub fn block_size(&self) -> u64 {
+        if self.header.version_minor >= MINOR_BLOCK_SIZE
+            && u32::from(self.header.incompat) & INCOMPAT_BLOCK_SIZE != 0
+        {
+            u32::from(self.header.block_size).into()
+        } else {
+            aead::BLOCK_SIZE
+        }
+    }
+
+    // Random part of the nonce of images of minor versions 14 and 15
+    fn short_nonce(&self) -> Option<[u8; SHORT_NONCE_LEN]> {
+        if self.header.version_minor >= MINOR_NONCE
+            && u32::from(self.header.incompat) & INCOMPAT_NONCE != 0
+        {
+            Some(self.header.short_nonce)
+        } else {
+            None
+        }
+    }
+
+    /// Salt the key was derived from, for images encrypted with a key
+    /// from a passphrase.
+    pub fn key_salt(&self) -> Option<[u8; SALT_LEN]> {
+        if self.header.version_minor >= MINOR_KEY_SALT
+            && u32::from(self.header.compat) & COMPAT_KEY_SALT != 0
+        {
+            Some(self.header.key_salt)
+        } else {
+            None
+        }
+    }
+
+    /// The key of the image, derived from `passphrase` with the salt of
+    /// the header.
+    pub fn passphrase_key(&self, passphrase: &[u8]) -> Result<Vec<u8>> {
+        let salt = self.key_salt().ok_or(Error::InvalidOperation(
+            "image key is not derived from a passphrase",
+        ))?;
+        let mut key = derive_key(passphrase, &salt)?;
+        key.truncate(self.encryption_type()?.key_len());
+        Ok(key)
+    }
+
+    fn has_merkle(&self) -> bool {
+        (self.header.version_minor >= MINOR_MERKLE
+            && u32::from(self.header.compat) & COMPAT_MERKLE != 0)
+            || self.has_dir_hashes()
+    }
+
+    // Whether directories store their hash, with INCOMPAT_MERKLE
+    fn has_dir_hashes(&self) -> bool {
+        u32::from(self.header.incompat) & INCOMPAT_MERKLE != 0
+    }
+
+    /// What a reader needs to open the image, and whether this build
+    /// has it.
+    pub fn requirements(&self) -> Vec<Requirement> {
+        let mut res = Vec::new();
+        let h = &self.header;
+        if h.version_major != VERSION_MAJOR {
+            res.push(Requirement::new(
+                format!(
+                    "format version {}.{}",
+                    h.version_major, h.version_minor
+                ),
+                false,
+            ));
+        }
+        match CompressionType::try_from(h.compression_type) {
+            Ok(CompressionType::None) => {}
+            Ok(ty @ CompressionType::Zstd) => res.push(Requirement::new(
+                "zstd".into(),
+                compress::check_supported(ty).is_ok(),
+            )),
+            Err(_) => res.push(Requirement::new(
+                format!("compression type {}", h.compression_type),
+                false,
+            )),
+        }
+        match EncryptionType::try_from(h.encryption_type) {
+            Ok(EncryptionType::None) => {}
+            Ok(ty) => res.push(Requirement::new(ty.name().into(), true)),
+            Err(_) => res.push(Requirement::new(
+                format!("encryption type {}", h.encryption_type),
+                false,
+            )),
+        }
+        let incompat = u32::from(h.incompat);
+        if incompat & INCOMPAT_COLLATION != 0 {
+            res.push(Requirement::new("collation".into(), true));
+        }
+        if incompat & INCOMPAT_SPLIT_DATA != 0 {
+            res.push(Requirement::new("separate data stream".into(), true));
+        }
+        if incompat & INCOMPAT_NONCE != 0 {
+            res.push(Requirement::new("random nonce".into(), true));
+        }
+        if incompat & INCOMPAT_MERKLE != 0 {
+            res.push(Requirement::new("hash trees".into(), true));
+        }
+        if incompat & INCOMPAT_WIDE_NONCE != 0 {
+            res.push(Requirement::new("wide nonce".into(), true));
+        }
+        if incompat & INCOMPAT_BLOCK_TABLES != 0 {
+            res.push(Requirement::new("block tables".into(), true));
+        }
+        if incompat & INCOMPAT_BLOCK_SIZE != 0 {
+            let size = self.block_size();
+            res.push(Requirement::new(
+                format!("encryption block size {}", size),
+                aead::valid_block_size(size),
+            ));
+        }
+        if incompat & !INCOMPAT_SUPPORTED != 0 {
+            res.push(Requirement::new(
+                format!(
+                    "incompatible features {:#x}",
+                    incompat & !INCOMPAT_SUPPORTED
+                ),
+                false,
+            ));
+        }
+        res
+    }
+}
+
+/// Something needed to open an image, see `ImageHeader::requirements`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Requirement {
+    pub name: String,
+    /// If this build supports it
+    pub available: bool,
+}
+
+impl Requirement {
+    fn new(name: String, available: bool) -> Self {
+        Requirement { name, available }
+    }
+}
+
+impl fmt::Display for Requirement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.available {
+            write!(f, "{} (available)", self.name)
+        } else {
+            write!(f, "{} (not available)", self.name)
+        }
+    }
+}
+
+/// Read the header of an image and report what is needed to open it,
+/// without opening it.
+pub fn probe_image<T: ReadAt>(file: &T) -> Result<Vec<Requirement>> {
+    let header = read_header(file)?;
+    if !header.magic_valid() {
+        return Err(Error::Format(
+            sniff_format(file, &header.header.magic).unwrap_or("Wrong magic"),
+        ));
+    }
+    Ok(header.requirements())
+}
+
+/// Read the header of an image without validating it or opening the
+/// rest of the image.
+pub fn read_header<T: ReadAt>(file: &T) -> Result<ImageHeader> {
     let mut buf = Header::default();
-    file.read_exact_at(struct_to_mut_slice(&mut buf), 0)?;
-    Ok(buf)
+    file.read_exact_at(
+        &mut struct_to_mut_slice(&mut buf)[..HEADER_BASE_SIZE],
+        0,
+    )?;
+    let size = header_size(buf.version_minor) as usize;
+    if buf.magic == MAGIC
+        && buf.version_major == VERSION_MAJOR
+        && size > HEADER_BASE_SIZE
+    {
+        file.read_exact_at(
+            &mut struct_to_mut_slice(&mut buf)[HEADER_BASE_SIZE..size],
+            HEADER_BASE_SIZE as u64,
+        )?;
+    }
+    Ok(ImageHeader { header: buf })
+}
+
+/// Check the digest stored by `seal_image` against the contents of the
+/// image. This works on the stored bytes so encrypted images don't need
+/// their key. For split images only the metadata is covered.
+pub fn verify_integrity<T: ReadAt>(file: &T) -> Result<bool> {
+    let header = read_header(file)?;
+    let expected = header
+        .digest()
+        .ok_or(Error::InvalidOperation("image has no integrity digest"))?;
+    let mut hasher = digest_header(header.header);
+    let mut pos = header_size(header.header.version_minor);
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = file.read_at(&mut buf, pos)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        pos += n as u64;
+    }
+    Ok(*hasher.finalize().as_bytes() == expected)
+}
+
+// Start the digest of an image with its header, where the digest
+// itself is zeroed
+fn digest_header(mut header: Header) -> blake3::Hasher {
+    header.digest = [0; 32];
+    let size = header_size(header.version_minor) as usize;
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&struct_to_slice(&header)[..size]);
+    hasher
 }
 
 pub trait ReadAt {
     fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize>;
 
+    /// Length of the stream if it is known. Images then refuse offsets
+    /// past it before reading.
+    fn stream_len(&self) -> Option<u64> {
+        None
+    }
+
     fn read_exact_at(&self, mut buf: &mut [u8], mut offset: u64) -> Result<()> {
         while !buf.is_empty() {
             match self.read_at(buf, offset) {
@@ -225,10 +998,26 @@ pub trait ReadAt {
     }
 }
 
+impl<T: ReadAt + ?Sized> ReadAt for Arc<T> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        self.as_ref().read_at(buf, offset)
+    }
+
+    fn stream_len(&self) -> Option<u64> {
+        self.as_ref().stream_len()
+    }
+}
+
 impl ReadAt for std::fs::File {
     fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
         FileExt::read_at(self, buf, offset).map_err(|e| e.into())
     }
+
+    // Block devices have a length of 0 in their metadata
+    fn stream_len(&self) -> Option<u64> {
+        let meta = self.metadata().ok()?;
+        meta.is_file().then_some(meta.len())
+    }
 }
 
 impl<T> ReadAt for Cursor<T>
@@ -245,40 +1034,223 @@ where
         buf[..sz].copy_from_slice(&s[off..off + sz]);
         Ok(sz)
     }
+
+    fn stream_len(&self) -> Option<u64> {
+        Some(self.get_ref().as_ref().len() as u64)
+    }
+}
+
+/// An image file mapped in memory once, so that reads are copies
+/// instead of system calls. The file must not be truncated while it is
+/// mapped: reading what was past its new end is a fault.
+#[cfg(feature = "mmap")]
+pub struct MmapImage {
+    map: memmap2::Mmap,
+}
+
+#[cfg(feature = "mmap")]
+impl MmapImage {
+    pub fn open<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        Self::new(&std::fs::File::open(path)?)
+    }
+
+    pub fn new(file: &std::fs::File) -> Result<Self> {
+        // See above for what makes this unsafe
+        let map = unsafe { memmap2::Mmap::map(file)? };
+        Ok(MmapImage { map })
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl ReadAt for MmapImage {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        Cursor::new(&self.map[..]).read_at(buf, offset)
+    }
+
+    fn stream_len(&self) -> Option<u64> {
+        Some(self.map.len() as u64)
+    }
+}
+
+// Offset and value of the magic of a tar archive
+const TAR_MAGIC_OFFSET: u64 = 257;
+const TAR_MAGIC: &[u8; 5] = b"ustar";
+
+/// Try to recognize common formats that could be mistaken for an image
+/// to give a better error message.
+fn sniff_format<T: ReadAt>(file: &T, magic: &[u8; 8]) -> Option<&'static str> {
+    if magic.starts_with(b"PK\x03\x04") {
+        return Some("this looks like a zip file, not a squashfile");
+    }
+    if magic.starts_with(b"\x1f\x8b") {
+        return Some("this looks like a gzip file, not a squashfile");
+    }
+    if magic.starts_with(b"hsqs") || magic.starts_with(b"sqsh") {
+        return Some("this looks like a squashfs image, not a squashfile");
+    }
+    let mut buf = [0; 5];
+    if file.read_exact_at(&mut buf, TAR_MAGIC_OFFSET).is_ok()
+        && &buf == TAR_MAGIC
+    {
+        return Some("this looks like a tar file, not a squashfile");
+    }
+    None
+}
+
+fn decrypting<F: ReadAt + 'static>(
+    file: F,
+    header: &ImageHeader,
+    key: Key,
+    data: bool,
+) -> Result<Box<dyn ReadAt>> {
+    let with_nonce = |enc: crypto::EncryptChaCha20<F>| match (
+        header.nonce(),
+        header.short_nonce(),
+    ) {
+        (Some(nonce), _) => enc.with_nonce(nonce),
+        (None, Some(nonce)) => enc.with_short_nonce(nonce),
+        (None, None) => enc,
+    };
+    Ok(match header.encryption_type()? {
+        EncryptionType::None => Box::new(file),
+        EncryptionType::ChaCha20 if data => {
+            Box::new(with_nonce(crypto::EncryptChaCha20::new_data(file, key)?))
+        }
+        EncryptionType::ChaCha20 => {
+            Box::new(with_nonce(crypto::EncryptChaCha20::new(file, key)?))
+        }
+        EncryptionType::XChaCha20Poly1305 if data => {
+            Box::new(aead::DecryptXChaCha20Poly1305::new_data(
+                file,
+                key,
+                0,
+                header.block_size(),
+            )?)
+        }
+        EncryptionType::XChaCha20Poly1305 => {
+            let base = header_size(header.header.version_minor);
+            Box::new(aead::DecryptXChaCha20Poly1305::new(
+                file,
+                key,
+                base,
+                header.block_size(),
+            )?)
+        }
+    })
 }
 
 pub fn open_file<F: ReadAt + 'static>(file: F, key: Key) -> Result<Image> {
-    let header = read_header(&file)?;
+    open_image(file, None::<F>, key)
+}
+
+/// Open a split image from its metadata and the separate stream with
+/// the contents of its files.
+pub fn open_split<F, D>(metadata: F, data: D, key: Key) -> Result<Image>
+where
+    F: ReadAt + 'static,
+    D: ReadAt + 'static,
+{
+    open_image(metadata, Some(data), key)
+}
+
+fn open_image<F, D>(file: F, data: Option<D>, key: Key) -> Result<Image>
+where
+    F: ReadAt + 'static,
+    D: ReadAt + 'static,
+{
+    let image_header = read_header(&file)?;
+    let header = image_header.header;
 
     if header.magic != MAGIC {
-        return Err(Error::Format("Wrong magic"));
+        return Err(Error::Format(
+            sniff_format(&file, &header.magic).unwrap_or("Wrong magic"),
+        ));
     }
 
     if header.version_major != VERSION_MAJOR {
-        return Err(Error::Format("Unsupported major version"));
+        return Err(Error::UnsupportedVersion {
+            found_major: header.version_major,
+            found_minor: header.version_minor,
+            supported_major: VERSION_MAJOR,
+            supported_minor: VERSION_MINOR,
+        });
     }
 
-    if header.version_minor != VERSION_MINOR {
-        return Err(Error::Format("Unsupported minor version"));
+    if u32::from(header.incompat) & !INCOMPAT_SUPPORTED != 0 {
+        return Err(Error::Format("Unsupported incompatible features"));
     }
 
-    let stream: Box<dyn ReadAt> =
-        match EncryptionType::try_from(header.encryption_type)? {
-            EncryptionType::None => Box::new(file),
-            EncryptionType::ChaCha20 => {
-                Box::new(crypto::EncryptChaCha20::new(file, key)?)
-            }
-        };
+    // Minor versions only add optional data in space that older
+    // readers treat as padding, so any minor version can be read.
+
+    let split = u32::from(header.incompat) & INCOMPAT_SPLIT_DATA != 0;
+    let data = match (split, data) {
+        (true, None) => {
+            return Err(Error::Format("image needs a separate data stream"))
+        }
+        (false, Some(_)) => {
+            return Err(Error::InvalidOperation(
+                "image doesn't have a separate data stream",
+            ))
+        }
+        (_, Some(d)) => Some(decrypting(d, &image_header, key, true)?),
+        (_, None) => None,
+    };
+    let raw = Arc::new(file);
+    let stream = decrypting(raw.clone(), &image_header, key, false)?;
+
+    let compression = CompressionType::try_from(header.compression_type)?;
+    compress::check_supported(compression)?;
 
-    let comp_type = CompressionType::try_from(header.compression_type)?;
-    if comp_type != CompressionType::None {
-        return Err(Error::Bounds("Unsupported compression type"));
+    // A zeroed or corrupt header could point the root inside the header
+    // or past the end of the image.
+    if u64::from(header.root_inode) < header_size(header.version_minor) {
+        return Err(Error::Format("invalid root inode offset"));
     }
 
-    Ok(Image {
+    let img = Image {
+        len: stream.stream_len(),
+        data_len: data.as_ref().and_then(|d| d.stream_len()),
         file: stream,
-        header: header,
-    })
+        raw,
+        data,
+        header,
+        compression,
+        link_target_max: LINK_TARGET_MAX,
+        verify_checksums: false,
+        pinned: RwLock::default(),
+        cache: Mutex::new(Cache::new(CACHE_CAPACITY)),
+        authentic: Mutex::new(Authentic::new()),
+    };
+    let err = match img.root_inode() {
+        Ok(_) => return Ok(img),
+        Err(e) => e,
+    };
+    match err.inner() {
+        Error::IO(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            Err(Error::Format("invalid root inode offset"))
+        }
+        Error::Bounds(PAST_END) => {
+            Err(Error::Format("invalid root inode offset"))
+        }
+        _ => Err(err),
+    }
+}
+
+const PAST_END: &str = "offset past the end of the image";
+
+// Refuse to read `size` bytes at `off` of a stream of length `len`
+fn check_end(len: Option<u64>, off: u64, size: usize) -> Result<()> {
+    match (len, off.checked_add(size as u64)) {
+        (None, _) => Ok(()),
+        (Some(len), Some(end)) if end <= len => Ok(()),
+        _ => Err(Error::Bounds(PAST_END)),
+    }
+}
+
+// Offsets come from the image so they can't be trusted not to overflow.
+fn add_offset(a: u64, b: u64) -> Result<u64> {
+    a.checked_add(b).ok_or(Error::Bounds("offset overflow"))
 }
 
 impl Header {
@@ -297,16 +1269,32 @@ impl Inode {
     }
 
     pub fn read_dirent(&self, pos: u64, img: &Image) -> Result<Dirent> {
+        img.read_dirent(self.dirent_offset(pos, img)?)
+    }
+
+    /// The entry at `pos` with its name, which are cached by `img`.
+    pub fn read_named_dirent(
+        &self,
+        pos: u64,
+        img: &Image,
+    ) -> Result<(Dirent, CString)> {
+        img.read_named_dirent(self.dirent_offset(pos, img)?)
+    }
+
+    fn dirent_offset(&self, pos: u64, img: &Image) -> Result<u64> {
         if self.inode_type()? != InodeType::Directory {
             return Err(Error::InvalidOperation(
                 "Reading dirents from non-directory",
             ));
         }
-        let offset = pos * std::mem::size_of::<Dirent>() as u64;
-        if offset > self.size() {
-            return Err(Error::Bounds("dirent pos is beyond the directory"));
-        }
-        img.read_dirent(offset + u64::from(self.offset))
+        let dirent_size = std::mem::size_of::<Dirent>() as u64;
+        let offset = pos
+            .checked_mul(dirent_size)
+            .filter(|&o| self.size().saturating_sub(o) >= dirent_size)
+            .ok_or(Error::Bounds("dirent pos is beyond the directory"))?;
+        // All of the entries, so a corrupt size isn't read as entries
+        check_end(img.len, self.offset.into(), self.size() as usize)?;
+        add_offset(self.offset.into(), offset)
     }
 
     pub fn read_at(
@@ -319,7 +1307,7 @@ impl Inode {
             return Ok(0);
         }
         let sz = min(buf.len() as u64, self.size() - off) as usize;
-        img.read_file(&mut buf[..sz], u64::from(self.offset) + off)?;
+        img.read_data(self, &mut buf[..sz], off)?;
         Ok(sz)
     }
 
@@ -329,19 +1317,34 @@ impl Inode {
         off: u64,
         img: &Image,
     ) -> Result<()> {
-        if off + buf.len() as u64 > self.size() {
+        if off.saturating_add(buf.len() as u64) > self.size() {
             Err(io::Error::from(io::ErrorKind::UnexpectedEof).into())
         } else {
-            img.read_file(buf, u64::from(self.offset) + off)
+            img.read_data(self, buf, off)
         }
     }
 
     pub fn size(&self) -> u64 {
         self.size.into()
     }
+
+    /// Offset of the entries of a directory or the contents of a file.
+    pub fn data_offset(&self) -> u64 {
+        self.offset.into()
+    }
+
+    /// Major and minor numbers, for devices.
+    pub fn rdev(&self) -> (u64, u64) {
+        (self.offset.into(), self.size.into())
+    }
 }
 
 impl Dirent {
+    /// Offset of the inode, which identifies it in the image.
+    pub fn inode_offset(&self) -> u64 {
+        self.inode.into()
+    }
+
     pub fn inode(&self, img: &Image) -> Result<Inode> {
         img.read_inode(self.inode.into())
     }
@@ -352,20 +1355,97 @@ impl Dirent {
 }
 
 impl Image {
+    pub fn link_target_max(&self) -> usize {
+        self.link_target_max
+    }
+
+    /// Change the limit on the length of symlink targets, which can't
+    /// be more than LINK_TARGET_HARD_MAX.
+    pub fn set_link_target_max(&mut self, max: usize) -> Result<()> {
+        if max > LINK_TARGET_HARD_MAX {
+            return Err(Error::InvalidOperation("link target limit too large"));
+        }
+        self.link_target_max = max;
+        Ok(())
+    }
+
+    /// Keep up to `capacity` inodes and as many directory entries in
+    /// memory, the ones used last. 0 disables caching.
+    pub fn set_cache_capacity(&mut self, capacity: usize) {
+        self.cache = Mutex::new(Cache::new(capacity));
+    }
+
+    pub fn verify_checksums(&self) -> bool {
+        self.verify_checksums
+    }
+
+    /// Check the CRC-32 of files once they have been read to the end,
+    /// see `FsOptions::verify_checksums`.
+    pub fn set_verify_checksums(&mut self, verify: bool) {
+        self.verify_checksums = verify;
+    }
+
     fn read_inode(&self, off: u64) -> Result<Inode> {
+        if let Some(inode) = self.pinned.read().unwrap().inodes.get(&off) {
+            return Ok(*inode);
+        }
+        if let Some(inode) = self.cache.lock().unwrap().inodes.get(&off) {
+            return Ok(inode);
+        }
         let mut buf = Inode::default();
-        self.file
-            .read_exact_at(struct_to_mut_slice(&mut buf), off)?;
+        // Older images only have the base part, the rest stays zeroed
+        let size = inode_size(self.header.version_minor);
+        check_end(self.len, off, size)
+            .and_then(|_| {
+                self.file.read_exact_at(
+                    &mut struct_to_mut_slice(&mut buf)[..size],
+                    off,
+                )
+            })
+            .map_err(|e| e.at_offset(off))?;
+        self.cache.lock().unwrap().inodes.insert(off, buf);
         Ok(buf)
     }
 
+    /// Read `len` bytes of the image at `offset`, decrypted but
+    /// otherwise uninterpreted.
+    #[cfg(feature = "internals")]
+    pub fn read_raw(&self, offset: u64, len: usize) -> Result<Vec<u8>> {
+        if len > RAW_READ_MAX {
+            return Err(Error::Bounds("raw read too large"));
+        }
+        add_offset(offset, len as u64)?;
+        let mut buf = vec![0; len];
+        match self.file.read_exact_at(&mut buf, offset) {
+            Err(Error::IO(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                Err(Error::Bounds("raw read past the end of the image"))
+            }
+            r => r.map(|_| buf),
+        }
+    }
+
     fn read_dirent(&self, off: u64) -> Result<Dirent> {
         let mut buf = Dirent::default();
+        check_end(self.len, off, std::mem::size_of::<Dirent>())?;
         self.file
             .read_exact_at(struct_to_mut_slice(&mut buf), off)?;
         Ok(buf)
     }
 
+    fn read_named_dirent(&self, off: u64) -> Result<(Dirent, CString)> {
+        if let Some(ent) = self.cache.lock().unwrap().dirents.get(&off) {
+            return Ok(ent);
+        }
+        let ent = self.read_dirent(off)?;
+        let name = ent.name(self)?;
+        self.cache
+            .lock()
+            .unwrap()
+            .dirents
+            .insert(off, (ent, name.clone()));
+        Ok((ent, name))
+    }
+
     fn read_str(&self, off: u64) -> Result<CString> {
         let mut buf = Vec::new();
         let mut off = off;
@@ -380,8 +1460,8 @@ impl Image {
             }
             // In case of a short read
             let tmp = &tmp_read[..read];
-            off += read as u64;
-            match memchr(0, &tmp) {
+            off = add_offset(off, read as u64)?;
+            match memchr(0, tmp) {
                 Some(i) => {
                     buf.extend_from_slice(&tmp[..=i]);
                     return Ok(unsafe {
@@ -390,14 +1470,423 @@ impl Image {
                 }
                 None => buf.extend_from_slice(tmp),
             }
+            if buf.len() > NAME_MAX {
+                return Err(Error::Bounds("name too long"));
+            }
         }
     }
 
     fn read_file(&self, buf: &mut [u8], off: u64) -> Result<()> {
-        self.file.read_exact_at(buf, off).map_err(|e| e.into())
+        check_end(self.len, off, buf.len())?;
+        self.file.read_exact_at(buf, off)
+    }
+
+    // Where the contents of `inode` are stored
+    fn data_source(&self, inode: &Inode) -> &dyn ReadAt {
+        match self.data {
+            Some(ref d) if inode.inode_type == u8::from(InodeType::File) => {
+                d.as_ref()
+            }
+            _ => self.file.as_ref(),
+        }
+    }
+
+    // Length of data_source
+    fn data_source_len(&self, inode: &Inode) -> Option<u64> {
+        match self.data {
+            Some(_) if inode.inode_type == u8::from(InodeType::File) => {
+                self.data_len
+            }
+            _ => self.len,
+        }
+    }
+
+    // Whether the contents of `inode` have a block table, see compress
+    fn has_block_table(&self, inode: &Inode) -> bool {
+        // Only file contents are compressed
+        (self.compression != CompressionType::None
+            || u32::from(self.header.incompat) & INCOMPAT_BLOCK_TABLES != 0)
+            && inode.inode_type == u8::from(InodeType::File)
+    }
+
+    // Offset of the hash tree of `inode`, if it has one
+    fn merkle_tree(&self, inode: &Inode) -> Option<u64> {
+        if self.header().has_merkle()
+            && inode.inode_type == u8::from(InodeType::File)
+        {
+            Some(inode.merkle.into())
+        } else {
+            None
+        }
+    }
+
+    // Check the root of the hash tree at `tree` of the file `inode`
+    // against the hash of its entry in its parent directory, and so on
+    // up to the root hash of the header, for images with
+    // INCOMPAT_MERKLE. Hard links are checked in the directory their
+    // parent inode is.
+    fn authenticate_file(&self, inode: &Inode, tree: u64) -> Result<()> {
+        if !self.header().has_dir_hashes() {
+            return Ok(());
+        }
+        let root = merkle::root(self.data_source(inode), tree, inode.size())?;
+        let parent = u64::from(inode.parent_inode);
+        if !self.authentic_entry(parent, &root)? {
+            return Err(Error::Crypto("file hash verification failed"));
+        }
+        Ok(())
+    }
+
+    // Whether `hash` is the hash of an entry of the directory at `off`,
+    // checking that directory and the ones above it first if they
+    // weren't yet.
+    fn authentic_entry(&self, off: u64, hash: &merkle::Hash) -> Result<bool> {
+        if let Some(hashes) = self.authentic.lock().unwrap().get(&off) {
+            return Ok(hashes.contains(hash));
+        }
+        // From the directory up to the first one checked or the root
+        let root = u64::from(self.header.root_inode);
+        let mut chain = vec![(off, self.read_inode(off)?)];
+        loop {
+            let (off, dir) = chain[chain.len() - 1];
+            let parent = u64::from(dir.parent_inode);
+            if off == root
+                || self.authentic.lock().unwrap().contains_key(&parent)
+            {
+                break;
+            }
+            if chain.iter().any(|&(o, _)| o == parent) {
+                return Err(Error::Format("directory cycle"));
+            }
+            chain.push((parent, self.read_inode(parent)?));
+        }
+        for (off, dir) in chain.into_iter().rev() {
+            self.authenticate_dir(off, &dir)
+                .map_err(|e| e.at_offset(off))?;
+        }
+        Ok(self.authentic.lock().unwrap()[&off].contains(hash))
+    }
+
+    // Check the entries of the directory `dir` at `off`, whose parent
+    // was checked, against the hash it stores and that hash against
+    // its entry in its parent.
+    fn authenticate_dir(&self, off: u64, dir: &Inode) -> Result<()> {
+        if dir.inode_type()? != InodeType::Directory {
+            return Err(Error::Format("parent inode is not a directory"));
+        }
+        let expected = self.dir_hash(dir)?;
+        let authentic = if off == u64::from(self.header.root_inode) {
+            expected == self.header.root_hash
+        } else {
+            let parent = u64::from(dir.parent_inode);
+            self.authentic.lock().unwrap()[&parent].contains(&expected)
+        };
+        let mut hasher = merkle::DirHasher::new();
+        let mut hashes = HashSet::new();
+        for pos in 0..dir.size() / std::mem::size_of::<Dirent>() as u64 {
+            let (ent, name) = dir.read_named_dirent(pos, self)?;
+            let hash = self.entry_hash(&ent.inode(self)?)?;
+            hasher.add(name.as_bytes(), &hash);
+            hashes.insert(hash);
+        }
+        if !authentic || hasher.finish() != expected {
+            return Err(Error::Crypto("directory hash verification failed"));
+        }
+        self.authentic.lock().unwrap().insert(off, hashes);
+        Ok(())
+    }
+
+    // The hash the directory `inode` stores, with INCOMPAT_MERKLE
+    fn dir_hash(&self, inode: &Inode) -> Result<merkle::Hash> {
+        let mut hash = merkle::Hash::default();
+        self.read_file(&mut hash, inode.merkle.into())?;
+        Ok(hash)
+    }
+
+    // Hash of `inode` as an entry of its directory, as they are
+    // combined by merkle::DirHasher
+    fn entry_hash(&self, inode: &Inode) -> Result<merkle::Hash> {
+        let ty = inode.inode_type()?;
+        match ty {
+            InodeType::File => merkle::root(
+                self.data_source(inode),
+                inode.merkle.into(),
+                inode.size(),
+            ),
+            InodeType::Directory => self.dir_hash(inode),
+            InodeType::Symlink => {
+                if inode.size() > self.link_target_max as u64 {
+                    return Err(Error::Bounds("link target too long"));
+                }
+                let mut target = vec![0; inode.size() as usize];
+                inode.read_exact_at(&mut target, 0, self)?;
+                Ok(merkle::symlink_hash(&target))
+            }
+            _ => Ok(merkle::special_hash(ty, inode.rdev())),
+        }
+    }
+
+    /// Positions of the entries of the directory `inode` that can be
+    /// named `name`, None if the directory has no hash index.
+    pub fn index_candidates(
+        &self,
+        inode: &Inode,
+        name: &[u8],
+    ) -> Result<Option<Vec<u64>>> {
+        let h = &self.header;
+        let offset = u64::from(inode.hash_index);
+        if h.version_minor < MINOR_HASH_INDEX
+            || u32::from(h.compat) & COMPAT_HASH_INDEX == 0
+            || inode.inode_type()? != InodeType::Directory
+            || offset == 0
+        {
+            return Ok(None);
+        }
+        let entries = inode.size() / std::mem::size_of::<Dirent>() as u64;
+        index::candidates(self.file.as_ref(), offset, entries, name).map(Some)
+    }
+
+    /// Extended attributes of `inode`, empty if it has none.
+    pub fn xattrs(&self, inode: &Inode) -> Result<Vec<(CString, Vec<u8>)>> {
+        let h = &self.header;
+        let offset = u64::from(inode.xattrs);
+        if h.version_minor < MINOR_XATTR
+            || u32::from(h.compat) & COMPAT_XATTR == 0
+            || offset == 0
+        {
+            return Ok(Vec::new());
+        }
+        xattr::decode(self.file.as_ref(), offset)
+    }
+
+    // Read the data of `inode` at `off`, checking it against its hash
+    // tree if it has one.
+    fn read_data(&self, inode: &Inode, buf: &mut [u8], off: u64) -> Result<()> {
+        let tree = match self.merkle_tree(inode) {
+            Some(t) if !buf.is_empty() => t,
+            _ => return self.read_stored(inode, buf, off),
+        };
+        self.authenticate_file(inode, tree)?;
+        // Whole blocks are needed to check them
+        let bs = merkle::BLOCK_SIZE;
+        let start = off / bs * bs;
+        let end = min(
+            add_offset(off, buf.len() as u64)?.div_ceil(bs) * bs,
+            inode.size(),
+        );
+        let mut tmp = vec![0; (end - start) as usize];
+        self.read_stored(inode, &mut tmp, start)?;
+        for (i, block) in tmp.chunks(bs as usize).enumerate() {
+            merkle::verify_block(
+                self.data_source(inode),
+                tree,
+                inode.size(),
+                start / bs + i as u64,
+                block,
+            )?;
+        }
+        let from = (off - start) as usize;
+        buf.copy_from_slice(&tmp[from..from + buf.len()]);
+        Ok(())
+    }
+
+    // Read the data of `inode` at `off`, decompressing it if needed.
+    //
+    // Uncompressed data without a block table is read directly.
+    fn read_stored(
+        &self,
+        inode: &Inode,
+        buf: &mut [u8],
+        off: u64,
+    ) -> Result<()> {
+        if self.has_block_table(inode) {
+            compress::read_at(
+                self.compression,
+                self.data_source(inode),
+                inode.offset.into(),
+                inode.size(),
+                buf,
+                off,
+            )
+        } else {
+            let off = add_offset(inode.offset.into(), off)?;
+            check_end(self.data_source_len(inode), off, buf.len())?;
+            self.data_source(inode).read_exact_at(buf, off)
+        }
     }
 
     pub fn root_inode(&self) -> Result<Inode> {
         self.header.root_inode(self)
     }
+
+    /// Keep the inode at `off` in memory so reading it again doesn't
+    /// touch the image.
+    pub fn pin_inode(&self, off: u64) -> Result<Inode> {
+        let inode = self.read_inode(off)?;
+        self.pinned.write().unwrap().inodes.insert(off, inode);
+        Ok(inode)
+    }
+
+    /// Keep the root inode in memory.
+    pub fn pin_root(&self) -> Result<Inode> {
+        self.pin_inode(self.header.root_inode.into())
+    }
+
+    /// Keep the parent of `inode` in memory.
+    pub fn pin_parent(&self, inode: &Inode) -> Result<Inode> {
+        self.pin_inode(inode.parent_inode.into())
+    }
+
+    /// Keep `ent`, the entry `name` of the directory `dir`, and its
+    /// inode in memory.
+    pub fn pin_entry(
+        &self,
+        dir: &Inode,
+        name: &[u8],
+        ent: Dirent,
+    ) -> Result<Inode> {
+        let inode = self.pin_inode(ent.inode.into())?;
+        self.pinned
+            .write()
+            .unwrap()
+            .entries
+            .entry(dir.offset.into())
+            .or_default()
+            .insert(name.to_vec(), ent);
+        Ok(inode)
+    }
+
+    /// The entry `name` of the directory `dir` if it was pinned.
+    pub fn pinned_entry(&self, dir: &Inode, name: &[u8]) -> Option<Dirent> {
+        // Empty directories could point at the entries of another one
+        if dir.size() == 0 {
+            return None;
+        }
+        let pinned = self.pinned.read().unwrap();
+        let entries = pinned.entries.get(&u64::from(dir.offset))?;
+        entries.get(name).copied()
+    }
+
+    pub fn header(&self) -> ImageHeader {
+        ImageHeader {
+            header: self.header,
+        }
+    }
+
+    pub fn verify_integrity(&self) -> Result<bool> {
+        verify_integrity(&self.raw)
+    }
+
+    /// Order of the entries of the directory `inode`.
+    pub fn collation(&self, inode: &Inode) -> Result<Collation> {
+        // Without the feature flag, this byte is padding
+        if u32::from(self.header.incompat) & INCOMPAT_COLLATION == 0 {
+            Ok(Collation::Bytes)
+        } else {
+            Collation::try_from(inode.collation)
+        }
+    }
+
+    /// Permission bits of `inode`, if the image stores them.
+    pub fn mode(&self, inode: &Inode) -> Option<u32> {
+        // Older writers left this as padding
+        if self.header.version_minor < MINOR_MODE {
+            None
+        } else {
+            Some(inode.mode.into())
+        }
+    }
+
+    /// Modification time of `inode` in seconds since the epoch, if the
+    /// image stores it.
+    pub fn mtime(&self, inode: &Inode) -> Option<u64> {
+        // Zero means unknown, older images have it zeroed by read_inode
+        match u64::from(inode.mtime) {
+            0 => None,
+            t => Some(t),
+        }
+    }
+
+    /// Owner of `inode` as (uid, gid), if the image stores it.
+    pub fn owner(&self, inode: &Inode) -> Option<(u32, u32)> {
+        // 0 is root so this can't use zero as unknown like mtime
+        if self.header.version_minor < MINOR_OWNER {
+            None
+        } else {
+            Some((inode.uid.into(), inode.gid.into()))
+        }
+    }
+
+    /// Total size of the files below the directory `inode`, if the
+    /// image stores it.
+    pub fn subtree_size(&self, inode: &Inode) -> Result<Option<u64>> {
+        if u32::from(self.header.compat) & COMPAT_SUBTREE_SIZE == 0
+            || inode.inode_type()? != InodeType::Directory
+        {
+            return Ok(None);
+        }
+        // It is stored right before the entries
+        let off = u64::from(inode.offset)
+            .checked_sub(8)
+            .ok_or(Error::Bounds("offset overflow"))?;
+        let mut buf = [0; 8];
+        self.read_file(&mut buf, off)?;
+        Ok(Some(u64::from_le_bytes(buf)))
+    }
+
+    /// CRC-32 of the contents of the file `inode`, if the image has them.
+    pub fn file_checksum(&self, inode: &Inode) -> Option<u32> {
+        let h = &self.header;
+        if h.version_minor >= MINOR_CRC32
+            && u32::from(h.compat) & COMPAT_CRC32 != 0
+            && inode.inode_type == u8::from(InodeType::File)
+        {
+            Some(inode.crc32.into())
+        } else {
+            None
+        }
+    }
+
+    /// Root of the hash tree of the file `inode`, if it has one.
+    pub fn file_root_hash(&self, inode: &Inode) -> Result<Option<[u8; 32]>> {
+        match self.merkle_tree(inode) {
+            Some(t) => Ok(Some(merkle::root(
+                self.data_source(inode),
+                t,
+                inode.size(),
+            )?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Range of the data stream taken by the contents of the file
+    /// `inode`, including the block table of compressed files.
+    pub fn data_extent(&self, inode: &Inode) -> Result<std::ops::Range<u64>> {
+        let offset = u64::from(inode.offset);
+        if self.has_block_table(inode) {
+            compress::extent(
+                self.compression,
+                self.data_source(inode),
+                offset,
+                inode.size(),
+            )
+        } else {
+            Ok(offset..add_offset(offset, inode.size())?)
+        }
+    }
+
+    /// Number of bytes the data of `inode` takes in the image, its
+    /// block table included.
+    pub fn stored_size(&self, inode: &Inode) -> Result<u64> {
+        if self.has_block_table(inode) {
+            compress::stored_size(
+                self.compression,
+                self.data_source(inode),
+                inode.offset.into(),
+                inode.size(),
+            )
+        } else {
+            Ok(inode.size())
+        }
+    }
 }
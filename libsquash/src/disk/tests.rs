@@ -1,5 +1,8 @@
+use std::io::Cursor;
 use std::matches;
+use std::path::Path;
 
+use crate::Error;
 use crate::Result;
 
 use crate::disk;
@@ -8,6 +11,7 @@ use crate::disk::u64le;
 use crate::disk::CompressionType;
 use crate::disk::EncryptionType;
 use crate::disk::InodeType;
+use crate::fs::{Directory, FSItem, FS};
 
 #[test]
 fn test_u64le() {
@@ -48,13 +52,57 @@ fn test_encryption_type() {
     let v: u8 = 2;
     let t: Result<EncryptionType> = v.try_into();
 
-    assert!(matches!(t, Err(_)));
+    assert!(matches!(t, Ok(EncryptionType::XChaCha20Poly1305)));
+
+    let v: u8 = 3;
+    let t: Result<EncryptionType> = v.try_into();
+
+    assert!(t.is_err());
 
     let v: u8 = EncryptionType::None.into();
     assert_eq!(v, 0);
 
     let v: u8 = EncryptionType::ChaCha20.into();
     assert_eq!(v, 1);
+
+    let v: u8 = EncryptionType::XChaCha20Poly1305.into();
+    assert_eq!(v, 2);
+}
+
+#[test]
+fn test_type_names() {
+    for ty in EncryptionType::all() {
+        assert_eq!(ty.to_string().parse::<EncryptionType>().unwrap(), ty);
+        assert_eq!(EncryptionType::try_from(u8::from(ty)).unwrap(), ty);
+    }
+    for ty in CompressionType::all() {
+        assert_eq!(ty.to_string().parse::<CompressionType>().unwrap(), ty);
+        assert_eq!(CompressionType::try_from(u8::from(ty)).unwrap(), ty);
+    }
+    assert_eq!(EncryptionType::all().count(), 3);
+    assert!(matches!(
+        "rot13".parse::<EncryptionType>(),
+        Err(Error::Format(_))
+    ));
+    assert!("".parse::<CompressionType>().is_err());
+}
+
+#[test]
+fn test_validate_key() {
+    let ty = EncryptionType::ChaCha20;
+    assert_eq!(ty.key_len(), 36);
+    assert!(matches!(ty.validate_key(Some(&[0; 36])), Ok(())));
+    assert!(matches!(ty.validate_key(None), Err(Error::KeyRequired)));
+
+    let err = ty.validate_key(Some(&[0; 20])).unwrap_err();
+    assert_eq!(err.to_string(), "chacha20 requires a 36-byte key; got 20");
+    let err = ty.validate_key(Some(&[0; 40])).unwrap_err();
+    assert_eq!(err.to_string(), "chacha20 requires a 36-byte key; got 40");
+
+    let ty = EncryptionType::None;
+    assert_eq!(ty.key_len(), 0);
+    assert!(matches!(ty.validate_key(None), Ok(())));
+    assert!(matches!(ty.validate_key(Some(&[0; 20])), Ok(())));
 }
 
 #[test]
@@ -67,10 +115,18 @@ fn test_compression_type() {
     let v: u8 = 1;
     let t: Result<CompressionType> = v.try_into();
 
-    assert!(matches!(t, Err(_)));
+    assert!(matches!(t, Ok(CompressionType::Zstd)));
+
+    let v: u8 = 2;
+    let t: Result<CompressionType> = v.try_into();
+
+    assert!(t.is_err());
 
     let v: u8 = CompressionType::None.into();
     assert_eq!(v, 0);
+
+    let v: u8 = CompressionType::Zstd.into();
+    assert_eq!(v, 1);
 }
 
 #[test]
@@ -90,10 +146,15 @@ fn test_inode_type() {
 
     assert!(matches!(t, Ok(InodeType::Symlink)));
 
-    let v: u8 = 3;
+    let v: u8 = 5;
+    let t = v.try_into();
+
+    assert!(matches!(t, Ok(InodeType::Fifo)));
+
+    let v: u8 = 7;
     let t: Result<InodeType> = v.try_into();
 
-    assert!(matches!(t, Err(_)));
+    assert!(t.is_err());
 
     let v: u8 = InodeType::Directory.into();
     assert_eq!(v, 0);
@@ -103,13 +164,16 @@ fn test_inode_type() {
 
     let v: u8 = InodeType::Symlink.into();
     assert_eq!(v, 2);
+
+    let v: u8 = InodeType::Socket.into();
+    assert_eq!(v, 6);
 }
 
 #[test]
 fn test_open() {
     let f = std::fs::File::open("test_data/small.sqh").unwrap();
     let img = disk::open_file(f, None);
-    assert!(matches!(img, Ok(_)));
+    assert!(img.is_ok());
 }
 
 #[test]
@@ -118,5 +182,1021 @@ fn test_get_root() {
     let img = disk::open_file(f, None).unwrap();
 
     let root = img.root_inode();
-    assert!(matches!(root, Ok(_)));
+    assert!(root.is_ok());
+}
+
+#[test]
+fn test_read_header() {
+    let f = std::fs::File::open("test_data/small.sqh").unwrap();
+    let hdr = disk::read_header(&f).unwrap();
+    assert!(hdr.magic_valid());
+    assert_eq!(&hdr.magic(), b"SQUASHFL");
+    assert_eq!(hdr.version_major(), 0);
+    assert_eq!(hdr.version_minor(), 0);
+    assert!(matches!(hdr.compression_type(), Ok(CompressionType::None)));
+    assert!(matches!(hdr.encryption_type(), Ok(EncryptionType::None)));
+    assert!(hdr.root_inode() >= std::mem::size_of::<disk::Header>() as u64);
+
+    // The same is available from an opened image
+    let fs = FS::open(f, None).unwrap();
+    assert_eq!(fs.header().root_inode(), hdr.root_inode());
+    assert_eq!(fs.header().version_minor(), 0);
+}
+
+#[test]
+fn test_read_header_encrypted() {
+    let key = [7; 36];
+    let mut out = Cursor::new(Vec::new());
+    disk::write::write_image(
+        "test_data/small",
+        &mut out,
+        Some(&key),
+        EncryptionType::ChaCha20,
+    )
+    .unwrap();
+    let hdr = disk::read_header(&out).unwrap();
+    assert!(hdr.magic_valid());
+    assert!(matches!(
+        hdr.encryption_type(),
+        Ok(EncryptionType::ChaCha20)
+    ));
+}
+
+#[test]
+fn test_open_key_errors() {
+    let key = [7; 36];
+    let mut out = Cursor::new(Vec::new());
+    disk::write::write_image(
+        "test_data/small",
+        &mut out,
+        Some(&key),
+        EncryptionType::ChaCha20,
+    )
+    .unwrap();
+    let data = out.into_inner();
+
+    assert!(matches!(
+        disk::open_file(Cursor::new(data.clone()), None),
+        Err(Error::KeyRequired)
+    ));
+    assert!(matches!(
+        disk::open_file(Cursor::new(data.clone()), Some(&key[..32])),
+        Err(Error::InvalidKeyLength {
+            cipher: "chacha20",
+            expected: 36,
+            found: 32,
+        })
+    ));
+    assert!(disk::open_file(Cursor::new(data), Some(&key)).is_ok());
+}
+
+#[test]
+fn test_probe_image() {
+    let mut data = build_image("test_data/small");
+    assert_eq!(
+        disk::probe_image(&Cursor::new(&data)).unwrap(),
+        [disk::Requirement {
+            name: "block tables".into(),
+            available: true
+        }]
+    );
+
+    let key = [7; 36];
+    let mut out = Cursor::new(Vec::new());
+    disk::write::write_image(
+        "test_data/small",
+        &mut out,
+        Some(&key),
+        EncryptionType::ChaCha20,
+    )
+    .unwrap();
+    assert_eq!(
+        disk::probe_image(&out).unwrap(),
+        [
+            disk::Requirement {
+                name: "chacha20".into(),
+                available: true
+            },
+            disk::Requirement {
+                name: "wide nonce".into(),
+                available: true
+            },
+            disk::Requirement {
+                name: "block tables".into(),
+                available: true
+            }
+        ]
+    );
+
+    // zstd and an unknown compression type
+    data[18] = 1;
+    let reqs = disk::probe_image(&Cursor::new(&data)).unwrap();
+    assert_eq!(reqs[0].name, "zstd");
+    assert_eq!(reqs[0].available, cfg!(feature = "zstd"));
+    data[18] = 9;
+    let reqs = disk::probe_image(&Cursor::new(&data)).unwrap();
+    assert_eq!(reqs[0].to_string(), "compression type 9 (not available)");
+    assert!(disk::open_file(Cursor::new(data.clone()), None).is_err());
+
+    data[18] = 0;
+    data[16] = disk::VERSION_MAJOR + 1;
+    data[20..24].copy_from_slice(&0x8000_0001u32.to_le_bytes());
+    let reqs: Vec<_> = disk::probe_image(&Cursor::new(&data))
+        .unwrap()
+        .iter()
+        .map(|r| r.to_string())
+        .collect();
+    assert_eq!(
+        reqs,
+        [
+            format!(
+                "format version {}.{} (not available)",
+                disk::VERSION_MAJOR + 1,
+                disk::VERSION_MINOR
+            ),
+            "collation (available)".into(),
+            "incompatible features 0x80000000 (not available)".into(),
+        ]
+    );
+
+    assert!(matches!(
+        disk::probe_image(&Cursor::new(vec![0; 64])),
+        Err(Error::Format("Wrong magic"))
+    ));
+}
+
+#[test]
+fn test_unsupported_version() {
+    let mut data = build_image("test_data/small");
+    data[16] = disk::VERSION_MAJOR + 1;
+    data[17] = 2;
+    let err = open_err(data);
+    assert!(matches!(
+        err,
+        Error::UnsupportedVersion {
+            found_major,
+            found_minor: 2,
+            supported_major,
+            supported_minor,
+        } if found_major == disk::VERSION_MAJOR + 1
+            && supported_major == disk::VERSION_MAJOR
+            && supported_minor == disk::VERSION_MINOR
+    ));
+    assert_eq!(
+        err.to_string(),
+        format!(
+            "image is version {}.2, this build supports {}.{}",
+            disk::VERSION_MAJOR + 1,
+            disk::VERSION_MAJOR,
+            disk::VERSION_MINOR
+        )
+    );
+}
+
+fn open_err(data: Vec<u8>) -> Error {
+    match disk::open_file(Cursor::new(data), None) {
+        Ok(_) => panic!("open succeeded"),
+        Err(e) => e.into_inner(),
+    }
+}
+
+#[test]
+fn test_other_formats() {
+    let mut data = vec![0; 32];
+    data[..4].copy_from_slice(b"PK\x03\x04");
+    assert!(matches!(
+        open_err(data),
+        Error::Format("this looks like a zip file, not a squashfile")
+    ));
+
+    let mut data = vec![0; 32];
+    data[..3].copy_from_slice(b"\x1f\x8b\x08");
+    assert!(matches!(
+        open_err(data),
+        Error::Format("this looks like a gzip file, not a squashfile")
+    ));
+
+    for magic in [b"hsqs", b"sqsh"] {
+        let mut data = vec![0; 32];
+        data[..4].copy_from_slice(magic);
+        assert!(matches!(
+            open_err(data),
+            Error::Format("this looks like a squashfs image, not a squashfile")
+        ));
+    }
+
+    let mut data = vec![0; 512];
+    data[..8].copy_from_slice(b"file.txt");
+    data[257..265].copy_from_slice(b"ustar\x0000");
+    assert!(matches!(
+        open_err(data),
+        Error::Format("this looks like a tar file, not a squashfile")
+    ));
+
+    assert!(matches!(
+        open_err(vec![0; 512]),
+        Error::Format("Wrong magic")
+    ));
+}
+
+#[test]
+fn test_invalid_root_inode() {
+    let data = build_image("test_data/small");
+
+    let mut bad = data.clone();
+    bad[8..16].fill(0);
+    assert!(matches!(
+        open_err(bad),
+        Error::Format("invalid root inode offset")
+    ));
+
+    let mut bad = data.clone();
+    bad[8..16].copy_from_slice(&16u64.to_le_bytes());
+    assert!(matches!(
+        open_err(bad),
+        Error::Format("invalid root inode offset")
+    ));
+
+    let mut bad = data.clone();
+    let past_end = data.len() as u64 - 8;
+    bad[8..16].copy_from_slice(&past_end.to_le_bytes());
+    assert!(matches!(
+        open_err(bad),
+        Error::Format("invalid root inode offset")
+    ));
+
+    let mut bad = data;
+    bad[8..16].copy_from_slice(&u64::MAX.to_le_bytes());
+    assert!(matches!(
+        open_err(bad),
+        Error::Format("invalid root inode offset")
+    ));
+}
+
+fn build_image<P: AsRef<Path>>(source: P) -> Vec<u8> {
+    let mut out = Cursor::new(Vec::new());
+    disk::write::write_image(source, &mut out, None, EncryptionType::None)
+        .unwrap();
+    out.into_inner()
+}
+
+fn build_image_with<P: AsRef<Path>>(
+    source: P,
+    compression: CompressionType,
+) -> Vec<u8> {
+    let opts = disk::write::WriteOptions {
+        compression,
+        ..Default::default()
+    };
+    let mut out = Cursor::new(Vec::new());
+    disk::write::write_image_with(
+        source,
+        &mut out,
+        None,
+        EncryptionType::None,
+        &opts,
+    )
+    .unwrap();
+    out.into_inner()
+}
+
+fn get_u64(buf: &[u8], off: u64) -> u64 {
+    let off = off as usize;
+    u64::from_le_bytes(buf[off..off + 8].try_into().unwrap())
+}
+
+#[test]
+fn test_write_summary() {
+    let mut out = Cursor::new(Vec::new());
+    let summary = disk::write::write_image(
+        "test_data/small",
+        &mut out,
+        None,
+        EncryptionType::None,
+    )
+    .unwrap();
+    assert_eq!(
+        summary,
+        disk::write::WriteSummary {
+            files: 3,
+            dirs: 3,
+            symlinks: 1,
+            specials: 0,
+            total_data_bytes: 21,
+            image_size: out.get_ref().len() as u64,
+            data_size: 0,
+            case_collisions: Vec::new(),
+            hardlinks: 0,
+            shared_symlinks: 0,
+            dedup_bytes: 0,
+            vanished: Vec::new(),
+            size_excluded: Vec::new(),
+        }
+    );
+}
+
+#[test]
+fn test_vanished_entries() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("file"), "data").unwrap();
+    std::fs::create_dir(dir.path().join("sub")).unwrap();
+    let file_type = |p: &str| {
+        std::fs::symlink_metadata(dir.path().join(p))
+            .unwrap()
+            .file_type()
+    };
+    let (file_ty, dir_ty) = (file_type("file"), file_type("sub"));
+    // As if they were removed after listing their directory
+    std::fs::remove_file(dir.path().join("file")).unwrap();
+    std::fs::remove_dir(dir.path().join("sub")).unwrap();
+
+    let opts = disk::write::WriteOptions::default();
+    let mut summary = disk::write::WriteSummary::default();
+    let mut out = Cursor::new(Vec::new());
+    for (name, ty) in [("file", file_ty), ("sub", dir_ty)] {
+        let path = dir.path().join(name);
+        let res = disk::write::write_entry(
+            &path,
+            ty,
+            &mut out,
+            None,
+            &opts,
+            &mut summary,
+            &mut Default::default(),
+        );
+        assert!(matches!(res, Ok(None)));
+    }
+    assert_eq!(
+        summary.vanished,
+        vec![dir.path().join("file"), dir.path().join("sub")]
+    );
+    assert!(out.get_ref().is_empty());
+
+    let opts = disk::write::WriteOptions {
+        skip_vanished: false,
+        ..Default::default()
+    };
+    let res = disk::write::write_entry(
+        &dir.path().join("file"),
+        file_ty,
+        &mut out,
+        None,
+        &opts,
+        &mut summary,
+        &mut Default::default(),
+    );
+    assert!(matches!(res, Err(Error::IO(_))));
+}
+
+#[test]
+fn test_case_collisions() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir(dir.path().join("sub")).unwrap();
+    for name in ["Foo", "foo", "bar", "sub/Baz", "sub/baz.txt"] {
+        std::fs::write(dir.path().join(name), name).unwrap();
+    }
+    let opts = disk::write::WriteOptions {
+        warn_case_collisions: true,
+        ..Default::default()
+    };
+    let summary = disk::write::write_image_with(
+        dir.path(),
+        Cursor::new(Vec::new()),
+        None,
+        EncryptionType::None,
+        &opts,
+    )
+    .unwrap();
+    assert_eq!(
+        summary.case_collisions,
+        [(dir.path().join("Foo"), dir.path().join("foo"))]
+    );
+
+    let summary = disk::write::write_image(
+        dir.path(),
+        Cursor::new(Vec::new()),
+        None,
+        EncryptionType::None,
+    )
+    .unwrap();
+    assert!(summary.case_collisions.is_empty());
+}
+
+#[test]
+fn test_newer_minor_version() {
+    let mut data = build_image("test_data/small");
+    // bump the minor version
+    data[17] = disk::VERSION_MINOR + 1;
+    // fill the padding of the root inode, its first dirent's inode
+    // and that inode's first dirent's inode (a file) with junk
+    let root = get_u64(&data, 8);
+    let dir = get_u64(&data, get_u64(&data, root + 8) + 8);
+    let file = get_u64(&data, get_u64(&data, dir + 8) + 8);
+    for inode in [root, dir, file] {
+        let pad = (inode + 25) as usize;
+        data[pad..pad + 7].fill(0xa5);
+    }
+
+    let fs = FS::open(Cursor::new(data), None).unwrap();
+    let names: Vec<_> = fs
+        .get_root()
+        .unwrap()
+        .iter()
+        .map(|e| e.unwrap().file_name().unwrap().into_bytes())
+        .collect();
+    assert_eq!(names, [&b"dir"[..], b"hello.txt", b"link"]);
+    match fs.resolve("dir/nested.txt").unwrap() {
+        Some(FSItem::File(f)) => {
+            let mut buf = vec![0; f.size() as usize];
+            f.read_exact_at(&mut buf, 0).unwrap();
+            assert_eq!(buf, b"nested\n");
+        }
+        _ => panic!("dir/nested.txt is not a file"),
+    }
+    match fs.resolve("link").unwrap() {
+        Some(FSItem::File(f)) => assert_eq!(f.size(), 14),
+        _ => panic!("link does not resolve to a file"),
+    }
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn test_zstd_size_mismatch() {
+    let mut data = build_image_with("test_data/small", CompressionType::Zstd);
+    // root -> hello.txt is the second entry
+    let root = get_u64(&data, 8);
+    let file = get_u64(&data, get_u64(&data, root + 8) + 16 + 8);
+    let size = (file + 16) as usize;
+    data[size..size + 8].copy_from_slice(&15u64.to_le_bytes());
+
+    let fs = FS::open(Cursor::new(data), None).unwrap();
+    match fs.resolve("hello.txt").unwrap() {
+        Some(FSItem::File(f)) => {
+            let mut buf = vec![0; f.size() as usize];
+            assert!(matches!(
+                f.read_exact_at(&mut buf, 0),
+                Err(Error::Compression("size mismatch"))
+            ));
+        }
+        _ => panic!("hello.txt is not a file"),
+    }
+}
+
+fn set_u64(buf: &mut [u8], off: u64, val: u64) {
+    let off = off as usize;
+    buf[off..off + 8].copy_from_slice(&val.to_le_bytes());
+}
+
+// Go through everything reachable from the root, with a depth limit
+// since a corrupt image can have cycles.
+fn exercise_dir(dir: &Directory, depth: u32) -> Result<()> {
+    if depth > 4 {
+        return Ok(());
+    }
+    for ent in dir.iter() {
+        let ent = ent?;
+        ent.metadata()?;
+        dir.resolve(ent.file_name()?.as_bytes())?;
+        match ent.item()? {
+            FSItem::File(f) => {
+                f.digest()?;
+                f.compressed_size()?;
+            }
+            FSItem::Directory(d) => exercise_dir(&d, depth + 1)?,
+            FSItem::Symlink(s) => {
+                s.get_link()?;
+            }
+            FSItem::Special(s) => {
+                s.rdev();
+            }
+        }
+    }
+    Ok(())
+}
+
+fn exercise(data: Vec<u8>) -> Result<()> {
+    let fs = FS::open(Cursor::new(data), None)?;
+    exercise_dir(&fs.get_root()?, 0)
+}
+
+// Corrupts what is read from `inner`, to check that a known good image
+// with a targeted corruption gives the expected error, not a panic.
+struct CorruptingReadAt<F> {
+    inner: F,
+    // Bytes replacing the ones at each offset
+    patches: Vec<(u64, Vec<u8>)>,
+    // Nothing can be read from there on, like a truncated file
+    end: Option<u64>,
+}
+
+impl<F: disk::ReadAt> CorruptingReadAt<F> {
+    fn new(inner: F) -> Self {
+        CorruptingReadAt {
+            inner,
+            patches: Vec::new(),
+            end: None,
+        }
+    }
+
+    fn patch(mut self, offset: u64, bytes: &[u8]) -> Self {
+        self.patches.push((offset, bytes.to_vec()));
+        self
+    }
+
+    fn patch_u64(self, offset: u64, val: u64) -> Self {
+        self.patch(offset, &val.to_le_bytes())
+    }
+
+    // Invert the bits of the byte at `offset`
+    fn flip(self, offset: u64) -> Self {
+        let mut byte = [0];
+        self.inner.read_exact_at(&mut byte, offset).unwrap();
+        self.patch(offset, &[!byte[0]])
+    }
+
+    fn truncate(mut self, end: u64) -> Self {
+        self.end = Some(end);
+        self
+    }
+}
+
+impl<F: disk::ReadAt> disk::ReadAt for CorruptingReadAt<F> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        let len = match self.end {
+            Some(end) if offset >= end => return Ok(0),
+            Some(end) => std::cmp::min(buf.len() as u64, end - offset),
+            None => buf.len() as u64,
+        };
+        let n = self.inner.read_at(&mut buf[..len as usize], offset)?;
+        for (at, bytes) in &self.patches {
+            for (i, b) in bytes.iter().enumerate() {
+                let pos = at + i as u64;
+                if pos >= offset && pos < offset + n as u64 {
+                    buf[(pos - offset) as usize] = *b;
+                }
+            }
+        }
+        Ok(n)
+    }
+
+    fn stream_len(&self) -> Option<u64> {
+        match (self.inner.stream_len(), self.end) {
+            (Some(len), Some(end)) => Some(std::cmp::min(len, end)),
+            (len, end) => len.or(end),
+        }
+    }
+}
+
+// Open the image and go through all of it, entries, contents and link
+// targets included
+fn read_everything<F>(img: F) -> Result<()>
+where
+    F: disk::ReadAt + 'static,
+{
+    let fs = FS::open(img, None)?;
+    for ent in fs.get_root()?.walk() {
+        let (_, ent) = ent?;
+        ent.metadata()?;
+        match ent.item()? {
+            FSItem::File(f) => {
+                f.digest()?;
+            }
+            FSItem::Symlink(s) => {
+                s.get_link()?;
+            }
+            FSItem::Directory(_) | FSItem::Special(_) => {}
+        }
+    }
+    Ok(())
+}
+
+// Whether an error is the one a corruption should produce
+type ErrorCheck = fn(&Error) -> bool;
+
+#[test]
+fn test_corruptions() {
+    let data = build_image("test_data/small");
+    let len = data.len() as u64;
+    // The root entries are dir, hello.txt and link
+    let root = get_u64(&data, 8);
+    let dirents = get_u64(&data, root + 8);
+    let dir = get_u64(&data, dirents + 8);
+    let file = get_u64(&data, dirents + 16 + 8);
+    let link = get_u64(&data, dirents + 32 + 8);
+    let dir_dirents = get_u64(&data, dir + 8);
+    let img = || CorruptingReadAt::new(Cursor::new(data.clone()));
+    assert!(read_everything(img()).is_ok());
+
+    // Offsets in the header are: magic 0, root inode 8, major version
+    // 16, compression 18, encryption 19, incompatible features 20. In
+    // inodes: entries or contents 8, size 16, type 24.
+    let corpus: [(_, _, ErrorCheck); 20] = [
+        // Not an image at all
+        ("bad magic", img().flip(0), |e| {
+            matches!(e, Error::Format("Wrong magic"))
+        }),
+        ("newer major version", img().patch(16, &[0xff]), |e| {
+            matches!(e, Error::UnsupportedVersion { .. })
+        }),
+        ("unknown compression", img().patch(18, &[0xff]), |e| {
+            matches!(e, Error::Format("CompressionType"))
+        }),
+        ("unknown encryption", img().patch(19, &[0xff]), |e| {
+            matches!(e, Error::Format("EncryptionType"))
+        }),
+        (
+            "unknown incompatible feature",
+            img().patch(23, &[0x80]),
+            |e| matches!(e, Error::Format("Unsupported incompatible features")),
+        ),
+        // The root must be an inode after the header
+        ("root inside the header", img().patch_u64(8, 16), |e| {
+            matches!(e, Error::Format("invalid root inode offset"))
+        }),
+        ("root past the end", img().patch_u64(8, len - 8), |e| {
+            matches!(e, Error::Format("invalid root inode offset"))
+        }),
+        ("root offset overflow", img().patch_u64(8, u64::MAX), |e| {
+            matches!(e, Error::Format("invalid root inode offset"))
+        }),
+        ("bad inode type", img().patch(root + 24, &[0xff]), |e| {
+            matches!(e, Error::Format("InodeType"))
+        }),
+        (
+            "root is a file",
+            img().patch(root + 24, &[u8::from(InodeType::File)]),
+            |e| matches!(e, Error::Format("root inode is not a directory")),
+        ),
+        // Offsets and sizes pointing out of the image
+        (
+            "entries past the end",
+            img().patch_u64(root + 8, len - 8),
+            |e| matches!(e, Error::Bounds("offset past the end of the image")),
+        ),
+        (
+            "oversized directory",
+            img().patch_u64(root + 16, u64::MAX),
+            |e| matches!(e, Error::Bounds("offset past the end of the image")),
+        ),
+        (
+            "entry inode past the end",
+            img().patch_u64(dirents + 8, u64::MAX),
+            |e| matches!(e, Error::Bounds("offset past the end of the image")),
+        ),
+        (
+            "contents past the end",
+            img().patch_u64(file + 8, len - 2),
+            |e| matches!(e, Error::Bounds("offset past the end of the image")),
+        ),
+        (
+            "contents offset overflow",
+            img().patch_u64(file + 8, u64::MAX - 4),
+            |e| matches!(e, Error::Bounds("offset past the end of the image")),
+        ),
+        (
+            "huge link target",
+            img().patch_u64(link + 16, u64::MAX),
+            |e| matches!(e, Error::Bounds("link target too long")),
+        ),
+        // A directory containing its parent
+        (
+            "directory cycle",
+            img().patch_u64(dir_dirents + 8, root),
+            |e| matches!(e, Error::Format("directory cycle")),
+        ),
+        // Files cut short
+        (
+            "truncated header",
+            img().truncate(20),
+            |e| matches!(e, Error::IO(e) if e.kind() == std::io::ErrorKind::UnexpectedEof),
+        ),
+        // The root inode is written last
+        ("truncated", img().truncate(len / 2), |e| {
+            matches!(e, Error::Format("invalid root inode offset"))
+        }),
+        ("truncated root", img().truncate(len - 1), |e| {
+            matches!(e, Error::Format("invalid root inode offset"))
+        }),
+    ];
+    for (name, img, expected) in corpus {
+        match read_everything(img) {
+            Ok(()) => panic!("{}: no error", name),
+            Err(e) => assert!(expected(e.inner()), "{}: {:?}", name, e),
+        }
+    }
+}
+
+#[test]
+fn test_read_dirent_bounds() {
+    let f = std::fs::File::open("test_data/small.sqh").unwrap();
+    let img = disk::open_file(f, None).unwrap();
+    let root = img.root_inode().unwrap();
+    // dir, hello.txt and link
+    assert!(root.read_dirent(2, &img).is_ok());
+    for pos in [3, 4, u64::MAX] {
+        assert!(matches!(
+            root.read_dirent(pos, &img),
+            Err(Error::Bounds("dirent pos is beyond the directory"))
+        ));
+    }
+}
+
+#[test]
+fn test_offsets_past_end() {
+    let data = build_image("test_data/small");
+    let root = get_u64(&data, 8);
+    let dirents = get_u64(&data, root + 8);
+    let file = get_u64(&data, dirents + 16 + 8);
+    let end = data.len() as u64;
+    let past_end = |bad: Vec<u8>| {
+        let fs = FS::open(Cursor::new(bad), None).unwrap();
+        let err = match fs.resolve("hello.txt") {
+            Ok(Some(FSItem::File(f))) => f.read_exact_at(&mut [0; 4], 0),
+            Ok(_) => panic!("hello.txt is not a file"),
+            Err(e) => Err(e),
+        };
+        matches!(
+            err.map_err(Error::into_inner),
+            Err(Error::Bounds("offset past the end of the image"))
+        )
+    };
+
+    // Inode, entries and contents just past the end
+    let mut bad = data.clone();
+    set_u64(&mut bad, dirents + 16 + 8, end - 8);
+    assert!(past_end(bad));
+    let mut bad = data.clone();
+    set_u64(&mut bad, root + 8, end - 8);
+    assert!(past_end(bad));
+    let mut bad = data.clone();
+    set_u64(&mut bad, file + 8, end - 2);
+    assert!(past_end(bad));
+    assert!(!past_end(data));
+}
+
+#[test]
+fn test_error_context() {
+    let mut data = build_image("test_data/small");
+    let root = get_u64(&data, 8);
+    let dirents = get_u64(&data, root + 8);
+    let end = data.len() as u64;
+    // The inode of hello.txt past the end
+    set_u64(&mut data, dirents + 16 + 8, end - 8);
+    let fs = FS::open(Cursor::new(data), None).unwrap();
+    let err = match fs.resolve("/hello.txt") {
+        Err(e) => e,
+        Ok(_) => panic!("hello.txt resolved"),
+    };
+    match &err {
+        Error::Context { offset, path, .. } => {
+            assert_eq!(*offset, Some(end - 8));
+            assert_eq!(path.as_deref(), Some(&b"/hello.txt"[..]));
+        }
+        e => panic!("no context: {:?}", e),
+    }
+    assert!(matches!(err.inner(), Error::Bounds(_)));
+    assert_eq!(
+        err.to_string(),
+        format!("error in \"/hello.txt\" at offset {}", end - 8)
+    );
+    // The inner error is only in the chain
+    let source = std::error::Error::source(&err).unwrap();
+    assert_eq!(
+        source.to_string(),
+        "Value out of bounds: offset past the end of the image"
+    );
+}
+
+#[test]
+fn test_name_without_nul() {
+    let mut data = build_image("test_data/small");
+    let root = get_u64(&data, 8);
+    let dirents = get_u64(&data, root + 8);
+    // The name of dir runs into the end of the image, across several
+    // reads of read_str
+    let end = data.len() as u64;
+    data.extend_from_slice(&[b'x'; 100]);
+    set_u64(&mut data, dirents, end);
+    let fs = FS::open(Cursor::new(data), None).unwrap();
+    let ent = fs.get_root().unwrap().get(0).unwrap().unwrap();
+    match ent.file_name() {
+        Err(Error::IO(e)) => {
+            assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof)
+        }
+        res => panic!("unexpected {:?}", res),
+    }
+}
+
+#[test]
+fn test_name_too_long() {
+    let mut data = build_image("test_data/small");
+    let root = get_u64(&data, 8);
+    let dirents = get_u64(&data, root + 8);
+    let end = data.len() as u64;
+    data.extend_from_slice(&[b'x'; disk::NAME_MAX + 100]);
+    data.push(0);
+    set_u64(&mut data, dirents, end);
+    let fs = FS::open(Cursor::new(data.clone()), None).unwrap();
+    let ent = fs.get_root().unwrap().get(0).unwrap().unwrap();
+    assert!(matches!(
+        ent.file_name(),
+        Err(Error::Bounds("name too long"))
+    ));
+
+    // Exactly NAME_MAX is fine
+    set_u64(&mut data, dirents, end + 100);
+    let fs = FS::open(Cursor::new(data), None).unwrap();
+    let ent = fs.get_root().unwrap().get(0).unwrap().unwrap();
+    assert_eq!(ent.file_name().unwrap().as_bytes().len(), disk::NAME_MAX);
+}
+
+type Corruption<'a> = Box<dyn Fn(&mut Vec<u8>) + 'a>;
+
+#[test]
+fn test_malformed_images() {
+    let data = build_image("test_data/small");
+    assert!(exercise(data.clone()).is_ok());
+
+    // root entries are dir, hello.txt and link
+    let root = get_u64(&data, 8);
+    let dirents = get_u64(&data, root + 8);
+    let file = get_u64(&data, dirents + 16 + 8);
+    let link = get_u64(&data, dirents + 32 + 8);
+
+    let corpus: Vec<(&str, Corruption)> = vec![
+        ("bad root type", Box::new(|d| d[root as usize + 24] = 7)),
+        (
+            "huge root size",
+            Box::new(|d| set_u64(d, root + 16, u64::MAX)),
+        ),
+        (
+            "root dirents past the end",
+            Box::new(|d| set_u64(d, root + 8, u64::MAX - 8)),
+        ),
+        (
+            "dirent name past the end",
+            Box::new(|d| set_u64(d, dirents, u64::MAX)),
+        ),
+        (
+            "dirent inode past the end",
+            Box::new(|d| set_u64(d, dirents + 8, u64::MAX)),
+        ),
+        (
+            "huge file size",
+            Box::new(|d| set_u64(d, file + 16, u64::MAX)),
+        ),
+        (
+            "file offset overflow",
+            Box::new(|d| set_u64(d, file + 8, u64::MAX - 4)),
+        ),
+        (
+            "huge link size",
+            Box::new(|d| set_u64(d, link + 16, u64::MAX)),
+        ),
+        ("bad file type", Box::new(|d| d[file as usize + 24] = 0xff)),
+        ("truncated", Box::new(|d| d.truncate(d.len() / 2))),
+    ];
+    for (name, corrupt) in corpus {
+        let mut bad = data.clone();
+        corrupt(&mut bad);
+        assert!(exercise(bad).is_err(), "{}", name);
+    }
+
+    // A link to an empty path resolves to its directory
+    let mut bad = data.clone();
+    set_u64(&mut bad, link + 16, 0);
+    let fs = FS::open(Cursor::new(bad), None).unwrap();
+    assert!(matches!(fs.resolve("link"), Ok(Some(FSItem::Directory(_)))));
+
+    // Offsets coming from the caller can't overflow either
+    let fs = FS::open(Cursor::new(data), None).unwrap();
+    match fs.resolve("hello.txt").unwrap() {
+        Some(FSItem::File(f)) => {
+            assert!(f.read_exact_at(&mut [0; 4], u64::MAX).is_err());
+            assert!(matches!(f.read_at(&mut [0; 4], u64::MAX), Ok(0)));
+        }
+        _ => panic!("hello.txt is not a file"),
+    }
+    assert!(matches!(fs.get_root().unwrap().get(u64::MAX), Ok(None)));
+}
+
+#[test]
+fn test_read_huge_size() {
+    let mut data = build_image("test_data/small");
+    let root = get_u64(&data, 8);
+    let file = get_u64(&data, get_u64(&data, root + 8) + 16 + 8);
+    // Far more than the image holds, or than could be allocated
+    set_u64(&mut data, file + 16, u64::MAX);
+    let fs = FS::open(Cursor::new(data), None).unwrap();
+    assert!(fs.read("hello.txt").is_err());
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn test_malformed_compressed() {
+    let data = build_image_with("test_data/small", CompressionType::Zstd);
+    let root = get_u64(&data, 8);
+    let file = get_u64(&data, get_u64(&data, root + 8) + 16 + 8);
+    // hello.txt is a single block so its table has two entries
+    let table = get_u64(&data, file + 8);
+    let block = get_u64(&data, table);
+
+    for (entry, val) in [(0, u64::MAX), (0, 0), (8, u64::MAX), (8, 0)] {
+        let mut bad = data.clone();
+        set_u64(&mut bad, table + entry, val);
+        assert!(exercise(bad).is_err());
+    }
+    let mut bad = data;
+    bad[block as usize..block as usize + 4].fill(0xff);
+    assert!(exercise(bad).is_err());
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn test_compressed_blocks() {
+    let dir = tempfile::tempdir().unwrap();
+    let bs = disk::compress::BLOCK_SIZE as usize;
+    let data: Vec<u8> = (0..bs * 3 + 100).map(|i| (i / 7) as u8).collect();
+    std::fs::write(dir.path().join("big"), &data).unwrap();
+    let mut img = build_image_with(dir.path(), CompressionType::Zstd);
+
+    let root = get_u64(&img, 8);
+    let file = get_u64(&img, get_u64(&img, root + 8) + 8);
+    let table = get_u64(&img, file + 8);
+    // corrupt the third block
+    let block = get_u64(&img, table + 16) as usize;
+    img[block..block + 4].fill(0xff);
+
+    let fs = FS::open(Cursor::new(img), None).unwrap();
+    let f = match fs.resolve("big").unwrap() {
+        Some(FSItem::File(f)) => f,
+        _ => panic!("big is not a file"),
+    };
+    // reads that don't touch the third block still work
+    let mut buf = vec![0; bs];
+    f.read_exact_at(&mut buf, bs as u64 / 2).unwrap();
+    assert_eq!(buf, data[bs / 2..bs / 2 + bs]);
+    let mut buf = vec![0; 100];
+    f.read_exact_at(&mut buf, bs as u64 * 3).unwrap();
+    assert_eq!(buf, data[bs * 3..]);
+    assert!(f.read_exact_at(&mut buf, bs as u64 * 2 - 50).is_err());
+}
+
+// Corrupting any single byte must never panic, whatever the outcome.
+#[test]
+fn test_corrupt_bytes() {
+    let mut types = vec![CompressionType::None];
+    if cfg!(feature = "zstd") {
+        types.push(CompressionType::Zstd);
+    }
+    for ty in types {
+        let data = build_image_with("test_data/small", ty);
+        for i in 0..data.len() {
+            for val in [0, 0x7f, 0xff] {
+                let mut bad = data.clone();
+                bad[i] = val;
+                let _ = exercise(bad);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "internals")]
+#[test]
+fn test_read_raw() {
+    let data = std::fs::read("test_data/small.sqh").unwrap();
+    let fs = FS::open(Cursor::new(data.clone()), None).unwrap();
+    assert_eq!(fs.read_raw(0, 8).unwrap(), b"SQUASHFL");
+    assert_eq!(fs.read_raw(100, 20).unwrap(), &data[100..120]);
+    let end = data.len() as u64;
+    assert_eq!(fs.read_raw(end, 0).unwrap(), b"");
+    assert!(matches!(fs.read_raw(end - 4, 8), Err(Error::Bounds(_))));
+    assert!(matches!(fs.read_raw(u64::MAX, 8), Err(Error::Bounds(_))));
+    assert!(matches!(fs.read_raw(0, 1 << 30), Err(Error::Bounds(_))));
+}
+
+#[test]
+fn test_hash_index_candidates() {
+    let names = ["a", "b", "c", "dd", "eee"];
+    let index = Cursor::new(disk::index::build(&names));
+    for (i, name) in names.iter().enumerate() {
+        let found =
+            disk::index::candidates(&index, 0, 5, name.as_bytes()).unwrap();
+        assert!(found.contains(&(i as u64)), "{}", name);
+    }
+    // 8 buckets, which can't point past the entries
+    let mut bad = disk::index::build(&names);
+    for b in 1..=8 {
+        bad[4 + 4 * b] = 100;
+    }
+    assert!(matches!(
+        disk::index::candidates(&Cursor::new(bad), 0, 5, b"a"),
+        Err(Error::Format("invalid hash index"))
+    ));
+    let mut bad = disk::index::build(&names);
+    bad[0] = 3;
+    assert!(matches!(
+        disk::index::candidates(&Cursor::new(bad), 0, 5, b"a"),
+        Err(Error::Format("invalid hash index"))
+    ));
 }
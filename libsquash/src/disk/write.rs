@@ -1,160 +1,1725 @@
 // Stuff to write images from a folder
 
+use std::io::Read;
 use std::io::Seek;
 use std::io::Write;
 use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
 
 use crate::error::Error;
 type Result<T> = std::result::Result<T, Error>;
 use crate::disk;
-use disk::Key;
+use crate::overlay;
+use crate::tar::{EntryKind, TarEntry};
+use disk::crc32::Crc32Reader;
+use disk::index::INDEX_ENTRIES_MAX;
+use disk::merkle::{self, Hash};
+use disk::{struct_to_mut_slice, struct_to_slice, Key};
 
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::CString;
 use std::fs;
 use std::io;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-trait SeekWrite: Seek + Write {}
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+// Smallest directory that gets a hash index by default, lookups in
+// smaller ones are as fast with a binary search (see benches/lookup.rs)
+const HASH_INDEX_MIN_ENTRIES: u64 = 32;
+
+// Most bytes of source files read ahead at once with
+// WriteOptions::parallel, larger files are written as they are read
+#[cfg(feature = "parallel")]
+const PREFETCH_BYTES: u64 = 64 << 20;
+
+/// Options controlling how an image is written.
+#[derive(Clone, Debug)]
+pub struct WriteOptions {
+    /// Report entries of the same directory whose names are equal
+    /// under ASCII case folding. Those would collide when extracted on
+    /// a case-insensitive filesystem.
+    pub warn_case_collisions: bool,
+    /// Order of the entries in directories. Anything other than
+    /// Collation::Bytes can only be read by versions that understand
+    /// INCOMPAT_COLLATION.
+    pub collation: disk::Collation,
+    /// How file contents are compressed
+    pub compression: disk::CompressionType,
+    /// Compression level, the meaning depends on the compression type
+    pub compression_level: i32,
+    /// Store the total size of the files below each directory.
+    pub subtree_sizes: bool,
+    /// Make the image only depend on the source tree and the key. The
+    /// UUID and the nonce of ChaCha20 images are then derived from the
+    /// contents instead of being random. Deterministic ChaCha20 images
+    /// are encrypted once written by reading them back, so only
+    /// `write_image_file_with` can write them and they can't be split.
+    pub deterministic: bool,
+    /// Store modification times no later than this (in seconds since
+    /// the epoch), like SOURCE_DATE_EPOCH.
+    pub clamp_mtime: Option<u64>,
+    /// Leave out entries that disappear between listing their
+    /// directory and reading them, which happens when imaging a live
+    /// tree. They are reported in `WriteSummary::vanished`. Otherwise
+    /// this fails the whole write.
+    pub skip_vanished: bool,
+    /// Store a hash tree for each file, the hash of each directory and
+    /// the root hash of the whole tree in the header. Reads check files
+    /// against their tree and the tree against the header.
+    pub merkle: bool,
+    /// Image the directory a symlink source points to. Otherwise a
+    /// symlink source is an error.
+    pub dereference_root: bool,
+    /// Add a hash index to directories with at least this many entries
+    /// to speed up lookups, None to never add one. The default comes
+    /// from benches/lookup.rs.
+    pub hash_index_min_entries: Option<u64>,
+    /// Refuse symlinks with longer targets, which readers with the
+    /// same FsOptions::link_target_max couldn't read.
+    pub link_target_max: usize,
+    /// Write symlinks with the same target once and point all their
+    /// entries to it. They then share the permissions, owner and
+    /// modification time of the first one.
+    pub dedup_symlinks: bool,
+    /// Hash the contents of files and write identical contents once,
+    /// sharing them between the inodes of all those files. This reads
+    /// every file twice.
+    pub dedup: bool,
+    /// Store the extended attributes of entries.
+    pub xattrs: bool,
+    /// Store the CRC-32 of the contents of each file, checked by
+    /// `File::verify` and FsOptions::verify_checksums.
+    pub checksums: bool,
+    /// Store a digest of the whole image in the header, see
+    /// `seal_image`. The image is read back to compute it, so only
+    /// `write_image_file_with` can do this and the other functions
+    /// refuse it.
+    pub integrity: bool,
+    /// Leave out regular files smaller than this. They are reported in
+    /// `WriteSummary::size_excluded`.
+    pub min_file_size: Option<u64>,
+    /// Leave out regular files larger than this, like `min_file_size`.
+    pub max_file_size: Option<u64>,
+    /// Read and compress the files of each directory in parallel before
+    /// writing them in order. The image is the same byte for byte as
+    /// without it. This needs the `parallel` feature, and is the
+    /// default with it.
+    pub parallel: bool,
+    /// Salt the key was derived from with `derive_key`, stored in the
+    /// header for readers to derive it again from the passphrase. Only
+    /// for encrypted images.
+    pub key_salt: Option<[u8; disk::SALT_LEN]>,
+    /// Plaintext size of the blocks XChaCha20-Poly1305 encrypts and
+    /// authenticates independently, a power of two from 512 bytes to
+    /// 1 MiB. Each block takes 40 more bytes in the image and reads
+    /// decrypt whole blocks. Other sizes than the default of 4096 can
+    /// only be read by versions that understand INCOMPAT_BLOCK_SIZE.
+    pub encryption_block_size: u64,
+}
+
+impl Default for WriteOptions {
+    fn default() -> Self {
+        WriteOptions {
+            warn_case_collisions: false,
+            collation: disk::Collation::default(),
+            compression: disk::CompressionType::None,
+            compression_level: disk::compress::DEFAULT_LEVEL,
+            subtree_sizes: false,
+            deterministic: false,
+            clamp_mtime: None,
+            skip_vanished: true,
+            merkle: false,
+            dereference_root: true,
+            hash_index_min_entries: Some(HASH_INDEX_MIN_ENTRIES),
+            link_target_max: disk::LINK_TARGET_MAX,
+            dedup_symlinks: false,
+            dedup: false,
+            xattrs: false,
+            checksums: false,
+            integrity: false,
+            min_file_size: None,
+            max_file_size: None,
+            parallel: cfg!(feature = "parallel"),
+            key_salt: None,
+            encryption_block_size: disk::aead::BLOCK_SIZE,
+        }
+    }
+}
+
+/// Counts of what was written to an image.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct WriteSummary {
+    /// Number of regular files
+    pub files: u64,
+    /// Number of directories, including the root
+    pub dirs: u64,
+    /// Number of symlinks
+    pub symlinks: u64,
+    /// Number of devices, FIFOs and sockets
+    pub specials: u64,
+    /// Total size of the contents of regular files
+    pub total_data_bytes: u64,
+    /// Size of the whole image, including the header. For split images
+    /// this is only the metadata.
+    pub image_size: u64,
+    /// Size of the data stream of split images
+    pub data_size: u64,
+    /// Pairs of source paths whose names only differ in case, if
+    /// requested in the options.
+    pub case_collisions: Vec<(PathBuf, PathBuf)>,
+    /// Paths written as another link to a file already in the image,
+    /// which are not counted in `files`
+    pub hardlinks: u64,
+    /// Symlinks written as the inode of an identical one, with
+    /// `WriteOptions::dedup_symlinks`, which are not counted in
+    /// `symlinks`
+    pub shared_symlinks: u64,
+    /// Size of the contents of files that were not written because
+    /// identical contents already were, with `WriteOptions::dedup`
+    pub dedup_bytes: u64,
+    /// Source paths left out because they were removed while writing,
+    /// see `WriteOptions::skip_vanished`.
+    pub vanished: Vec<PathBuf>,
+    /// Files left out because of `WriteOptions::min_file_size` or
+    /// `WriteOptions::max_file_size`
+    pub size_excluded: Vec<PathBuf>,
+}
+
+pub(super) trait SeekWrite: Seek + Write {}
 
 impl<T: Seek + Write> SeekWrite for T {}
 
-fn struct_to_slice<T>(ptr: &T) -> &[u8] {
-    unsafe {
-        std::slice::from_raw_parts(
-            (ptr as *const T) as *const u8,
-            std::mem::size_of::<T>(),
-        )
+/// Hashes everything written through it, to derive a UUID.
+struct HashWriter<W> {
+    out: W,
+    hasher: blake3::Hasher,
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let sz = self.out.write(buf)?;
+        self.hasher.update(&buf[..sz]);
+        Ok(sz)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+impl<W: Seek> Seek for HashWriter<W> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let res = self.out.seek(pos)?;
+        // Seeks matter as much as the data
+        self.hasher.update(&res.to_le_bytes());
+        Ok(res)
     }
+
+    // Only where the writer is, which depends on how it was written
+    fn stream_position(&mut self) -> io::Result<u64> {
+        self.out.stream_position()
+    }
+}
+
+// Set the version and variant bits of a UUID
+fn make_uuid(mut bytes: [u8; 16], version: u8) -> [u8; 16] {
+    bytes[6] = (bytes[6] & 0x0f) | (version << 4);
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    bytes
+}
+
+fn permission_bits(meta: &fs::Metadata) -> u32 {
+    meta.permissions().mode() & 0o7777
+}
+
+// Call `get` with growing buffers until the value fits
+fn xattr_call<F>(mut get: F) -> io::Result<Vec<u8>>
+where
+    F: FnMut(*mut libc::c_void, usize) -> libc::ssize_t,
+{
+    loop {
+        let size = get(std::ptr::null_mut(), 0);
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buf = vec![0u8; size as usize];
+        let res = get(buf.as_mut_ptr() as *mut libc::c_void, buf.len());
+        if res >= 0 {
+            buf.truncate(res as usize);
+            return Ok(buf);
+        }
+        let e = io::Error::last_os_error();
+        // It grew since we asked for its size
+        if e.raw_os_error() != Some(libc::ERANGE) {
+            return Err(e);
+        }
+    }
+}
+
+// Extended attributes of `path`, following symlinks if `follow`
+fn read_xattrs(path: &Path, follow: bool) -> Result<Vec<(CString, Vec<u8>)>> {
+    let cpath = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| Error::InvalidOperation("path contains a NUL byte"))?;
+    let p = cpath.as_ptr();
+    // SAFETY: the path is NUL terminated and the buffers are as large
+    // as the sizes given with them
+    let names = xattr_call(|buf, size| unsafe {
+        if follow {
+            libc::listxattr(p, buf as *mut libc::c_char, size)
+        } else {
+            libc::llistxattr(p, buf as *mut libc::c_char, size)
+        }
+    });
+    let names = match names {
+        Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => return Ok(vec![]),
+        r => r?,
+    };
+    let mut res = Vec::new();
+    for name in names.split(|&c| c == 0).filter(|n| !n.is_empty()) {
+        let name = CString::new(name).unwrap();
+        let n = name.as_ptr();
+        // SAFETY: as above
+        let value = xattr_call(|buf, size| unsafe {
+            if follow {
+                libc::getxattr(p, n, buf, size)
+            } else {
+                libc::lgetxattr(p, n, buf, size)
+            }
+        });
+        match value {
+            // Removed since it was listed
+            Err(e) if e.raw_os_error() == Some(libc::ENODATA) => continue,
+            r => res.push((name, r?)),
+        }
+    }
+    Ok(res)
 }
 
-fn write_header<S: SeekWrite>(
+// Write the extended attributes of `path` if requested, returning
+// their offset or 0 if there are none
+fn write_xattrs<S: SeekWrite>(
+    path: &Path,
+    follow: bool,
     out: &mut S,
-    root_inode: u64,
-    enc_type: disk::EncryptionType,
-) -> Result<()> {
-    let mut header = disk::Header::default();
-    header.magic = disk::MAGIC;
-    header.root_inode = root_inode.into();
-    header.version_major = disk::VERSION_MAJOR;
-    header.version_minor = disk::VERSION_MINOR;
-    header.compression_type = disk::CompressionType::None as u8;
-    header.encryption_type = enc_type as u8;
+    opts: &WriteOptions,
+) -> Result<u64> {
+    if !opts.xattrs {
+        return Ok(0);
+    }
+    let attrs = read_xattrs(path, follow)?;
+    if attrs.is_empty() {
+        return Ok(0);
+    }
+    let size: usize = attrs
+        .iter()
+        .map(|(n, v)| n.as_bytes().len() + v.len())
+        .sum();
+    if size as u64 > disk::xattr::XATTRS_SIZE_MAX {
+        return Err(Error::Bounds("extended attributes too large"));
+    }
+    let pos = out.stream_position()?;
+    out.write_all(&disk::xattr::encode(&attrs))?;
+    Ok(pos)
+}
+
+// Seconds since the epoch, 0 if unknown
+fn mtime(meta: &fs::Metadata, opts: &WriteOptions) -> u64 {
+    let t = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs());
+    match opts.clamp_mtime {
+        Some(max) => std::cmp::min(t, max),
+        None => t,
+    }
+}
+
+// Fills in the magic and version
+fn write_header<S: SeekWrite>(out: &mut S, header: disk::Header) -> Result<()> {
+    let header = disk::Header {
+        magic: disk::MAGIC,
+        version_major: disk::VERSION_MAJOR,
+        version_minor: disk::VERSION_MINOR,
+        ..header
+    };
     out.write_all(struct_to_slice(&header))
         .map_err(|e| e.into())
 }
 
+// Position of the inode of an entry, and its hash if building hash
+// trees
+type Written = (u64, Option<Hash>);
+
+/// What was already written that later entries can point to instead of
+/// being written again, for the whole image.
+#[derive(Default)]
+pub(super) struct Shared {
+    // File inodes by source (dev, ino), for files with hard links
+    hardlinks: HashMap<(u64, u64), Written>,
+    // Symlink inodes by target, with WriteOptions::dedup_symlinks
+    symlinks: HashMap<Vec<u8>, Written>,
+    // Offset, size and hash tree of file contents by their hash, with
+    // WriteOptions::dedup
+    contents: HashMap<Hash, Contents>,
+    // Contents of files about to be written, with WriteOptions::parallel
+    prepared: HashMap<PathBuf, Prepared>,
+}
+
+// Offset and size of the contents, hash tree and CRC-32
+type Contents = (u64, u64, Option<(u64, Hash)>, u32);
+
+// Returns the offset of the block table and size of the contents, like
+// compress
+fn write_data<R: io::Read, S: SeekWrite + ?Sized>(
+    src: &mut R,
+    mut out: &mut S,
+    opts: &WriteOptions,
+) -> Result<(u64, u64)> {
+    disk::compress::compress(
+        opts.compression,
+        opts.compression_level,
+        src,
+        &mut out,
+    )
+}
+
+// Write the contents of a file with its hash tree if requested
+fn write_contents<R: io::Read>(
+    src: &mut R,
+    dest: &mut dyn SeekWrite,
+    opts: &WriteOptions,
+) -> Result<Contents> {
+    // Always computed, it is only stored with opts.checksums
+    let mut src = Crc32Reader::new(src);
+    // The tree goes right after the data, with its root last
+    Ok(if opts.merkle {
+        let mut reader = merkle::TreeReader::new(&mut src);
+        let (offset, size) = write_data(&mut reader, dest, opts)?;
+        let nodes = reader.finish();
+        let tree = dest.stream_position()?;
+        for node in &nodes {
+            dest.write_all(node)?;
+        }
+        let tree = Some((tree, nodes[nodes.len() - 1]));
+        (offset, size, tree, src.finish())
+    } else {
+        let (offset, size) = write_data(&mut src, dest, opts)?;
+        (offset, size, None, src.finish())
+    })
+}
+
+// Contents of a file written to memory ahead of time, as if `buf` was
+// at the start of the image
+#[cfg_attr(not(feature = "parallel"), allow(dead_code))]
+struct Prepared {
+    digest: Option<Hash>,
+    buf: Vec<u8>,
+    contents: Contents,
+}
+
+// Hash of the contents of `src`, if needed for WriteOptions::dedup
+fn file_digest(
+    src: &mut fs::File,
+    opts: &WriteOptions,
+) -> Result<Option<Hash>> {
+    if !opts.dedup {
+        return Ok(None);
+    }
+    let mut hasher = blake3::Hasher::new();
+    io::copy(src, &mut hasher)?;
+    src.rewind()?;
+    Ok(Some(*hasher.finalize().as_bytes()))
+}
+
+#[cfg(feature = "parallel")]
+fn prepare_file(path: &Path, opts: &WriteOptions) -> Result<Prepared> {
+    let mut src = fs::File::open(path)?;
+    let digest = file_digest(&mut src, opts)?;
+    let mut buf = io::Cursor::new(Vec::new());
+    let contents = write_contents(&mut src, &mut buf, opts)?;
+    Ok(Prepared {
+        digest,
+        buf: buf.into_inner(),
+        contents,
+    })
+}
+
+// Write `prepared` at the current position of `dest`
+fn write_prepared(
+    prepared: Prepared,
+    dest: &mut dyn SeekWrite,
+    opts: &WriteOptions,
+) -> Result<Contents> {
+    let (offset, size, tree, crc32) = prepared.contents;
+    let mut buf = prepared.buf;
+    let base = dest.stream_position()?;
+    disk::compress::relocate(opts.compression, &mut buf, offset, size, base);
+    dest.write_all(&buf)?;
+    let tree = tree.map(|(pos, hash)| (pos + base, hash));
+    Ok((offset + base, size, tree, crc32))
+}
+
+// Regular files of a directory, prepared in parallel in batches of up to
+// PREFETCH_BYTES when the first file of each batch is about to be
+// written
+#[cfg(feature = "parallel")]
+struct Prefetch {
+    // In directory order, with their size
+    files: Vec<(PathBuf, u64)>,
+    // First file of the next batch
+    next: usize,
+}
+
+#[cfg(feature = "parallel")]
+impl Prefetch {
+    fn new(entries: &[fs::DirEntry], opts: &WriteOptions) -> Self {
+        let files = entries
+            .iter()
+            .filter_map(|entry| {
+                let meta = entry.metadata().ok()?;
+                let size = meta.len();
+                let wanted = meta.is_file()
+                    && size <= PREFETCH_BYTES
+                    && !size_out_of_range(size, opts);
+                wanted.then(|| (entry.path(), size))
+            })
+            .collect();
+        Prefetch { files, next: 0 }
+    }
+
+    // Called before writing the entry at `path`
+    fn fill(&mut self, path: &Path, opts: &WriteOptions, shared: &mut Shared) {
+        if self.files.get(self.next).map(|f| f.0.as_path()) != Some(path) {
+            return;
+        }
+        let mut total = 0;
+        let end = self.files[self.next..]
+            .iter()
+            .position(|f| {
+                total += f.1;
+                total > PREFETCH_BYTES
+            })
+            .map_or(self.files.len(), |n| self.next + n);
+        let batch = &self.files[self.next..end];
+        let prepared: Vec<_> = batch
+            .par_iter()
+            .map(|(path, _)| prepare_file(path, opts))
+            .collect();
+        // Failures are left for the writer to report or skip
+        for ((path, _), res) in batch.iter().zip(prepared) {
+            if let Ok(p) = res {
+                shared.prepared.insert(path.clone(), p);
+            }
+        }
+        self.next = end;
+    }
+}
+
+// `data` is the separate data stream of split images
 fn write_file<P: AsRef<Path>, S: SeekWrite>(
     file: P,
     out: &mut S,
-) -> Result<u64> {
-    let mut inode = disk::Inode::default();
-    inode.offset = out.stream_position()?.into();
-    inode.inode_type = disk::InodeType::File.into();
-    inode.size = io::copy(&mut fs::File::open(file)?, out)?.into();
+    data: Option<&mut dyn SeekWrite>,
+    opts: &WriteOptions,
+    summary: &mut WriteSummary,
+    shared: &mut Shared,
+) -> Result<Written> {
+    let prepared = shared.prepared.remove(file.as_ref());
+    let mut src = fs::File::open(&file)?;
+    let meta = src.metadata()?;
+    // Other links to the same file share its inode
+    let source_id = (meta.dev(), meta.ino());
+    if meta.nlink() > 1 {
+        if let Some(&written) = shared.hardlinks.get(&source_id) {
+            summary.hardlinks += 1;
+            return Ok(written);
+        }
+    }
+    let digest = match &prepared {
+        Some(p) => p.digest,
+        None => file_digest(&mut src, opts)?,
+    };
+    let known = digest.and_then(|d| shared.contents.get(&d).copied());
+    let dest: &mut dyn SeekWrite = match data {
+        Some(d) => d,
+        None => out,
+    };
+    let (offset, size, tree, crc32) = if let Some(contents) = known {
+        summary.dedup_bytes += contents.1;
+        contents
+    } else if let Some(p) = prepared {
+        write_prepared(p, dest, opts)?
+    } else {
+        write_contents(&mut src, dest, opts)?
+    };
+    if let (Some(d), None) = (digest, known) {
+        shared.contents.insert(d, (offset, size, tree, crc32));
+    }
+    let xattrs = write_xattrs(file.as_ref(), true, out, opts)?;
+    summary.files += 1;
+    summary.total_data_bytes += size;
+    let inode = disk::Inode {
+        offset: offset.into(),
+        size: size.into(),
+        inode_type: disk::InodeType::File.into(),
+        mode: permission_bits(&meta).into(),
+        mtime: mtime(&meta, opts).into(),
+        uid: meta.uid().into(),
+        gid: meta.gid().into(),
+        merkle: tree.map_or(0, |t| t.0).into(),
+        xattrs: xattrs.into(),
+        crc32: crc32.into(),
+        ..Default::default()
+    };
     let inode_pos = out.stream_position()?;
     out.write_all(struct_to_slice(&inode))?;
-    Ok(inode_pos)
+    let written = (inode_pos, tree.map(|t| t.1));
+    if meta.nlink() > 1 {
+        shared.hardlinks.insert(source_id, written);
+    }
+    Ok(written)
 }
 
 fn write_symlink<P: AsRef<Path>, S: SeekWrite>(
     link: P,
     out: &mut S,
-) -> Result<u64> {
-    let mut inode = disk::Inode::default();
-    inode.offset = out.stream_position()?.into();
-    inode.inode_type = disk::InodeType::Symlink.into();
-    let link_data = fs::read_link(link)?;
+    opts: &WriteOptions,
+    summary: &mut WriteSummary,
+    shared: &mut Shared,
+) -> Result<Written> {
+    let meta = fs::symlink_metadata(&link)?;
+    let link_data = fs::read_link(&link)?;
     let buf = link_data.as_os_str();
-    inode.size = (buf.len() as u64).into();
+    if buf.len() > opts.link_target_max {
+        return Err(Error::Bounds("link target too long"));
+    }
+    if opts.dedup_symlinks {
+        if let Some(&written) = shared.symlinks.get(buf.as_bytes()) {
+            summary.shared_symlinks += 1;
+            return Ok(written);
+        }
+    }
+    let xattrs = write_xattrs(link.as_ref(), false, out, opts)?;
+    let inode = disk::Inode {
+        offset: out.stream_position()?.into(),
+        size: (buf.len() as u64).into(),
+        inode_type: disk::InodeType::Symlink.into(),
+        mode: permission_bits(&meta).into(),
+        mtime: mtime(&meta, opts).into(),
+        uid: meta.uid().into(),
+        gid: meta.gid().into(),
+        xattrs: xattrs.into(),
+        ..Default::default()
+    };
     out.write_all(buf.as_bytes())?;
+    summary.symlinks += 1;
+    let inode_pos = out.stream_position()?;
+    out.write_all(struct_to_slice(&inode))?;
+    let hash = opts.merkle.then(|| merkle::symlink_hash(buf.as_bytes()));
+    if opts.dedup_symlinks {
+        shared
+            .symlinks
+            .insert(buf.as_bytes().to_vec(), (inode_pos, hash));
+    }
+    Ok((inode_pos, hash))
+}
+
+fn write_special<P: AsRef<Path>, S: SeekWrite>(
+    path: P,
+    ty: disk::InodeType,
+    out: &mut S,
+    opts: &WriteOptions,
+    summary: &mut WriteSummary,
+) -> Result<Written> {
+    let meta = fs::symlink_metadata(&path)?;
+    let rdev = match ty {
+        disk::InodeType::CharDevice | disk::InodeType::BlockDevice => (
+            libc::major(meta.rdev()) as u64,
+            libc::minor(meta.rdev()) as u64,
+        ),
+        _ => (0, 0),
+    };
+    let xattrs = write_xattrs(path.as_ref(), false, out, opts)?;
+    let inode = disk::Inode {
+        offset: rdev.0.into(),
+        size: rdev.1.into(),
+        inode_type: ty.into(),
+        mode: permission_bits(&meta).into(),
+        mtime: mtime(&meta, opts).into(),
+        uid: meta.uid().into(),
+        gid: meta.gid().into(),
+        xattrs: xattrs.into(),
+        ..Default::default()
+    };
+    summary.specials += 1;
     let inode_pos = out.stream_position()?;
     out.write_all(struct_to_slice(&inode))?;
+    Ok((
+        inode_pos,
+        opts.merkle.then(|| merkle::special_hash(ty, rdev)),
+    ))
+}
+
+fn find_case_collisions(paths: &[fs::DirEntry], summary: &mut WriteSummary) {
+    let mut folded: Vec<_> = paths
+        .iter()
+        .map(|e| (e.file_name().as_bytes().to_ascii_lowercase(), e.path()))
+        .collect();
+    folded.sort();
+    for pair in folded.windows(2) {
+        if pair[0].0 == pair[1].0 {
+            summary
+                .case_collisions
+                .push((pair[0].1.clone(), pair[1].1.clone()));
+        }
+    }
+}
+
+// Whether the file at `path` is outside of the sizes of `opts`
+fn size_excluded(path: &Path, opts: &WriteOptions) -> Result<bool> {
+    if opts.min_file_size.is_none() && opts.max_file_size.is_none() {
+        return Ok(false);
+    }
+    Ok(size_out_of_range(fs::metadata(path)?.len(), opts))
+}
+
+fn size_out_of_range(size: u64, opts: &WriteOptions) -> bool {
+    opts.min_file_size.is_some_and(|min| size < min)
+        || opts.max_file_size.is_some_and(|max| size > max)
+}
+
+// Write the entry at `path`. This returns None if it was removed since
+// its directory was listed and `opts.skip_vanished` is set.
+pub(super) fn write_entry<S: SeekWrite>(
+    path: &Path,
+    ft: fs::FileType,
+    out: &mut S,
+    data: Option<&mut dyn SeekWrite>,
+    opts: &WriteOptions,
+    summary: &mut WriteSummary,
+    shared: &mut Shared,
+) -> Result<Option<Written>> {
+    let res = if ft.is_file() {
+        match size_excluded(path, opts) {
+            Ok(true) => {
+                summary.size_excluded.push(path.to_path_buf());
+                return Ok(None);
+            }
+            Ok(false) => write_file(path, out, data, opts, summary, shared),
+            Err(e) => Err(e),
+        }
+    } else if ft.is_symlink() {
+        write_symlink(path, out, opts, summary, shared)
+    } else if ft.is_dir() {
+        write_directory(path, out, data, opts, summary, shared)
+    } else if ft.is_char_device() {
+        write_special(path, disk::InodeType::CharDevice, out, opts, summary)
+    } else if ft.is_block_device() {
+        write_special(path, disk::InodeType::BlockDevice, out, opts, summary)
+    } else if ft.is_fifo() {
+        write_special(path, disk::InodeType::Fifo, out, opts, summary)
+    } else if ft.is_socket() {
+        write_special(path, disk::InodeType::Socket, out, opts, summary)
+    } else {
+        return Err(Error::InvalidOperation("Unsupported file type"));
+    };
+    match res {
+        // Nothing is written before the source is opened, so there is
+        // nothing to undo
+        Err(Error::IO(e))
+            if e.kind() == io::ErrorKind::NotFound && opts.skip_vanished =>
+        {
+            summary.vanished.push(path.to_path_buf());
+            Ok(None)
+        }
+        res => res.map(Some),
+    }
+}
+
+// Write the hash of a directory, if there is one. This returns where
+// it was written, or 0 when there is no hash.
+fn write_dir_hash<S: SeekWrite>(
+    out: &mut S,
+    hash: Option<Hash>,
+) -> Result<u64> {
+    Ok(match hash {
+        Some(hash) => {
+            let pos = out.stream_position()?;
+            out.write_all(&hash)?;
+            pos
+        }
+        None => 0,
+    })
+}
+
+// Write the entries and inode of a directory, with the metadata of
+// `inode`, and make it the parent of the entries. `data_bytes` is
+// WriteSummary::total_data_bytes from before the entries.
+fn write_dir_inode<S: SeekWrite>(
+    out: &mut S,
+    entries: &[disk::Dirent],
+    names: &[Vec<u8>],
+    mut inode: disk::Inode,
+    data_bytes: u64,
+    opts: &WriteOptions,
+    summary: &mut WriteSummary,
+) -> Result<u64> {
+    let buf = unsafe {
+        std::slice::from_raw_parts(
+            entries.as_ptr() as *const u8,
+            std::mem::size_of_val(entries),
+        )
+    };
+    if opts.subtree_sizes {
+        let size = summary.total_data_bytes - data_bytes;
+        out.write_all(&size.to_le_bytes())?;
+    }
+    let offset = out.stream_position()?;
+    out.write_all(buf)?;
+    let indexed = match opts.hash_index_min_entries {
+        Some(min) => {
+            entries.len() as u64 >= min && entries.len() <= INDEX_ENTRIES_MAX
+        }
+        None => false,
+    };
+    let hash_index = if indexed {
+        let pos = out.stream_position()?;
+        out.write_all(&disk::index::build(names))?;
+        pos
+    } else {
+        0
+    };
+    inode.offset = offset.into();
+    inode.size = (buf.len() as u64).into();
+    inode.inode_type = disk::InodeType::Directory.into();
+    inode.collation = opts.collation.into();
+    inode.hash_index = hash_index.into();
+    let inode_pos = out.stream_position()?;
+    out.write_all(struct_to_slice(&inode))?;
+    let inode_ref: disk::u64le = inode_pos.into();
+
+    // Fix up the inodes in the directory to add the correct parent inode
+    let cur_pos = out.stream_position()?;
+    for dentry in entries {
+        out.seek(io::SeekFrom::Start(dentry.inode.into()))?;
+        out.write_all(struct_to_slice(&inode_ref))?;
+    }
+    out.seek(io::SeekFrom::Start(cur_pos))?;
+    summary.dirs += 1;
     Ok(inode_pos)
 }
 
 fn write_directory<P: AsRef<Path>, S: SeekWrite>(
     dir: P,
     out: &mut S,
-) -> Result<u64> {
+    mut data: Option<&mut dyn SeekWrite>,
+    opts: &WriteOptions,
+    summary: &mut WriteSummary,
+    shared: &mut Shared,
+) -> Result<Written> {
+    let meta = fs::metadata(&dir)?;
+    let xattrs = write_xattrs(dir.as_ref(), true, out, opts)?;
     let mut entries = Vec::new();
+    let mut names = Vec::new();
+    let mut hasher = opts.merkle.then(merkle::DirHasher::new);
+    let data_bytes = summary.total_data_bytes;
     let iter = fs::read_dir(dir)?;
     let tmp: std::result::Result<Vec<_>, io::Error> = iter.collect();
     let mut paths = tmp?;
-    paths.sort_by_key(|e| e.path());
+    paths.sort_by(|a, b| {
+        let (a, b) = (a.file_name(), b.file_name());
+        opts.collation.compare(a.as_bytes(), b.as_bytes())
+    });
+    if opts.warn_case_collisions {
+        find_case_collisions(&paths, summary);
+    }
+    #[cfg(feature = "parallel")]
+    let mut prefetch = opts.parallel.then(|| Prefetch::new(&paths, opts));
     for entry in paths {
         let ft = entry.file_type()?;
+        let path = entry.path();
+        #[cfg(feature = "parallel")]
+        if let Some(prefetch) = prefetch.as_mut() {
+            prefetch.fill(&path, opts, shared);
+        }
+        let data = data.as_mut().map(|d| &mut **d as &mut dyn SeekWrite);
+        let (inode_pos, hash) =
+            match write_entry(&path, ft, out, data, opts, summary, shared)? {
+                Some(written) => written,
+                None => continue,
+            };
+        if let (Some(h), Some(hash)) = (hasher.as_mut(), hash) {
+            h.add(entry.file_name().as_bytes(), &hash);
+        }
+        // After the entry, so nothing is left behind if it vanished
         let name_pos = out.stream_position()?;
         out.write_all(entry.file_name().as_bytes())?;
-        out.write(b"\0")?;
-
-        let inode_pos = if ft.is_file() {
-            write_file(entry.path(), out)?
-        } else if ft.is_symlink() {
-            write_symlink(entry.path(), out)?
-        } else if ft.is_dir() {
-            write_directory(entry.path(), out)?
-        } else {
-            return Err(Error::InvalidOperation("Unsupported file type"));
-        };
+        out.write_all(b"\0")?;
         entries.push(disk::Dirent {
             name: name_pos.into(),
             inode: inode_pos.into(),
-        })
+        });
+        names.push(entry.file_name().as_bytes().to_vec());
     }
-    let mut dir_inode = disk::Inode::default();
-    let buf = unsafe {
-        std::slice::from_raw_parts(
-            entries.as_ptr() as *const u8,
-            entries.len() * std::mem::size_of::<disk::Dirent>(),
-        )
+    let hash = hasher.map(|h| h.finish());
+    let dir_inode = disk::Inode {
+        mode: permission_bits(&meta).into(),
+        mtime: mtime(&meta, opts).into(),
+        uid: meta.uid().into(),
+        gid: meta.gid().into(),
+        xattrs: xattrs.into(),
+        merkle: write_dir_hash(out, hash)?.into(),
+        ..Default::default()
     };
-    dir_inode.offset = out.stream_position()?.into();
-    dir_inode.size = (buf.len() as u64).into();
-    dir_inode.inode_type = disk::InodeType::Directory.into();
-    out.write_all(buf)?;
-    let dir_inode_pos = out.stream_position()?;
-    out.write_all(struct_to_slice(&dir_inode))?;
-    let inode_ref: disk::u64le = dir_inode_pos.into();
+    let dir_inode_pos = write_dir_inode(
+        out, &entries, &names, dir_inode, data_bytes, opts, summary,
+    )?;
+    Ok((dir_inode_pos, hash))
+}
 
-    // Fix up the inodes in the directory to add the correct parent inode
-    let cur_pos = out.stream_position()?;
-    for dentry in entries {
-        out.seek(io::SeekFrom::Start(dentry.inode.into()))?;
-        out.write_all(struct_to_slice(&inode_ref))?;
+pub fn write_image<P: AsRef<Path>, S: Seek + Write>(
+    source: P,
+    out: S,
+    key: Key,
+    enc_type: disk::EncryptionType,
+) -> Result<WriteSummary> {
+    write_image_with(source, out, key, enc_type, &WriteOptions::default())
+}
+
+pub fn write_image_with<P: AsRef<Path>, S: Seek + Write>(
+    source: P,
+    out: S,
+    key: Key,
+    enc_type: disk::EncryptionType,
+    opts: &WriteOptions,
+) -> Result<WriteSummary> {
+    write_image_impl(source, out, None, None, key, enc_type, opts)
+}
+
+// Encrypt in place what was written to `out` after the header up to
+// `end`, reading it back from `readback`
+fn encrypt_readback<W: Seek + Write>(
+    out: &mut W,
+    readback: &dyn disk::ReadAt,
+    end: u64,
+) -> Result<()> {
+    let mut pos = out.seek(io::SeekFrom::Start(
+        std::mem::size_of::<disk::Header>() as u64,
+    ))?;
+    let mut buf = vec![0; 1 << 16];
+    while pos < end {
+        let len = std::cmp::min(buf.len() as u64, end - pos) as usize;
+        readback.read_exact_at(&mut buf[..len], pos)?;
+        out.write_all(&buf[..len])?;
+        pos += len as u64;
     }
-    out.seek(io::SeekFrom::Start(cur_pos))?;
+    Ok(out.flush()?)
+}
 
-    Ok(dir_inode_pos)
+// Like write_image_with, with `readback` reading what was written to
+// `out`, which XChaCha20-Poly1305 and deterministic ChaCha20 need
+pub(crate) fn write_image_readback<P: AsRef<Path>, S: Seek + Write>(
+    source: P,
+    out: S,
+    readback: &dyn disk::ReadAt,
+    key: Key,
+    enc_type: disk::EncryptionType,
+    opts: &WriteOptions,
+) -> Result<WriteSummary> {
+    write_image_impl(source, out, None, Some(readback), key, enc_type, opts)
 }
 
-pub fn write_image<P: AsRef<Path>, S: Seek + Write>(
+/// Write a split image: the contents of files go to `data` and the
+/// rest to `out`. See `FS::open_split`.
+pub fn write_image_split_with<
+    P: AsRef<Path>,
+    S: Seek + Write,
+    D: Seek + Write,
+>(
     source: P,
-    mut out: S,
+    out: S,
+    mut data: D,
     key: Key,
     enc_type: disk::EncryptionType,
+    opts: &WriteOptions,
+) -> Result<WriteSummary> {
+    write_image_impl(source, out, Some(&mut data), None, key, enc_type, opts)
+}
+
+/// Store a digest of the whole image in `file` in its header, checked
+/// by `verify_integrity`. The digest covers the stored bytes, so the
+/// ciphertext of encrypted images, and for split images only the
+/// metadata.
+pub fn seal_image<F: Read + Write + Seek>(mut file: F) -> Result<[u8; 32]> {
+    let mut header = disk::Header::default();
+    file.rewind()?;
+    file.read_exact(struct_to_mut_slice(&mut header))?;
+    if header.magic != disk::MAGIC {
+        return Err(Error::Format("Wrong magic"));
+    }
+    if header.version_major != disk::VERSION_MAJOR
+        || header.version_minor < disk::MINOR_DIGEST
+    {
+        return Err(Error::InvalidOperation(
+            "image version doesn't support digests",
+        ));
+    }
+    let compat = u32::from(header.compat) | disk::COMPAT_DIGEST;
+    header.compat = compat.into();
+    let mut hasher = disk::digest_header(header);
+    file.seek(io::SeekFrom::Start(disk::header_size(header.version_minor)))?;
+    io::copy(&mut file, &mut hasher)?;
+    header.digest = *hasher.finalize().as_bytes();
+    file.rewind()?;
+    file.write_all(struct_to_slice(&header))?;
+    file.flush()?;
+    Ok(header.digest)
+}
+
+/// Metadata to change with `set_metadata`, fields left to None keep
+/// their value.
+#[derive(Clone, Debug, Default)]
+pub struct MetadataUpdate {
+    /// Permission bits, as in `st_mode & 0o7777`
+    pub mode: Option<u32>,
+    /// Modification time in seconds since the epoch
+    pub mtime: Option<u64>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+// Rewrite the fields of `update` in the inode at `offset` of the
+// unencrypted image `file`, then its digest if it has one. Nothing
+// else moves, so entries and contents stay where they are.
+pub(crate) fn update_inode<F: Read + Write + Seek>(
+    mut file: F,
+    offset: u64,
+    update: &MetadataUpdate,
 ) -> Result<()> {
-    if !fs::metadata(&source)?.is_dir() {
+    let mut header = disk::Header::default();
+    file.rewind()?;
+    file.read_exact(struct_to_mut_slice(&mut header))?;
+    if header.magic != disk::MAGIC {
+        return Err(Error::Format("Wrong magic"));
+    }
+    let minor = header.version_minor;
+    if header.version_major != disk::VERSION_MAJOR
+        || (update.mode.is_some() && minor < disk::MINOR_MODE)
+        || (update.mtime.is_some() && minor < disk::MINOR_INODE_EXT)
+        || ((update.uid.is_some() || update.gid.is_some())
+            && minor < disk::MINOR_OWNER)
+    {
+        return Err(Error::InvalidOperation(
+            "image version doesn't store this metadata",
+        ));
+    }
+    if update.mode.is_some_and(|m| m & !0o7777 != 0) {
+        return Err(Error::InvalidOperation("mode has more than permissions"));
+    }
+    if offset < disk::header_size(minor) {
+        return Err(Error::Bounds("inode inside the header"));
+    }
+    let mut inode = disk::Inode::default();
+    let buf = &mut struct_to_mut_slice(&mut inode)[..disk::inode_size(minor)];
+    file.seek(io::SeekFrom::Start(offset))?;
+    file.read_exact(buf)?;
+    if let Some(mode) = update.mode {
+        inode.mode = mode.into();
+    }
+    if let Some(mtime) = update.mtime {
+        inode.mtime = mtime.into();
+    }
+    if let Some(uid) = update.uid {
+        inode.uid = uid.into();
+    }
+    if let Some(gid) = update.gid {
+        inode.gid = gid.into();
+    }
+    file.seek(io::SeekFrom::Start(offset))?;
+    file.write_all(&struct_to_slice(&inode)[..disk::inode_size(minor)])?;
+    if u32::from(header.compat) & disk::COMPAT_DIGEST != 0 {
+        seal_image(&mut file)?;
+    }
+    file.flush()?;
+    Ok(())
+}
+
+/// Builds an image from entries added one by one instead of from a
+/// directory. Parent directories are created as needed.
+///
+/// Files are read when the image is written by `finish`. Entries get
+/// the usual permissions (0o644 for files, 0o755 for directories), are
+/// owned by root and have no modification time. `WriteOptions::dedup`,
+/// `xattrs` and `warn_case_collisions` only apply to `write_image`.
+#[derive(Default)]
+pub struct ImageBuilder<'a> {
+    root: NodeDir<'a>,
+    // Of the root
+    meta: Option<Meta>,
+}
+
+type NodeDir<'a> = BTreeMap<Vec<u8>, Node<'a>>;
+
+enum Node<'a> {
+    File(Box<dyn io::Read + 'a>),
+    Symlink(Vec<u8>),
+    // Inode type and device numbers
+    Special(disk::InodeType, (u64, u64)),
+    Directory(NodeDir<'a>, Option<Meta>),
+    // Already in the image, see write_image_from_tar
+    Written(Written),
+}
+
+// Inode metadata of a node, instead of the defaults of ImageBuilder
+#[derive(Clone, Copy)]
+struct Meta {
+    mode: u32,
+    mtime: u64,
+    uid: u32,
+    gid: u32,
+}
+
+impl Meta {
+    fn apply(&self, inode: &mut disk::Inode, opts: &WriteOptions) {
+        let mtime = match opts.clamp_mtime {
+            Some(max) => std::cmp::min(self.mtime, max),
+            None => self.mtime,
+        };
+        inode.mode = self.mode.into();
+        inode.mtime = mtime.into();
+        inode.uid = self.uid.into();
+        inode.gid = self.gid.into();
+    }
+}
+
+impl<'a> ImageBuilder<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The directory that holds `path` and the name of `path` in it
+    fn parent<'b>(
+        &'b mut self,
+        path: &'b [u8],
+    ) -> Result<(&'b mut NodeDir<'a>, &'b [u8])> {
+        let elems: Vec<&[u8]> = path.split(|&c| c == b'/').collect();
+        if elems.iter().any(|e| {
+            e.is_empty() || *e == b"." || *e == b".." || e.contains(&0)
+        }) {
+            return Err(Error::InvalidOperation("invalid path"));
+        }
+        if elems.iter().any(|e| e.len() > disk::NAME_MAX) {
+            return Err(Error::Bounds("name too long"));
+        }
+        let (name, parents) = elems.split_last().unwrap();
+        let mut dir = &mut self.root;
+        for elem in parents {
+            let node = dir
+                .entry(elem.to_vec())
+                .or_insert_with(|| Node::Directory(BTreeMap::new(), None));
+            dir = match node {
+                Node::Directory(d, _) => d,
+                _ => {
+                    return Err(Error::InvalidOperation(
+                        "parent is not a directory",
+                    ))
+                }
+            };
+        }
+        Ok((dir, name))
+    }
+
+    fn add(&mut self, path: &[u8], node: Node<'a>) -> Result<()> {
+        let (dir, name) = self.parent(path)?;
+        match dir.entry(name.to_vec()) {
+            Entry::Occupied(_) => {
+                Err(Error::InvalidOperation("path already exists"))
+            }
+            Entry::Vacant(e) => {
+                e.insert(node);
+                Ok(())
+            }
+        }
+    }
+
+    /// Add a file at `path` (relative to the root, with '/' separators)
+    /// with the contents read from `reader`.
+    pub fn add_file<P: AsRef<[u8]>, R: io::Read + 'a>(
+        &mut self,
+        path: P,
+        reader: R,
+    ) -> Result<()> {
+        self.add(path.as_ref(), Node::File(Box::new(reader)))
+    }
+
+    pub fn add_symlink<P: AsRef<[u8]>, T: AsRef<[u8]>>(
+        &mut self,
+        path: P,
+        target: T,
+    ) -> Result<()> {
+        self.add(path.as_ref(), Node::Symlink(target.as_ref().to_vec()))
+    }
+
+    /// Add an empty directory, which is fine if it already exists.
+    pub fn add_dir<P: AsRef<[u8]>>(&mut self, path: P) -> Result<()> {
+        let (dir, name) = self.parent(path.as_ref())?;
+        match dir
+            .entry(name.to_vec())
+            .or_insert_with(|| Node::Directory(BTreeMap::new(), None))
+        {
+            Node::Directory(..) => Ok(()),
+            _ => Err(Error::InvalidOperation("path already exists")),
+        }
+    }
+
+    // Put `node` at `path`, in place of whatever was there
+    fn replace(&mut self, path: &[u8], node: Node<'a>) -> Result<()> {
+        let (dir, name) = self.parent(path)?;
+        dir.insert(name.to_vec(), node);
+        Ok(())
+    }
+
+    // Make `path` a directory with `meta`, keeping its entries if it
+    // already was one. An empty path is the root.
+    fn set_dir(&mut self, path: &[u8], meta: Option<Meta>) -> Result<()> {
+        if path.is_empty() {
+            self.meta = meta;
+            return Ok(());
+        }
+        let (dir, name) = self.parent(path)?;
+        match dir
+            .entry(name.to_vec())
+            .or_insert_with(|| Node::Directory(BTreeMap::new(), None))
+        {
+            Node::Directory(_, m) => *m = meta,
+            node => *node = Node::Directory(BTreeMap::new(), meta),
+        }
+        Ok(())
+    }
+
+    /// Add a whiteout for `path`, which hides it in the layers below
+    /// when the image is used as a layer of an `OverlayFS`.
+    pub fn add_whiteout<P: AsRef<[u8]>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let (parent, name) = match path.iter().rposition(|&c| c == b'/') {
+            Some(i) => (&path[..=i], &path[i + 1..]),
+            None => (&path[..0], path),
+        };
+        if name.is_empty() || name == b"." || name == b".." {
+            return Err(Error::InvalidOperation("invalid path"));
+        }
+        let mut marker = parent.to_vec();
+        marker.extend_from_slice(&overlay::whiteout_name(name));
+        self.add(&marker, Node::File(Box::new(io::empty())))
+    }
+
+    /// Add the directory `path` if needed and make it opaque, so that
+    /// it hides the contents of the same directory in the layers below
+    /// when used in an `OverlayFS`. An empty path is the root.
+    pub fn add_opaque_dir<P: AsRef<[u8]>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let mut marker = Vec::new();
+        if !path.is_empty() {
+            self.add_dir(path)?;
+            marker.extend_from_slice(path);
+            marker.push(b'/');
+        }
+        marker.extend_from_slice(overlay::OPAQUE_MARKER);
+        self.add(&marker, Node::File(Box::new(io::empty())))
+    }
+
+    /// Write the image, like `write_image_with`.
+    pub fn finish<S: Seek + Write>(
+        self,
+        out: S,
+        key: Key,
+        enc_type: disk::EncryptionType,
+        opts: &WriteOptions,
+    ) -> Result<WriteSummary> {
+        let (root, meta) = (self.root, self.meta);
+        write_streams(
+            out,
+            None,
+            None,
+            key,
+            enc_type,
+            opts,
+            |mut out, data, summary| {
+                write_node_dir(root, meta, &mut out, data, opts, summary)
+            },
+        )
+    }
+}
+
+/// Write an image of the tree in the tar archive `tar`, like
+/// `ImageBuilder` does for its entries but with the permissions, owners
+/// and modification times of the archive. Regular files, directories,
+/// symlinks, hard links, devices and FIFOs are supported, other entries
+/// are an error. As when extracting, a path that is repeated in the
+/// archive gets its last entry, though the contents of the earlier
+/// files still take space in the image.
+///
+/// Files are written as the archive is read, only the directory
+/// structure is kept until the end, so entries can come in any order
+/// except for hard links, which come after their target.
+pub fn write_image_from_tar<R: io::Read, S: Seek + Write>(
+    tar: R,
+    out: S,
+    key: Key,
+    enc_type: disk::EncryptionType,
+    opts: &WriteOptions,
+) -> Result<WriteSummary> {
+    let mut tar = tar::Archive::new(tar);
+    write_streams(
+        out,
+        None,
+        None,
+        key,
+        enc_type,
+        opts,
+        |mut out, _, summary| {
+            let mut builder = ImageBuilder::new();
+            // What is at each path that isn't a directory, for hard links
+            let mut written = HashMap::new();
+            for data in tar.entries()? {
+                let mut data = data?;
+                let entry = TarEntry::new(&data)?;
+                let path = entry.path.as_slice();
+                let meta = Meta {
+                    mode: entry.mode,
+                    mtime: entry.mtime,
+                    uid: entry.uid,
+                    gid: entry.gid,
+                };
+                let node = match entry.kind {
+                    EntryKind::Directory => {
+                        builder.set_dir(path, Some(meta))?;
+                        written.remove(path);
+                        continue;
+                    }
+                    EntryKind::File => Node::File(Box::new(&mut data)),
+                    EntryKind::Symlink => Node::Symlink(entry.link.clone()),
+                    EntryKind::HardLink => {
+                        let target = *written.get(&entry.link).ok_or(
+                            Error::InvalidOperation(
+                                "hard link target not found",
+                            ),
+                        )?;
+                        summary.hardlinks += 1;
+                        Node::Written(target)
+                    }
+                    EntryKind::Special(ty) => {
+                        let ty = match ty {
+                            tar::EntryType::Char => disk::InodeType::CharDevice,
+                            tar::EntryType::Block => {
+                                disk::InodeType::BlockDevice
+                            }
+                            _ => disk::InodeType::Fifo,
+                        };
+                        Node::Special(ty, entry.rdev.unwrap_or((0, 0)))
+                    }
+                    EntryKind::Other(_) => {
+                        return Err(Error::InvalidOperation(
+                            "unsupported tar entry type",
+                        ))
+                    }
+                };
+                let inode = write_node(
+                    node,
+                    Some(meta),
+                    &mut out,
+                    None,
+                    opts,
+                    summary,
+                )?;
+                builder.replace(path, Node::Written(inode))?;
+                written.insert(entry.path.clone(), inode);
+            }
+            write_node_dir(
+                builder.root,
+                builder.meta,
+                &mut out,
+                None,
+                opts,
+                summary,
+            )
+        },
+    )
+}
+
+// `meta` replaces the default metadata of files, symlinks and specials
+fn write_node<S: SeekWrite>(
+    node: Node,
+    meta: Option<Meta>,
+    out: &mut S,
+    data: Option<&mut dyn SeekWrite>,
+    opts: &WriteOptions,
+    summary: &mut WriteSummary,
+) -> Result<Written> {
+    let inode = match node {
+        Node::Directory(entries, meta) => {
+            return write_node_dir(entries, meta, out, data, opts, summary)
+        }
+        Node::Written(written) => return Ok(written),
+        Node::File(mut src) => {
+            let dest: &mut dyn SeekWrite = match data {
+                Some(d) => d,
+                None => out,
+            };
+            let (offset, size, tree, crc32) =
+                write_contents(&mut src, dest, opts)?;
+            summary.files += 1;
+            summary.total_data_bytes += size;
+            let inode = disk::Inode {
+                offset: offset.into(),
+                size: size.into(),
+                inode_type: disk::InodeType::File.into(),
+                mode: 0o644.into(),
+                merkle: tree.map_or(0, |t| t.0).into(),
+                crc32: crc32.into(),
+                ..Default::default()
+            };
+            (inode, tree.map(|t| t.1))
+        }
+        Node::Symlink(target) => {
+            if target.len() > opts.link_target_max {
+                return Err(Error::Bounds("link target too long"));
+            }
+            let inode = disk::Inode {
+                offset: out.stream_position()?.into(),
+                size: (target.len() as u64).into(),
+                inode_type: disk::InodeType::Symlink.into(),
+                mode: 0o777.into(),
+                ..Default::default()
+            };
+            out.write_all(&target)?;
+            summary.symlinks += 1;
+            (inode, opts.merkle.then(|| merkle::symlink_hash(&target)))
+        }
+        Node::Special(ty, rdev) => {
+            let inode = disk::Inode {
+                offset: rdev.0.into(),
+                size: rdev.1.into(),
+                inode_type: ty.into(),
+                mode: 0o644.into(),
+                ..Default::default()
+            };
+            summary.specials += 1;
+            (inode, opts.merkle.then(|| merkle::special_hash(ty, rdev)))
+        }
+    };
+    let mut inode = inode;
+    if let Some(meta) = meta {
+        meta.apply(&mut inode.0, opts);
+    }
+    let inode_pos = out.stream_position()?;
+    out.write_all(struct_to_slice(&inode.0))?;
+    Ok((inode_pos, inode.1))
+}
+
+fn write_node_dir<S: SeekWrite>(
+    entries: NodeDir,
+    meta: Option<Meta>,
+    out: &mut S,
+    mut data: Option<&mut dyn SeekWrite>,
+    opts: &WriteOptions,
+    summary: &mut WriteSummary,
+) -> Result<Written> {
+    let mut entries: Vec<_> = entries.into_iter().collect();
+    entries.sort_by(|a, b| opts.collation.compare(&a.0, &b.0));
+    let mut dirents = Vec::with_capacity(entries.len());
+    let mut names = Vec::with_capacity(entries.len());
+    let mut hasher = opts.merkle.then(merkle::DirHasher::new);
+    let data_bytes = summary.total_data_bytes;
+    for (name, node) in entries {
+        let data = data.as_mut().map(|d| &mut **d as &mut dyn SeekWrite);
+        let (inode_pos, hash) =
+            write_node(node, None, out, data, opts, summary)?;
+        if let (Some(h), Some(hash)) = (hasher.as_mut(), hash) {
+            h.add(&name, &hash);
+        }
+        let name_pos = out.stream_position()?;
+        out.write_all(&name)?;
+        out.write_all(b"\0")?;
+        dirents.push(disk::Dirent {
+            name: name_pos.into(),
+            inode: inode_pos.into(),
+        });
+        names.push(name);
+    }
+    let hash = hasher.map(|h| h.finish());
+    let mut inode = disk::Inode {
+        mode: 0o755.into(),
+        merkle: write_dir_hash(out, hash)?.into(),
+        ..Default::default()
+    };
+    if let Some(meta) = meta {
+        meta.apply(&mut inode, opts);
+    }
+    let inode_pos = write_dir_inode(
+        out, &dirents, &names, inode, data_bytes, opts, summary,
+    )?;
+    Ok((inode_pos, hash))
+}
+
+fn write_image_impl<P: AsRef<Path>, S: Seek + Write>(
+    source: P,
+    out: S,
+    data: Option<&mut dyn SeekWrite>,
+    readback: Option<&dyn disk::ReadAt>,
+    key: Key,
+    enc_type: disk::EncryptionType,
+    opts: &WriteOptions,
+) -> Result<WriteSummary> {
+    let not_found = |e: io::Error| match e.kind() {
+        io::ErrorKind::NotFound => Error::InvalidOperation("source not found"),
+        _ => e.into(),
+    };
+    let meta = fs::symlink_metadata(&source).map_err(not_found)?;
+    if meta.file_type().is_symlink() && !opts.dereference_root {
+        return Err(Error::InvalidOperation("root is a symlink"));
+    }
+    // A dangling symlink is as good as missing
+    if !fs::metadata(&source).map_err(not_found)?.is_dir() {
         return Err(Error::InvalidOperation("root is not a directory"));
     }
+    if opts.parallel && !cfg!(feature = "parallel") {
+        return Err(Error::InvalidOperation("parallel support is not enabled"));
+    }
+    write_streams(
+        out,
+        data,
+        readback,
+        key,
+        enc_type,
+        opts,
+        |mut out, data, summary| {
+            write_directory(
+                &source,
+                &mut out,
+                data,
+                opts,
+                summary,
+                &mut Shared::default(),
+            )
+        },
+    )
+}
+
+// Set up the streams of an image, have `root` write the root directory
+// to them and finish with the header. `readback` reads what was written
+// to `out`.
+fn write_streams<S, F>(
+    mut out: S,
+    data: Option<&mut dyn SeekWrite>,
+    readback: Option<&dyn disk::ReadAt>,
+    key: Key,
+    enc_type: disk::EncryptionType,
+    opts: &WriteOptions,
+    root: F,
+) -> Result<WriteSummary>
+where
+    S: Seek + Write,
+    F: FnOnce(
+        &mut dyn SeekWrite,
+        Option<&mut dyn SeekWrite>,
+        &mut WriteSummary,
+    ) -> Result<Written>,
+{
+    disk::compress::check_supported(opts.compression)?;
+    if opts.integrity {
+        return Err(Error::InvalidOperation(
+            "integrity digests need to read the image back, see seal_image",
+        ));
+    }
+    if enc_type == disk::EncryptionType::XChaCha20Poly1305 && readback.is_none()
+    {
+        return Err(Error::InvalidOperation(
+            "XChaCha20-Poly1305 images are read back while written, so only \
+             write_image_file_with can write them",
+        ));
+    }
+    // Encrypted once the nonce is known
+    let encrypt_after =
+        opts.deterministic && enc_type == disk::EncryptionType::ChaCha20;
+    if encrypt_after && (readback.is_none() || data.is_some()) {
+        return Err(Error::InvalidOperation(
+            "deterministic ChaCha20 images are read back to be encrypted, so \
+             only write_image_file_with can write them, unsplit",
+        ));
+    }
+    let block_size = opts.encryption_block_size != disk::aead::BLOCK_SIZE;
+    if block_size && enc_type != disk::EncryptionType::XChaCha20Poly1305 {
+        return Err(Error::InvalidOperation(
+            "encryption block size without XChaCha20-Poly1305",
+        ));
+    }
+    if !disk::aead::valid_block_size(opts.encryption_block_size) {
+        return Err(Error::InvalidOperation("invalid encryption block size"));
+    }
+    if opts.key_salt.is_some() && enc_type == disk::EncryptionType::None {
+        return Err(Error::InvalidOperation("key salt without encryption"));
+    }
     // Skip the header for now
     out.seek(io::SeekFrom::Start(
         std::mem::size_of::<disk::Header>() as u64
     ))?;
 
+    let mut nonce = match enc_type {
+        disk::EncryptionType::ChaCha20 if !encrypt_after => {
+            Some(disk::crypto::new_nonce()?)
+        }
+        _ => None,
+    };
     // wrap with encrypter eventually
-    let mut out_enc: Box<dyn SeekWrite> = match enc_type {
+    let out_enc: Box<dyn SeekWrite> = match enc_type {
         disk::EncryptionType::None => Box::new(&mut out),
+        disk::EncryptionType::ChaCha20 if encrypt_after => Box::new(&mut out),
         disk::EncryptionType::ChaCha20 => {
             let enc = disk::crypto::EncryptChaCha20::new(&mut out, key)?;
-            Box::new(enc)
+            Box::new(enc.with_nonce(nonce.unwrap_or_default()))
+        }
+        disk::EncryptionType::XChaCha20Poly1305 => {
+            Box::new(disk::aead::EncryptXChaCha20Poly1305::new(
+                &mut out,
+                key,
+                readback,
+                opts.encryption_block_size,
+            )?)
         }
     };
-    let root_inode = write_directory(&source, &mut out_enc)?;
+    let mut out_enc = HashWriter {
+        out: out_enc,
+        hasher: blake3::Hasher::new(),
+    };
+    let split = data.is_some();
+    let mut data_enc = match data {
+        None => None,
+        Some(d) => {
+            let d: Box<dyn SeekWrite> = match enc_type {
+                disk::EncryptionType::None => Box::new(d),
+                disk::EncryptionType::ChaCha20 => Box::new(
+                    disk::crypto::EncryptChaCha20::new_data(d, key)?
+                        .with_nonce(nonce.unwrap_or_default()),
+                ),
+                disk::EncryptionType::XChaCha20Poly1305 => {
+                    Box::new(disk::aead::EncryptXChaCha20Poly1305::new_data(
+                        d,
+                        key,
+                        opts.encryption_block_size,
+                    )?)
+                }
+            };
+            Some(HashWriter {
+                out: d,
+                hasher: blake3::Hasher::new(),
+            })
+        }
+    };
+    let mut summary = WriteSummary::default();
+    let (root_inode, root_hash) = root(
+        &mut out_enc,
+        data_enc.as_mut().map(|d| d as &mut dyn SeekWrite),
+        &mut summary,
+    )?;
     // Set the parent of the root inode to itself
     let root_inode_ref: disk::u64le = root_inode.into();
     out_enc.seek(io::SeekFrom::Start(root_inode))?;
     out_enc.write_all(struct_to_slice(&root_inode_ref))?;
+    let uuid = if opts.deterministic {
+        let mut hasher = out_enc.hasher.clone();
+        if let Some(ref d) = data_enc {
+            hasher.update(d.hasher.finalize().as_bytes());
+        }
+        let hash = hasher.finalize();
+        if encrypt_after {
+            let key = key.ok_or(Error::KeyRequired)?;
+            nonce = Some(disk::crypto::derive_nonce(key, hash.as_bytes()));
+        }
+        make_uuid(hash.as_bytes()[..16].try_into().unwrap(), 8)
+    } else {
+        let mut bytes = [0; 16];
+        getrandom::getrandom(&mut bytes).map_err(io::Error::from)?;
+        make_uuid(bytes, 4)
+    };
+    out_enc.flush()?;
     drop(out_enc);
+    if let Some(mut d) = data_enc {
+        // The data is only ever appended
+        summary.data_size = d.stream_position()?;
+        d.flush()?;
+    }
 
+    summary.image_size = out.seek(io::SeekFrom::End(0))?;
+    if let (true, Some(readback), Some(nonce)) =
+        (encrypt_after, readback, nonce)
+    {
+        let mut enc = disk::crypto::EncryptChaCha20::new(&mut out, key)?
+            .with_nonce(nonce);
+        encrypt_readback(&mut enc, readback, summary.image_size)?;
+    }
+    let mut incompat = if opts.collation != disk::Collation::Bytes {
+        disk::INCOMPAT_COLLATION
+    } else {
+        0
+    };
+    if split {
+        incompat |= disk::INCOMPAT_SPLIT_DATA;
+    }
+    if nonce.is_some() {
+        incompat |= disk::INCOMPAT_WIDE_NONCE;
+    }
+    if opts.merkle {
+        incompat |= disk::INCOMPAT_MERKLE;
+    }
+    // Compressed contents always had a table
+    if opts.compression == disk::CompressionType::None {
+        incompat |= disk::INCOMPAT_BLOCK_TABLES;
+    }
+    if block_size {
+        incompat |= disk::INCOMPAT_BLOCK_SIZE;
+    }
+    let mut compat = if opts.subtree_sizes {
+        disk::COMPAT_SUBTREE_SIZE
+    } else {
+        0
+    };
+    if opts.hash_index_min_entries.is_some() {
+        compat |= disk::COMPAT_HASH_INDEX;
+    }
+    if opts.xattrs {
+        compat |= disk::COMPAT_XATTR;
+    }
+    if opts.checksums {
+        compat |= disk::COMPAT_CRC32;
+    }
+    if opts.key_salt.is_some() {
+        compat |= disk::COMPAT_KEY_SALT;
+    }
     out.rewind()?;
-    write_header(&mut out, root_inode, enc_type)
+    write_header(
+        &mut out,
+        disk::Header {
+            root_inode: root_inode.into(),
+            compression_type: opts.compression.into(),
+            encryption_type: enc_type.into(),
+            incompat: incompat.into(),
+            compat: compat.into(),
+            nonce: nonce.unwrap_or_default(),
+            uuid,
+            root_hash: root_hash.unwrap_or_default(),
+            key_salt: opts.key_salt.unwrap_or_default(),
+            block_size: if block_size {
+                (opts.encryption_block_size as u32).into()
+            } else {
+                0.into()
+            },
+            ..Default::default()
+        },
+    )?;
+    Ok(summary)
 }
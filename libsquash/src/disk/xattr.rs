@@ -0,0 +1,71 @@
+// Extended attributes of inodes
+//
+// The attributes of an inode are stored together, as the number of
+// attributes followed by, for each attribute, the length of its name,
+// the length of its value, the name (without NUL) and the value. The
+// counts and lengths are u32le.
+
+use crate::disk::{add_offset, ReadAt};
+use crate::error::Error;
+use crate::Result;
+
+use std::ffi::CString;
+
+const LEN_SIZE: u64 = std::mem::size_of::<u32>() as u64;
+
+/// Most bytes of names and values the attributes of an inode can have,
+/// well above the limits of Linux.
+pub const XATTRS_SIZE_MAX: u64 = 1 << 20;
+
+/// Serialized form of `attrs`.
+pub fn encode(attrs: &[(CString, Vec<u8>)]) -> Vec<u8> {
+    let mut res = (attrs.len() as u32).to_le_bytes().to_vec();
+    for (name, value) in attrs {
+        let name = name.as_bytes();
+        res.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        res.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        res.extend_from_slice(name);
+        res.extend_from_slice(value);
+    }
+    res
+}
+
+fn read_u32(file: &dyn ReadAt, offset: u64) -> Result<u64> {
+    let mut buf = [0; LEN_SIZE as usize];
+    file.read_exact_at(&mut buf, offset)?;
+    Ok(u32::from_le_bytes(buf) as u64)
+}
+
+/// The attributes stored at `offset`.
+pub fn decode(
+    file: &dyn ReadAt,
+    offset: u64,
+) -> Result<Vec<(CString, Vec<u8>)>> {
+    let count = read_u32(file, offset)?;
+    let mut pos = add_offset(offset, LEN_SIZE)?;
+    let mut total = 0;
+    let mut res = Vec::new();
+    for _ in 0..count {
+        let name_len = read_u32(file, pos)?;
+        let value_len = read_u32(file, add_offset(pos, LEN_SIZE)?)?;
+        // Empty names also bound the number of attributes
+        if name_len == 0 {
+            return Err(Error::Format("empty extended attribute name"));
+        }
+        total += name_len + value_len;
+        if total > XATTRS_SIZE_MAX {
+            return Err(Error::Bounds("extended attributes too large"));
+        }
+        pos = add_offset(pos, 2 * LEN_SIZE)?;
+        let mut name = vec![0; name_len as usize];
+        file.read_exact_at(&mut name, pos)?;
+        pos = add_offset(pos, name_len)?;
+        let mut value = vec![0; value_len as usize];
+        file.read_exact_at(&mut value, pos)?;
+        pos = add_offset(pos, value_len)?;
+        let name = CString::new(name)
+            .map_err(|_| Error::Format("NUL in extended attribute name"))?;
+        res.push((name, value));
+    }
+    Ok(res)
+}
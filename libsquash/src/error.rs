@@ -9,6 +9,14 @@ pub enum Error {
     Hex(#[from] hex::FromHexError),
     #[error("Crypto error")]
     Crypto(&'static str),
+    #[error("the image is encrypted and no key was provided")]
+    KeyRequired,
+    #[error("{cipher} requires a {expected}-byte key; got {found}")]
+    InvalidKeyLength {
+        cipher: &'static str,
+        expected: usize,
+        found: usize,
+    },
     #[error("Decompression error")]
     Compression(&'static str),
     #[error("Invalid value: {0}")]
@@ -17,4 +25,99 @@ pub enum Error {
     Bounds(&'static str),
     #[error("Invalid operation: {0}")]
     InvalidOperation(&'static str),
+    /// Resolving a path met more symlinks than can be followed, as a
+    /// loop of them does.
+    #[error("too many levels of symbolic links")]
+    LinkLoop,
+    #[error(
+        "image is version {found_major}.{found_minor}, this build supports \
+         {supported_major}.{supported_minor}"
+    )]
+    UnsupportedVersion {
+        found_major: u8,
+        found_minor: u8,
+        supported_major: u8,
+        supported_minor: u8,
+    },
+    /// Where in the image `source` happened, see `Error::inner`. Only
+    /// the place is displayed, `source` is the rest of the chain.
+    #[error("{}", describe_context(*.offset, .path.as_deref()))]
+    Context {
+        /// Offset of the inode being read
+        offset: Option<u64>,
+        /// Path being resolved
+        path: Option<Vec<u8>>,
+        source: Box<Error>,
+    },
+}
+
+fn describe_context(offset: Option<u64>, path: Option<&[u8]>) -> String {
+    let mut res = String::from("error");
+    if let Some(path) = path {
+        res += &format!(" in {:?}", String::from_utf8_lossy(path));
+    }
+    if let Some(offset) = offset {
+        res += &format!(" at offset {}", offset);
+    }
+    res
+}
+
+impl Error {
+    /// The error without the context around it.
+    pub fn inner(&self) -> &Error {
+        match self {
+            Error::Context { source, .. } => source.inner(),
+            e => e,
+        }
+    }
+
+    /// Like `inner`, by value.
+    pub fn into_inner(self) -> Error {
+        match self {
+            Error::Context { source, .. } => source.into_inner(),
+            e => e,
+        }
+    }
+
+    // Record that this happened reading the inode at `offset`
+    pub(crate) fn at_offset(self, offset: u64) -> Error {
+        match self {
+            Error::Context {
+                offset: None,
+                path,
+                source,
+            } => Error::Context {
+                offset: Some(offset),
+                path,
+                source,
+            },
+            e @ Error::Context { .. } => e,
+            e => Error::Context {
+                offset: Some(offset),
+                path: None,
+                source: Box::new(e),
+            },
+        }
+    }
+
+    // Record that this happened resolving `path`
+    pub(crate) fn in_path(self, path: &[u8]) -> Error {
+        match self {
+            Error::Context {
+                offset,
+                path: None,
+                source,
+            } => Error::Context {
+                offset,
+                path: Some(path.to_vec()),
+                source,
+            },
+            e @ Error::Context { .. } => e,
+            e => Error::Context {
+                offset: None,
+                path: Some(path.to_vec()),
+                source: Box::new(e),
+            },
+        }
+    }
 }
@@ -0,0 +1,359 @@
+// Extraction of image contents to a folder
+
+use crate::error::Error;
+use crate::fs;
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::{CString, OsStr};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+type Result<T> = std::result::Result<T, Error>;
+
+// Size of the reads of file contents, large enough to cover a few
+// compressed blocks at once
+const BUF_SIZE: usize = 1 << 20;
+
+/// What to do when an entry to extract already exists in the target.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum OverwritePolicy {
+    /// Replace existing files and symlinks. Existing directories are
+    /// kept and extracted into.
+    Overwrite,
+    /// Leave existing entries untouched. Existing directories are
+    /// still extracted into.
+    Skip,
+    /// Fail on the first existing entry.
+    #[default]
+    Error,
+}
+
+/// Options controlling how an image is extracted.
+#[derive(Clone, Debug, Default)]
+pub struct ExtractOptions {
+    pub overwrite: OverwritePolicy,
+    /// Stop with `Error::InvalidOperation("cancelled")` once this is
+    /// set. It is checked between entries and between chunks of files.
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// Restore the owner of entries. This is only attempted when
+    /// running as root, see `ExtractSummary::unowned`.
+    pub preserve_owner: bool,
+    /// Ranges of user ids to map the stored owners through with
+    /// `preserve_owner`, as (id in the image, id on the host, count)
+    /// like newuidmap takes them. Ids are kept as is when empty.
+    pub uid_map: Vec<(u32, u32, u32)>,
+    /// Ranges of group ids, like `uid_map`.
+    pub gid_map: Vec<(u32, u32, u32)>,
+    /// Id given to owners outside of `uid_map` or `gid_map`, like the
+    /// overflow id 65534 of user namespaces. Without it those are an
+    /// error.
+    pub unmapped_id: Option<u32>,
+}
+
+/// Counts of what was extracted.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct ExtractSummary {
+    pub files: u64,
+    pub dirs: u64,
+    pub symlinks: u64,
+    /// Entries left untouched because they already existed
+    pub skipped: u64,
+    /// Entries whose owner was not restored because we are not root
+    pub unowned: u64,
+    /// Files created as hard links to a file extracted before
+    pub hardlinks: u64,
+    /// Devices, FIFOs and sockets
+    pub specials: u64,
+    /// Extended attributes that could not be set, because the target
+    /// doesn't support them or we are not allowed to
+    pub unset_xattrs: u64,
+}
+
+enum Action {
+    Create,
+    Reuse,
+    Skip,
+}
+
+fn prepare(path: &Path, is_dir: bool, opts: &ExtractOptions) -> Result<Action> {
+    let meta = match std::fs::symlink_metadata(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Ok(Action::Create)
+        }
+        Err(e) => return Err(e.into()),
+        Ok(m) => m,
+    };
+    match opts.overwrite {
+        OverwritePolicy::Error => {
+            Err(io::Error::from(io::ErrorKind::AlreadyExists).into())
+        }
+        _ if is_dir && meta.is_dir() => Ok(Action::Reuse),
+        OverwritePolicy::Skip => Ok(Action::Skip),
+        OverwritePolicy::Overwrite => {
+            if meta.is_dir() {
+                return Err(Error::InvalidOperation(
+                    "cannot overwrite a directory",
+                ));
+            }
+            std::fs::remove_file(path)?;
+            Ok(Action::Create)
+        }
+    }
+}
+
+fn set_mode(path: &Path, mode: Option<u32>) -> Result<()> {
+    if let Some(mode) = mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
+    Ok(())
+}
+
+fn set_modified(file: &std::fs::File, md: &fs::Metadata) -> Result<()> {
+    if let Ok(time) = md.modified() {
+        file.set_modified(time)?;
+    }
+    Ok(())
+}
+
+// `id` mapped through the ranges of `map`, if it is in one
+fn map_id(id: u32, map: &[(u32, u32, u32)]) -> Option<u32> {
+    if map.is_empty() {
+        return Some(id);
+    }
+    map.iter().find_map(|&(inner, outer, count)| {
+        let delta = id.checked_sub(inner)?;
+        (delta < count).then(|| outer.checked_add(delta)).flatten()
+    })
+}
+
+fn set_owner(
+    path: &Path,
+    md: &fs::Metadata,
+    opts: &ExtractOptions,
+    summary: &mut ExtractSummary,
+) -> Result<()> {
+    let (Some(uid), Some(gid)) = (md.uid(), md.gid()) else {
+        return Ok(());
+    };
+    if !opts.preserve_owner {
+        return Ok(());
+    }
+    // Before mapping, ids that can't be mapped don't matter if the
+    // owner isn't restored anyway
+    // SAFETY: geteuid can't fail and has no side effects
+    if unsafe { libc::geteuid() } != 0 {
+        summary.unowned += 1;
+        return Ok(());
+    }
+    let uid = map_id(uid, &opts.uid_map)
+        .or(opts.unmapped_id)
+        .ok_or(Error::InvalidOperation("uid is not in uid_map"))?;
+    let gid = map_id(gid, &opts.gid_map)
+        .or(opts.unmapped_id)
+        .ok_or(Error::InvalidOperation("gid is not in gid_map"))?;
+    std::os::unix::fs::lchown(path, Some(uid), Some(gid))?;
+    Ok(())
+}
+
+fn set_xattrs(
+    path: &Path,
+    attrs: &[(CString, Vec<u8>)],
+    summary: &mut ExtractSummary,
+) -> Result<()> {
+    if attrs.is_empty() {
+        return Ok(());
+    }
+    let cpath = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| Error::InvalidOperation("path contains a NUL byte"))?;
+    for (name, value) in attrs {
+        // SAFETY: the strings are NUL terminated and the value is as
+        // long as the size given with it
+        let res = unsafe {
+            libc::lsetxattr(
+                cpath.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr() as *const libc::c_void,
+                value.len(),
+                0,
+            )
+        };
+        if res != 0 {
+            let e = io::Error::last_os_error();
+            match e.raw_os_error() {
+                Some(libc::EPERM | libc::EACCES | libc::ENOTSUP) => {
+                    summary.unset_xattrs += 1
+                }
+                _ => return Err(e.into()),
+            }
+        }
+    }
+    Ok(())
+}
+
+// Devices can only be created by root
+fn make_special(path: &Path, s: &fs::Special) -> Result<()> {
+    let ft = s.file_type();
+    let kind = if ft.is_char_device() {
+        libc::S_IFCHR
+    } else if ft.is_block_device() {
+        libc::S_IFBLK
+    } else if ft.is_fifo() {
+        libc::S_IFIFO
+    } else {
+        libc::S_IFSOCK
+    };
+    let dev = s.rdev().map_or(0, |(major, minor)| {
+        libc::makedev(major as u32, minor as u32)
+    });
+    let cpath = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| Error::InvalidOperation("path contains a NUL byte"))?;
+    // SAFETY: cpath is a valid NUL terminated string
+    if unsafe { libc::mknod(cpath.as_ptr(), kind | 0o600, dev) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+pub fn extract<P: AsRef<Path>>(
+    dir: &fs::Directory,
+    targ: P,
+    opts: &ExtractOptions,
+    summary: &mut ExtractSummary,
+) -> Result<()> {
+    let mut state = State::default();
+    extract_dir(dir, targ.as_ref(), opts, summary, &mut state)
+}
+
+/// Extract what is at `path` in `fs` to `target`, which must be an
+/// existing directory. The contents of a directory are extracted like
+/// with `extract`, anything else is extracted in `target` under its
+/// name. A symlink at the end of `path` is extracted as a symlink.
+pub fn extract_path<P: AsRef<Path>>(
+    fs: &fs::FS,
+    path: &[u8],
+    targ: P,
+    opts: &ExtractOptions,
+    summary: &mut ExtractSummary,
+) -> Result<()> {
+    let mut state = State::default();
+    let item = fs
+        .resolve_nofollow(path)?
+        .ok_or(Error::InvalidOperation("path not found"))?;
+    if let fs::FSItem::Directory(d) = item {
+        return extract_dir(&d, targ.as_ref(), opts, summary, &mut state);
+    }
+    let name = path
+        .split(|&c| c == b'/')
+        .rfind(|c| !c.is_empty())
+        .filter(|&name| name != b"." && name != b"..")
+        .ok_or(Error::InvalidOperation("path has no entry"))?;
+    let subp = targ.as_ref().join(OsStr::from_bytes(name));
+    // Nothing else is extracted, so there are no hard links to track
+    extract_entry(item, 0, subp, opts, summary, &mut state)
+}
+
+#[derive(Default)]
+struct State {
+    // Inode of the files extracted so far and their path
+    links: HashMap<u64, PathBuf>,
+    // Inode of the directories met so far, to stop on cycles
+    dirs: HashSet<u64>,
+    // Reused for the contents of every file
+    buf: Vec<u8>,
+}
+
+fn extract_dir(
+    dir: &fs::Directory,
+    target: &Path,
+    opts: &ExtractOptions,
+    summary: &mut ExtractSummary,
+    state: &mut State,
+) -> Result<()> {
+    for e in dir.iter() {
+        fs::check_cancel(opts.cancel.as_deref())?;
+        let dent = e?;
+        let subp = target.join(OsStr::from_bytes(dent.file_name()?.as_bytes()));
+        extract_entry(dent.item()?, dent.ino(), subp, opts, summary, state)?;
+    }
+    Ok(())
+}
+
+// Extract `item`, whose inode number is `ino`, to `subp`
+fn extract_entry(
+    item: fs::FSItem,
+    ino: u64,
+    subp: PathBuf,
+    opts: &ExtractOptions,
+    summary: &mut ExtractSummary,
+    state: &mut State,
+) -> Result<()> {
+    let is_dir = matches!(item, fs::FSItem::Directory(_));
+    let action = prepare(&subp, is_dir, opts)?;
+    if let Action::Skip = action {
+        summary.skipped += 1;
+        return Ok(());
+    }
+    match item {
+        fs::FSItem::File(_) if state.links.contains_key(&ino) => {
+            std::fs::hard_link(&state.links[&ino], &subp)?;
+            summary.hardlinks += 1;
+        }
+        fs::FSItem::File(f) => {
+            let mut t = std::fs::File::create(&subp)?;
+            if state.buf.is_empty() {
+                state.buf = vec![0; BUF_SIZE];
+            }
+            let cancel = opts.cancel.as_deref();
+            f.read_range_in(0..f.size(), &mut t, cancel, &mut state.buf)?;
+            let md = f.metadata()?;
+            set_modified(&t, &md)?;
+            // Before the mode, which could make the file read-only
+            set_xattrs(&subp, &f.xattrs()?, summary)?;
+            // Before the mode since chown clears setuid bits
+            set_owner(&subp, &md, opts, summary)?;
+            set_mode(&subp, md.mode())?;
+            state.links.insert(ino, subp);
+            summary.files += 1;
+        }
+        fs::FSItem::Directory(d) => {
+            if !state.dirs.insert(ino) {
+                return Err(Error::Format("directory cycle"));
+            }
+            if let Action::Create = action {
+                std::fs::create_dir(&subp)?;
+            }
+            extract_dir(&d, &subp, opts, summary, state)?;
+            // Only now in case the directory is read-only
+            if let Action::Create = action {
+                let md = d.metadata()?;
+                set_modified(&std::fs::File::open(&subp)?, &md)?;
+                set_xattrs(&subp, &d.xattrs()?, summary)?;
+                set_owner(&subp, &md, opts, summary)?;
+                set_mode(&subp, md.mode())?;
+            }
+            summary.dirs += 1;
+        }
+        fs::FSItem::Symlink(s) => {
+            std::os::unix::fs::symlink(
+                OsStr::from_bytes(s.get_link()?.as_slice()),
+                &subp,
+            )?;
+            set_xattrs(&subp, &s.xattrs()?, summary)?;
+            set_owner(&subp, &s.metadata()?, opts, summary)?;
+            summary.symlinks += 1;
+        }
+        fs::FSItem::Special(s) => {
+            make_special(&subp, &s)?;
+            set_xattrs(&subp, &s.xattrs()?, summary)?;
+            let md = s.metadata()?;
+            set_owner(&subp, &md, opts, summary)?;
+            set_mode(&subp, md.mode())?;
+            summary.specials += 1;
+        }
+    }
+    Ok(())
+}
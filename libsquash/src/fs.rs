@@ -1,29 +1,59 @@
 // std::fs-like interface (read-only of course)
 
 use crate::disk;
-use crate::disk::Key;
+use crate::disk::crc32::Crc32;
+use crate::disk::{ImageHeader, Key};
 use crate::error::Error;
+use crate::glob;
 
 use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
 use std::ffi::CString;
 use std::io;
 use std::iter::Iterator;
+use std::ops::Range;
 use std::path;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 type Result<T> = std::result::Result<T, Error>;
 
 // This is relatively low because we deal with it by recursion and
 // I don't want to blow the stack.
-const LINK_LOOP_MAX: u16 = 100;
-// Max length of a symlink target
-const LINK_TARGET_MAX: usize = 1024;
+pub(crate) const LINK_LOOP_MAX: u16 = 100;
+// How much of a file is read to detect its type
+const SNIFF_SIZE: u64 = 8192;
+// Size of the reads done when going through a whole file
+const CHUNK_SIZE: usize = 65536;
+// Size of the buffer of a FileReader
+const READER_BUF_SIZE: usize = 8192;
+
+// Fail if `cancel` is set
+pub(crate) fn check_cancel(cancel: Option<&AtomicBool>) -> Result<()> {
+    match cancel {
+        Some(c) if c.load(AtomicOrdering::Relaxed) => {
+            Err(Error::InvalidOperation("cancelled"))
+        }
+        _ => Ok(()),
+    }
+}
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub struct FileType {
     ty: disk::InodeType,
 }
 
+/// Metadata about an entry, like std::fs::Metadata.
+#[derive(Copy, Clone, Debug)]
+pub struct Metadata {
+    ty: FileType,
+    size: u64,
+    mode: Option<u32>,
+    mtime: Option<u64>,
+    owner: Option<(u32, u32)>,
+}
+
 #[derive(Clone)]
 pub struct DirEntry {
     img: Arc<disk::Image>,
@@ -41,6 +71,25 @@ pub struct File {
     img: Arc<disk::Image>,
     inode: disk::Inode,
     pos: u64,
+    // With FsOptions::verify_checksums, for files that have one
+    check: Option<CrcCheck>,
+}
+
+// Checksum of a file being read in order, see FsOptions::verify_checksums
+struct CrcCheck {
+    expected: u32,
+    // Offset of the read that continues the check, None after reads out
+    // of order until the file is read from the start again
+    state: Mutex<Option<(u64, Crc32)>>,
+}
+
+/// Buffered reader over a file, see `File::reader`.
+pub struct FileReader {
+    file: File,
+    buf: Box<[u8]>,
+    // Consumed and filled part of buf
+    start: usize,
+    end: usize,
 }
 
 #[derive(Clone)]
@@ -49,10 +98,18 @@ pub struct Symlink {
     inode: disk::Inode,
 }
 
+/// A character or block device, FIFO or socket.
+#[derive(Clone)]
+pub struct Special {
+    img: Arc<disk::Image>,
+    inode: disk::Inode,
+}
+
 pub enum FSItem {
     File(File),
     Directory(Directory),
     Symlink(Symlink),
+    Special(Special),
 }
 
 fn new_fsitem(img: Arc<disk::Image>, inode: disk::Inode) -> Result<FSItem> {
@@ -62,18 +119,82 @@ fn new_fsitem(img: Arc<disk::Image>, inode: disk::Inode) -> Result<FSItem> {
             FSItem::Directory(Directory::new(inode, img))
         }
         disk::InodeType::Symlink => FSItem::Symlink(Symlink::new(inode, img)),
+        _ => FSItem::Special(Special::new(inode, img)),
     })
 }
 
+impl FSItem {
+    pub fn metadata(&self) -> Result<Metadata> {
+        match self {
+            FSItem::File(f) => f.metadata(),
+            FSItem::Directory(d) => d.metadata(),
+            FSItem::Symlink(s) => s.metadata(),
+            FSItem::Special(s) => s.metadata(),
+        }
+    }
+}
+
 pub struct ReadDir {
     dir: Directory,
     pos: u64,
+    // One past the last entry for reverse iteration
+    end: u64,
+}
+
+/// Depth-first iterator over a directory tree, see `Directory::walk`.
+pub struct Walk {
+    stack: Vec<(Vec<u8>, ReadDir)>,
+    // Inodes of the directories met, to stop on cycles
+    seen: HashSet<u64>,
+}
+
+/// Inodes reachable from the root, see `FS::walk_reachable_inodes`.
+pub struct ReachableInodes {
+    // Returned before walking
+    root: Option<(u64, FileType, u64)>,
+    walk: Walk,
+    seen: HashSet<u64>,
+}
+
+/// Options for opening an image, see `FS::open_with`.
+#[derive(Clone, Debug)]
+pub struct FsOptions {
+    /// Longest symlink target to read, longer ones are an
+    /// Error::Bounds. It can't be more than LINK_TARGET_HARD_MAX.
+    pub link_target_max: usize,
+    /// Check the CRC-32 of files written with WriteOptions::checksums
+    /// when they are read in order up to their end, or whole by
+    /// extraction, `read_range`, `digest` or `content_eq`. A mismatch
+    /// makes that last read an Error::Format. Reads out of order are not
+    /// checked until the file is read from the start again.
+    pub verify_checksums: bool,
+    /// How many inodes, and as many directory entries, are kept in
+    /// memory once read so that lookups in the same directories read
+    /// the image less. 0 disables caching.
+    pub cache_capacity: usize,
+}
+
+impl Default for FsOptions {
+    fn default() -> Self {
+        FsOptions {
+            link_target_max: disk::LINK_TARGET_MAX,
+            verify_checksums: false,
+            cache_capacity: disk::CACHE_CAPACITY,
+        }
+    }
 }
 
 pub struct FS {
     img: Arc<disk::Image>,
 }
 
+/// An image opened with `FS::open_lazy`, only read when first used.
+pub struct LazyFS {
+    file: Arc<dyn disk::ReadAt>,
+    key: Option<Vec<u8>>,
+    fs: OnceLock<FS>,
+}
+
 impl FileType {
     pub fn is_dir(&self) -> bool {
         self.ty == disk::InodeType::Directory
@@ -84,9 +205,89 @@ impl FileType {
     pub fn is_symlink(&self) -> bool {
         self.ty == disk::InodeType::Symlink
     }
+    pub fn is_char_device(&self) -> bool {
+        self.ty == disk::InodeType::CharDevice
+    }
+    pub fn is_block_device(&self) -> bool {
+        self.ty == disk::InodeType::BlockDevice
+    }
+    pub fn is_fifo(&self) -> bool {
+        self.ty == disk::InodeType::Fifo
+    }
+    pub fn is_socket(&self) -> bool {
+        self.ty == disk::InodeType::Socket
+    }
+}
+
+impl Metadata {
+    fn new(img: &disk::Image, inode: &disk::Inode) -> Result<Self> {
+        let ty = inode.inode_type()?;
+        Ok(Metadata {
+            ty: FileType { ty },
+            // The size of devices is their minor number
+            size: if ty.is_special() { 0 } else { inode.size() },
+            mode: img.mode(inode),
+            mtime: img.mtime(inode),
+            owner: img.owner(inode),
+        })
+    }
+
+    pub fn file_type(&self) -> FileType {
+        self.ty
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.ty.is_dir()
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.ty.is_file()
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.ty.is_symlink()
+    }
+
+    /// Size of the contents for files, of the target for symlinks and
+    /// of the entry table for directories. It is 0 for other types.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Permission bits (as in `st_mode & 0o7777`), None for images
+    /// that don't store them. Readers that predate them ignore them,
+    /// so they can't be relied on to restrict access.
+    pub fn mode(&self) -> Option<u32> {
+        self.mode
+    }
+
+    /// Owner user id, None for images that don't store it.
+    pub fn uid(&self) -> Option<u32> {
+        self.owner.map(|o| o.0)
+    }
+
+    /// Owner group id, None for images that don't store it.
+    pub fn gid(&self) -> Option<u32> {
+        self.owner.map(|o| o.1)
+    }
+
+    /// Modification time, to the second. Like std::fs::Metadata, this
+    /// is an error for images that don't store it.
+    pub fn modified(&self) -> Result<SystemTime> {
+        match self.mtime {
+            Some(t) => Ok(UNIX_EPOCH + Duration::from_secs(t)),
+            None => {
+                Err(Error::InvalidOperation("modification time is not stored"))
+            }
+        }
+    }
 }
 
 impl DirEntry {
+    pub fn metadata(&self) -> Result<Metadata> {
+        Metadata::new(self.img.as_ref(), &self.ent.inode(self.img.as_ref())?)
+    }
+
     pub fn file_type(&self) -> Result<FileType> {
         Ok(FileType {
             ty: self.ent.inode(self.img.as_ref())?.inode_type()?,
@@ -97,29 +298,113 @@ impl DirEntry {
         self.ent.name(self.img.as_ref())
     }
 
+    /// Inode number of the entry, like DirEntryExt::ino. Entries with
+    /// the same number are hard links to each other.
+    pub fn ino(&self) -> u64 {
+        self.ent.inode_offset()
+    }
+
     pub fn item(&self) -> Result<FSItem> {
         let inode = self.ent.inode(self.img.as_ref())?;
-        Ok(new_fsitem(self.img.clone(), inode)?)
+        new_fsitem(self.img.clone(), inode)
     }
 }
 
 impl Directory {
     fn new(inode: disk::Inode, img: Arc<disk::Image>) -> Self {
-        std::debug_assert!(
-            inode.inode_type().expect("") == disk::InodeType::Directory
-        );
-        Directory {
-            inode: inode,
-            img: img,
-        }
+        std::debug_assert!(matches!(
+            inode.inode_type(),
+            Ok(disk::InodeType::Directory)
+        ));
+        Directory { inode, img }
     }
 
     pub fn len(&self) -> u64 {
         self.inode.size() / std::mem::size_of::<disk::Dirent>() as u64
     }
 
+    pub fn metadata(&self) -> Result<Metadata> {
+        Metadata::new(self.img.as_ref(), &self.inode)
+    }
+
+    /// Extended attributes, see `File::xattrs`.
+    pub fn xattrs(&self) -> Result<Vec<(CString, Vec<u8>)>> {
+        self.img.xattrs(&self.inode)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Tells apart the non-empty directories of all open images
+    pub(crate) fn id(&self) -> (usize, u64) {
+        (Arc::as_ptr(&self.img) as usize, self.inode.data_offset())
+    }
+
+    /// Total size of the contents of the files below this directory.
+    ///
+    /// This is only available if the image was written with
+    /// `WriteOptions::subtree_sizes`.
+    pub fn subtree_size(&self) -> Result<Option<u64>> {
+        self.img.subtree_size(&self.inode)
+    }
+
     pub fn resolve<P: AsRef<[u8]>>(&self, path: P) -> Result<Option<FSItem>> {
-        resolve_dir(self.img.clone(), self, path)
+        resolve_dir(self.img.clone(), self, path, true)
+    }
+
+    /// Like `resolve` but a symlink at the end of `path` is returned
+    /// instead of what it points to, like lstat. Symlinks before it
+    /// and a symlink followed by a '/' are still followed.
+    pub fn resolve_nofollow<P: AsRef<[u8]>>(
+        &self,
+        path: P,
+    ) -> Result<Option<FSItem>> {
+        resolve_dir(self.img.clone(), self, path, false)
+    }
+
+    /// Resolve `path` to its entry in its parent directory, following
+    /// symlinks.
+    ///
+    /// This is cheaper than `resolve` when only the name or type is
+    /// needed. Paths without an entry, like "/" or "..", are an error.
+    pub fn resolve_entry<P: AsRef<[u8]>>(
+        &self,
+        path: P,
+    ) -> Result<Option<DirEntry>> {
+        let ent = resolve_entry_path(
+            self.img.as_ref(),
+            &self.inode,
+            path.as_ref(),
+            0,
+        )?;
+        Ok(ent.map(|ent| DirEntry {
+            ent,
+            img: self.img.clone(),
+        }))
+    }
+
+    /// The entry named `name` in this directory, found with a binary
+    /// search. Symlinks are not followed and names with a '/' or a NUL
+    /// are an `Error::InvalidOperation`.
+    pub fn get_by_name<N: AsRef<[u8]>>(
+        &self,
+        name: N,
+    ) -> Result<Option<DirEntry>> {
+        let name = name.as_ref();
+        if name.contains(&b'/') || name.contains(&0) {
+            return Err(Error::InvalidOperation("invalid entry name"));
+        }
+        self.lookup(name)
+    }
+
+    // The entry named `name`, without following symlinks
+    pub(crate) fn lookup(&self, name: &[u8]) -> Result<Option<DirEntry>> {
+        let ent = binary_search(self.img.as_ref(), &self.inode, name)?;
+        Ok(ent.map(|ent| DirEntry {
+            ent,
+            img: self.img.clone(),
+        }))
     }
 
     pub fn get(&self, pos: u64) -> Result<Option<DirEntry>> {
@@ -134,42 +419,357 @@ impl Directory {
     }
 
     pub fn iter(&self) -> ReadDir {
+        self.iter_from(0)
+    }
+
+    /// Iterate over the entries starting at position `pos`.
+    ///
+    /// Used with `ReadDir::position()` this allows resuming an
+    /// iteration later.
+    pub fn iter_from(&self, pos: u64) -> ReadDir {
         ReadDir {
             dir: self.clone(),
-            pos: 0,
+            pos,
+            end: self.len(),
+        }
+    }
+
+    /// Iterate over the entries from last to first.
+    pub fn iter_rev(&self) -> std::iter::Rev<ReadDir> {
+        self.iter().rev()
+    }
+
+    /// Iterate recursively over the entries below this directory.
+    ///
+    /// Each entry comes with its path relative to this directory and
+    /// directories are listed before their contents. A directory met
+    /// twice, which only a corrupt image can have, is an
+    /// `Error::Format("directory cycle")`.
+    pub fn walk(&self) -> Walk {
+        Walk {
+            stack: vec![(Vec::new(), self.iter())],
+            seen: HashSet::new(),
         }
     }
 }
 
 impl File {
     fn new(inode: disk::Inode, img: Arc<disk::Image>) -> Self {
-        std::debug_assert!(
-            inode.inode_type().expect("") == disk::InodeType::File
-        );
+        std::debug_assert!(matches!(
+            inode.inode_type(),
+            Ok(disk::InodeType::File)
+        ));
+        let check = img
+            .file_checksum(&inode)
+            .filter(|_| img.verify_checksums())
+            .map(|expected| CrcCheck {
+                expected,
+                state: Mutex::new(None),
+            });
         File {
-            inode: inode,
-            img: img,
+            inode,
+            img,
             pos: 0,
+            check,
         }
     }
 
+    /// Size of the contents of the file.
     pub fn size(&self) -> u64 {
         self.inode.size()
     }
 
+    pub fn metadata(&self) -> Result<Metadata> {
+        Metadata::new(self.img.as_ref(), &self.inode)
+    }
+
+    /// Extended attributes, as (name, value) pairs in the order they
+    /// were listed when writing. Images written without
+    /// WriteOptions::xattrs have none.
+    pub fn xattrs(&self) -> Result<Vec<(CString, Vec<u8>)>> {
+        self.img.xattrs(&self.inode)
+    }
+
+    pub fn modified(&self) -> Result<SystemTime> {
+        self.metadata()?.modified()
+    }
+
+    /// Root of the hash tree of the contents, for images written with
+    /// WriteOptions::merkle. Reads are checked against it.
+    pub fn root_hash(&self) -> Result<Option<[u8; 32]>> {
+        self.img.file_root_hash(&self.inode)
+    }
+
+    pub fn uid(&self) -> Option<u32> {
+        self.img.owner(&self.inode).map(|o| o.0)
+    }
+
+    pub fn gid(&self) -> Option<u32> {
+        self.img.owner(&self.inode).map(|o| o.1)
+    }
+
+    /// Size the contents of the file take in the image, which can be
+    /// smaller than `size()` if they are compressed.
+    pub fn compressed_size(&self) -> Result<u64> {
+        self.img.stored_size(&self.inode)
+    }
+
+    // Where the contents are in the image, see Image::data_extent
+    pub(crate) fn data_extent(&self) -> Result<Range<u64>> {
+        self.img.data_extent(&self.inode)
+    }
+
+    /// Read the contents at `offset`, like `FileExt::read_at`. This
+    /// returns how many bytes were read, which is less than asked for
+    /// only at the end of the file, and 0 past it.
     pub fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
-        self.inode.read_at(buf, offset, self.img.as_ref())
+        let sz = self.inode.read_at(buf, offset, self.img.as_ref())?;
+        if let Some(check) = &self.check {
+            check.update(offset, &buf[..sz], self.size())?;
+        }
+        Ok(sz)
     }
 
+    /// Fill `buf` with the contents at `offset`. Reads that don't fit
+    /// in the file are an `UnexpectedEof` error, as with
+    /// `FileExt::read_exact_at`.
     pub fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
-        self.inode.read_exact_at(buf, offset, self.img.as_ref())
+        self.inode.read_exact_at(buf, offset, self.img.as_ref())?;
+        if let Some(check) = &self.check {
+            check.update(offset, buf, self.size())?;
+        }
+        Ok(())
+    }
+
+    /// CRC-32 of the contents, for images written with
+    /// WriteOptions::checksums.
+    pub fn checksum(&self) -> Option<u32> {
+        self.img.file_checksum(&self.inode)
+    }
+
+    /// Read the whole file and check it against its CRC-32.
+    pub fn verify(&self) -> Result<bool> {
+        let expected = self
+            .checksum()
+            .ok_or(Error::InvalidOperation("file has no checksum"))?;
+        // A mismatch is the result here, not an error
+        let file = File {
+            check: None,
+            ..self.clone()
+        };
+        let mut crc = Crc32::new();
+        file.for_each_chunk(0..self.size(), None, |chunk| {
+            crc.update(chunk);
+            Ok(true)
+        })?;
+        Ok(crc.finish() == expected)
+    }
+
+    // Call `f` on successive chunks of `range` until it returns false.
+    // Returns false if it stopped early.
+    fn for_each_chunk<F>(
+        &self,
+        range: Range<u64>,
+        cancel: Option<&AtomicBool>,
+        f: F,
+    ) -> Result<bool>
+    where
+        F: FnMut(&[u8]) -> Result<bool>,
+    {
+        self.for_each_chunk_in(range, cancel, &mut vec![0; CHUNK_SIZE], f)
+    }
+
+    // Like for_each_chunk with chunks of the size of `buf`, aligned to
+    // it in the file so that reads line up with compressed blocks.
+    fn for_each_chunk_in<F>(
+        &self,
+        range: Range<u64>,
+        cancel: Option<&AtomicBool>,
+        buf: &mut [u8],
+        mut f: F,
+    ) -> Result<bool>
+    where
+        F: FnMut(&[u8]) -> Result<bool>,
+    {
+        if range.start > range.end || range.end > self.size() {
+            return Err(Error::Bounds("read past the end of the file"));
+        }
+        // Whole files are checked as File::read_at would
+        let mut crc = self
+            .check
+            .as_ref()
+            .filter(|_| range == (0..self.size()))
+            .map(|c| (c.expected, Crc32::new()));
+        let chunk = buf.len() as u64;
+        let mut off = range.start;
+        while off < range.end {
+            check_cancel(cancel)?;
+            let len = std::cmp::min(chunk - off % chunk, range.end - off);
+            let sz = self.inode.read_at(
+                &mut buf[..len as usize],
+                off,
+                self.img.as_ref(),
+            )?;
+            if sz == 0 {
+                return Err(
+                    io::Error::from(io::ErrorKind::UnexpectedEof).into()
+                );
+            }
+            if let Some((expected, crc)) = &mut crc {
+                crc.update(&buf[..sz]);
+                if off + sz as u64 == range.end && crc.finish() != *expected {
+                    return Err(Error::Format("file checksum mismatch"));
+                }
+            }
+            if !f(&buf[..sz])? {
+                return Ok(false);
+            }
+            off += sz as u64;
+        }
+        Ok(true)
+    }
+
+    /// Copy the bytes of `range` to `out` in chunks, returning how many
+    /// were copied.
+    ///
+    /// If `cancel` gets set, this stops before the next chunk with
+    /// `Error::InvalidOperation("cancelled")`.
+    pub fn read_range<W: io::Write>(
+        &self,
+        range: Range<u64>,
+        out: &mut W,
+        cancel: Option<&AtomicBool>,
+    ) -> Result<u64> {
+        self.read_range_in(range, out, cancel, &mut vec![0; CHUNK_SIZE])
+    }
+
+    // Like read_range, reading in chunks of the size of `buf`
+    pub(crate) fn read_range_in<W: io::Write>(
+        &self,
+        range: Range<u64>,
+        out: &mut W,
+        cancel: Option<&AtomicBool>,
+        buf: &mut [u8],
+    ) -> Result<u64> {
+        let mut total = 0;
+        self.for_each_chunk_in(range, cancel, buf, |chunk| {
+            out.write_all(chunk)?;
+            total += chunk.len() as u64;
+            Ok(true)
+        })?;
+        Ok(total)
+    }
+
+    /// Buffered reader starting at the current position of the file.
+    ///
+    /// This implements Read, BufRead and Seek.
+    pub fn reader(self) -> FileReader {
+        FileReader {
+            file: self,
+            buf: vec![0; READER_BUF_SIZE].into_boxed_slice(),
+            start: 0,
+            end: 0,
+        }
+    }
+
+    /// BLAKE3 hash of the contents of the file.
+    ///
+    /// The file is read in chunks so memory use stays bounded.
+    pub fn digest(&self) -> Result<[u8; 32]> {
+        let mut hasher = blake3::Hasher::new();
+        self.for_each_chunk(0..self.size(), None, |chunk| {
+            hasher.update(chunk);
+            Ok(true)
+        })?;
+        Ok(*hasher.finalize().as_bytes())
+    }
+
+    /// Check if two files have the same contents.
+    ///
+    /// The files are compared chunk by chunk, stopping at the first
+    /// difference.
+    pub fn content_eq(&self, other: &File) -> Result<bool> {
+        if self.size() != other.size() {
+            return Ok(false);
+        }
+        let mut buf = vec![0; CHUNK_SIZE];
+        let mut off = 0;
+        self.for_each_chunk(0..self.size(), None, |chunk| {
+            let other_buf = &mut buf[..chunk.len()];
+            other.read_exact_at(other_buf, off)?;
+            off += chunk.len() as u64;
+            Ok(chunk == other_buf)
+        })
+    }
+
+    /// Check if the contents of the file are the same as what can be
+    /// read from `r`, which is read at most up to one byte past the
+    /// size of the file.
+    pub fn content_eq_reader<R: io::Read>(&self, mut r: R) -> Result<bool> {
+        let mut buf = vec![0; CHUNK_SIZE];
+        let same = self.for_each_chunk(0..self.size(), None, |chunk| {
+            let other_buf = &mut buf[..chunk.len()];
+            match r.read_exact(other_buf) {
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+                Err(e) => Err(e.into()),
+                Ok(()) => Ok(chunk == other_buf),
+            }
+        })?;
+        // The reader must not have more data
+        Ok(same && r.read(&mut [0])? == 0)
+    }
+
+    /// Guess the MIME type of the contents from its magic number.
+    ///
+    /// Only the first few KiB of the file are read. Returns None for
+    /// empty files and unknown content.
+    pub fn detect_type(&self) -> Result<Option<&'static str>> {
+        let mut buf = vec![0; std::cmp::min(self.size(), SNIFF_SIZE) as usize];
+        self.read_exact_at(&mut buf, 0)?;
+        Ok(infer::get(&buf).map(|t| t.mime_type()))
     }
 }
 
 fn convert_to_io_error(e: Error) -> io::Error {
     match e {
         Error::IO(ioe) => ioe,
-        _ => io::Error::new(io::ErrorKind::Other, e),
+        _ => io::Error::other(e),
+    }
+}
+
+impl CrcCheck {
+    fn update(&self, offset: u64, data: &[u8], size: u64) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if offset == 0 {
+            *state = Some((0, Crc32::new()));
+        }
+        let (next, crc) = match state.as_mut().filter(|s| s.0 == offset) {
+            Some(s) => s,
+            None => {
+                *state = None;
+                return Ok(());
+            }
+        };
+        crc.update(data);
+        *next += data.len() as u64;
+        if *next < size {
+            return Ok(());
+        }
+        let ok = crc.finish() == self.expected;
+        *state = None;
+        if !ok {
+            return Err(Error::Format("file checksum mismatch"));
+        }
+        Ok(())
+    }
+}
+
+impl Clone for CrcCheck {
+    fn clone(&self) -> Self {
+        CrcCheck {
+            expected: self.expected,
+            state: Mutex::new(*self.state.lock().unwrap()),
+        }
     }
 }
 
@@ -181,27 +781,19 @@ impl io::Read for File {
     }
 }
 
-fn checked_sub(a: u64, b: i64) -> Option<u64> {
-    (if b > 0 {
-        u64::checked_sub
-    } else {
-        u64::checked_add
-    })(a, b.abs() as u64)
-}
-
 impl io::Seek for File {
     fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
         match pos {
             io::SeekFrom::Start(s) => self.pos = s,
             io::SeekFrom::End(s) => {
-                if let Some(v) = checked_sub(self.size(), s) {
+                if let Some(v) = self.size().checked_add_signed(s) {
                     self.pos = v
                 } else {
                     return Err(io::Error::from(io::ErrorKind::InvalidInput));
                 }
             }
             io::SeekFrom::Current(s) => {
-                if let Some(v) = checked_sub(self.pos, s) {
+                if let Some(v) = self.pos.checked_add_signed(s) {
                     self.pos = v
                 } else {
                     return Err(io::Error::from(io::ErrorKind::InvalidInput));
@@ -216,62 +808,289 @@ impl io::Seek for File {
     }
 }
 
+impl FileReader {
+    pub fn into_inner(self) -> File {
+        self.file
+    }
+}
+
+impl io::Read for FileReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Skip our buffer for large reads
+        if self.start == self.end && buf.len() >= self.buf.len() {
+            return self.file.read(buf);
+        }
+        let avail = io::BufRead::fill_buf(self)?;
+        let sz = std::cmp::min(avail.len(), buf.len());
+        buf[..sz].copy_from_slice(&avail[..sz]);
+        io::BufRead::consume(self, sz);
+        Ok(sz)
+    }
+}
+
+impl io::BufRead for FileReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.start == self.end {
+            self.end = io::Read::read(&mut self.file, &mut self.buf)?;
+            self.start = 0;
+        }
+        Ok(&self.buf[self.start..self.end])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.start = std::cmp::min(self.start + amt, self.end);
+    }
+}
+
+impl io::Seek for FileReader {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        // The file is ahead of us by what is left in the buffer
+        if let io::SeekFrom::Current(_) = pos {
+            self.file.pos -= (self.end - self.start) as u64;
+        }
+        let res = self.file.seek(pos)?;
+        self.start = 0;
+        self.end = 0;
+        Ok(res)
+    }
+
+    fn stream_position(&mut self) -> io::Result<u64> {
+        Ok(self.file.pos - (self.end - self.start) as u64)
+    }
+}
+
 impl Symlink {
     fn new(inode: disk::Inode, img: Arc<disk::Image>) -> Self {
-        std::debug_assert!(
-            inode.inode_type().expect("") == disk::InodeType::Symlink
-        );
-        Symlink {
-            inode: inode,
-            img: img,
-        }
+        std::debug_assert!(matches!(
+            inode.inode_type(),
+            Ok(disk::InodeType::Symlink)
+        ));
+        Symlink { inode, img }
+    }
+
+    /// Length of the target, which `get_link` refuses to read if it
+    /// is over the limit of FsOptions::link_target_max.
+    pub fn target_len(&self) -> u64 {
+        self.inode.size()
     }
 
     pub fn get_link(&self) -> Result<Vec<u8>> {
         get_link(self.inode, self.img.as_ref())
     }
+
+    pub fn metadata(&self) -> Result<Metadata> {
+        Metadata::new(self.img.as_ref(), &self.inode)
+    }
+
+    /// Extended attributes, see `File::xattrs`.
+    pub fn xattrs(&self) -> Result<Vec<(CString, Vec<u8>)>> {
+        self.img.xattrs(&self.inode)
+    }
+}
+
+impl Special {
+    fn new(inode: disk::Inode, img: Arc<disk::Image>) -> Self {
+        std::debug_assert!(matches!(
+            inode.inode_type(),
+            Ok(t) if t.is_special()
+        ));
+        Special { inode, img }
+    }
+
+    pub fn file_type(&self) -> FileType {
+        FileType {
+            ty: self.inode.inode_type().unwrap(),
+        }
+    }
+
+    /// Major and minor numbers of devices, None for FIFOs and sockets.
+    pub fn rdev(&self) -> Option<(u64, u64)> {
+        let ft = self.file_type();
+        (ft.is_char_device() || ft.is_block_device()).then(|| self.inode.rdev())
+    }
+
+    pub fn metadata(&self) -> Result<Metadata> {
+        Metadata::new(self.img.as_ref(), &self.inode)
+    }
+
+    /// Extended attributes, see `File::xattrs`.
+    pub fn xattrs(&self) -> Result<Vec<(CString, Vec<u8>)>> {
+        self.img.xattrs(&self.inode)
+    }
+
+    pub(crate) fn hash(&self) -> [u8; 32] {
+        disk::merkle::special_hash(self.file_type().ty, self.inode.rdev())
+    }
 }
 
 fn get_link(inode: disk::Inode, img: &disk::Image) -> Result<Vec<u8>> {
-    let sz = inode.size() as usize;
-    if sz > LINK_TARGET_MAX {
+    if inode.size() > img.link_target_max() as u64 {
         return Err(Error::Bounds("link target too long"));
     }
-    let mut res = vec![0; sz];
+    let mut res = vec![0; inode.size() as usize];
     inode.read_at(res.as_mut_slice(), 0, img)?;
     Ok(res)
 }
 
+impl ReadDir {
+    /// Position of the next entry returned by `next()`, to pass to
+    /// `Directory::iter_from()`.
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+}
+
 impl Iterator for ReadDir {
     type Item = Result<DirEntry>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.end {
+            return None;
+        }
         let res = self.dir.get(self.pos);
         self.pos += 1;
-        match res {
-            Err(e) => Some(Err(e)),
-            Ok(None) => None,
-            Ok(Some(v)) => Some(Ok(v)),
-        }
+        res.transpose()
     }
 
     fn size_hint(&self) -> (usize, Option<usize>) {
-        let size = (self.dir.len() - self.pos) as usize;
+        let size = self.end.saturating_sub(self.pos) as usize;
         (size, Some(size))
     }
 }
 
+impl DoubleEndedIterator for ReadDir {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.end {
+            return None;
+        }
+        self.end -= 1;
+        self.dir.get(self.end).transpose()
+    }
+}
+
+impl ExactSizeIterator for ReadDir {}
+
+impl Walk {
+    fn next_entry(&mut self) -> Result<Option<(Vec<u8>, DirEntry)>> {
+        loop {
+            let (prefix, rd) = match self.stack.last_mut() {
+                None => return Ok(None),
+                Some(v) => v,
+            };
+            let ent = match rd.next() {
+                None => {
+                    self.stack.pop();
+                    continue;
+                }
+                Some(e) => e.map_err(|e| e.in_path(prefix))?,
+            };
+            let mut path = prefix.clone();
+            let name = ent.file_name().map_err(|e| e.in_path(prefix))?;
+            path.extend_from_slice(name.as_bytes());
+            if let FSItem::Directory(d) =
+                ent.item().map_err(|e| e.in_path(&path))?
+            {
+                if !self.seen.insert(ent.ino()) {
+                    return Err(Error::Format("directory cycle").in_path(&path));
+                }
+                let mut sub = path.clone();
+                sub.push(b'/');
+                self.stack.push((sub, d.iter()));
+            }
+            return Ok(Some((path, ent)));
+        }
+    }
+}
+
+impl Iterator for Walk {
+    type Item = Result<(Vec<u8>, DirEntry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry().transpose()
+    }
+}
+
+impl Iterator for ReachableInodes {
+    type Item = Result<(u64, FileType, u64)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(root) = self.root.take() {
+            return Some(Ok(root));
+        }
+        loop {
+            let ent = match self.walk.next()? {
+                Ok((_, ent)) => ent,
+                Err(e) => return Some(Err(e)),
+            };
+            // Hard links and shared symlinks are only returned once
+            if self.seen.insert(ent.ino()) {
+                return Some(
+                    ent.metadata().map(|m| (ent.ino(), m.file_type(), m.size)),
+                );
+            }
+        }
+    }
+}
+
 impl FS {
     pub fn open<F: disk::ReadAt + 'static>(f: F, key: Key) -> Result<FS> {
+        FS::open_with(f, key, &FsOptions::default())
+    }
+
+    // The image is never shared across threads, the Arc is only there
+    // so handles can share ownership of it
+    #[allow(clippy::arc_with_non_send_sync)]
+    pub fn open_with<F: disk::ReadAt + 'static>(
+        f: F,
+        key: Key,
+        opts: &FsOptions,
+    ) -> Result<FS> {
+        let mut img = disk::open_file(f, key)?;
+        img.set_link_target_max(opts.link_target_max)?;
+        img.set_verify_checksums(opts.verify_checksums);
+        img.set_cache_capacity(opts.cache_capacity);
+        Ok(FS { img: Arc::new(img) })
+    }
+
+    /// Open an image written with `write_image_split_with`, reading
+    /// the structure from `metadata` and file contents from `data`.
+    #[allow(clippy::arc_with_non_send_sync)]
+    pub fn open_split<F, D>(metadata: F, data: D, key: Key) -> Result<FS>
+    where
+        F: disk::ReadAt + 'static,
+        D: disk::ReadAt + 'static,
+    {
         Ok(FS {
-            img: Arc::new(disk::open_file(f, key)?),
+            img: Arc::new(disk::open_split(metadata, data, key)?),
         })
     }
 
+    /// Like `open` but without reading anything yet. The header is
+    /// read and checked on the first use of the image, which returns
+    /// the errors of `open`.
+    pub fn open_lazy<F: disk::ReadAt + 'static>(f: F, key: Key) -> LazyFS {
+        LazyFS {
+            file: Arc::new(f),
+            key: key.map(|k| k.to_vec()),
+            fs: OnceLock::new(),
+        }
+    }
+
     pub fn open_file<P: AsRef<path::Path>>(path: P, key: Key) -> Result<FS> {
         FS::open(std::fs::File::open(path)?, key)
     }
 
+    /// Open an image encrypted with a key derived from `passphrase`,
+    /// see `WriteOptions::key_salt`.
+    pub fn open_file_with_passphrase<P: AsRef<path::Path>>(
+        path: P,
+        passphrase: &[u8],
+    ) -> Result<FS> {
+        let file = std::fs::File::open(path)?;
+        let key = disk::read_header(&file)?.passphrase_key(passphrase)?;
+        FS::open(file, Some(&key))
+    }
+
     pub fn get_root(&self) -> Result<Directory> {
         let inode = self.img.root_inode()?;
         if inode.inode_type()? != disk::InodeType::Directory {
@@ -281,7 +1100,189 @@ impl FS {
     }
 
     pub fn resolve<P: AsRef<[u8]>>(&self, path: P) -> Result<Option<FSItem>> {
-        resolve_dir(self.img.clone(), &self.get_root()?, path)
+        resolve_dir(self.img.clone(), &self.get_root()?, path, true)
+    }
+
+    /// See `Directory::resolve_nofollow`.
+    pub fn resolve_nofollow<P: AsRef<[u8]>>(
+        &self,
+        path: P,
+    ) -> Result<Option<FSItem>> {
+        resolve_dir(self.img.clone(), &self.get_root()?, path, false)
+    }
+
+    /// Resolve each of `paths` once and keep the inodes and directory
+    /// entries met along the way in memory, so resolving them again
+    /// doesn't read the image. Symlink targets are still read.
+    ///
+    /// Paths that don't exist are skipped.
+    pub fn precache<P: AsRef<[u8]>>(&self, paths: &[P]) -> Result<()> {
+        let root = self.img.pin_root()?;
+        for p in paths {
+            resolve_path(self.img.as_ref(), &root, p, 0, true, true)?;
+        }
+        Ok(())
+    }
+
+    /// Header of the image, as read when it was opened.
+    pub fn header(&self) -> ImageHeader {
+        self.img.header()
+    }
+
+    /// Hash of the whole tree of the image, for images written with
+    /// WriteOptions::merkle. It covers names and contents, and
+    /// `verify` checks that the image matches it.
+    pub fn root_hash(&self) -> Option<[u8; 32]> {
+        self.img.header().root_hash()
+    }
+
+    /// Check the digest of the whole image stored with
+    /// WriteOptions::integrity, see `verify_integrity`. Unlike
+    /// `verify` this reads every byte of the image once, without
+    /// decoding anything.
+    pub fn verify_integrity(&self) -> Result<bool> {
+        self.img.verify_integrity()
+    }
+
+    /// Check that the contents of the file at `path`, following
+    /// symlinks, have the BLAKE3 hash `expected`, as from
+    /// `File::digest`. Only this file is read.
+    pub fn verify_file<P: AsRef<[u8]>>(
+        &self,
+        path: P,
+        expected: &[u8],
+    ) -> Result<bool> {
+        match self.resolve(path)? {
+            Some(FSItem::File(f)) => Ok(f.digest()? == expected),
+            Some(_) => Err(Error::InvalidOperation("path is not a file")),
+            None => Err(Error::InvalidOperation("path not found")),
+        }
+    }
+
+    /// The whole contents of the file at `path`, following symlinks.
+    pub fn read<P: AsRef<[u8]>>(&self, path: P) -> Result<Vec<u8>> {
+        match self.resolve(path)? {
+            Some(FSItem::File(f)) => {
+                // The size comes from the image, don't preallocate all of it
+                let cap = std::cmp::min(f.size(), CHUNK_SIZE as u64);
+                let mut buf = Vec::with_capacity(cap as usize);
+                f.read_range(0..f.size(), &mut buf, None)?;
+                Ok(buf)
+            }
+            Some(_) => Err(Error::InvalidOperation("path is not a file")),
+            None => Err(Error::InvalidOperation("path not found")),
+        }
+    }
+
+    /// The target of the symlink at `path`. Symlinks before the last
+    /// element are followed.
+    pub fn read_link<P: AsRef<[u8]>>(&self, path: P) -> Result<Vec<u8>> {
+        match self.resolve_nofollow(path)? {
+            Some(FSItem::Symlink(s)) => s.get_link(),
+            Some(_) => Err(Error::InvalidOperation("path is not a symlink")),
+            None => Err(Error::InvalidOperation("path not found")),
+        }
+    }
+
+    /// Paths of the entries that match the shell-style `pattern`,
+    /// relative to the root and in the order of `Directory::walk`.
+    ///
+    /// '*' and '?' match any bytes and any one byte of a name and "**"
+    /// matches any number of directories. Symlinks are not followed.
+    pub fn glob<P: AsRef<[u8]>>(&self, pattern: P) -> Result<Vec<Vec<u8>>> {
+        glob::glob(&self.get_root()?, pattern.as_ref())
+    }
+
+    /// Read bytes of the image at any offset, through the decryption
+    /// of normal reads, to inspect its structure. At most 1MiB is read
+    /// at once.
+    #[cfg(feature = "internals")]
+    pub fn read_raw(&self, offset: u64, len: usize) -> Result<Vec<u8>> {
+        self.img.read_raw(offset, len)
+    }
+
+    /// Every inode reachable from the root, once each, as its offset
+    /// in the image, its type and its size (as in Metadata::size). The
+    /// root comes first and the others in the order of `walk`.
+    ///
+    /// This is meant to inspect the structure of an image.
+    pub fn walk_reachable_inodes(&self) -> Result<ReachableInodes> {
+        let root = self.get_root()?;
+        let offset = self.header().root_inode();
+        let meta = root.metadata()?;
+        Ok(ReachableInodes {
+            root: Some((offset, meta.file_type(), meta.size)),
+            walk: root.walk(),
+            seen: HashSet::from([offset]),
+        })
+    }
+
+    /// UUID of the image, if it has one.
+    pub fn uuid(&self) -> Option<[u8; 16]> {
+        self.img.header().uuid()
+    }
+
+    /// Get the metadata of many paths at once, following symlinks.
+    ///
+    /// The results are in the same order as `paths` with None for
+    /// paths that don't exist. Parent directories shared by several
+    /// paths are only resolved once.
+    pub fn stat_many<P: AsRef<[u8]>>(
+        &self,
+        paths: &[P],
+    ) -> Result<Vec<Option<Metadata>>> {
+        let img = self.img.as_ref();
+        let root = self.get_root()?.inode;
+        let mut dirs: HashMap<&[u8], Option<disk::Inode>> = HashMap::new();
+        let mut res = Vec::with_capacity(paths.len());
+        for p in paths {
+            let p = p.as_ref();
+            let (parent, name) = match p.iter().rposition(|&c| c == b'/') {
+                Some(i) => (&p[..=i], &p[i + 1..]),
+                None => (&p[..0], p),
+            };
+            let dir = match dirs.get(parent) {
+                Some(d) => *d,
+                None => {
+                    let d = if parent.is_empty() {
+                        Some(root)
+                    } else {
+                        resolve_path(img, &root, parent, 0, true, false)?
+                    };
+                    dirs.insert(parent, d);
+                    d
+                }
+            };
+            let inode = match dir {
+                None => None,
+                Some(d) if name.is_empty() => Some(d),
+                Some(d) => resolve_path(img, &d, name, 0, true, false)?,
+            };
+            res.push(
+                inode.as_ref().map(|i| Metadata::new(img, i)).transpose()?,
+            );
+        }
+        Ok(res)
+    }
+}
+
+impl LazyFS {
+    /// The image, opened on the first call. Until it opens, each call
+    /// tries again and returns the error.
+    pub fn fs(&self) -> Result<&FS> {
+        if let Some(fs) = self.fs.get() {
+            return Ok(fs);
+        }
+        let fs = FS::open(self.file.clone(), self.key.as_deref())?;
+        Ok(self.fs.get_or_init(|| fs))
+    }
+
+    pub fn get_root(&self) -> Result<Directory> {
+        self.fs()?.get_root()
+    }
+
+    pub fn resolve<P: AsRef<[u8]>>(&self, path: P) -> Result<Option<FSItem>> {
+        self.fs()?.resolve(path)
     }
 }
 
@@ -289,55 +1290,92 @@ fn binary_search(
     img: &disk::Image,
     inode: &disk::Inode,
     name: &[u8],
-) -> Result<Option<disk::Inode>> {
+) -> Result<Option<disk::Dirent>> {
+    if let Some(ent) = img.pinned_entry(inode, name) {
+        return Ok(Some(ent));
+    }
+    if let Some(positions) = img.index_candidates(inode, name)? {
+        for pos in positions {
+            let (val, val_name) = inode.read_named_dirent(pos, img)?;
+            if val_name.as_bytes() == name {
+                return Ok(Some(val));
+            }
+        }
+        return Ok(None);
+    }
+    // Search in [min, max)
     let mut min = 0;
     let mut max = inode.size() / std::mem::size_of::<disk::Dirent>() as u64;
-    while min <= max {
+    let collation = img.collation(inode)?;
+    while min < max {
         let mid = ((max - min) / 2) + min;
-        let val = inode.read_dirent(mid, img)?;
-        match name.cmp(val.name(img)?.into_bytes().as_slice()) {
-            Ordering::Equal => return Ok(Some(val.inode(img)?)),
-            Ordering::Less => max = mid - 1,
+        let (val, val_name) = inode.read_named_dirent(mid, img)?;
+        match collation.compare(name, val_name.as_bytes()) {
+            Ordering::Equal => return Ok(Some(val)),
+            Ordering::Less => max = mid,
             Ordering::Greater => min = mid + 1,
         }
     }
     Ok(None)
 }
 
+// Without `follow`, a symlink as the last element is returned instead
+// of its target, like lstat. With `pin`, what is met along the way is
+// kept in memory.
 fn resolve_path<P: AsRef<[u8]>>(
     img: &disk::Image,
     root: &disk::Inode,
     path: P,
     count: u16,
+    follow: bool,
+    pin: bool,
 ) -> Result<Option<disk::Inode>> {
     if count > LINK_LOOP_MAX {
-        return Err(Error::Bounds("maximum symlink loop count encoutered"));
+        return Err(Error::LinkLoop);
     }
     let path: &[u8] = path.as_ref();
     let mut cur = *root;
+    // An empty path is the starting directory
+    if path.is_empty() {
+        return Ok(Some(cur));
+    }
     if path[0] == b'/' {
-        cur = img.root_inode()?;
+        cur = if pin {
+            img.pin_root()?
+        } else {
+            img.root_inode()?
+        };
     }
-    for elem in path.split(|c| c == &b'/') {
+    let mut elems = path.split(|c| c == &b'/').peekable();
+    while let Some(elem) = elems.next() {
         if cur.inode_type()? != disk::InodeType::Directory {
             return Err(Error::InvalidOperation(
                 "path traversal met non-directory",
             ));
         }
-        if elem.len() == 0 || elem == [b'.'] {
+        if elem.is_empty() || elem == [b'.'] {
             continue;
         }
         if elem == [b'.', b'.'] {
-            cur = cur.parent_inode(img)?;
+            cur = if pin {
+                img.pin_parent(&cur)?
+            } else {
+                cur.parent_inode(img)?
+            };
             continue;
         }
         let new = match binary_search(img, &cur, elem)? {
             None => return Ok(None),
-            Some(i) => i,
+            Some(d) if pin => img.pin_entry(&cur, elem, d)?,
+            Some(d) => d.inode(img)?,
         };
-        if new.inode_type()? == disk::InodeType::Symlink {
-            let link_path = get_link(new, &img)?;
-            cur = match resolve_path(img, &cur, link_path, count + 1)? {
+        // A trailing '/' leaves an empty last element so the link is
+        // followed
+        let last = elems.peek().is_none();
+        if new.inode_type()? == disk::InodeType::Symlink && (follow || !last) {
+            let link_path = get_link(new, img)?;
+            let res = resolve_path(img, &cur, link_path, count + 1, true, pin);
+            cur = match res? {
                 None => return Ok(None),
                 Some(i) => i,
             };
@@ -348,14 +1386,60 @@ fn resolve_path<P: AsRef<[u8]>>(
     Ok(Some(cur))
 }
 
+// Like resolve_path but return the entry of the last element in its
+// directory.
+fn resolve_entry_path(
+    img: &disk::Image,
+    root: &disk::Inode,
+    path: &[u8],
+    count: u16,
+) -> Result<Option<disk::Dirent>> {
+    if count > LINK_LOOP_MAX {
+        return Err(Error::LinkLoop);
+    }
+    let path = match path.iter().rposition(|&c| c != b'/') {
+        Some(i) => &path[..=i],
+        None => return Err(Error::InvalidOperation("path has no entry")),
+    };
+    let (parent, name) = match path.iter().rposition(|&c| c == b'/') {
+        Some(i) => (&path[..=i], &path[i + 1..]),
+        None => (&path[..0], path),
+    };
+    if name == b"." || name == b".." {
+        return Err(Error::InvalidOperation("path has no entry"));
+    }
+    let dir = match resolve_path(img, root, parent, count, true, false)? {
+        None => return Ok(None),
+        Some(d) => d,
+    };
+    if dir.inode_type()? != disk::InodeType::Directory {
+        return Err(Error::InvalidOperation(
+            "path traversal met non-directory",
+        ));
+    }
+    let ent = match binary_search(img, &dir, name)? {
+        None => return Ok(None),
+        Some(e) => e,
+    };
+    let inode = ent.inode(img)?;
+    if inode.inode_type()? == disk::InodeType::Symlink {
+        let link_path = get_link(inode, img)?;
+        return resolve_entry_path(img, &dir, &link_path, count + 1);
+    }
+    Ok(Some(ent))
+}
+
 fn resolve_dir<P: AsRef<[u8]>>(
     img: Arc<disk::Image>,
     root: &Directory,
     path: P,
+    follow: bool,
 ) -> Result<Option<FSItem>> {
-    let inode = resolve_path(img.as_ref(), &root.inode, path, 0)?;
+    let path = path.as_ref();
+    let inode = resolve_path(img.as_ref(), &root.inode, path, 0, follow, false)
+        .map_err(|e| e.in_path(path))?;
     match inode {
         None => Ok(None),
-        Some(i) => Ok(Some(new_fsitem(img, i)?)),
+        Some(i) => Ok(Some(new_fsitem(img, i).map_err(|e| e.in_path(path))?)),
     }
 }
@@ -0,0 +1,98 @@
+// Matching paths of an image against shell-style patterns
+
+use std::collections::HashSet;
+
+use crate::error::Error;
+use crate::fs::{Directory, FSItem};
+
+type Result<T> = std::result::Result<T, Error>;
+
+fn components(path: &[u8]) -> Vec<&[u8]> {
+    path.split(|&c| c == b'/')
+        .filter(|c| !c.is_empty())
+        .collect()
+}
+
+// Whether `name` matches `pat`, where '*' matches any bytes and '?' any
+// one byte
+fn match_name(pat: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // Where to resume after the last '*'
+    let mut star = None;
+    while n < name.len() {
+        match pat.get(p) {
+            Some(b'*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((sp, sn)) => {
+                    p = sp + 1;
+                    n = sn + 1;
+                    star = Some((sp, sn + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pat[p..].iter().all(|&c| c == b'*')
+}
+
+// Whether `path` matches `pat`, where "**" matches any number of
+// components. With `prefix`, whether a path below `path` could match.
+fn match_components(pat: &[&[u8]], path: &[&[u8]], prefix: bool) -> bool {
+    match pat.split_first() {
+        None => path.is_empty(),
+        Some((&b"**", rest)) => {
+            prefix
+                || (0..=path.len())
+                    .any(|i| match_components(rest, &path[i..], false))
+        }
+        Some((p, rest)) => match path.split_first() {
+            None => prefix,
+            Some((c, tail)) => {
+                match_name(p, c) && match_components(rest, tail, prefix)
+            }
+        },
+    }
+}
+
+/// Paths below `root` that match `pattern`, in the order of
+/// `Directory::walk`. Symlinks are not followed and, as in the walk, a
+/// directory met twice is an `Error::Format("directory cycle")`.
+pub(crate) fn glob(root: &Directory, pattern: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let pat = components(pattern);
+    let mut res = Vec::new();
+    let mut seen = HashSet::new();
+    let mut stack = vec![(Vec::new(), root.iter())];
+    while let Some((prefix, rd)) = stack.last_mut() {
+        let ent = match rd.next() {
+            None => {
+                stack.pop();
+                continue;
+            }
+            Some(e) => e?,
+        };
+        let mut path = prefix.clone();
+        path.extend_from_slice(ent.file_name()?.as_bytes());
+        let comps = components(&path);
+        if match_components(&pat, &comps, false) {
+            res.push(path.clone());
+        }
+        if !match_components(&pat, &comps, true) {
+            continue;
+        }
+        if let FSItem::Directory(d) = ent.item()? {
+            if !seen.insert(ent.ino()) {
+                return Err(Error::Format("directory cycle").in_path(&path));
+            }
+            path.push(b'/');
+            stack.push((path, d.iter()));
+        }
+    }
+    Ok(res)
+}
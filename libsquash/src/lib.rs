@@ -1,55 +1,132 @@
 #[macro_use]
 extern crate static_assertions;
 
-use std::ffi::OsStr;
 use std::io::Cursor;
-use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
 
+mod dedup;
 mod disk;
 pub mod error;
+mod extract;
 pub mod fs;
+mod glob;
+#[cfg(feature = "fuse")]
+mod mount;
+mod overlay;
+mod tar;
+mod verify;
 
-pub use disk::{CompressionType, EncryptionType, Key};
+#[cfg(test)]
+mod tests;
+
+pub use dedup::{dedup_report, DedupReport, ImageDedup};
+#[cfg(feature = "mmap")]
+pub use disk::MmapImage;
+pub use disk::{
+    derive_key, new_salt, probe_image, read_header, verify_integrity,
+    Collation, CompressionType, EncryptionType, ImageHeader, Key, ReadAt,
+    Requirement, CHACHA20_KEY_LEN, COMPAT_DIGEST, COMPAT_HASH_INDEX,
+    COMPAT_KEY_SALT, COMPAT_SUBTREE_SIZE, COMPAT_XATTR, INCOMPAT_BLOCK_SIZE,
+    INCOMPAT_BLOCK_TABLES, INCOMPAT_COLLATION, INCOMPAT_MERKLE, INCOMPAT_NONCE,
+    INCOMPAT_SPLIT_DATA, INCOMPAT_WIDE_NONCE, LINK_TARGET_HARD_MAX,
+    LINK_TARGET_MAX, NAME_MAX, NONCE_LEN, SALT_LEN, XCHACHA20_POLY1305_KEY_LEN,
+};
 pub use error::Error;
+pub use extract::{ExtractOptions, ExtractSummary, OverwritePolicy};
+#[cfg(feature = "fuse")]
+pub use mount::mount;
+pub use overlay::{
+    OverlayDir, OverlayEntry, OverlayFS, OverlayItem, OverlayWalk,
+    OPAQUE_MARKER, WHITEOUT_PREFIX,
+};
+pub use tar::{diff_against_tar, export_tar, Difference};
+pub use verify::{verify, VerifyReport};
 pub type Result<T> = std::result::Result<T, Error>;
 
-pub use disk::write::write_image;
+pub use disk::write::{
+    seal_image, write_image, write_image_from_tar, write_image_split_with,
+    write_image_with, ImageBuilder, MetadataUpdate, WriteOptions, WriteSummary,
+};
 
 pub fn write_image_file<P: AsRef<Path>, S: AsRef<Path>>(
     source: &P,
     file: &S,
     key: Key,
     enc_type: EncryptionType,
-) -> Result<()> {
-    let mut file = std::fs::File::create(file)?;
-    write_image(source, &mut file, key, enc_type)
+) -> Result<WriteSummary> {
+    write_image_file_with(source, file, key, enc_type, &WriteOptions::default())
 }
 
-fn extract<P: AsRef<Path>>(dir: &fs::Directory, targ: P) -> Result<()> {
-    let target: &Path = targ.as_ref();
-    for e in dir.iter() {
-        let dent = e?;
-        let subp =
-            target.join(OsStr::from_bytes(&dent.file_name()?.as_bytes()));
-        match dent.item()? {
-            fs::FSItem::File(ref mut f) => {
-                let mut t = std::fs::File::create(&subp)?;
-                std::io::copy(f, &mut t)?;
-            }
-            fs::FSItem::Directory(d) => {
-                std::fs::create_dir(&subp)?;
-                extract(&d, &subp)?;
-            }
-            fs::FSItem::Symlink(s) => {
-                std::os::unix::fs::symlink(
-                    OsStr::from_bytes(s.get_link()?.as_slice()),
-                    &subp,
-                )?;
-            }
-        }
+pub fn write_image_file_with<P: AsRef<Path>, S: AsRef<Path>>(
+    source: &P,
+    file: &S,
+    key: Key,
+    enc_type: EncryptionType,
+    opts: &WriteOptions,
+) -> Result<WriteSummary> {
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(file)?;
+    let inner = WriteOptions {
+        integrity: false,
+        ..opts.clone()
+    };
+    let readback = file.try_clone()?;
+    let summary = disk::write::write_image_readback(
+        source, &mut file, &readback, key, enc_type, &inner,
+    )?;
+    if opts.integrity {
+        seal_image(&mut file)?;
     }
-    Ok(())
+    Ok(summary)
+}
+
+/// Change the metadata of what is at `path` in the image file `image`
+/// in place, without rewriting anything else. A symlink at the end of
+/// `path` is changed itself and an empty path or "/" is the root. The
+/// digest of sealed images is updated too.
+///
+/// Encrypted images are refused: their inodes can't be rewritten
+/// without reusing keystream or tags. Images too old to store a field
+/// of `update` are an `Error::InvalidOperation`.
+pub fn set_metadata<P: AsRef<Path>, I: AsRef<[u8]>>(
+    image: &P,
+    path: I,
+    update: &MetadataUpdate,
+) -> Result<()> {
+    let header = read_header_file(image)?;
+    if header.encryption_type()? != EncryptionType::None {
+        return Err(Error::InvalidOperation(
+            "encrypted images can't be updated in place",
+        ));
+    }
+    let path = path.as_ref();
+    let offset = match path.iter().rposition(|&c| c != b'/') {
+        None => header.root_inode(),
+        Some(end) => {
+            let path = &path[..=end];
+            let (parent, name) = match path.iter().rposition(|&c| c == b'/') {
+                Some(i) => (&path[..i], &path[i + 1..]),
+                None => (&path[..0], path),
+            };
+            let fs = open_image_file(image, None)?;
+            let ent = match fs.resolve(parent)? {
+                Some(fs::FSItem::Directory(d)) if name != b".." => {
+                    d.get_by_name(name)?
+                }
+                _ => None,
+            };
+            ent.ok_or(Error::InvalidOperation("path not found"))?.ino()
+        }
+    };
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(image)?;
+    disk::write::update_inode(file, offset, update)
 }
 
 pub fn extract_image_file<P: AsRef<Path>, T: AsRef<Path>>(
@@ -57,8 +134,53 @@ pub fn extract_image_file<P: AsRef<Path>, T: AsRef<Path>>(
     target: &T,
     key: Key,
 ) -> Result<()> {
+    extract_image_file_with(image, target, key, &ExtractOptions::default())?;
+    Ok(())
+}
+
+pub fn extract_image_file_with<P: AsRef<Path>, T: AsRef<Path>>(
+    image: &P,
+    target: &T,
+    key: Key,
+    opts: &ExtractOptions,
+) -> Result<ExtractSummary> {
     let fs = open_image_file(image, key)?;
-    extract(&fs.get_root()?, target)
+    extract_fs(&fs, target, opts)
+}
+
+/// Extract only what is at `in_path` in the image, see
+/// `extract_subtree_with`.
+pub fn extract_subtree<P: AsRef<Path>, I: AsRef<[u8]>, T: AsRef<Path>>(
+    image: &P,
+    in_path: I,
+    target: &T,
+    key: Key,
+) -> Result<()> {
+    extract_subtree_with(
+        image,
+        in_path,
+        target,
+        key,
+        &ExtractOptions::default(),
+    )?;
+    Ok(())
+}
+
+/// Extract only what is at `in_path` in the image to `target`, which
+/// must be an existing directory. The contents of a directory are
+/// extracted in `target` and a file or symlink is extracted there
+/// under its name.
+pub fn extract_subtree_with<P: AsRef<Path>, I: AsRef<[u8]>, T: AsRef<Path>>(
+    image: &P,
+    in_path: I,
+    target: &T,
+    key: Key,
+    opts: &ExtractOptions,
+) -> Result<ExtractSummary> {
+    let fs = open_image_file(image, key)?;
+    let mut summary = ExtractSummary::default();
+    extract::extract_path(&fs, in_path.as_ref(), target, opts, &mut summary)?;
+    Ok(summary)
 }
 
 pub fn extract_image<T: AsRef<Path>>(
@@ -68,9 +190,31 @@ pub fn extract_image<T: AsRef<Path>>(
 ) -> Result<()> {
     let tmp = Cursor::new(image_data.to_vec());
     let fs = fs::FS::open(tmp, key)?;
-    extract(&fs.get_root()?, target)
+    extract_fs(&fs, target, &ExtractOptions::default())?;
+    Ok(())
+}
+
+/// Extract the whole contents of an opened image to `target`, which
+/// must be an existing directory.
+pub fn extract_fs<T: AsRef<Path>>(
+    fs: &fs::FS,
+    target: &T,
+    opts: &ExtractOptions,
+) -> Result<ExtractSummary> {
+    let mut summary = ExtractSummary::default();
+    extract::extract(&fs.get_root()?, target, opts, &mut summary)?;
+    Ok(summary)
 }
 
 pub fn open_image_file<P: AsRef<Path>>(img: P, key: Key) -> Result<fs::FS> {
     fs::FS::open_file(img, key)
 }
+
+pub fn read_header_file<P: AsRef<Path>>(img: P) -> Result<ImageHeader> {
+    read_header(&std::fs::File::open(img)?)
+}
+
+/// See `probe_image`.
+pub fn probe_image_file<P: AsRef<Path>>(img: P) -> Result<Vec<Requirement>> {
+    probe_image(&std::fs::File::open(img)?)
+}
@@ -0,0 +1,311 @@
+// Read-only FUSE filesystem backed by an image
+//
+// Inode numbers are the offsets of the inodes in the image, except for
+// the root which FUSE wants as FUSE_ROOT_ID. The items the kernel knows
+// about are kept until it forgets them, so that inode numbers don't
+// need to be turned back into inodes.
+
+use crate::error::Error;
+use crate::fs::{self, FSItem, FS};
+use crate::Result;
+
+use fuser::{
+    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData,
+    ReplyDirectory, ReplyEntry, Request, FUSE_ROOT_ID,
+};
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::os::raw::c_int;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+// Images never change
+const TTL: Duration = Duration::from_secs(3600);
+
+const BLOCK_SIZE: u32 = 4096;
+
+struct Node {
+    item: FSItem,
+    parent: u64,
+    // Lookups not yet forgotten by the kernel
+    lookups: u64,
+}
+
+pub(crate) struct ImageFs {
+    nodes: HashMap<u64, Node>,
+    // Owner of entries of images that don't store it
+    uid: u32,
+    gid: u32,
+}
+
+fn errno(e: Error) -> c_int {
+    match e.into_inner() {
+        Error::IO(e) => e.raw_os_error().unwrap_or(libc::EIO),
+        Error::LinkLoop => libc::ELOOP,
+        _ => libc::EIO,
+    }
+}
+
+fn file_type(ft: fs::FileType) -> FileType {
+    if ft.is_dir() {
+        FileType::Directory
+    } else if ft.is_file() {
+        FileType::RegularFile
+    } else if ft.is_symlink() {
+        FileType::Symlink
+    } else if ft.is_char_device() {
+        FileType::CharDevice
+    } else if ft.is_block_device() {
+        FileType::BlockDevice
+    } else if ft.is_fifo() {
+        FileType::NamedPipe
+    } else {
+        FileType::Socket
+    }
+}
+
+impl ImageFs {
+    pub fn new(fs: &FS) -> Result<Self> {
+        let root = Node {
+            item: FSItem::Directory(fs.get_root()?),
+            parent: FUSE_ROOT_ID,
+            lookups: 1,
+        };
+        Ok(ImageFs {
+            nodes: HashMap::from([(FUSE_ROOT_ID, root)]),
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+        })
+    }
+
+    fn node(&self, ino: u64) -> std::result::Result<&Node, c_int> {
+        self.nodes.get(&ino).ok_or(libc::ENOENT)
+    }
+
+    pub fn attr(
+        &self,
+        ino: u64,
+        item: &FSItem,
+    ) -> std::result::Result<FileAttr, c_int> {
+        let meta = item.metadata().map_err(errno)?;
+        let kind = file_type(meta.file_type());
+        let default_mode = match kind {
+            FileType::Directory => 0o755,
+            FileType::Symlink => 0o777,
+            _ => 0o644,
+        };
+        let rdev = match item {
+            FSItem::Special(s) => s.rdev().map_or(0, |(major, minor)| {
+                libc::makedev(major as u32, minor as u32)
+            }),
+            _ => 0,
+        };
+        let mtime = meta.modified().unwrap_or(UNIX_EPOCH);
+        Ok(FileAttr {
+            ino,
+            size: meta.size(),
+            blocks: meta.size().div_ceil(512),
+            atime: mtime,
+            mtime,
+            ctime: mtime,
+            crtime: mtime,
+            kind,
+            perm: meta.mode().unwrap_or(default_mode) as u16,
+            nlink: if kind == FileType::Directory { 2 } else { 1 },
+            uid: meta.uid().unwrap_or(self.uid),
+            gid: meta.gid().unwrap_or(self.gid),
+            rdev: rdev as u32,
+            blksize: BLOCK_SIZE,
+            flags: 0,
+        })
+    }
+
+    pub fn lookup(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+    ) -> std::result::Result<FileAttr, c_int> {
+        let dir = match &self.node(parent)?.item {
+            FSItem::Directory(d) => d,
+            _ => return Err(libc::ENOTDIR),
+        };
+        let ent = dir
+            .lookup(name.as_bytes())
+            .map_err(errno)?
+            .ok_or(libc::ENOENT)?;
+        let ino = ent.ino();
+        let item = ent.item().map_err(errno)?;
+        let attr = self.attr(ino, &item)?;
+        self.nodes
+            .entry(ino)
+            .or_insert(Node {
+                item,
+                parent,
+                lookups: 0,
+            })
+            .lookups += 1;
+        Ok(attr)
+    }
+
+    pub fn forget(&mut self, ino: u64, nlookup: u64) {
+        if ino == FUSE_ROOT_ID {
+            return;
+        }
+        if let Some(node) = self.nodes.get_mut(&ino) {
+            node.lookups = node.lookups.saturating_sub(nlookup);
+            if node.lookups == 0 {
+                self.nodes.remove(&ino);
+            }
+        }
+    }
+
+    pub fn getattr(&self, ino: u64) -> std::result::Result<FileAttr, c_int> {
+        self.attr(ino, &self.node(ino)?.item)
+    }
+
+    pub fn read(
+        &self,
+        ino: u64,
+        offset: i64,
+        size: u32,
+    ) -> std::result::Result<Vec<u8>, c_int> {
+        let file = match &self.node(ino)?.item {
+            FSItem::File(f) => f,
+            FSItem::Directory(_) => return Err(libc::EISDIR),
+            _ => return Err(libc::EINVAL),
+        };
+        let offset = u64::try_from(offset).map_err(|_| libc::EINVAL)?;
+        let mut buf = vec![0; size as usize];
+        let n = file.read_at(&mut buf, offset).map_err(errno)?;
+        buf.truncate(n);
+        Ok(buf)
+    }
+
+    pub fn readlink(&self, ino: u64) -> std::result::Result<Vec<u8>, c_int> {
+        match &self.node(ino)?.item {
+            FSItem::Symlink(s) => s.get_link().map_err(errno),
+            _ => Err(libc::EINVAL),
+        }
+    }
+
+    /// Pass the entries of the directory `ino` from `offset` to `add`,
+    /// as (inode, offset of the next entry, type, name), starting with
+    /// "." and "..", until it returns true.
+    pub fn readdir<F>(
+        &self,
+        ino: u64,
+        offset: i64,
+        mut add: F,
+    ) -> std::result::Result<(), c_int>
+    where
+        F: FnMut(u64, i64, FileType, &OsStr) -> bool,
+    {
+        let node = self.node(ino)?;
+        let dir = match &node.item {
+            FSItem::Directory(d) => d,
+            _ => return Err(libc::ENOTDIR),
+        };
+        let start = u64::try_from(offset).map_err(|_| libc::EINVAL)?;
+        let dots = [(ino, "."), (node.parent, "..")];
+        for (i, (ino, name)) in
+            dots.into_iter().enumerate().skip(start as usize)
+        {
+            if add(ino, i as i64 + 1, FileType::Directory, name.as_ref()) {
+                return Ok(());
+            }
+        }
+        let mut entries = dir.iter_from(start.saturating_sub(2));
+        while let Some(ent) = entries.next() {
+            let ent = ent.map_err(errno)?;
+            let kind = file_type(ent.file_type().map_err(errno)?);
+            let name = ent.file_name().map_err(errno)?;
+            let next = entries.position() as i64 + 2;
+            if add(ent.ino(), next, kind, OsStr::from_bytes(name.as_bytes())) {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Filesystem for ImageFs {
+    fn lookup(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        match ImageFs::lookup(self, parent, name) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn forget(&mut self, _req: &Request<'_>, ino: u64, nlookup: u64) {
+        ImageFs::forget(self, ino, nlookup)
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        match ImageFs::getattr(self, ino) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        match ImageFs::read(self, ino, offset, size) {
+            Ok(data) => reply.data(&data),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        match ImageFs::readlink(self, ino) {
+            Ok(target) => reply.data(&target),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        // add returns true once the buffer is full
+        let res =
+            ImageFs::readdir(self, ino, offset, |ino, next, kind, name| {
+                reply.add(ino, next, kind, name)
+            });
+        match res {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+}
+
+/// Mount `fs` read-only at `target` with FUSE until it is unmounted.
+pub fn mount<P: AsRef<Path>>(fs: &FS, target: P) -> Result<()> {
+    let options = [
+        MountOption::RO,
+        MountOption::FSName("squashfile".into()),
+        MountOption::DefaultPermissions,
+    ];
+    fuser::mount2(ImageFs::new(fs)?, target, &options)?;
+    Ok(())
+}
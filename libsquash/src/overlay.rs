@@ -0,0 +1,339 @@
+// Read-only merged view of several images
+//
+// Layers are stacked and an entry of an upper layer shadows the entry
+// of the same path in the layers below it, except that directories
+// present in several layers are merged. Deletions follow the OCI image
+// convention: an entry named ".wh.<name>" in a directory hides <name>
+// in the layers below, and a directory containing ".wh..wh..opq" hides
+// the contents of the same directory in the layers below. Whiteouts are
+// never part of the merged view.
+
+use crate::error::Error;
+use crate::fs::{self, DirEntry, Directory, FileType, Metadata, FS};
+
+use std::collections::{BTreeSet, HashSet};
+use std::sync::Arc;
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Prefix of the names of whiteout entries.
+pub const WHITEOUT_PREFIX: &[u8] = b".wh.";
+/// Name of the entry that makes its directory opaque.
+pub const OPAQUE_MARKER: &[u8] = b".wh..wh..opq";
+
+pub(crate) fn whiteout_name(name: &[u8]) -> Vec<u8> {
+    let mut res = WHITEOUT_PREFIX.to_vec();
+    res.extend_from_slice(name);
+    res
+}
+
+fn is_opaque(dir: &Directory) -> Result<bool> {
+    Ok(dir.lookup(OPAQUE_MARKER)?.is_some())
+}
+
+/// A merged view of a stack of images.
+pub struct OverlayFS {
+    // Top first
+    layers: Vec<Arc<FS>>,
+}
+
+/// A directory of the merged view, made of the directories at its path
+/// in each layer that contributes to it.
+#[derive(Clone)]
+pub struct OverlayDir {
+    // Top first, never empty
+    dirs: Vec<Directory>,
+}
+
+/// An entry of a merged directory.
+#[derive(Clone)]
+pub struct OverlayEntry {
+    name: Vec<u8>,
+    // From the topmost layer that has it
+    ent: DirEntry,
+    // The directories merged under this name, empty for other types
+    dirs: Vec<Directory>,
+}
+
+pub enum OverlayItem {
+    File(fs::File),
+    Directory(OverlayDir),
+    Symlink(fs::Symlink),
+    Special(fs::Special),
+}
+
+/// Depth-first iterator over the merged tree, see `OverlayFS::walk`.
+pub struct OverlayWalk {
+    stack: Vec<(Vec<u8>, std::vec::IntoIter<OverlayEntry>)>,
+    // Error listing the root, returned once
+    err: Option<Error>,
+    // Directories of the layers already listed
+    seen: HashSet<(usize, u64)>,
+}
+
+impl OverlayEntry {
+    pub fn file_name(&self) -> &[u8] {
+        &self.name
+    }
+
+    pub fn file_type(&self) -> Result<FileType> {
+        self.ent.file_type()
+    }
+
+    /// Metadata of the entry in the topmost layer that has it.
+    pub fn metadata(&self) -> Result<Metadata> {
+        self.ent.metadata()
+    }
+
+    pub fn item(&self) -> Result<OverlayItem> {
+        if !self.dirs.is_empty() {
+            return Ok(OverlayItem::Directory(OverlayDir {
+                dirs: self.dirs.clone(),
+            }));
+        }
+        Ok(match self.ent.item()? {
+            fs::FSItem::File(f) => OverlayItem::File(f),
+            fs::FSItem::Symlink(s) => OverlayItem::Symlink(s),
+            fs::FSItem::Special(s) => OverlayItem::Special(s),
+            fs::FSItem::Directory(_) => {
+                unreachable!("directories always have a layer")
+            }
+        })
+    }
+}
+
+impl OverlayDir {
+    /// Metadata of the directory in the topmost layer that has it.
+    pub fn metadata(&self) -> Result<Metadata> {
+        self.dirs[0].metadata()
+    }
+
+    /// The entry named `name`, without following symlinks.
+    fn lookup(&self, name: &[u8]) -> Result<Option<OverlayEntry>> {
+        if name.starts_with(WHITEOUT_PREFIX) {
+            return Ok(None);
+        }
+        let whiteout = whiteout_name(name);
+        let mut found: Option<OverlayEntry> = None;
+        for dir in &self.dirs {
+            if let Some(ent) = dir.lookup(name)? {
+                let is_dir = ent.file_type()?.is_dir();
+                match &mut found {
+                    None => {
+                        let dirs = match ent.item()? {
+                            fs::FSItem::Directory(d) => vec![d],
+                            _ => Vec::new(),
+                        };
+                        found = Some(OverlayEntry {
+                            name: name.to_vec(),
+                            ent,
+                            dirs,
+                        });
+                    }
+                    Some(f) if is_dir && !f.dirs.is_empty() => {
+                        if let fs::FSItem::Directory(d) = ent.item()? {
+                            f.dirs.push(d);
+                        }
+                    }
+                    // Anything else hides the lower layers
+                    Some(_) => break,
+                }
+                if !is_dir {
+                    break;
+                }
+                if let Some(d) = found.as_ref().and_then(|f| f.dirs.last()) {
+                    if is_opaque(d)? {
+                        break;
+                    }
+                }
+            }
+            if dir.lookup(&whiteout)?.is_some() {
+                break;
+            }
+        }
+        Ok(found)
+    }
+
+    /// The entries of the merged directory, sorted by name.
+    pub fn entries(&self) -> Result<Vec<OverlayEntry>> {
+        let mut names = BTreeSet::new();
+        for dir in &self.dirs {
+            for e in dir.iter() {
+                let name = e?.file_name()?.into_bytes();
+                if !name.starts_with(WHITEOUT_PREFIX) {
+                    names.insert(name);
+                }
+            }
+        }
+        let mut res = Vec::with_capacity(names.len());
+        for name in names {
+            // Names only present in layers below a whiteout vanish
+            if let Some(ent) = self.lookup(&name)? {
+                res.push(ent);
+            }
+        }
+        Ok(res)
+    }
+}
+
+enum Step {
+    Dir(OverlayDir),
+    Entry(OverlayEntry),
+}
+
+impl OverlayFS {
+    /// Stack `layers`, given from the bottom (the base image) to the
+    /// top.
+    pub fn new(mut layers: Vec<Arc<FS>>) -> Result<OverlayFS> {
+        if layers.is_empty() {
+            return Err(Error::InvalidOperation("no layers"));
+        }
+        layers.reverse();
+        Ok(OverlayFS { layers })
+    }
+
+    pub fn get_root(&self) -> Result<OverlayDir> {
+        let mut dirs = Vec::new();
+        for layer in &self.layers {
+            let root = layer.get_root()?;
+            let opaque = is_opaque(&root)?;
+            dirs.push(root);
+            if opaque {
+                break;
+            }
+        }
+        Ok(OverlayDir { dirs })
+    }
+
+    fn resolve_step(&self, path: &[u8]) -> Result<Option<Step>> {
+        // Directories from the root to where we are
+        let mut stack = vec![self.get_root()?];
+        // Components left, last one first
+        let mut todo: Vec<Vec<u8>> = path
+            .split(|&c| c == b'/')
+            .rev()
+            .map(|c| c.to_vec())
+            .collect();
+        let mut links = 0;
+        while let Some(elem) = todo.pop() {
+            match elem.as_slice() {
+                b"" | b"." => continue,
+                b".." => {
+                    if stack.len() > 1 {
+                        stack.pop();
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+            let ent = match stack.last().unwrap().lookup(&elem)? {
+                None => return Ok(None),
+                Some(ent) => ent,
+            };
+            if !ent.dirs.is_empty() {
+                stack.push(OverlayDir { dirs: ent.dirs });
+                continue;
+            }
+            match ent.item()? {
+                OverlayItem::Symlink(s) => {
+                    links += 1;
+                    if links > fs::LINK_LOOP_MAX {
+                        return Err(Error::LinkLoop);
+                    }
+                    let target = s.get_link()?;
+                    if target.first() == Some(&b'/') {
+                        stack.truncate(1);
+                    }
+                    todo.extend(
+                        target.split(|&c| c == b'/').rev().map(|c| c.to_vec()),
+                    );
+                }
+                _ if todo.is_empty() => return Ok(Some(Step::Entry(ent))),
+                _ => {
+                    return Err(Error::InvalidOperation(
+                        "path traversal met non-directory",
+                    ))
+                }
+            }
+        }
+        Ok(stack.pop().map(Step::Dir))
+    }
+
+    /// Find `path` in the merged view, following symlinks, which are
+    /// resolved in the merged view too.
+    pub fn resolve<P: AsRef<[u8]>>(
+        &self,
+        path: P,
+    ) -> Result<Option<OverlayItem>> {
+        Ok(match self.resolve_step(path.as_ref())? {
+            None => None,
+            Some(Step::Dir(d)) => Some(OverlayItem::Directory(d)),
+            Some(Step::Entry(e)) => Some(e.item()?),
+        })
+    }
+
+    /// Every entry of the merged view with its path, directories before
+    /// their contents. A directory of a layer met twice, which only a
+    /// corrupt image can have, is an `Error::Format("directory cycle")`.
+    pub fn walk(&self) -> OverlayWalk {
+        let (stack, err) = match self.get_root().and_then(|r| r.entries()) {
+            Ok(entries) => (vec![(Vec::new(), entries.into_iter())], None),
+            Err(e) => (Vec::new(), Some(e)),
+        };
+        OverlayWalk {
+            stack,
+            err,
+            seen: HashSet::new(),
+        }
+    }
+}
+
+impl Iterator for OverlayWalk {
+    type Item = Result<(Vec<u8>, OverlayEntry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(e) = self.err.take() {
+            return Some(Err(e));
+        }
+        loop {
+            let (prefix, iter) = self.stack.last_mut()?;
+            let ent = match iter.next() {
+                Some(ent) => ent,
+                None => {
+                    self.stack.pop();
+                    continue;
+                }
+            };
+            let mut path = prefix.clone();
+            if !path.is_empty() {
+                path.push(b'/');
+            }
+            path.extend_from_slice(&ent.name);
+            if !ent.dirs.is_empty() {
+                // Empty directories have nothing to loop through, and a
+                // layer may be stacked more than once
+                let ids: HashSet<_> = ent
+                    .dirs
+                    .iter()
+                    .filter(|d| !d.is_empty())
+                    .map(|d| d.id())
+                    .collect();
+                if !ids.is_disjoint(&self.seen) {
+                    let err = Error::Format("directory cycle").in_path(&path);
+                    return Some(Err(err));
+                }
+                self.seen.extend(ids);
+                let dir = OverlayDir {
+                    dirs: ent.dirs.clone(),
+                };
+                match dir.entries() {
+                    Ok(entries) => {
+                        self.stack.push((path.clone(), entries.into_iter()))
+                    }
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+            return Some(Ok((path, ent)));
+        }
+    }
+}
@@ -0,0 +1,326 @@
+// Reading and writing tar archives
+//
+// The archive format itself is handled by the tar crate, this only maps
+// its entries to and from the entries of an image.
+
+use crate::error::Error;
+use crate::fs;
+
+use std::collections::{hash_map, BTreeMap, HashMap};
+use std::ffi::OsStr;
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::time::UNIX_EPOCH;
+
+use tar::{Archive, Builder, EntryType, Header};
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Kind of a tar entry.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+    /// Another name for the entry at `TarEntry::link`
+    HardLink,
+    /// A device or FIFO
+    Special(EntryType),
+    /// Any other typeflag
+    Other(u8),
+}
+
+/// What the image needs from the header of an archive entry.
+#[derive(Clone, Debug)]
+pub(crate) struct TarEntry {
+    /// Path with any leading "./" or "/" and trailing "/" removed
+    pub path: Vec<u8>,
+    pub kind: EntryKind,
+    pub size: u64,
+    /// Target of symlinks, or normalized path of the target of hard
+    /// links
+    pub link: Vec<u8>,
+    pub mode: u32,
+    pub mtime: u64,
+    pub uid: u32,
+    pub gid: u32,
+    /// Major and minor numbers of devices
+    pub rdev: Option<(u64, u64)>,
+}
+
+fn owner(id: u64) -> Result<u32> {
+    id.try_into()
+        .map_err(|_| Error::Bounds("tar owner out of range"))
+}
+
+impl TarEntry {
+    pub fn new<R: Read>(entry: &tar::Entry<R>) -> Result<Self> {
+        let header = entry.header();
+        let path = entry.path_bytes();
+        let kind = match header.entry_type() {
+            EntryType::Regular | EntryType::Continuous
+                if path.ends_with(b"/") =>
+            {
+                EntryKind::Directory
+            }
+            EntryType::Regular | EntryType::Continuous => EntryKind::File,
+            EntryType::Directory => EntryKind::Directory,
+            EntryType::Symlink => EntryKind::Symlink,
+            EntryType::Link => EntryKind::HardLink,
+            t @ (EntryType::Char | EntryType::Block | EntryType::Fifo) => {
+                EntryKind::Special(t)
+            }
+            t => EntryKind::Other(t.as_byte()),
+        };
+        let mut link = entry
+            .link_name_bytes()
+            .map_or_else(Vec::new, |l| l.into_owned());
+        if kind == EntryKind::HardLink {
+            link = normalize(&link);
+        }
+        let rdev = match kind {
+            EntryKind::Special(EntryType::Char | EntryType::Block) => Some((
+                header.device_major()?.unwrap_or(0).into(),
+                header.device_minor()?.unwrap_or(0).into(),
+            )),
+            _ => None,
+        };
+        Ok(TarEntry {
+            path: normalize(&path),
+            kind,
+            size: entry.size(),
+            link,
+            mode: header.mode()? & 0o7777,
+            mtime: header.mtime()?,
+            uid: owner(header.uid()?)?,
+            gid: owner(header.gid()?)?,
+            rdev,
+        })
+    }
+}
+
+fn normalize(path: &[u8]) -> Vec<u8> {
+    let mut p = path;
+    loop {
+        if let Some(rest) = p.strip_prefix(b"./") {
+            p = rest;
+        } else if let Some(rest) = p.strip_prefix(b"/") {
+            p = rest;
+        } else {
+            break;
+        }
+    }
+    while let Some(rest) = p.strip_suffix(b"/") {
+        p = rest;
+    }
+    if p == b"." {
+        p = b"";
+    }
+    p.to_vec()
+}
+
+// Type of the tar entries of devices and FIFOs, tar has none for sockets
+fn special_type(s: &fs::Special) -> Option<EntryType> {
+    let ft = s.file_type();
+    if ft.is_char_device() {
+        Some(EntryType::Char)
+    } else if ft.is_block_device() {
+        Some(EntryType::Block)
+    } else if ft.is_fifo() {
+        Some(EntryType::Fifo)
+    } else {
+        None
+    }
+}
+
+fn device_number(n: u64) -> Result<u32> {
+    n.try_into()
+        .map_err(|_| Error::Bounds("device number too large for tar"))
+}
+
+/// Write the whole contents of an image to `out` as a tar archive.
+///
+/// Entries are written in the order of `Directory::walk`. Permissions,
+/// owners and modification times are only set for images that store
+/// them, others get the usual defaults and 0. Sockets are left out.
+pub fn export_tar<W: Write>(fs: &fs::FS, out: W) -> Result<()> {
+    let mut tar = Builder::new(out);
+    for e in fs.get_root()?.walk() {
+        let (path, ent) = e?;
+        let path = OsStr::from_bytes(&path);
+        let meta = ent.metadata()?;
+        let mut header = Header::new_gnu();
+        let item = ent.item()?;
+        let default_mode = match &item {
+            fs::FSItem::File(_) | fs::FSItem::Special(_) => 0o644,
+            fs::FSItem::Directory(_) => 0o755,
+            fs::FSItem::Symlink(_) => 0o777,
+        };
+        header.set_mode(meta.mode().unwrap_or(default_mode) & 0o7777);
+        header.set_uid(meta.uid().unwrap_or(0).into());
+        header.set_gid(meta.gid().unwrap_or(0).into());
+        header.set_mtime(
+            meta.modified()
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |d| d.as_secs()),
+        );
+        header.set_size(0);
+        match item {
+            fs::FSItem::File(f) => {
+                header.set_entry_type(EntryType::Regular);
+                header.set_size(f.size());
+                tar.append_data(&mut header, path, f.reader())?;
+            }
+            fs::FSItem::Directory(_) => {
+                header.set_entry_type(EntryType::Directory);
+                tar.append_data(&mut header, path, io::empty())?;
+            }
+            fs::FSItem::Symlink(s) => {
+                header.set_entry_type(EntryType::Symlink);
+                let link = s.get_link()?;
+                tar.append_link(&mut header, path, OsStr::from_bytes(&link))?;
+            }
+            fs::FSItem::Special(s) => {
+                let ty = match special_type(&s) {
+                    Some(ty) => ty,
+                    None => continue,
+                };
+                header.set_entry_type(ty);
+                if let Some((major, minor)) = s.rdev() {
+                    header.set_device_major(device_number(major)?)?;
+                    header.set_device_minor(device_number(minor)?)?;
+                }
+                tar.append_data(&mut header, path, io::empty())?;
+            }
+        }
+    }
+    tar.into_inner()?;
+    Ok(())
+}
+
+/// A difference found by `diff_against_tar`.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Difference {
+    /// The path is in the archive but not in the image
+    OnlyInTar(Vec<u8>),
+    /// The path is in the image but not in the archive
+    OnlyInImage(Vec<u8>),
+    /// The path is not the same kind of entry in both
+    Type(Vec<u8>),
+    /// The files don't have the same size
+    Size { path: Vec<u8>, image: u64, tar: u64 },
+    /// The files, symlink targets, device numbers or hard link targets
+    /// differ
+    Contents(Vec<u8>),
+    /// The archive entry can't be in an image, like a GNU sparse file
+    Unsupported(Vec<u8>),
+}
+
+fn diff_entry<R: Read>(
+    image: &BTreeMap<Vec<u8>, fs::DirEntry>,
+    ent: &fs::DirEntry,
+    entry: &TarEntry,
+    data: R,
+    compare_contents: bool,
+) -> Result<Option<Difference>> {
+    let path = entry.path.clone();
+    Ok(match (ent.item()?, entry.kind) {
+        (fs::FSItem::Directory(_), EntryKind::Directory) => None,
+        (fs::FSItem::File(f), EntryKind::File) => {
+            if f.size() != entry.size {
+                Some(Difference::Size {
+                    path,
+                    image: f.size(),
+                    tar: entry.size,
+                })
+            } else if compare_contents && !f.content_eq_reader(data)? {
+                Some(Difference::Contents(path))
+            } else {
+                None
+            }
+        }
+        (fs::FSItem::Symlink(s), EntryKind::Symlink) => {
+            if compare_contents && s.get_link()? != entry.link {
+                Some(Difference::Contents(path))
+            } else {
+                None
+            }
+        }
+        (fs::FSItem::Special(s), EntryKind::Special(ty))
+            if special_type(&s) == Some(ty) =>
+        {
+            if compare_contents && s.rdev() != entry.rdev {
+                Some(Difference::Contents(path))
+            } else {
+                None
+            }
+        }
+        (_, EntryKind::HardLink) => {
+            let target = image.get(&entry.link).map(|t| t.ino());
+            if compare_contents && target != Some(ent.ino()) {
+                Some(Difference::Contents(path))
+            } else {
+                None
+            }
+        }
+        _ => Some(Difference::Type(path)),
+    })
+}
+
+/// Compare an image with a tar archive.
+///
+/// Paths, entry types and file sizes are compared, and with
+/// `compare_contents` also the contents of files, the targets of
+/// symlinks and hard links and the numbers of devices. The archive is
+/// read once in whatever order it is in while the paths of the image
+/// are kept in memory. Differences in the archive are reported in its
+/// order, followed by the paths missing from it in sorted order. As
+/// when extracting, a path that is repeated in the archive is compared
+/// as its last entry.
+pub fn diff_against_tar<R: Read>(
+    fs: &fs::FS,
+    tar: R,
+    compare_contents: bool,
+) -> Result<Vec<Difference>> {
+    let mut image = BTreeMap::new();
+    for e in fs.get_root()?.walk() {
+        let (path, ent) = e?;
+        image.insert(path, ent);
+    }
+    // The difference of each path of the archive, by the position of
+    // its first entry
+    let mut res = Vec::new();
+    let mut seen = HashMap::new();
+    let mut tar = Archive::new(tar);
+    for data in tar.entries()? {
+        let mut data = data?;
+        let entry = TarEntry::new(&data)?;
+        // The root is always there
+        if entry.path.is_empty() {
+            continue;
+        }
+        let diff = if let EntryKind::Other(_) = entry.kind {
+            Some(Difference::Unsupported(entry.path.clone()))
+        } else if let Some(ent) = image.get(&entry.path) {
+            diff_entry(&image, ent, &entry, &mut data, compare_contents)?
+        } else {
+            Some(Difference::OnlyInTar(entry.path.clone()))
+        };
+        match seen.entry(entry.path) {
+            hash_map::Entry::Occupied(e) => res[*e.get()] = diff,
+            hash_map::Entry::Vacant(e) => {
+                e.insert(res.len());
+                res.push(diff);
+            }
+        }
+    }
+    let mut res: Vec<_> = res.into_iter().flatten().collect();
+    res.extend(
+        image
+            .into_keys()
+            .filter(|p| !seen.contains_key(p))
+            .map(Difference::OnlyInImage),
+    );
+    Ok(res)
+}
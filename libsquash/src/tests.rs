@@ -0,0 +1,3123 @@
+// Images opened from a Cursor aren't shared between threads here
+#![allow(clippy::arc_with_non_send_sync)]
+
+use std::io::{self, Cursor};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::Arc;
+
+use crate::fs::{FSItem, FsOptions, FS};
+use crate::Difference;
+use crate::{extract_fs, Collation, ExtractOptions, OverwritePolicy};
+use crate::{write_image, write_image_with, EncryptionType, WriteOptions};
+
+fn open_dir<P: AsRef<Path>>(source: P) -> FS {
+    let mut out = Cursor::new(Vec::new());
+    write_image(source, &mut out, None, EncryptionType::None).unwrap();
+    FS::open(out, None).unwrap()
+}
+
+fn open_dir_with<P: AsRef<Path>>(source: P, opts: &WriteOptions) -> FS {
+    let mut out = Cursor::new(Vec::new());
+    write_image_with(source, &mut out, None, EncryptionType::None, opts)
+        .unwrap();
+    FS::open(out, None).unwrap()
+}
+
+fn make_tree(files: &[&str]) -> tempfile::TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    for name in files {
+        let path = dir.path().join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, name).unwrap();
+    }
+    dir
+}
+
+fn names(dir: &crate::fs::Directory) -> Vec<Vec<u8>> {
+    dir.iter()
+        .map(|e| e.unwrap().file_name().unwrap().into_bytes())
+        .collect()
+}
+
+fn get_file(fs: &FS, path: &str) -> crate::fs::File {
+    match fs.resolve(path).unwrap() {
+        Some(FSItem::File(f)) => f,
+        _ => panic!("{} is not a file", path),
+    }
+}
+
+// Counts the reads made to the image
+struct CountingReadAt {
+    inner: Cursor<Vec<u8>>,
+    reads: Arc<AtomicUsize>,
+}
+
+impl crate::disk::ReadAt for CountingReadAt {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> crate::Result<usize> {
+        self.reads.fetch_add(1, AtomicOrdering::SeqCst);
+        self.inner.read_at(buf, offset)
+    }
+}
+
+fn read_all(f: &crate::fs::File) -> Vec<u8> {
+    let mut buf = vec![0; f.size() as usize];
+    f.read_exact_at(&mut buf, 0).unwrap();
+    buf
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn test_zstd_roundtrip() {
+    let dir = tempfile::tempdir().unwrap();
+    let text = "the quick brown fox jumps over the lazy dog\n".repeat(1000);
+    std::fs::create_dir(dir.path().join("sub")).unwrap();
+    std::fs::write(dir.path().join("big.txt"), &text).unwrap();
+    std::fs::write(dir.path().join("sub/small.txt"), "small\n").unwrap();
+    std::fs::write(dir.path().join("empty"), "").unwrap();
+    std::os::unix::fs::symlink("big.txt", dir.path().join("link")).unwrap();
+    let opts = WriteOptions {
+        compression: crate::CompressionType::Zstd,
+        ..Default::default()
+    };
+    let fs = open_dir_with(dir.path(), &opts);
+
+    for (path, data) in [
+        ("big.txt", text.as_bytes()),
+        ("sub/small.txt", b"small\n"),
+        ("empty", b""),
+        ("link", text.as_bytes()),
+    ] {
+        let f = get_file(&fs, path);
+        assert_eq!(f.size(), data.len() as u64);
+        let mut buf = vec![0; data.len()];
+        f.read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(buf, data);
+    }
+
+    let f = get_file(&fs, "big.txt");
+    assert!(f.compressed_size().unwrap() < f.size());
+    let mut buf = [0; 9];
+    f.read_exact_at(&mut buf, 4 * 44 + 4).unwrap();
+    assert_eq!(&buf, b"quick bro");
+}
+
+#[test]
+fn test_read_at_end() {
+    let dir = tempfile::tempdir().unwrap();
+    let data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+    std::fs::write(dir.path().join("big"), &data).unwrap();
+    let mut compressions = vec![crate::CompressionType::None];
+    if cfg!(feature = "zstd") {
+        compressions.push(crate::CompressionType::Zstd);
+    }
+    for compression in compressions {
+        let opts = WriteOptions {
+            compression,
+            ..Default::default()
+        };
+        let fs = open_dir_with(dir.path(), &opts);
+        let f = get_file(&fs, "big");
+        let len = data.len() as u64;
+        let mut buf = vec![0; 200_000];
+        // Across a block boundary up to the end
+        assert_eq!(f.read_at(&mut buf, 100_000).unwrap(), 200_000);
+        assert_eq!(buf, data[100_000..]);
+        assert_eq!(f.read_at(&mut buf, len - 10).unwrap(), 10);
+        assert_eq!(buf[..10], data[data.len() - 10..]);
+        for offset in [len, len + 1, u64::MAX] {
+            assert_eq!(f.read_at(&mut buf, offset).unwrap(), 0);
+        }
+        assert!(f.read_exact_at(&mut buf[..10], len - 10).is_ok());
+        match f.read_exact_at(&mut buf[..11], len - 10) {
+            Err(crate::Error::IO(e)) => {
+                assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof)
+            }
+            _ => panic!("short read_exact_at succeeded"),
+        }
+    }
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn test_zstd_blocks() {
+    let dir = tempfile::tempdir().unwrap();
+    let data: Vec<u8> = (0..400_000u32).map(|i| (i % 251) as u8).collect();
+    std::fs::write(dir.path().join("big"), &data).unwrap();
+    let opts = WriteOptions {
+        compression: crate::CompressionType::Zstd,
+        ..Default::default()
+    };
+    let fs = open_dir_with(dir.path(), &opts);
+    let f = get_file(&fs, "big");
+    assert_eq!(f.size(), data.len() as u64);
+    assert_eq!(f.digest().unwrap(), *blake3::hash(&data).as_bytes());
+
+    // windows inside a block, across one or more boundaries and at the end
+    let bs = 128 * 1024;
+    for (off, len) in [
+        (10, 100),
+        (bs - 10, 20),
+        (bs - 1, bs + 2),
+        (0, data.len()),
+        (data.len() - 5, 5),
+        (data.len(), 0),
+    ] {
+        let mut buf = vec![0; len];
+        f.read_exact_at(&mut buf, off as u64).unwrap();
+        assert_eq!(buf, data[off..off + len]);
+    }
+    assert_eq!(f.read_at(&mut [0; 10], data.len() as u64 - 3).unwrap(), 3);
+}
+
+#[test]
+fn test_detect_type() {
+    let dir = tempfile::tempdir().unwrap();
+    let files: [(&str, &[u8]); 4] = [
+        ("image.png", b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"),
+        ("archive.gz", b"\x1f\x8b\x08\0\0\0\0\0"),
+        ("text.txt", b"just some text\n"),
+        ("empty", b""),
+    ];
+    for (name, data) in files {
+        std::fs::write(dir.path().join(name), data).unwrap();
+    }
+    let fs = open_dir(dir.path());
+
+    let ty = get_file(&fs, "image.png").detect_type().unwrap();
+    assert_eq!(ty, Some("image/png"));
+    let ty = get_file(&fs, "archive.gz").detect_type().unwrap();
+    assert_eq!(ty, Some("application/gzip"));
+    let ty = get_file(&fs, "text.txt").detect_type().unwrap();
+    assert_eq!(ty, None);
+    let ty = get_file(&fs, "empty").detect_type().unwrap();
+    assert_eq!(ty, None);
+}
+
+#[test]
+fn test_file_sizes() {
+    let fs = open_dir("test_data/small");
+    for path in ["hello.txt", "dir/nested.txt", "dir/sub/.keep"] {
+        let f = get_file(&fs, path);
+        let len = std::fs::metadata(Path::new("test_data/small").join(path))
+            .unwrap()
+            .len();
+        assert_eq!(f.size(), len);
+        // With the table of the boundaries of the single block
+        let table = if len == 0 { 8 } else { 16 };
+        assert_eq!(f.compressed_size().unwrap(), len + table);
+        let mut buf = vec![0; f.size() as usize];
+        f.read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(buf.len() as u64, len);
+    }
+}
+
+fn check_collation(coll: Collation, sorted: &[&str]) {
+    let mut files = sorted.to_vec();
+    files.reverse();
+    let dir = make_tree(&files);
+    let opts = WriteOptions {
+        collation: coll,
+        ..Default::default()
+    };
+    let fs = open_dir_with(dir.path(), &opts);
+    let expected: Vec<_> = sorted.iter().map(|n| n.as_bytes()).collect();
+    assert_eq!(names(&fs.get_root().unwrap()), expected);
+    for name in sorted {
+        let mut buf = vec![0; name.len()];
+        get_file(&fs, name).read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(buf, name.as_bytes());
+    }
+}
+
+#[test]
+fn test_collation_natural() {
+    check_collation(
+        Collation::Natural,
+        &["img1", "img02", "img2", "img3", "img10", "img20", "imgA"],
+    );
+}
+
+#[test]
+fn test_collation_case_insensitive() {
+    check_collation(
+        Collation::CaseInsensitive,
+        &["A", "a", "b", "C", "c", "Zed", "zz"],
+    );
+}
+
+#[test]
+fn test_collation_bytes() {
+    check_collation(Collation::Bytes, &["A", "C", "Zed", "a", "b", "c"]);
+}
+
+#[test]
+fn test_collation_header_flag() {
+    let dir = make_tree(&["a"]);
+    let mut out = Cursor::new(Vec::new());
+    write_image(dir.path(), &mut out, None, EncryptionType::None).unwrap();
+    assert_eq!(
+        crate::read_header(&out).unwrap().incompat_features(),
+        crate::INCOMPAT_BLOCK_TABLES
+    );
+
+    let opts = WriteOptions {
+        collation: Collation::Natural,
+        ..Default::default()
+    };
+    let mut out = Cursor::new(Vec::new());
+    write_image_with(dir.path(), &mut out, None, EncryptionType::None, &opts)
+        .unwrap();
+    let hdr = crate::read_header(&out).unwrap();
+    assert_eq!(
+        hdr.incompat_features(),
+        crate::INCOMPAT_COLLATION | crate::INCOMPAT_BLOCK_TABLES
+    );
+
+    // Unknown incompatible features are refused
+    let mut data = out.into_inner();
+    data[23] = 0x80;
+    assert!(FS::open(Cursor::new(data), None).is_err());
+}
+
+fn extract_with(
+    fs: &FS,
+    target: &Path,
+    policy: OverwritePolicy,
+) -> crate::Result<crate::ExtractSummary> {
+    let opts = ExtractOptions {
+        overwrite: policy,
+        ..Default::default()
+    };
+    extract_fs(fs, &target, &opts)
+}
+
+#[test]
+fn test_extract_overwrite() {
+    let fs = open_dir("test_data/small");
+    let target = tempfile::tempdir().unwrap();
+    let hello = target.path().join("hello.txt");
+
+    let summary =
+        extract_with(&fs, target.path(), OverwritePolicy::Error).unwrap();
+    assert_eq!(summary.files, 3);
+    assert_eq!(summary.dirs, 2);
+    assert_eq!(summary.symlinks, 1);
+    assert_eq!(summary.skipped, 0);
+    assert_eq!(std::fs::read(&hello).unwrap(), b"Hello, world!\n");
+
+    // Error refuses to touch anything that exists
+    let r = extract_with(&fs, target.path(), OverwritePolicy::Error);
+    assert!(
+        matches!(r, Err(crate::Error::IO(e)) if e.kind() == std::io::ErrorKind::AlreadyExists)
+    );
+
+    // Skip leaves existing entries alone but goes into directories
+    std::fs::write(&hello, "changed").unwrap();
+    std::fs::remove_file(target.path().join("dir/nested.txt")).unwrap();
+    let summary =
+        extract_with(&fs, target.path(), OverwritePolicy::Skip).unwrap();
+    assert_eq!(summary.files, 1);
+    assert_eq!(summary.skipped, 3);
+    assert_eq!(std::fs::read(&hello).unwrap(), b"changed");
+    assert_eq!(
+        std::fs::read(target.path().join("dir/nested.txt")).unwrap(),
+        b"nested\n"
+    );
+
+    // Overwrite replaces files and symlinks
+    std::fs::remove_file(target.path().join("link")).unwrap();
+    std::os::unix::fs::symlink("dir", target.path().join("link")).unwrap();
+    let summary =
+        extract_with(&fs, target.path(), OverwritePolicy::Overwrite).unwrap();
+    assert_eq!(summary.files, 3);
+    assert_eq!(summary.symlinks, 1);
+    assert_eq!(summary.skipped, 0);
+    assert_eq!(std::fs::read(&hello).unwrap(), b"Hello, world!\n");
+    assert_eq!(
+        std::fs::read_link(target.path().join("link")).unwrap(),
+        Path::new("hello.txt")
+    );
+}
+
+#[test]
+fn test_extract_large_files() {
+    let dir = tempfile::tempdir().unwrap();
+    // Spanning several read buffers, and exactly one
+    let sizes = [3 * (1 << 20) + 12345, 1 << 20];
+    for (i, size) in sizes.iter().enumerate() {
+        let data: Vec<u8> = (0..*size).map(|j| (j % 251) as u8).collect();
+        std::fs::write(dir.path().join(format!("f{}", i)), data).unwrap();
+    }
+    let fs = open_dir(dir.path());
+    let target = tempfile::tempdir().unwrap();
+    extract_with(&fs, target.path(), OverwritePolicy::Error).unwrap();
+    for i in 0..sizes.len() {
+        let name = format!("f{}", i);
+        assert_eq!(
+            std::fs::read(target.path().join(&name)).unwrap(),
+            std::fs::read(dir.path().join(&name)).unwrap()
+        );
+    }
+}
+
+#[test]
+fn test_extract_subtree() {
+    let dir = make_tree(&["a", "sub/b", "sub/deeper/c", "sub/deeper/d"]);
+    std::os::unix::fs::symlink("b", dir.path().join("sub/link")).unwrap();
+    let image = tempfile::NamedTempFile::new().unwrap();
+    crate::write_image_file(
+        &dir.path(),
+        &image.path(),
+        None,
+        EncryptionType::None,
+    )
+    .unwrap();
+    let extract = |path: &str| {
+        let target = tempfile::tempdir().unwrap();
+        crate::extract_subtree(&image.path(), path, &target.path(), None)
+            .map(|_| target)
+    };
+
+    // The contents of a directory
+    let target = extract("sub/deeper").unwrap();
+    let mut found: Vec<_> = std::fs::read_dir(target.path())
+        .unwrap()
+        .map(|e| e.unwrap().file_name())
+        .collect();
+    found.sort();
+    assert_eq!(found, ["c", "d"]);
+    assert_eq!(
+        std::fs::read(target.path().join("d")).unwrap(),
+        b"sub/deeper/d"
+    );
+
+    // A file or symlink under its name
+    let target = extract("/sub/b").unwrap();
+    assert_eq!(std::fs::read(target.path().join("b")).unwrap(), b"sub/b");
+    let target = extract("sub/link").unwrap();
+    assert_eq!(
+        std::fs::read_link(target.path().join("link")).unwrap(),
+        Path::new("b")
+    );
+
+    assert!(matches!(
+        extract("sub/missing"),
+        Err(crate::Error::InvalidOperation("path not found"))
+    ));
+}
+
+#[test]
+fn test_digest() {
+    let fs = open_dir("test_data/small");
+    let digest = get_file(&fs, "hello.txt").digest().unwrap();
+    assert_eq!(digest, *blake3::hash(b"Hello, world!\n").as_bytes());
+}
+
+#[test]
+fn test_verify_file() {
+    let fs = open_dir("test_data/small");
+    let hash = blake3::hash(b"Hello, world!\n");
+    assert!(fs.verify_file("hello.txt", hash.as_bytes()).unwrap());
+    assert!(fs
+        .verify_file("/dir/../hello.txt", hash.as_bytes())
+        .unwrap());
+    assert!(!fs.verify_file("dir/nested.txt", hash.as_bytes()).unwrap());
+    assert!(!fs.verify_file("hello.txt", &hash.as_bytes()[..16]).unwrap());
+    assert!(matches!(
+        fs.verify_file("dir", hash.as_bytes()),
+        Err(crate::Error::InvalidOperation("path is not a file"))
+    ));
+    assert!(matches!(
+        fs.verify_file("missing", hash.as_bytes()),
+        Err(crate::Error::InvalidOperation("path not found"))
+    ));
+}
+
+#[test]
+fn test_read_whole() {
+    let fs = open_dir("test_data/small");
+    assert_eq!(fs.read("hello.txt").unwrap(), b"Hello, world!\n");
+    // symlinks are followed
+    assert_eq!(fs.read("/dir/../link").unwrap(), b"Hello, world!\n");
+    assert_eq!(fs.read_link("link").unwrap(), b"hello.txt");
+    assert!(matches!(
+        fs.read("dir"),
+        Err(crate::Error::InvalidOperation("path is not a file"))
+    ));
+    assert!(matches!(
+        fs.read_link("hello.txt"),
+        Err(crate::Error::InvalidOperation("path is not a symlink"))
+    ));
+    for res in [fs.read("missing"), fs.read_link("dir/missing")] {
+        assert!(matches!(
+            res,
+            Err(crate::Error::InvalidOperation("path not found"))
+        ));
+    }
+}
+
+#[test]
+fn test_glob() {
+    let dir = make_tree(&[
+        "a.txt",
+        "b.so",
+        "dir/c.txt",
+        "dir/sub/d.txt",
+        "dir/sub/libx.so.1",
+    ]);
+    let fs = open_dir(dir.path());
+    let glob = |pattern: &str| -> Vec<String> {
+        fs.glob(pattern)
+            .unwrap()
+            .into_iter()
+            .map(|p| String::from_utf8(p).unwrap())
+            .collect()
+    };
+    assert_eq!(glob("*.txt"), ["a.txt"]);
+    assert_eq!(glob("**/*.txt"), ["a.txt", "dir/c.txt", "dir/sub/d.txt"]);
+    assert_eq!(
+        glob("dir/**"),
+        [
+            "dir",
+            "dir/c.txt",
+            "dir/sub",
+            "dir/sub/d.txt",
+            "dir/sub/libx.so.1"
+        ]
+    );
+    assert_eq!(glob("dir/**/*.so*"), ["dir/sub/libx.so.1"]);
+    assert_eq!(glob("/dir/sub/d.txt"), ["dir/sub/d.txt"]);
+    assert_eq!(glob("d?r/*"), ["dir/c.txt", "dir/sub"]);
+    assert!(glob("missing/**").is_empty());
+    assert!(glob("dir/c.txt/*").is_empty());
+}
+
+#[test]
+fn test_file_reader() {
+    use std::io::{BufRead, Read, Seek, SeekFrom};
+
+    let dir = tempfile::tempdir().unwrap();
+    let text: String = (0..2000).map(|i| format!("line {}\n", i)).collect();
+    std::fs::write(dir.path().join("text"), &text).unwrap();
+    let fs = open_dir(dir.path());
+
+    let lines: Vec<String> =
+        std::io::BufReader::new(get_file(&fs, "text").reader())
+            .lines()
+            .map(|l| l.unwrap())
+            .collect();
+    assert_eq!(lines.len(), 2000);
+    assert_eq!(lines[1234], "line 1234");
+
+    let mut r = get_file(&fs, "text").reader();
+    let mut line = String::new();
+    r.read_line(&mut line).unwrap();
+    assert_eq!(line, "line 0\n");
+    assert_eq!(r.stream_position().unwrap(), 7);
+    r.seek(SeekFrom::Current(7)).unwrap();
+    line.clear();
+    r.read_line(&mut line).unwrap();
+    assert_eq!(line, "line 2\n");
+    r.seek(SeekFrom::End(-10)).unwrap();
+    line.clear();
+    r.read_to_string(&mut line).unwrap();
+    assert_eq!(line, "line 1999\n");
+
+    // Seeking past the end is allowed and reads nothing
+    r.seek(SeekFrom::Start(text.len() as u64 + 10)).unwrap();
+    assert_eq!(r.read(&mut [0; 10]).unwrap(), 0);
+    r.seek(SeekFrom::Start(0)).unwrap();
+    let mut all = String::new();
+    r.read_to_string(&mut all).unwrap();
+    assert_eq!(all, text);
+}
+
+#[test]
+fn test_content_eq() {
+    let big: Vec<u8> = (0..200_000u32).map(|i| (i % 253) as u8).collect();
+    let mut big2 = big.clone();
+    big2[150_000] ^= 1;
+    let dir = tempfile::tempdir().unwrap();
+    for (name, data) in [
+        ("a", &b"same contents"[..]),
+        ("b", b"same contents"),
+        ("c", b"some contents"),
+        ("d", b"same contents!"),
+        ("big", &big),
+        ("big2", &big2),
+    ] {
+        std::fs::write(dir.path().join(name), data).unwrap();
+    }
+    let fs = open_dir(dir.path());
+    let a = get_file(&fs, "a");
+    assert!(a.content_eq(&get_file(&fs, "b")).unwrap());
+    assert!(!a.content_eq(&get_file(&fs, "c")).unwrap());
+    assert!(!a.content_eq(&get_file(&fs, "d")).unwrap());
+    let b = get_file(&fs, "big");
+    assert!(b.content_eq(&b).unwrap());
+    assert!(!b.content_eq(&get_file(&fs, "big2")).unwrap());
+
+    assert!(a.content_eq_reader(&b"same contents"[..]).unwrap());
+    assert!(!a.content_eq_reader(&b"some contents"[..]).unwrap());
+    assert!(!a.content_eq_reader(&b"same contents!"[..]).unwrap());
+    assert!(!a.content_eq_reader(&b"same"[..]).unwrap());
+    assert!(b.content_eq_reader(&big[..]).unwrap());
+    assert!(!b.content_eq_reader(&big2[..]).unwrap());
+}
+
+#[test]
+fn test_lookup() {
+    for n in [0, 1, 2, 50] {
+        // "b00", "b02", ... so that there is room before, after and
+        // between the names
+        let files: Vec<String> =
+            (0..n).map(|i| format!("b{:02}", i * 2)).collect();
+        let dir = tempfile::tempdir().unwrap();
+        for name in &files {
+            std::fs::write(dir.path().join(name), name).unwrap();
+        }
+        let fs = open_dir(dir.path());
+        let root = fs.get_root().unwrap();
+        assert_eq!(root.len(), n);
+
+        for name in &files {
+            assert!(matches!(root.resolve(name), Ok(Some(FSItem::File(_)))));
+        }
+        let mut missing = vec![
+            "a".to_string(),
+            "b".to_string(),
+            "c".to_string(),
+            "b99".to_string(),
+        ];
+        missing.extend((0..n).map(|i| format!("b{:02}", i * 2 + 1)));
+        for name in &missing {
+            assert!(matches!(root.resolve(name), Ok(None)), "{}", name);
+        }
+        assert!(matches!(root.get(n), Ok(None)));
+    }
+}
+
+#[test]
+fn test_lookup_names() {
+    // Names whose byte order differs from other orders
+    let files = ["A", "a", "Z", "_", "a.b", "a-b", "ab", "é", "\u{1f600}"];
+    let dir = make_tree(&files);
+    let fs = open_dir(dir.path());
+    let root = fs.get_root().unwrap();
+    let mut sorted: Vec<&[u8]> = files.iter().map(|n| n.as_bytes()).collect();
+    sorted.sort();
+    assert_eq!(names(&root), sorted);
+    for name in files {
+        assert!(matches!(root.resolve(name), Ok(Some(FSItem::File(_)))));
+    }
+}
+
+#[test]
+fn test_iter_from() {
+    let dir = make_tree(&["a", "b", "c", "d", "e"]);
+    let fs = open_dir(dir.path());
+    let root = fs.get_root().unwrap();
+    let all = names(&root);
+
+    for k in 0..=6 {
+        let tail: Vec<_> = root
+            .iter_from(k)
+            .map(|e| e.unwrap().file_name().unwrap().into_bytes())
+            .collect();
+        assert_eq!(tail, all[std::cmp::min(k as usize, all.len())..]);
+    }
+
+    // Save the position and resume later
+    let mut it = root.iter();
+    it.next().unwrap().unwrap();
+    it.next().unwrap().unwrap();
+    let pos = it.position();
+    assert_eq!(pos, 2);
+    let rest: Vec<_> = it
+        .map(|e| e.unwrap().file_name().unwrap().into_bytes())
+        .collect();
+    for _ in 0..2 {
+        let resumed: Vec<_> = root
+            .iter_from(pos)
+            .map(|e| e.unwrap().file_name().unwrap().into_bytes())
+            .collect();
+        assert_eq!(resumed, rest);
+    }
+
+    let rev: Vec<_> = root
+        .iter_rev()
+        .map(|e| e.unwrap().file_name().unwrap().into_bytes())
+        .collect();
+    assert_eq!(rev, [&b"e"[..], b"d", b"c", b"b", b"a"]);
+}
+
+#[test]
+fn test_read_dir_both_ends() {
+    let dir = make_tree(&["a", "b", "c", "d", "e"]);
+    let fs = open_dir(dir.path());
+    let root = fs.get_root().unwrap();
+    let name = |e: Option<crate::Result<crate::fs::DirEntry>>| {
+        e.unwrap().unwrap().file_name().unwrap().into_bytes()
+    };
+
+    let mut it = root.iter();
+    assert_eq!(it.len(), 5);
+    for expected in [b"a", b"b", b"c", b"d", b"e"] {
+        assert_eq!(name(it.next()), expected);
+    }
+    assert_eq!(it.len(), 0);
+    assert!(it.next().is_none());
+
+    let mut it = root.iter();
+    for expected in [b"e", b"d", b"c", b"b", b"a"] {
+        assert_eq!(name(it.next_back()), expected);
+    }
+    assert!(it.next_back().is_none());
+
+    // Both ends meet in the middle
+    let mut it = root.iter();
+    assert_eq!(name(it.next()), b"a");
+    assert_eq!(name(it.next_back()), b"e");
+    assert_eq!(name(it.next_back()), b"d");
+    assert_eq!(it.len(), 2);
+    assert_eq!(name(it.next()), b"b");
+    assert_eq!(name(it.next()), b"c");
+    assert_eq!(it.len(), 0);
+    assert!(it.next().is_none());
+    assert!(it.next_back().is_none());
+    assert_eq!(root.iter_from(7).len(), 0);
+}
+
+#[test]
+fn test_resolve_root() {
+    let fs = open_dir("test_data/small");
+    let sub = match fs.resolve("dir").unwrap() {
+        Some(FSItem::Directory(d)) => d,
+        _ => panic!("dir is not a directory"),
+    };
+    for (path, expected) in [
+        ("", names(&sub)),
+        ("/", names(&fs.get_root().unwrap())),
+        ("//", names(&fs.get_root().unwrap())),
+        ("/./", names(&fs.get_root().unwrap())),
+    ] {
+        match sub.resolve(path).unwrap() {
+            Some(FSItem::Directory(d)) => assert_eq!(names(&d), expected),
+            _ => panic!("{:?} is not a directory", path),
+        }
+    }
+    assert!(matches!(fs.resolve(""), Ok(Some(FSItem::Directory(_)))));
+}
+
+#[test]
+fn test_resolve_nofollow() {
+    let dir = make_tree(&["d/file"]);
+    for (link, target) in [("ld", "d"), ("lf", "d/file"), ("ld2", "ld")] {
+        std::os::unix::fs::symlink(target, dir.path().join(link)).unwrap();
+    }
+    let fs = open_dir(dir.path());
+    let link = |item| match item {
+        Ok(Some(FSItem::Symlink(s))) => s.get_link().unwrap(),
+        _ => panic!("not a symlink"),
+    };
+    assert_eq!(link(fs.resolve_nofollow("lf")), b"d/file");
+    assert_eq!(link(fs.resolve_nofollow("/ld2")), b"ld");
+    assert!(matches!(fs.resolve("lf"), Ok(Some(FSItem::File(_)))));
+    assert!(matches!(fs.resolve("ld2"), Ok(Some(FSItem::Directory(_)))));
+    // Only the last element isn't followed
+    assert!(matches!(
+        fs.resolve_nofollow("ld2/file"),
+        Ok(Some(FSItem::File(_)))
+    ));
+    assert!(matches!(
+        fs.resolve_nofollow("ld/"),
+        Ok(Some(FSItem::Directory(_)))
+    ));
+    assert!(matches!(fs.resolve_nofollow("missing"), Ok(None)));
+
+    let sub = match fs.resolve("ld").unwrap() {
+        Some(FSItem::Directory(d)) => d,
+        _ => panic!("ld is not a directory"),
+    };
+    assert_eq!(link(sub.resolve_nofollow("../ld")), b"d");
+    assert!(matches!(
+        sub.resolve_nofollow("file"),
+        Ok(Some(FSItem::File(_)))
+    ));
+}
+
+#[test]
+fn test_open_lazy() {
+    let lazy = FS::open_lazy(Cursor::new(vec![b'x'; 4096]), None);
+    for _ in 0..2 {
+        assert!(matches!(lazy.get_root(), Err(crate::Error::Format(_))));
+    }
+    assert!(lazy.resolve("hello.txt").is_err());
+
+    // Nothing is read before the first use
+    let reads = Arc::new(AtomicUsize::new(0));
+    let img = CountingReadAt {
+        inner: Cursor::new(std::fs::read("test_data/small.sqh").unwrap()),
+        reads: reads.clone(),
+    };
+    let lazy = FS::open_lazy(img, None);
+    assert_eq!(reads.load(AtomicOrdering::SeqCst), 0);
+    assert!(matches!(
+        lazy.resolve("hello.txt"),
+        Ok(Some(FSItem::File(_)))
+    ));
+    assert_eq!(
+        names(&lazy.get_root().unwrap()),
+        [&b"dir"[..], b"hello.txt", b"link"]
+    );
+    assert!(lazy.fs().unwrap().uuid().is_none());
+}
+
+#[test]
+fn test_precache() {
+    let dir = make_tree(&["a", "sub/b", "sub/deep/c", "other/d"]);
+    let mut out = Cursor::new(Vec::new());
+    write_image(dir.path(), &mut out, None, EncryptionType::None).unwrap();
+    let reads = Arc::new(AtomicUsize::new(0));
+    let img = CountingReadAt {
+        inner: Cursor::new(out.into_inner()),
+        reads: reads.clone(),
+    };
+    let fs = FS::open(img, None).unwrap();
+    fs.precache(&[&b"sub/deep/c"[..], b"/a", b"missing/x"])
+        .unwrap();
+
+    let before = reads.load(AtomicOrdering::SeqCst);
+    for path in ["sub/deep/c", "/a", "sub/deep/../deep/c"] {
+        assert!(fs.resolve(path).unwrap().is_some());
+    }
+    assert_eq!(reads.load(AtomicOrdering::SeqCst), before);
+    // Other paths still read the image
+    assert!(fs.resolve("other/d").unwrap().is_some());
+    assert!(reads.load(AtomicOrdering::SeqCst) > before);
+}
+
+#[test]
+fn test_cache() {
+    let dir = make_tree(&["a", "sub/b", "sub/deep/c", "other/d"]);
+    let mut out = Cursor::new(Vec::new());
+    write_image(dir.path(), &mut out, None, EncryptionType::None).unwrap();
+    let data = out.into_inner();
+    let resolve_reads = |cache_capacity| {
+        let reads = Arc::new(AtomicUsize::new(0));
+        let img = CountingReadAt {
+            inner: Cursor::new(data.clone()),
+            reads: reads.clone(),
+        };
+        let opts = FsOptions {
+            cache_capacity,
+            ..Default::default()
+        };
+        let fs = FS::open_with(img, None, &opts).unwrap();
+        assert!(fs.resolve("sub/deep/c").unwrap().is_some());
+        let before = reads.load(AtomicOrdering::SeqCst);
+        assert!(fs.resolve("sub/deep/c").unwrap().is_some());
+        reads.load(AtomicOrdering::SeqCst) - before
+    };
+
+    assert_eq!(resolve_reads(16), 0);
+    // Capacity 0 disables caching
+    assert!(resolve_reads(0) > 0);
+}
+
+#[test]
+fn test_resolve_entry() {
+    let fs = open_dir("test_data/small");
+    let root = fs.get_root().unwrap();
+    let name_type = |path: &str| {
+        root.resolve_entry(path).unwrap().map(|e| {
+            (e.file_name().unwrap().into_bytes(), e.file_type().unwrap())
+        })
+    };
+
+    let (name, ty) = name_type("dir/nested.txt").unwrap();
+    assert_eq!(name, b"nested.txt");
+    assert!(ty.is_file());
+    let (name, ty) = name_type("/dir/sub/").unwrap();
+    assert_eq!(name, b"sub");
+    assert!(ty.is_dir());
+    // symlinks are followed
+    let (name, ty) = name_type("link").unwrap();
+    assert_eq!(name, b"hello.txt");
+    assert!(ty.is_file());
+
+    assert!(name_type("missing").is_none());
+    assert!(name_type("missing/file").is_none());
+    for path in ["", "/", "dir/..", "."] {
+        assert!(root.resolve_entry(path).is_err(), "{}", path);
+    }
+    assert!(root.resolve_entry("hello.txt/x").is_err());
+}
+
+#[test]
+fn test_get_by_name() {
+    let fs = open_dir("test_data/small");
+    let root = fs.get_root().unwrap();
+    let ent = root.get_by_name("dir").unwrap().unwrap();
+    assert_eq!(ent.file_name().unwrap().as_bytes(), b"dir");
+    assert!(ent.file_type().unwrap().is_dir());
+    // symlinks are not followed
+    let ent = root.get_by_name(b"link").unwrap().unwrap();
+    assert!(ent.file_type().unwrap().is_symlink());
+
+    assert!(root.get_by_name("missing").unwrap().is_none());
+    assert!(root.get_by_name("..").unwrap().is_none());
+    for name in [&b"dir/nested.txt"[..], b"/", b"a\0b"] {
+        assert!(matches!(
+            root.get_by_name(name),
+            Err(crate::Error::InvalidOperation(_))
+        ));
+    }
+}
+
+#[test]
+fn test_subtree_size() {
+    let dir = make_tree(&["a", "sub/bb", "sub/deeper/ccc", "sub/empty/x"]);
+    std::fs::remove_file(dir.path().join("sub/empty/x")).unwrap();
+    std::os::unix::fs::symlink("a", dir.path().join("sub/link")).unwrap();
+    let opts = WriteOptions {
+        subtree_sizes: true,
+        ..Default::default()
+    };
+    let fs = open_dir_with(dir.path(), &opts);
+    let walk_size = |d: &crate::fs::Directory| -> u64 {
+        d.walk()
+            .map(|e| e.unwrap().1.metadata().unwrap())
+            .filter(|m| m.is_file())
+            .map(|m| m.size())
+            .sum()
+    };
+    let root = fs.get_root().unwrap();
+    assert_eq!(root.subtree_size().unwrap(), Some(walk_size(&root)));
+    assert_eq!(root.subtree_size().unwrap(), Some(1 + 6 + 14));
+    for (path, _) in root.walk().map(|e| e.unwrap()) {
+        if let Some(FSItem::Directory(d)) = root.resolve(&path).unwrap() {
+            assert_eq!(d.subtree_size().unwrap(), Some(walk_size(&d)));
+        }
+    }
+
+    let fs = open_dir(dir.path());
+    assert_eq!(fs.get_root().unwrap().subtree_size().unwrap(), None);
+}
+
+#[test]
+fn test_mode() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = make_tree(&["script", "secret", "sub/file"]);
+    for (path, mode) in [("script", 0o755), ("secret", 0o600), ("sub", 0o750)] {
+        let perms = std::fs::Permissions::from_mode(mode);
+        std::fs::set_permissions(dir.path().join(path), perms).unwrap();
+    }
+    let fs = open_dir(dir.path());
+    assert_eq!(
+        get_file(&fs, "script").metadata().unwrap().mode(),
+        Some(0o755)
+    );
+    assert_eq!(
+        get_file(&fs, "secret").metadata().unwrap().mode(),
+        Some(0o600)
+    );
+    match fs.resolve("sub").unwrap() {
+        Some(FSItem::Directory(d)) => {
+            assert_eq!(d.metadata().unwrap().mode(), Some(0o750))
+        }
+        _ => panic!("sub is not a directory"),
+    }
+
+    let out = tempfile::tempdir().unwrap();
+    extract_fs(&fs, &out.path(), &ExtractOptions::default()).unwrap();
+    for (path, mode) in [("script", 0o755), ("secret", 0o600), ("sub", 0o750)] {
+        let meta = std::fs::metadata(out.path().join(path)).unwrap();
+        assert_eq!(meta.permissions().mode() & 0o7777, mode, "{}", path);
+    }
+
+    // Older images don't have it
+    let fs = FS::open_file("test_data/small.sqh", None).unwrap();
+    assert_eq!(get_file(&fs, "hello.txt").metadata().unwrap().mode(), None);
+}
+
+#[test]
+fn test_set_metadata() {
+    use crate::{set_metadata, MetadataUpdate};
+
+    let dir = make_tree(&["a", "sub/b"]);
+    std::os::unix::fs::symlink("a", dir.path().join("link")).unwrap();
+    let out = tempfile::tempdir().unwrap();
+    let image = out.path().join("image.sqh");
+    let opts = WriteOptions {
+        integrity: true,
+        ..Default::default()
+    };
+    crate::write_image_file_with(
+        &dir.path(),
+        &image,
+        None,
+        EncryptionType::None,
+        &opts,
+    )
+    .unwrap();
+    let before = crate::open_image_file(&image, None).unwrap();
+    let extent = get_file(&before, "a").data_extent().unwrap();
+
+    let mode = MetadataUpdate {
+        mode: Some(0o600),
+        ..Default::default()
+    };
+    set_metadata(&image, "a", &mode).unwrap();
+    let fs = crate::open_image_file(&image, None).unwrap();
+    let a = get_file(&fs, "a");
+    assert_eq!(a.metadata().unwrap().mode(), Some(0o600));
+    // Only the mode changed
+    let old = get_file(&before, "a").metadata().unwrap();
+    assert_eq!(
+        a.metadata().unwrap().modified().unwrap(),
+        old.modified().unwrap()
+    );
+    assert_eq!(a.uid(), get_file(&before, "a").uid());
+    assert_eq!(a.data_extent().unwrap(), extent);
+    assert_eq!(read_all(&a), b"a");
+    assert_eq!(read_all(&get_file(&fs, "link")), b"a");
+    assert!(fs.verify_integrity().unwrap());
+
+    // The symlink itself, a directory and the root
+    let owner = MetadataUpdate {
+        mtime: Some(1234567890),
+        uid: Some(1000),
+        gid: Some(100),
+        ..Default::default()
+    };
+    for path in ["link", "sub/", "/"] {
+        set_metadata(&image, path, &owner).unwrap();
+    }
+    let fs = crate::open_image_file(&image, None).unwrap();
+    let link = match fs.resolve_nofollow("link").unwrap() {
+        Some(FSItem::Symlink(l)) => l.metadata().unwrap(),
+        _ => panic!("link is not a symlink"),
+    };
+    let sub = match fs.resolve("sub").unwrap() {
+        Some(FSItem::Directory(d)) => d.metadata().unwrap(),
+        _ => panic!("sub is not a directory"),
+    };
+    let root = fs.get_root().unwrap().metadata().unwrap();
+    for meta in [link, sub, root] {
+        assert_eq!((meta.uid(), meta.gid()), (Some(1000), Some(100)));
+        let mtime = meta.modified().unwrap();
+        assert_eq!(
+            mtime
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            1234567890
+        );
+    }
+    assert_eq!(read_all(&get_file(&fs, "sub/b")), b"sub/b");
+    assert_eq!(get_file(&fs, "a").metadata().unwrap().mode(), Some(0o600));
+    assert!(fs.verify_integrity().unwrap());
+
+    assert!(set_metadata(&image, "missing", &mode).is_err());
+    let bad = MetadataUpdate {
+        mode: Some(0o100644),
+        ..Default::default()
+    };
+    assert!(set_metadata(&image, "a", &bad).is_err());
+    let key = [7; crate::CHACHA20_KEY_LEN];
+    let encrypted = out.path().join("encrypted.sqh");
+    crate::write_image_file(
+        &dir.path(),
+        &encrypted,
+        Some(&key[..]),
+        EncryptionType::ChaCha20,
+    )
+    .unwrap();
+    assert!(matches!(
+        set_metadata(&encrypted, "a", &mode),
+        Err(crate::Error::InvalidOperation(_))
+    ));
+}
+
+#[test]
+fn test_split() {
+    let dir = tempfile::tempdir().unwrap();
+    let text = "split data\n".repeat(20000);
+    std::fs::create_dir(dir.path().join("sub")).unwrap();
+    std::fs::write(dir.path().join("big.txt"), &text).unwrap();
+    std::fs::write(dir.path().join("sub/small.txt"), "small\n").unwrap();
+    std::os::unix::fs::symlink("big.txt", dir.path().join("link")).unwrap();
+
+    let key = [7; crate::CHACHA20_KEY_LEN];
+    let mut comps = vec![crate::CompressionType::None];
+    if cfg!(feature = "zstd") {
+        comps.push(crate::CompressionType::Zstd);
+    }
+    for comp in comps {
+        for (enc, key) in [
+            (EncryptionType::None, None),
+            (EncryptionType::ChaCha20, Some(&key[..])),
+        ] {
+            let opts = WriteOptions {
+                compression: comp,
+                ..Default::default()
+            };
+            let mut meta = Cursor::new(Vec::new());
+            let mut data = Cursor::new(Vec::new());
+            let summary = crate::write_image_split_with(
+                dir.path(),
+                &mut meta,
+                &mut data,
+                key,
+                enc,
+                &opts,
+            )
+            .unwrap();
+            let (meta, data) = (meta.into_inner(), data.into_inner());
+            assert_eq!(summary.image_size, meta.len() as u64);
+            assert_eq!(summary.data_size, data.len() as u64);
+            // No file data in the metadata
+            assert!(meta.len() < 2000);
+
+            assert!(FS::open(Cursor::new(meta.clone()), key).is_err());
+            let fs = FS::open_split(Cursor::new(meta), Cursor::new(data), key)
+                .unwrap();
+            for (path, contents) in [
+                ("big.txt", text.as_bytes()),
+                ("sub/small.txt", b"small\n"),
+                ("link", text.as_bytes()),
+            ] {
+                let f = get_file(&fs, path);
+                let mut buf = vec![0; contents.len()];
+                f.read_exact_at(&mut buf, 0).unwrap();
+                assert_eq!(buf, contents);
+            }
+        }
+    }
+
+    // Regular images have no data stream
+    let mut out = Cursor::new(Vec::new());
+    write_image(dir.path(), &mut out, None, EncryptionType::None).unwrap();
+    let res = FS::open_split(out, Cursor::new(Vec::new()), None);
+    assert!(res.is_err());
+}
+
+#[test]
+fn test_cancel() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    let dir = tempfile::tempdir().unwrap();
+    let data: Vec<u8> = (0..1_000_000u32).map(|i| i as u8).collect();
+    std::fs::write(dir.path().join("big"), &data).unwrap();
+    let fs = open_dir(dir.path());
+    let f = get_file(&fs, "big");
+
+    let mut out = Vec::new();
+    assert_eq!(f.read_range(10..100_010, &mut out, None).unwrap(), 100_000);
+    assert_eq!(out, &data[10..100_010]);
+    assert!(f.read_range(0..1_000_001, &mut out, None).is_err());
+
+    // Set the flag from the writer after the first chunk
+    struct Cancelling<'a>(&'a AtomicBool, Vec<u8>);
+    impl std::io::Write for Cancelling<'_> {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.1.extend_from_slice(buf);
+            self.0.store(true, Ordering::Relaxed);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    let cancel = AtomicBool::new(false);
+    let mut out = Cancelling(&cancel, Vec::new());
+    match f.read_range(0..f.size(), &mut out, Some(&cancel)) {
+        Err(crate::Error::InvalidOperation("cancelled")) => (),
+        r => panic!("unexpected result: {:?}", r),
+    }
+    assert!(!out.1.is_empty() && out.1.len() < data.len());
+    assert_eq!(out.1, &data[..out.1.len()]);
+
+    let opts = ExtractOptions {
+        cancel: Some(Arc::new(AtomicBool::new(true))),
+        ..Default::default()
+    };
+    let target = tempfile::tempdir().unwrap();
+    assert!(extract_fs(&fs, &target.path(), &opts).is_err());
+    assert!(!target.path().join("big").exists());
+}
+
+#[test]
+fn test_extract_hardlinks() {
+    use std::os::unix::fs::MetadataExt;
+
+    let names: Vec<String> = (0..1000).map(|i| format!("f{:04}", i)).collect();
+    let names: Vec<&str> = names.iter().map(|n| n.as_str()).collect();
+    let dir = make_tree(&names);
+    let mut out = Cursor::new(Vec::new());
+    write_image(dir.path(), &mut out, None, EncryptionType::None).unwrap();
+    let mut img = out.into_inner();
+
+    // Point all the entries of the root to the inode of the first one
+    let u64_at = |img: &[u8], off: usize| {
+        u64::from_le_bytes(img[off..off + 8].try_into().unwrap()) as usize
+    };
+    let root = u64_at(&img, 8);
+    let dirents = u64_at(&img, root + 8);
+    let first = img[dirents + 8..dirents + 16].to_vec();
+    for i in 1..names.len() {
+        let off = dirents + i * 16 + 8;
+        img[off..off + 8].copy_from_slice(&first);
+    }
+    let fs = FS::open(Cursor::new(img), None).unwrap();
+
+    let target = tempfile::tempdir().unwrap();
+    let summary =
+        extract_fs(&fs, &target.path(), &ExtractOptions::default()).unwrap();
+    assert_eq!(summary.files, 1);
+    assert_eq!(summary.hardlinks, 999);
+    let meta = std::fs::metadata(target.path().join("f0999")).unwrap();
+    assert_eq!(meta.nlink(), 1000);
+    assert_eq!(
+        std::fs::read(target.path().join("f0999")).unwrap(),
+        b"f0000"
+    );
+}
+
+#[test]
+fn test_verify() {
+    let dir = make_tree(&["a", "b", "sub/c"]);
+    let report = crate::verify(&open_dir(dir.path())).unwrap();
+    assert!(report.is_ok());
+    assert_eq!(report.entries, 4);
+
+    let mut out = Cursor::new(Vec::new());
+    write_image(dir.path(), &mut out, None, EncryptionType::None).unwrap();
+    let mut img = out.into_inner();
+    // Make b claim much more data than the image has
+    let u64_at = |img: &[u8], off: usize| {
+        u64::from_le_bytes(img[off..off + 8].try_into().unwrap()) as usize
+    };
+    let root = u64_at(&img, 8);
+    let dirents = u64_at(&img, root + 8);
+    let b = u64_at(&img, dirents + 16 + 8);
+    img[b + 16..b + 24].copy_from_slice(&(1u64 << 40).to_le_bytes());
+
+    let fs = FS::open(Cursor::new(img), None).unwrap();
+    let report = crate::verify(&fs).unwrap();
+    assert!(!report.is_ok());
+    assert_eq!(report.entries, 3);
+    assert_eq!(report.failures.len(), 1);
+    assert_eq!(report.failures[0].0, b"b");
+}
+
+#[test]
+fn test_directory_cycle() {
+    let dir = make_tree(&["a/b/file"]);
+    let mut out = Cursor::new(Vec::new());
+    write_image(dir.path(), &mut out, None, EncryptionType::None).unwrap();
+    let mut img = out.into_inner();
+    // Make a/b point back to a
+    let u64_at = |img: &[u8], off: usize| {
+        u64::from_le_bytes(img[off..off + 8].try_into().unwrap()) as usize
+    };
+    let root = u64_at(&img, 8);
+    let a = u64_at(&img, u64_at(&img, root + 8) + 8);
+    let a_dirents = u64_at(&img, a + 8);
+    img[a_dirents + 8..a_dirents + 16]
+        .copy_from_slice(&(a as u64).to_le_bytes());
+
+    let fs = FS::open(Cursor::new(img), None).unwrap();
+    fn cycle<T>(res: crate::Result<T>) -> bool {
+        matches!(
+            res.map_err(crate::Error::into_inner),
+            Err(crate::Error::Format("directory cycle"))
+        )
+    }
+    let root = fs.get_root().unwrap();
+    assert!(cycle(root.walk().collect::<crate::Result<Vec<_>>>()));
+    assert!(cycle(fs.glob("**/file")));
+    let target = tempfile::tempdir().unwrap();
+    let opts = ExtractOptions::default();
+    assert!(cycle(extract_fs(&fs, &target.path(), &opts)));
+    assert!(cycle(crate::dedup_report(&[&fs])));
+    let inodes = fs.walk_reachable_inodes().unwrap();
+    assert!(cycle(inodes.collect::<crate::Result<Vec<_>>>()));
+    assert!(!crate::verify(&fs).unwrap().is_ok());
+}
+
+#[test]
+fn test_verify_overlap() {
+    let dir = make_tree(&["a", "b"]);
+    let mut out = Cursor::new(Vec::new());
+    write_image(dir.path(), &mut out, None, EncryptionType::None).unwrap();
+    let mut img = out.into_inner();
+    // Make b start in the last byte of the block table of a
+    let u64_at = |img: &[u8], off: usize| {
+        u64::from_le_bytes(img[off..off + 8].try_into().unwrap()) as usize
+    };
+    let set_u64 = |img: &mut [u8], off: usize, val: usize| {
+        img[off..off + 8].copy_from_slice(&(val as u64).to_le_bytes());
+    };
+    let root = u64_at(&img, 8);
+    let dirents = u64_at(&img, root + 8);
+    let a = u64_at(&img, dirents + 8);
+    let b = u64_at(&img, dirents + 16 + 8);
+    let start = u64_at(&img, a + 8) + 15;
+    let table = u64_at(&img, b + 8);
+    let end = u64_at(&img, table + 8);
+    set_u64(&mut img, table, start);
+    set_u64(&mut img, b + 16, end - start);
+
+    let fs = FS::open(Cursor::new(img), None).unwrap();
+    let report = crate::verify(&fs).unwrap();
+    assert_eq!(report.entries, 2);
+    assert_eq!(report.failures.len(), 1);
+    assert!(matches!(
+        report.failures[0].1,
+        crate::Error::Format("overlapping inode data")
+    ));
+}
+
+#[test]
+fn test_integrity() {
+    let dir = make_tree(&["a", "sub/b"]);
+    let key = [7u8; crate::CHACHA20_KEY_LEN];
+    let image = dir.path().join("image.sqh");
+    let opts = WriteOptions {
+        integrity: true,
+        ..Default::default()
+    };
+    let src = dir.path().join("sub");
+    crate::write_image_file_with(
+        &src,
+        &image,
+        Some(&key),
+        EncryptionType::ChaCha20,
+        &opts,
+    )
+    .unwrap();
+    let fs = crate::open_image_file(&image, Some(&key)).unwrap();
+    assert!(fs.header().digest().is_some());
+    assert!(fs.verify_integrity().unwrap());
+
+    // The ciphertext is hashed, so no key is needed
+    let mut img = std::fs::read(&image).unwrap();
+    assert!(crate::verify_integrity(&Cursor::new(img.clone())).unwrap());
+    let last = img.len() - 1;
+    img[last] ^= 1;
+    assert!(!crate::verify_integrity(&Cursor::new(img.clone())).unwrap());
+    img[last] ^= 1;
+    img[8] ^= 1;
+    assert!(!crate::verify_integrity(&Cursor::new(img)).unwrap());
+
+    // Other outputs are sealed after writing
+    let mut out = Cursor::new(Vec::new());
+    assert!(matches!(
+        write_image_with(&src, &mut out, None, EncryptionType::None, &opts),
+        Err(crate::Error::InvalidOperation(_))
+    ));
+    write_image(&src, &mut out, None, EncryptionType::None).unwrap();
+    assert!(matches!(
+        open_dir(&src).verify_integrity(),
+        Err(crate::Error::InvalidOperation(_))
+    ));
+    let digest = crate::seal_image(&mut out).unwrap();
+    let fs = FS::open(out, None).unwrap();
+    assert_eq!(fs.header().digest(), Some(digest));
+    assert!(fs.verify_integrity().unwrap());
+}
+
+#[test]
+fn test_random_nonce() {
+    let dir = make_tree(&["a", "sub/b"]);
+    let key = [7u8; crate::CHACHA20_KEY_LEN];
+    let write = |opts: &WriteOptions| {
+        let mut out = Cursor::new(Vec::new());
+        write_image_with(
+            dir.path(),
+            &mut out,
+            Some(&key),
+            EncryptionType::ChaCha20,
+            opts,
+        )
+        .map(|_| out.into_inner())
+    };
+    // The bodies only differ by the keystream
+    let opts = WriteOptions {
+        clamp_mtime: Some(0),
+        ..Default::default()
+    };
+    let (a, b) = (write(&opts).unwrap(), write(&opts).unwrap());
+    let header_len = 144;
+    assert_ne!(a[header_len..], b[header_len..]);
+    for img in [a, b] {
+        let fs = FS::open(Cursor::new(img), Some(&key)).unwrap();
+        assert!(fs.header().nonce().is_some());
+        assert_eq!(read_all(&get_file(&fs, "sub/b")), b"sub/b");
+    }
+
+    // Deterministic images derive their nonce from their contents, which
+    // they are read back to be encrypted with
+    let opts = WriteOptions {
+        deterministic: true,
+        ..opts
+    };
+    assert!(matches!(
+        write(&opts),
+        Err(crate::Error::InvalidOperation(_))
+    ));
+    let out = tempfile::tempdir().unwrap();
+    let write_file = |name: &str| {
+        let image = out.path().join(name);
+        crate::write_image_file_with(
+            &dir.path(),
+            &image,
+            Some(&key),
+            EncryptionType::ChaCha20,
+            &opts,
+        )
+        .unwrap();
+        std::fs::read(image).unwrap()
+    };
+    let img = write_file("a.sqh");
+    assert_eq!(img, write_file("b.sqh"));
+    let fs = FS::open(Cursor::new(img.clone()), Some(&key)).unwrap();
+    let nonce = fs.header().nonce().unwrap();
+    assert_eq!(read_all(&get_file(&fs, "a")), b"a");
+    assert_eq!(read_all(&get_file(&fs, "sub/b")), b"sub/b");
+    assert!(!img.windows(5).any(|w| w == b"sub/b"));
+
+    std::fs::write(dir.path().join("a"), "b").unwrap();
+    let fs = FS::open(Cursor::new(write_file("c.sqh")), Some(&key)).unwrap();
+    assert_ne!(fs.header().nonce().unwrap(), nonce);
+    assert_eq!(read_all(&get_file(&fs, "a")), b"b");
+}
+
+#[test]
+fn test_passphrase() {
+    let dir = make_tree(&["a", "sub/b"]);
+    let image = dir.path().join("image.sqh");
+    let salt = crate::new_salt().unwrap();
+    let key = crate::derive_key(b"correct horse", &salt).unwrap();
+    let opts = WriteOptions {
+        key_salt: Some(salt),
+        ..Default::default()
+    };
+    let src = dir.path().join("sub");
+    crate::write_image_file_with(
+        &src,
+        &image,
+        Some(&key),
+        EncryptionType::ChaCha20,
+        &opts,
+    )
+    .unwrap();
+    let fs = FS::open_file_with_passphrase(&image, b"correct horse").unwrap();
+    assert_eq!(fs.header().key_salt(), Some(salt));
+    assert_eq!(read_all(&get_file(&fs, "b")), b"sub/b");
+    // The derived key works as any other key
+    assert!(crate::open_image_file(&image, Some(&key)).is_ok());
+
+    // Only encrypted images have a salt
+    let mut out = Cursor::new(Vec::new());
+    assert!(matches!(
+        write_image_with(&src, &mut out, None, EncryptionType::None, &opts),
+        Err(crate::Error::InvalidOperation(_))
+    ));
+    assert!(matches!(
+        FS::open_file_with_passphrase("test_data/small.sqh", b"x"),
+        Err(crate::Error::InvalidOperation(_))
+    ));
+}
+
+#[test]
+fn test_aead() {
+    let dir = make_tree(&["a", "sub/b"]);
+    let image = dir.path().join("image.sqh");
+    let key = [7u8; crate::XCHACHA20_POLY1305_KEY_LEN];
+    let src = dir.path().join("sub");
+    crate::write_image_file_with(
+        &src,
+        &image,
+        Some(&key),
+        EncryptionType::XChaCha20Poly1305,
+        &WriteOptions::default(),
+    )
+    .unwrap();
+    let fs = crate::open_image_file(&image, Some(&key)).unwrap();
+    assert_eq!(read_all(&get_file(&fs, "b")), b"sub/b");
+
+    // The root inode is written last
+    let mut img = std::fs::read(&image).unwrap();
+    *img.last_mut().unwrap() ^= 1;
+    assert!(matches!(
+        FS::open(Cursor::new(img), Some(&key))
+            .map_err(crate::Error::into_inner),
+        Err(crate::Error::Crypto(_))
+    ));
+
+    // Blocks are read back while writing
+    let mut out = Cursor::new(Vec::new());
+    assert!(matches!(
+        write_image_with(
+            &src,
+            &mut out,
+            Some(&key),
+            EncryptionType::XChaCha20Poly1305,
+            &WriteOptions::default(),
+        ),
+        Err(crate::Error::InvalidOperation(_))
+    ));
+}
+
+#[test]
+fn test_aead_block_size() {
+    let dir = make_tree(&["a", "sub/b"]);
+    let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+    std::fs::write(dir.path().join("big"), &data).unwrap();
+    let out = tempfile::tempdir().unwrap();
+    let key = [7u8; crate::XCHACHA20_POLY1305_KEY_LEN];
+    let write = |name: &str, size: u64, enc_type: EncryptionType| {
+        let opts = WriteOptions {
+            encryption_block_size: size,
+            ..Default::default()
+        };
+        let image = out.path().join(name);
+        crate::write_image_file_with(
+            &dir.path(),
+            &image,
+            Some(&key),
+            enc_type,
+            &opts,
+        )
+        .map(|_| image)
+    };
+    let mut sizes = Vec::new();
+    for size in [512, 4096, 65536] {
+        let image = write("image.sqh", size, EncryptionType::XChaCha20Poly1305)
+            .unwrap();
+        sizes.push(std::fs::metadata(&image).unwrap().len());
+        let fs = crate::open_image_file(&image, Some(&key)).unwrap();
+        assert_eq!(fs.header().block_size(), size);
+        assert_eq!(read_all(&get_file(&fs, "sub/b")), b"sub/b");
+        let mut buf = vec![0; 5000];
+        get_file(&fs, "big")
+            .read_exact_at(&mut buf, 70_000)
+            .unwrap();
+        assert_eq!(buf, &data[70_000..75_000]);
+        let reqs =
+            crate::probe_image(&std::fs::File::open(&image).unwrap()).unwrap();
+        assert_eq!(
+            reqs.iter()
+                .any(|r| r.name.starts_with("encryption block size")),
+            size != 4096
+        );
+    }
+    // Larger blocks take less space
+    assert!(sizes[0] > sizes[1] && sizes[1] > sizes[2]);
+
+    for size in [0, 1000, 2 << 20] {
+        assert!(matches!(
+            write("bad.sqh", size, EncryptionType::XChaCha20Poly1305),
+            Err(crate::Error::InvalidOperation(_))
+        ));
+    }
+    assert!(matches!(
+        write("chacha.sqh", 512, EncryptionType::ChaCha20),
+        Err(crate::Error::InvalidOperation(_))
+    ));
+
+    // A block size that was not written is refused when read
+    let image =
+        write("image.sqh", 512, EncryptionType::XChaCha20Poly1305).unwrap();
+    let mut img = std::fs::read(&image).unwrap();
+    img[144..148].copy_from_slice(&1000u32.to_le_bytes());
+    assert!(matches!(
+        FS::open(Cursor::new(img), Some(&key))
+            .map_err(crate::Error::into_inner),
+        Err(crate::Error::Format("invalid encryption block size"))
+    ));
+}
+
+#[test]
+fn test_checksums() {
+    let dir = make_tree(&["a"]);
+    std::fs::write(dir.path().join("a"), "123456789").unwrap();
+    let data: Vec<u8> = (0..10000u32).map(|i| (i % 251) as u8).collect();
+    std::fs::write(dir.path().join("big"), &data).unwrap();
+    let opts = WriteOptions {
+        checksums: true,
+        ..Default::default()
+    };
+    let mut out = Cursor::new(Vec::new());
+    write_image_with(dir.path(), &mut out, None, EncryptionType::None, &opts)
+        .unwrap();
+    let mut img = out.into_inner();
+
+    let fs = FS::open(Cursor::new(img.clone()), None).unwrap();
+    assert_eq!(get_file(&fs, "a").checksum(), Some(0xcbf43926));
+    assert!(get_file(&fs, "big").verify().unwrap());
+    assert_eq!(get_file(&open_dir(dir.path()), "a").checksum(), None);
+
+    // Flip a byte in the middle of big
+    let f = get_file(&fs, "big");
+    let offset = f.data_extent().unwrap().start as usize;
+    img[offset + 5000] ^= 1;
+    let fs = FS::open(Cursor::new(img.clone()), None).unwrap();
+    let f = get_file(&fs, "big");
+    assert!(!f.verify().unwrap());
+    assert!(!crate::verify(&fs).unwrap().is_ok());
+    // Not checked without FsOptions::verify_checksums
+    let mut buf = Vec::new();
+    io::Read::read_to_end(&mut f.clone(), &mut buf).unwrap();
+
+    let opts = FsOptions {
+        verify_checksums: true,
+        ..Default::default()
+    };
+    let fs = FS::open_with(Cursor::new(img), None, &opts).unwrap();
+    let f = get_file(&fs, "big");
+    // Partial and out of order reads aren't checked
+    let mut buf = vec![0; 6000];
+    f.read_exact_at(&mut buf, 0).unwrap();
+    f.read_exact_at(&mut buf[..4000], 5000).unwrap();
+    f.read_exact_at(&mut buf[..1000], 9000).unwrap();
+    f.read_exact_at(&mut buf, 0).unwrap();
+    assert!(matches!(
+        f.read_exact_at(&mut buf[..4000], 6000),
+        Err(crate::Error::Format("file checksum mismatch"))
+    ));
+    let mut buf = Vec::new();
+    let err = io::Read::read_to_end(&mut f.clone(), &mut buf).unwrap_err();
+    assert!(err.to_string().contains("checksum"));
+    let mut buf = Vec::new();
+    io::Read::read_to_end(&mut get_file(&fs, "a"), &mut buf).unwrap();
+    assert_eq!(buf, b"123456789");
+    // Nor are whole files read in chunks
+    assert!(!f.verify().unwrap());
+    let mut out = Vec::new();
+    assert!(matches!(
+        f.read_range(0..f.size(), &mut out, None),
+        Err(crate::Error::Format("file checksum mismatch"))
+    ));
+    f.read_range(0..5000, &mut out, None).unwrap();
+    let target = tempfile::tempdir().unwrap();
+    let res = extract_fs(&fs, &target.path(), &ExtractOptions::default());
+    assert!(matches!(
+        res.map_err(crate::Error::into_inner),
+        Err(crate::Error::Format("file checksum mismatch"))
+    ));
+}
+
+#[cfg(all(feature = "parallel", feature = "zstd"))]
+#[test]
+fn test_parallel_same_bytes() {
+    let dir = make_tree(&["a", "sub/b", "sub/deep/c", "other/d"]);
+    let data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+    std::fs::write(dir.path().join("big"), &data).unwrap();
+    std::fs::write(dir.path().join("sub/big2"), &data).unwrap();
+    std::fs::write(dir.path().join("sub/huge"), [&data[..], &data].concat())
+        .unwrap();
+    std::fs::hard_link(dir.path().join("a"), dir.path().join("sub/a2"))
+        .unwrap();
+    for i in 0..100 {
+        std::fs::write(dir.path().join(format!("f{}", i)), i.to_string())
+            .unwrap();
+    }
+    let write = |opts: &WriteOptions| {
+        let mut out = Cursor::new(Vec::new());
+        write_image_with(
+            dir.path(),
+            &mut out,
+            None,
+            EncryptionType::None,
+            opts,
+        )
+        .unwrap();
+        out.into_inner()
+    };
+    for compression in
+        [crate::CompressionType::None, crate::CompressionType::Zstd]
+    {
+        let opts = WriteOptions {
+            compression,
+            merkle: true,
+            dedup: true,
+            checksums: true,
+            deterministic: true,
+            max_file_size: Some(500_000),
+            ..Default::default()
+        };
+        assert!(opts.parallel);
+        let serial = WriteOptions {
+            parallel: false,
+            ..opts.clone()
+        };
+        assert_eq!(write(&opts), write(&serial));
+    }
+}
+
+#[cfg(not(feature = "parallel"))]
+#[test]
+fn test_parallel_unsupported() {
+    let dir = make_tree(&["a"]);
+    let opts = WriteOptions {
+        parallel: true,
+        ..Default::default()
+    };
+    let res = write_image_with(
+        dir.path(),
+        Cursor::new(Vec::new()),
+        None,
+        EncryptionType::None,
+        &opts,
+    );
+    assert!(matches!(res, Err(crate::error::Error::InvalidOperation(_))));
+}
+
+#[test]
+fn test_merkle() {
+    let dir = make_tree(&["sub/small"]);
+    let data: Vec<u8> = (0..10000u32).map(|i| (i % 251) as u8).collect();
+    std::fs::write(dir.path().join("big"), &data).unwrap();
+    std::os::unix::fs::symlink("big", dir.path().join("link")).unwrap();
+    let opts = WriteOptions {
+        merkle: true,
+        deterministic: true,
+        ..Default::default()
+    };
+    let mut out = Cursor::new(Vec::new());
+    write_image_with(dir.path(), &mut out, None, EncryptionType::None, &opts)
+        .unwrap();
+    let mut img = out.into_inner();
+
+    let fs = FS::open(Cursor::new(img.clone()), None).unwrap();
+    let root_hash = fs.root_hash().unwrap();
+    let f = get_file(&fs, "big");
+    assert!(f.root_hash().unwrap().is_some());
+    let mut buf = vec![0; data.len()];
+    f.read_exact_at(&mut buf, 0).unwrap();
+    assert_eq!(buf, data);
+    assert!(crate::verify(&fs).unwrap().is_ok());
+
+    // The root hash only depends on the tree
+    let fs = open_dir_with(dir.path(), &opts);
+    assert_eq!(fs.root_hash(), Some(root_hash));
+    std::fs::write(dir.path().join("sub/small"), "changed").unwrap();
+    let fs = open_dir_with(dir.path(), &opts);
+    assert_ne!(fs.root_hash(), Some(root_hash));
+
+    // Flip a byte in the second block of big
+    let u64_at = |img: &[u8], off: usize| {
+        u64::from_le_bytes(img[off..off + 8].try_into().unwrap()) as usize
+    };
+    let root = u64_at(&img, 8);
+    let dirents = u64_at(&img, root + 8);
+    let big = u64_at(&img, dirents + 8);
+    // The block table starts with the offset of the contents
+    let offset = u64_at(&img, u64_at(&img, big + 8));
+    img[offset + 5000] ^= 1;
+
+    let fs = FS::open(Cursor::new(img), None).unwrap();
+    let f = get_file(&fs, "big");
+    let mut buf = vec![0; 4096];
+    f.read_exact_at(&mut buf, 0).unwrap();
+    f.read_exact_at(&mut buf[..100], 9000).unwrap();
+    match f.read_exact_at(&mut buf[..10], 4100) {
+        Err(crate::Error::Crypto("block verification failed")) => (),
+        r => panic!("unexpected result: {:?}", r),
+    }
+    let report = crate::verify(&fs).unwrap();
+    assert_eq!(report.failures.len(), 1);
+    assert_eq!(report.failures[0].0, b"big");
+
+    // Compressed and split data work the same
+    if cfg!(feature = "zstd") {
+        let opts = WriteOptions {
+            merkle: true,
+            compression: crate::CompressionType::Zstd,
+            ..Default::default()
+        };
+        let fs = open_dir_with(dir.path(), &opts);
+        let mut buf = vec![0; 5000];
+        get_file(&fs, "big").read_exact_at(&mut buf, 3000).unwrap();
+        assert_eq!(buf, &data[3000..8000]);
+        assert!(crate::verify(&fs).unwrap().is_ok());
+    }
+    let mut meta = Cursor::new(Vec::new());
+    let mut split = Cursor::new(Vec::new());
+    crate::write_image_split_with(
+        dir.path(),
+        &mut meta,
+        &mut split,
+        None,
+        EncryptionType::None,
+        &opts,
+    )
+    .unwrap();
+    let fs = FS::open_split(meta, split, None).unwrap();
+    assert!(crate::verify(&fs).unwrap().is_ok());
+
+    // Without the option there is nothing to check
+    let fs = open_dir(dir.path());
+    assert_eq!(fs.root_hash(), None);
+    assert_eq!(get_file(&fs, "big").root_hash().unwrap(), None);
+
+    // Contents swapped along with their hash tree pass the block checks
+    // but not the hashes of the directories up to the header
+    let dir = make_tree(&["aa", "sub/bb", "sub/cc"]);
+    let opts = WriteOptions {
+        merkle: true,
+        ..Default::default()
+    };
+    let mut out = Cursor::new(Vec::new());
+    write_image_with(dir.path(), &mut out, None, EncryptionType::None, &opts)
+        .unwrap();
+    let mut img = out.into_inner();
+    assert_ne!(img[20] & crate::INCOMPAT_MERKLE as u8, 0);
+    let root = u64_at(&img, 8);
+    let sub = u64_at(&img, u64_at(&img, root + 8) + 24);
+    let dirents = u64_at(&img, sub + 8);
+    let (bb, cc) = (u64_at(&img, dirents + 8), u64_at(&img, dirents + 24));
+    for (start, end) in [(8, 16), (48, 56), (72, 76)] {
+        img.copy_within(cc + start..cc + end, bb + start);
+    }
+    let fs = FS::open(Cursor::new(img), None).unwrap();
+    let mut buf = [0; 2];
+    get_file(&fs, "aa").read_exact_at(&mut buf, 0).unwrap();
+    assert_eq!(&buf, b"aa");
+    for path in ["sub/bb", "sub/cc"] {
+        match get_file(&fs, path).read_exact_at(&mut buf, 0) {
+            Err(e) => assert!(matches!(
+                e.inner(),
+                crate::Error::Crypto("directory hash verification failed")
+            )),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+}
+
+#[test]
+fn test_mtime() {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let dir = make_tree(&["old", "sub/new"]);
+    let set = |path: &str, secs: u64| {
+        let f = std::fs::File::open(dir.path().join(path)).unwrap();
+        f.set_modified(UNIX_EPOCH + Duration::from_secs(secs))
+            .unwrap();
+    };
+    set("old", 1_000_000_000);
+    set("sub/new", 2_000_000_000);
+    set("sub", 1_500_000_000);
+    let fs = open_dir(dir.path());
+    assert_eq!(
+        get_file(&fs, "old").modified().unwrap(),
+        UNIX_EPOCH + Duration::from_secs(1_000_000_000)
+    );
+
+    let out = tempfile::tempdir().unwrap();
+    extract_fs(&fs, &out.path(), &ExtractOptions::default()).unwrap();
+    for (path, secs) in [
+        ("old", 1_000_000_000),
+        ("sub/new", 2_000_000_000),
+        ("sub", 1_500_000_000),
+    ] {
+        let meta = std::fs::metadata(out.path().join(path)).unwrap();
+        let t = UNIX_EPOCH + Duration::from_secs(secs);
+        assert_eq!(meta.modified().unwrap(), t, "{}", path);
+    }
+
+    // Older images don't have it
+    let fs = FS::open_file("test_data/small.sqh", None).unwrap();
+    assert!(get_file(&fs, "hello.txt").modified().is_err());
+}
+
+#[test]
+fn test_owner() {
+    use std::os::unix::fs::MetadataExt;
+
+    let dir = make_tree(&["file", "sub/file"]);
+    let is_root = unsafe { libc::geteuid() } == 0;
+    if is_root {
+        std::os::unix::fs::chown(
+            dir.path().join("file"),
+            Some(1234),
+            Some(5678),
+        )
+        .unwrap();
+    }
+    let expected = std::fs::metadata(dir.path().join("file")).unwrap();
+    let fs = open_dir(dir.path());
+    let f = get_file(&fs, "file");
+    assert_eq!(f.uid(), Some(expected.uid()));
+    assert_eq!(f.gid(), Some(expected.gid()));
+    assert_eq!(f.metadata().unwrap().uid(), Some(expected.uid()));
+
+    // Without the option, extracted files belong to whoever extracts
+    let out = tempfile::tempdir().unwrap();
+    let mut summary =
+        extract_fs(&fs, &out.path(), &ExtractOptions::default()).unwrap();
+    assert_eq!(summary.unowned, 0);
+    let meta = std::fs::metadata(out.path().join("file")).unwrap();
+    assert_eq!(meta.uid(), unsafe { libc::geteuid() });
+
+    let opts = ExtractOptions {
+        preserve_owner: true,
+        ..Default::default()
+    };
+    let out = tempfile::tempdir().unwrap();
+    summary = extract_fs(&fs, &out.path(), &opts).unwrap();
+    let meta = std::fs::metadata(out.path().join("file")).unwrap();
+    if is_root {
+        assert_eq!(summary.unowned, 0);
+        assert_eq!((meta.uid(), meta.gid()), (1234, 5678));
+    } else {
+        assert_eq!(summary.unowned, 3);
+    }
+
+    // Older images don't have it
+    let fs = FS::open_file("test_data/small.sqh", None).unwrap();
+    assert_eq!(get_file(&fs, "hello.txt").uid(), None);
+}
+
+#[test]
+fn test_owner_map() {
+    use std::os::unix::fs::MetadataExt;
+
+    let is_root = unsafe { libc::geteuid() } == 0;
+    let dir = make_tree(&["root", "user"]);
+    if is_root {
+        let user = dir.path().join("user");
+        std::os::unix::fs::chown(user, Some(1000), Some(1001)).unwrap();
+        for p in [dir.path(), &dir.path().join("root")] {
+            std::os::unix::fs::chown(p, Some(0), Some(0)).unwrap();
+        }
+    }
+    let fs = open_dir(dir.path());
+    let extract = |uid_map, gid_map, unmapped_id| {
+        let opts = ExtractOptions {
+            preserve_owner: true,
+            uid_map,
+            gid_map,
+            unmapped_id,
+            ..Default::default()
+        };
+        let out = tempfile::tempdir().unwrap();
+        extract_fs(&fs, &out.path(), &opts).map(|summary| (summary, out))
+    };
+    let owner = |out: &tempfile::TempDir, name| {
+        let meta = std::fs::symlink_metadata(out.path().join(name)).unwrap();
+        (meta.uid(), meta.gid())
+    };
+
+    let (uid, gid) = (100000, 200000);
+    let uid_map = vec![(0, uid, 1000), (1000, uid + 5000, 1)];
+    let gid_map = vec![(0, gid, 65536)];
+    if !is_root {
+        let (summary, _) = extract(uid_map, gid_map, None).unwrap();
+        assert_eq!(summary.unowned, 2);
+        // Owners that would not be mapped are not restored either
+        let none = vec![(u32::MAX - 1, uid, 1)];
+        let (summary, _) = extract(none.clone(), none, None).unwrap();
+        assert_eq!(summary.unowned, 2);
+        return;
+    }
+    let (_, out) = extract(uid_map.clone(), gid_map.clone(), None).unwrap();
+    assert_eq!(owner(&out, "root"), (uid, gid));
+    assert_eq!(owner(&out, "user"), (uid + 5000, gid + 1001));
+
+    // 1000 is outside of the ranges
+    let uid_map = vec![(0, uid, 1000)];
+    assert!(matches!(
+        extract(uid_map.clone(), gid_map.clone(), None),
+        Err(crate::Error::InvalidOperation("uid is not in uid_map"))
+    ));
+    let (_, out) = extract(uid_map, gid_map, Some(65534)).unwrap();
+    assert_eq!(owner(&out, "root"), (uid, gid));
+    assert_eq!(owner(&out, "user"), (65534, gid + 1001));
+}
+
+#[test]
+fn test_uuid() {
+    let a = open_dir("test_data/small").uuid().unwrap();
+    let b = open_dir("test_data/small").uuid().unwrap();
+    assert_ne!(a, b);
+    // version 4
+    assert_eq!(a[6] >> 4, 4);
+    assert_eq!(a[8] >> 6, 2);
+
+    let opts = WriteOptions {
+        deterministic: true,
+        ..Default::default()
+    };
+    let a = open_dir_with("test_data/small", &opts).uuid().unwrap();
+    let b = open_dir_with("test_data/small", &opts).uuid().unwrap();
+    assert_eq!(a, b);
+    assert_eq!(a[6] >> 4, 8);
+    let dir = make_tree(&["other"]);
+    let c = open_dir_with(dir.path(), &opts).uuid().unwrap();
+    assert_ne!(a, c);
+
+    // Older images don't have one
+    let fs = FS::open_file("test_data/small.sqh", None).unwrap();
+    assert_eq!(fs.uuid(), None);
+}
+
+#[test]
+fn test_get_link() {
+    let dir = tempfile::tempdir().unwrap();
+    let target = "some/where/../over the rainbow";
+    std::os::unix::fs::symlink(target, dir.path().join("link")).unwrap();
+    let fs = open_dir(dir.path());
+    let ent = fs.get_root().unwrap().get(0).unwrap().unwrap();
+    match ent.item().unwrap() {
+        FSItem::Symlink(s) => {
+            assert_eq!(s.get_link().unwrap(), target.as_bytes())
+        }
+        _ => panic!("link is not a symlink"),
+    }
+}
+
+#[test]
+fn test_bad_links() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir(dir.path().join("sub")).unwrap();
+    std::os::unix::fs::symlink("sub", dir.path().join("good")).unwrap();
+    std::os::unix::fs::symlink("missing", dir.path().join("dangling")).unwrap();
+    std::os::unix::fs::symlink("loop", dir.path().join("loop")).unwrap();
+    let fs = open_dir(dir.path());
+
+    assert!(matches!(fs.resolve("good"), Ok(Some(FSItem::Directory(_)))));
+    assert!(matches!(fs.resolve("dangling"), Ok(None)));
+    assert!(matches!(
+        fs.resolve("loop").map_err(crate::Error::into_inner),
+        Err(crate::Error::LinkLoop)
+    ));
+}
+
+#[test]
+fn test_dedup_report() {
+    let a = tempfile::tempdir().unwrap();
+    std::fs::write(a.path().join("one"), "shared").unwrap();
+    std::fs::write(a.path().join("two"), "shared").unwrap();
+    std::fs::write(a.path().join("three"), "only in a").unwrap();
+    let b = tempfile::tempdir().unwrap();
+    std::fs::create_dir(b.path().join("sub")).unwrap();
+    std::fs::write(b.path().join("sub/one"), "shared").unwrap();
+    std::fs::write(b.path().join("four"), "only in b!").unwrap();
+    let (a, b) = (open_dir(a.path()), open_dir(b.path()));
+
+    let report = crate::dedup_report(&[&a, &b]).unwrap();
+    assert_eq!(report.total_bytes, 6 * 3 + 9 + 10);
+    assert_eq!(report.unique_bytes, 6 + 9 + 10);
+    assert_eq!(report.savings(), 12);
+    assert_eq!(
+        report.images,
+        [
+            crate::ImageDedup {
+                files: 3,
+                total_bytes: 21,
+                shared_bytes: 12,
+            },
+            crate::ImageDedup {
+                files: 2,
+                total_bytes: 16,
+                shared_bytes: 6,
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_walk() {
+    let fs = open_dir("test_data/small");
+    let entries: Vec<_> =
+        fs.get_root().unwrap().walk().map(|e| e.unwrap()).collect();
+    let paths: Vec<_> = entries.iter().map(|(p, _)| p.as_slice()).collect();
+    assert_eq!(
+        paths,
+        [
+            &b"dir"[..],
+            b"dir/nested.txt",
+            b"dir/sub",
+            b"dir/sub/.keep",
+            b"hello.txt",
+            b"link"
+        ]
+    );
+    let dirs: Vec<_> = entries
+        .iter()
+        .filter(|(_, e)| e.file_type().unwrap().is_dir())
+        .map(|(p, _)| p.as_slice())
+        .collect();
+    assert_eq!(dirs, [&b"dir"[..], b"dir/sub"]);
+    let links: Vec<_> = entries
+        .iter()
+        .filter(|(_, e)| e.file_type().unwrap().is_symlink())
+        .map(|(p, _)| p.as_slice())
+        .collect();
+    assert_eq!(links, [b"link"]);
+
+    let sub = match fs.resolve("dir").unwrap() {
+        Some(FSItem::Directory(d)) => d,
+        _ => panic!("dir is not a directory"),
+    };
+    let paths: Vec<_> = sub.walk().map(|e| e.unwrap().0).collect();
+    assert_eq!(paths, [&b"nested.txt"[..], b"sub", b"sub/.keep"]);
+}
+
+#[test]
+fn test_stat_many() {
+    let fs = open_dir("test_data/small");
+    let paths = [
+        "hello.txt",
+        "missing",
+        "dir/nested.txt",
+        "dir/sub",
+        "dir/missing",
+        "missing/hello.txt",
+        "link",
+        "dir/sub/.keep",
+        "/dir/../hello.txt",
+    ];
+    let stats = fs.stat_many(&paths).unwrap();
+    assert_eq!(stats.len(), paths.len());
+    let summary: Vec<_> = stats
+        .iter()
+        .map(|m| m.map(|m| (m.is_file(), m.is_dir(), m.size())))
+        .collect();
+    assert_eq!(
+        summary,
+        [
+            Some((true, false, 14)),
+            None,
+            Some((true, false, 7)),
+            Some((false, true, 16)),
+            None,
+            None,
+            Some((true, false, 14)),
+            Some((true, false, 0)),
+            Some((true, false, 14)),
+        ]
+    );
+}
+
+#[test]
+fn test_clamp_mtime() {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let dir = make_tree(&["old", "sub/new"]);
+    let set = |path: &str, secs: u64| {
+        let f = std::fs::File::open(dir.path().join(path)).unwrap();
+        f.set_modified(UNIX_EPOCH + Duration::from_secs(secs))
+            .unwrap();
+    };
+    let opts = WriteOptions {
+        clamp_mtime: Some(1_200_000_000),
+        deterministic: true,
+        ..Default::default()
+    };
+    let image = || {
+        let mut out = Cursor::new(Vec::new());
+        write_image_with(
+            dir.path(),
+            &mut out,
+            None,
+            EncryptionType::None,
+            &opts,
+        )
+        .unwrap();
+        out.into_inner()
+    };
+    set("old", 1_000_000_000);
+    set("sub/new", 2_000_000_000);
+    let a = image();
+    // Times later than the epoch all end up the same
+    set("sub/new", 3_000_000_000);
+    assert_eq!(image(), a);
+
+    let fs = FS::open(Cursor::new(a), None).unwrap();
+    assert_eq!(
+        get_file(&fs, "old").modified().unwrap(),
+        UNIX_EPOCH + Duration::from_secs(1_000_000_000)
+    );
+    assert_eq!(
+        get_file(&fs, "sub/new").modified().unwrap(),
+        UNIX_EPOCH + Duration::from_secs(1_200_000_000)
+    );
+}
+
+#[test]
+fn test_diff_against_tar() {
+    let long = format!("{}/{}", "d".repeat(80), "f".repeat(80));
+    let dir = make_tree(&["a.txt", "sub/b.txt", &long]);
+    std::fs::create_dir(dir.path().join("empty")).unwrap();
+    std::fs::write(dir.path().join("big"), vec![7; 1000]).unwrap();
+    std::os::unix::fs::symlink("l".repeat(150), dir.path().join("link"))
+        .unwrap();
+    let fs = open_dir(dir.path());
+    let mut tar = Vec::new();
+    crate::export_tar(&fs, &mut tar).unwrap();
+    assert_eq!(tar.len() % 512, 0);
+    let diffs = crate::diff_against_tar(&fs, tar.as_slice(), true).unwrap();
+    assert_eq!(diffs, []);
+
+    std::fs::write(dir.path().join("a.txt"), "A.txt").unwrap();
+    std::fs::write(dir.path().join("big"), vec![7; 10]).unwrap();
+    std::fs::remove_file(dir.path().join("sub/b.txt")).unwrap();
+    std::fs::write(dir.path().join("new"), "").unwrap();
+    std::fs::remove_dir(dir.path().join("empty")).unwrap();
+    std::fs::write(dir.path().join("empty"), "").unwrap();
+    let other = open_dir(dir.path());
+    let mut diffs =
+        crate::diff_against_tar(&other, tar.as_slice(), true).unwrap();
+    diffs.sort();
+    assert_eq!(
+        diffs,
+        [
+            Difference::OnlyInTar(b"sub/b.txt".to_vec()),
+            Difference::OnlyInImage(b"new".to_vec()),
+            Difference::Type(b"empty".to_vec()),
+            Difference::Size {
+                path: b"big".to_vec(),
+                image: 10,
+                tar: 1000
+            },
+            Difference::Contents(b"a.txt".to_vec()),
+        ]
+    );
+    // Without contents only the sizes are compared
+    let diffs = crate::diff_against_tar(&other, tar.as_slice(), false).unwrap();
+    assert_eq!(diffs.len(), 4);
+    assert!(!diffs.contains(&Difference::Contents(b"a.txt".to_vec())));
+}
+
+#[test]
+fn test_write_image_from_tar() {
+    let long = format!("{}/{}", "d".repeat(80), "f".repeat(80));
+    let dir = make_tree(&["a/b", "sub/c.txt", &long]);
+    std::fs::create_dir(dir.path().join("empty")).unwrap();
+    std::os::unix::fs::symlink("a/b", dir.path().join("link")).unwrap();
+    let mut tar = Vec::new();
+    crate::export_tar(&open_dir(dir.path()), &mut tar).unwrap();
+    // Move a/b and its data block before the a directory
+    let blocks: Vec<&[u8]> = tar.chunks(512).collect();
+    let dir_a = blocks.iter().position(|b| b.starts_with(b"a\0")).unwrap();
+    let file = blocks.iter().position(|b| b.starts_with(b"a/b\0")).unwrap();
+    assert!(dir_a < file);
+    let mut moved = blocks[file..file + 2].concat();
+    for (i, block) in blocks.iter().enumerate() {
+        if i != file && i != file + 1 {
+            moved.extend_from_slice(block);
+        }
+    }
+
+    let mut out = Cursor::new(Vec::new());
+    let summary = crate::write_image_from_tar(
+        moved.as_slice(),
+        &mut out,
+        None,
+        EncryptionType::None,
+        &WriteOptions::default(),
+    )
+    .unwrap();
+    assert_eq!((summary.files, summary.symlinks), (3, 1));
+    let fs = FS::open(out, None).unwrap();
+    assert_eq!(
+        crate::diff_against_tar(&fs, tar.as_slice(), true).unwrap(),
+        []
+    );
+    assert!(crate::verify(&fs).unwrap().is_ok());
+
+    // Metadata, hard links, devices and FIFOs, with the last of
+    // repeated paths
+    use std::time::{Duration, UNIX_EPOCH};
+    let mut b = ::tar::Builder::new(Vec::new());
+    let mut entry = |ty: ::tar::EntryType, path: &str, link: &str, data| {
+        let mut h = ::tar::Header::new_gnu();
+        h.set_entry_type(ty);
+        h.set_mode(0o640);
+        h.set_mtime(1234567890);
+        h.set_uid(1000);
+        h.set_gid(100);
+        h.set_device_major(1).unwrap();
+        h.set_device_minor(3).unwrap();
+        h.set_size(<[u8]>::len(data) as u64);
+        if link.is_empty() {
+            b.append_data(&mut h, path, data).unwrap();
+        } else {
+            b.append_link(&mut h, path, link).unwrap();
+        }
+    };
+    entry(::tar::EntryType::Directory, "d", "", b"");
+    entry(::tar::EntryType::Regular, "d/f", "", b"first");
+    entry(::tar::EntryType::Regular, "d/f", "", b"second");
+    entry(::tar::EntryType::Link, "h", "d/f", b"");
+    entry(::tar::EntryType::Char, "null", "", b"");
+    entry(::tar::EntryType::Fifo, "fifo", "", b"");
+    let tar = b.into_inner().unwrap();
+    let mut out = Cursor::new(Vec::new());
+    let summary = crate::write_image_from_tar(
+        tar.as_slice(),
+        &mut out,
+        None,
+        EncryptionType::None,
+        &WriteOptions::default(),
+    )
+    .unwrap();
+    assert_eq!((summary.hardlinks, summary.specials), (1, 2));
+    let fs = FS::open(out, None).unwrap();
+    let f = get_file(&fs, "d/f");
+    assert_eq!(read_all(&f), b"second");
+    assert_eq!(read_all(&get_file(&fs, "h")), b"second");
+    let d = match fs.resolve("d").unwrap() {
+        Some(FSItem::Directory(d)) => d.metadata().unwrap(),
+        _ => panic!("d is not a directory"),
+    };
+    for meta in [d, f.metadata().unwrap()] {
+        assert_eq!(meta.mode(), Some(0o640));
+        assert_eq!((meta.uid(), meta.gid()), (Some(1000), Some(100)));
+        assert_eq!(
+            meta.modified().unwrap(),
+            UNIX_EPOCH + Duration::from_secs(1234567890)
+        );
+    }
+    match fs.resolve("null").unwrap() {
+        Some(FSItem::Special(s)) => {
+            assert!(s.file_type().is_char_device());
+            assert_eq!(s.rdev(), Some((1, 3)));
+        }
+        _ => panic!("null is not a special file"),
+    }
+    match fs.resolve("fifo").unwrap() {
+        Some(FSItem::Special(s)) => assert!(s.file_type().is_fifo()),
+        _ => panic!("fifo is not a special file"),
+    }
+    assert_eq!(
+        crate::diff_against_tar(&fs, tar.as_slice(), true).unwrap(),
+        []
+    );
+
+    // A hard link needs an earlier target
+    let mut b = ::tar::Builder::new(Vec::new());
+    let mut h = ::tar::Header::new_gnu();
+    h.set_entry_type(::tar::EntryType::Link);
+    h.set_mode(0o644);
+    h.set_mtime(0);
+    h.set_uid(0);
+    h.set_gid(0);
+    h.set_size(0);
+    b.append_link(&mut h, "h", "missing").unwrap();
+    assert!(matches!(
+        crate::write_image_from_tar(
+            b.into_inner().unwrap().as_slice(),
+            Cursor::new(Vec::new()),
+            None,
+            EncryptionType::None,
+            &WriteOptions::default(),
+        ),
+        Err(crate::Error::InvalidOperation("hard link target not found"))
+    ));
+
+    // Other entries can't be written
+    let mut volume = tar[..512].to_vec();
+    volume[156] = b'V';
+    volume[148..156].copy_from_slice(b"        ");
+    let sum: u32 = volume.iter().map(|&b| b as u32).sum();
+    volume[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+    assert!(matches!(
+        crate::write_image_from_tar(
+            volume.as_slice(),
+            Cursor::new(Vec::new()),
+            None,
+            EncryptionType::None,
+            &WriteOptions::default(),
+        ),
+        Err(crate::Error::InvalidOperation("unsupported tar entry type"))
+    ));
+}
+
+#[test]
+fn test_symlink_root() {
+    let dir = make_tree(&["src/a.txt"]);
+    let link = dir.path().join("link");
+    std::os::unix::fs::symlink("src", &link).unwrap();
+    let fs = open_dir(&link);
+    assert!(fs.resolve("a.txt").unwrap().is_some());
+
+    let opts = WriteOptions {
+        dereference_root: false,
+        ..Default::default()
+    };
+    let mut out = Cursor::new(Vec::new());
+    let res =
+        write_image_with(&link, &mut out, None, EncryptionType::None, &opts);
+    assert!(matches!(
+        res,
+        Err(crate::Error::InvalidOperation("root is a symlink"))
+    ));
+    // Real directories are still fine
+    open_dir_with(dir.path().join("src"), &opts);
+}
+
+#[test]
+fn test_missing_root() {
+    let dir = tempfile::tempdir().unwrap();
+    std::os::unix::fs::symlink("missing", dir.path().join("dangling")).unwrap();
+    for name in ["missing", "dangling"] {
+        let mut out = Cursor::new(Vec::new());
+        let res = write_image(
+            dir.path().join(name),
+            &mut out,
+            None,
+            EncryptionType::None,
+        );
+        assert!(matches!(
+            res,
+            Err(crate::Error::InvalidOperation("source not found"))
+        ));
+    }
+}
+
+#[test]
+fn test_overlay() {
+    use crate::{OverlayFS, OverlayItem};
+    use std::sync::Arc;
+
+    let base = make_tree(&["a.txt", "dir/b.txt", "dir/c.txt", "gone.txt"]);
+    std::fs::create_dir(base.path().join("old")).unwrap();
+    std::fs::write(base.path().join("old/x"), "x").unwrap();
+    let top = make_tree(&["dir/d.txt", "dir/.wh.c.txt", ".wh.gone.txt"]);
+    std::fs::write(top.path().join("a.txt"), "new a").unwrap();
+    // Replaced by a file
+    std::fs::write(top.path().join("old"), "file").unwrap();
+    std::os::unix::fs::symlink("dir/b.txt", top.path().join("link")).unwrap();
+    let fs = OverlayFS::new(vec![
+        Arc::new(open_dir(base.path())),
+        Arc::new(open_dir(top.path())),
+    ])
+    .unwrap();
+
+    let paths: Vec<_> = fs.walk().map(|e| e.unwrap().0).collect();
+    assert_eq!(
+        paths,
+        [
+            &b"a.txt"[..],
+            b"dir",
+            b"dir/b.txt",
+            b"dir/d.txt",
+            b"link",
+            b"old"
+        ]
+    );
+    let read = |path: &str| match fs.resolve(path).unwrap() {
+        Some(OverlayItem::File(f)) => {
+            let mut buf = vec![0; f.size() as usize];
+            f.read_exact_at(&mut buf, 0).unwrap();
+            buf
+        }
+        _ => panic!("{} is not a file", path),
+    };
+    assert_eq!(read("a.txt"), b"new a");
+    assert_eq!(read("dir/b.txt"), b"dir/b.txt");
+    assert_eq!(read("dir/d.txt"), b"dir/d.txt");
+    assert_eq!(read("old"), b"file");
+    // Symlinks are resolved in the merged view
+    assert_eq!(read("link"), b"dir/b.txt");
+    assert_eq!(read("/dir/../link"), b"dir/b.txt");
+    for path in ["gone.txt", "dir/c.txt", "dir/.wh.c.txt"] {
+        assert!(fs.resolve(path).unwrap().is_none(), "{} is visible", path);
+    }
+    assert!(fs.resolve("old/x").is_err());
+    assert!(matches!(
+        fs.resolve("dir").unwrap(),
+        Some(OverlayItem::Directory(_))
+    ));
+    assert!(OverlayFS::new(Vec::new()).is_err());
+}
+
+#[test]
+fn test_overlay_cycle() {
+    use crate::OverlayFS;
+    use std::sync::Arc;
+
+    let dir = make_tree(&["a/b/file"]);
+    let mut out = Cursor::new(Vec::new());
+    write_image(dir.path(), &mut out, None, EncryptionType::None).unwrap();
+    let mut img = out.into_inner();
+    // Make a/b point back to a
+    let u64_at = |img: &[u8], off: usize| {
+        u64::from_le_bytes(img[off..off + 8].try_into().unwrap()) as usize
+    };
+    let root = u64_at(&img, 8);
+    let a = u64_at(&img, u64_at(&img, root + 8) + 8);
+    let a_dirents = u64_at(&img, a + 8);
+    img[a_dirents + 8..a_dirents + 16]
+        .copy_from_slice(&(a as u64).to_le_bytes());
+
+    let cyclic = Arc::new(FS::open(Cursor::new(img), None).unwrap());
+    // The same tree as a separate layer is not a cycle
+    let other = Arc::new(open_dir(dir.path()));
+    let fs = OverlayFS::new(vec![other.clone(), other]).unwrap();
+    assert_eq!(fs.walk().count(), 3);
+    assert!(fs.walk().all(|e| e.is_ok()));
+    for layers in [
+        vec![cyclic.clone()],
+        vec![cyclic, Arc::new(open_dir(dir.path()))],
+    ] {
+        let fs = OverlayFS::new(layers).unwrap();
+        let err = fs.walk().find_map(|e| e.err()).unwrap();
+        assert!(matches!(
+            err.inner(),
+            crate::Error::Format("directory cycle")
+        ));
+    }
+}
+
+#[test]
+fn test_overlay_builder_markers() {
+    use crate::{ImageBuilder, OverlayFS};
+    use std::sync::Arc;
+
+    let build = |builder: ImageBuilder| {
+        let mut out = Cursor::new(Vec::new());
+        builder
+            .finish(&mut out, None, EncryptionType::None, &Default::default())
+            .unwrap();
+        Arc::new(FS::open(out, None).unwrap())
+    };
+    let mut base = ImageBuilder::new();
+    for path in ["keep", "gone", "dir/gone", "dir/keep", "opaque/old"] {
+        base.add_file(path, &b"base"[..]).unwrap();
+    }
+    let mut delta = ImageBuilder::new();
+    delta.add_whiteout("gone").unwrap();
+    delta.add_whiteout("dir/gone").unwrap();
+    delta.add_opaque_dir("opaque").unwrap();
+    delta.add_file("opaque/new", &b"delta"[..]).unwrap();
+    assert!(delta.add_whiteout("dir/").is_err());
+    assert!(delta.add_whiteout("gone").is_err());
+    let delta = build(delta);
+    assert!(delta.resolve(".wh.gone").unwrap().is_some());
+    assert!(delta.resolve("opaque/.wh..wh..opq").unwrap().is_some());
+
+    let fs = OverlayFS::new(vec![build(base), delta.clone()]).unwrap();
+    let paths: Vec<_> = fs.walk().map(|e| e.unwrap().0).collect();
+    assert_eq!(
+        paths,
+        [&b"dir"[..], b"dir/keep", b"keep", b"opaque", b"opaque/new"]
+    );
+    assert!(fs.resolve("gone").unwrap().is_none());
+    assert!(fs.resolve("opaque/old").unwrap().is_none());
+
+    // An opaque root hides everything below
+    let mut top = ImageBuilder::new();
+    top.add_opaque_dir("").unwrap();
+    top.add_file("only", &b""[..]).unwrap();
+    let fs = OverlayFS::new(vec![delta, build(top)]).unwrap();
+    let paths: Vec<_> = fs.walk().map(|e| e.unwrap().0).collect();
+    assert_eq!(paths, [b"only"]);
+}
+
+#[test]
+fn test_hash_index() {
+    let mut files: Vec<String> = (0..100).map(|i| format!("f{}", i)).collect();
+    files.extend(["sub/a", "sub/b", "Upper"].map(String::from));
+    let refs: Vec<&str> = files.iter().map(|f| f.as_str()).collect();
+    let dir = make_tree(&refs);
+    for collation in [Collation::Bytes, Collation::CaseInsensitive] {
+        let images: Vec<_> = [None, Some(0), Some(50)]
+            .into_iter()
+            .map(|min| {
+                open_dir_with(
+                    dir.path(),
+                    &WriteOptions {
+                        hash_index_min_entries: min,
+                        collation,
+                        ..Default::default()
+                    },
+                )
+            })
+            .collect();
+        assert_eq!(
+            images[1].header().compat_features() & crate::COMPAT_HASH_INDEX,
+            crate::COMPAT_HASH_INDEX
+        );
+        for fs in &images {
+            for name in &files {
+                assert_eq!(get_file(fs, name).size(), name.len() as u64);
+            }
+            for missing in ["f100", "F1", "upper", "sub/c", "zzz"] {
+                assert!(fs.resolve(missing).unwrap().is_none(), "{}", missing);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_link_target_max() {
+    assert_eq!(
+        FsOptions::default().link_target_max,
+        WriteOptions::default().link_target_max
+    );
+    // The longest target Linux allows
+    let target = "t".repeat(4095);
+    let dir = tempfile::tempdir().unwrap();
+    std::os::unix::fs::symlink(&target, dir.path().join("link")).unwrap();
+    let write = |max| {
+        let mut out = Cursor::new(Vec::new());
+        let opts = WriteOptions {
+            link_target_max: max,
+            ..Default::default()
+        };
+        write_image_with(
+            dir.path(),
+            &mut out,
+            None,
+            EncryptionType::None,
+            &opts,
+        )
+        .map(|_| out)
+    };
+    assert!(matches!(write(4094), Err(crate::Error::Bounds(_))));
+    let image = write(4095).unwrap();
+
+    let read = |max| {
+        let opts = FsOptions {
+            link_target_max: max,
+            ..Default::default()
+        };
+        let fs = FS::open_with(image.clone(), None, &opts).unwrap();
+        let link = match fs.get_root().unwrap().get(0).unwrap().unwrap().item()
+        {
+            Ok(FSItem::Symlink(l)) => l,
+            _ => panic!("not a symlink"),
+        };
+        assert_eq!(link.target_len(), 4095);
+        link.get_link()
+    };
+    assert_eq!(read(4095).unwrap(), target.as_bytes());
+    assert_eq!(read(crate::LINK_TARGET_MAX).unwrap(), target.as_bytes());
+    assert!(matches!(read(4094), Err(crate::Error::Bounds(_))));
+    let opts = FsOptions {
+        link_target_max: crate::LINK_TARGET_HARD_MAX + 1,
+        ..Default::default()
+    };
+    assert!(FS::open_with(image, None, &opts).is_err());
+}
+
+#[test]
+fn test_write_hardlinks() {
+    let data = vec![42; 10000];
+    let build = |linked: bool| {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::write(dir.path().join("a"), &data).unwrap();
+        if linked {
+            std::fs::hard_link(dir.path().join("a"), dir.path().join("sub/b"))
+                .unwrap();
+        } else {
+            std::fs::write(dir.path().join("sub/b"), &data).unwrap();
+        }
+        let mut out = Cursor::new(Vec::new());
+        let summary =
+            write_image(dir.path(), &mut out, None, EncryptionType::None)
+                .unwrap();
+        (summary, FS::open(out, None).unwrap())
+    };
+    let (copies, _) = build(false);
+    let (summary, fs) = build(true);
+    assert_eq!(summary.files, 1);
+    assert_eq!(summary.hardlinks, 1);
+    assert!(summary.image_size + 10000 <= copies.image_size);
+    assert_eq!(read_all(&get_file(&fs, "a")), data);
+    assert_eq!(read_all(&get_file(&fs, "sub/b")), data);
+
+    // They extract as links too
+    let target = tempfile::tempdir().unwrap();
+    let extracted =
+        extract_fs(&fs, &target.path(), &ExtractOptions::default()).unwrap();
+    assert_eq!(extracted.hardlinks, 1);
+}
+
+#[test]
+fn test_dedup_symlinks() {
+    let dir = make_tree(&["v1.2.3/file"]);
+    for i in 0..100 {
+        std::os::unix::fs::symlink(
+            "v1.2.3",
+            dir.path().join(format!("l{}", i)),
+        )
+        .unwrap();
+    }
+    let opts = WriteOptions {
+        dedup_symlinks: true,
+        ..Default::default()
+    };
+    let mut out = Cursor::new(Vec::new());
+    let summary = write_image_with(
+        dir.path(),
+        &mut out,
+        None,
+        EncryptionType::None,
+        &opts,
+    )
+    .unwrap();
+    assert_eq!(summary.symlinks, 1);
+    assert_eq!(summary.shared_symlinks, 99);
+    let image = out.into_inner();
+    // Once as the target, the others are the name and contents of the
+    // directory
+    assert_eq!(image.windows(6).filter(|w| w == b"v1.2.3").count(), 3);
+
+    let fs = FS::open(Cursor::new(image), None).unwrap();
+    let target = tempfile::tempdir().unwrap();
+    let extracted =
+        extract_fs(&fs, &target.path(), &ExtractOptions::default()).unwrap();
+    assert_eq!(extracted.symlinks, 100);
+    for i in 0..100 {
+        let link = target.path().join(format!("l{}", i));
+        assert_eq!(std::fs::read_link(&link).unwrap(), Path::new("v1.2.3"));
+        assert_eq!(std::fs::read(link.join("file")).unwrap(), b"v1.2.3/file");
+    }
+}
+
+#[test]
+fn test_dedup_contents() {
+    let dir = tempfile::tempdir().unwrap();
+    let data: Vec<u8> = (0..20000u32).map(|i| (i % 251) as u8).collect();
+    std::fs::create_dir(dir.path().join("sub")).unwrap();
+    std::fs::write(dir.path().join("a"), &data).unwrap();
+    std::fs::write(dir.path().join("sub/b"), &data).unwrap();
+    std::fs::write(dir.path().join("c"), b"other").unwrap();
+    let build = |dedup: bool| {
+        let opts = WriteOptions {
+            dedup,
+            merkle: true,
+            ..Default::default()
+        };
+        let mut out = Cursor::new(Vec::new());
+        let summary = write_image_with(
+            dir.path(),
+            &mut out,
+            None,
+            EncryptionType::None,
+            &opts,
+        )
+        .unwrap();
+        (summary, FS::open(out, None).unwrap())
+    };
+    let (copies, _) = build(false);
+    let (summary, fs) = build(true);
+    assert_eq!(summary.files, 3);
+    assert_eq!(summary.hardlinks, 0);
+    assert_eq!(summary.dedup_bytes, data.len() as u64);
+    assert!(summary.image_size + 20000 <= copies.image_size);
+    assert_eq!(read_all(&get_file(&fs, "a")), data);
+    assert_eq!(read_all(&get_file(&fs, "sub/b")), data);
+    assert_eq!(read_all(&get_file(&fs, "c")), b"other");
+    assert!(crate::verify(&fs).unwrap().is_ok());
+
+    // Separate inodes for the same contents, so not hard links
+    let target = tempfile::tempdir().unwrap();
+    let extracted =
+        extract_fs(&fs, &target.path(), &ExtractOptions::default()).unwrap();
+    assert_eq!(extracted.hardlinks, 0);
+}
+
+#[test]
+fn test_file_size_filter() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(dir.path().join("big/sub")).unwrap();
+    std::fs::create_dir(dir.path().join("mixed")).unwrap();
+    std::fs::write(dir.path().join("empty"), b"").unwrap();
+    std::fs::write(dir.path().join("small"), b"small").unwrap();
+    std::fs::write(dir.path().join("big/large"), vec![1; 10000]).unwrap();
+    std::fs::write(dir.path().join("big/sub/large"), vec![2; 10000]).unwrap();
+    std::fs::write(dir.path().join("mixed/large"), vec![3; 10000]).unwrap();
+    std::fs::write(dir.path().join("mixed/limit"), vec![4; 100]).unwrap();
+    let build = |min_file_size, max_file_size| {
+        let opts = WriteOptions {
+            min_file_size,
+            max_file_size,
+            ..Default::default()
+        };
+        let mut out = Cursor::new(Vec::new());
+        let summary = write_image_with(
+            dir.path(),
+            &mut out,
+            None,
+            EncryptionType::None,
+            &opts,
+        )
+        .unwrap();
+        let fs = FS::open(out, None).unwrap();
+        let mut paths: Vec<_> = fs
+            .get_root()
+            .unwrap()
+            .walk()
+            .map(|e| String::from_utf8(e.unwrap().0).unwrap())
+            .collect();
+        paths.sort();
+        let mut excluded: Vec<_> = summary
+            .size_excluded
+            .iter()
+            .map(|p| p.strip_prefix(dir.path()).unwrap().to_path_buf())
+            .collect();
+        excluded.sort();
+        (summary.files, paths, excluded)
+    };
+
+    let (files, paths, excluded) = build(None, Some(100));
+    assert_eq!(files, 3);
+    // Directories left empty are still there
+    assert_eq!(
+        paths,
+        ["big", "big/sub", "empty", "mixed", "mixed/limit", "small"]
+    );
+    assert_eq!(
+        excluded,
+        [
+            Path::new("big/large"),
+            Path::new("big/sub/large"),
+            Path::new("mixed/large")
+        ]
+    );
+
+    let (files, paths, excluded) = build(Some(100), None);
+    assert_eq!(files, 4);
+    assert_eq!(
+        paths,
+        [
+            "big",
+            "big/large",
+            "big/sub",
+            "big/sub/large",
+            "mixed",
+            "mixed/large",
+            "mixed/limit"
+        ]
+    );
+    assert_eq!(excluded, [Path::new("empty"), Path::new("small")]);
+
+    let (files, paths, _) = build(Some(1), Some(100));
+    assert_eq!(files, 2);
+    assert_eq!(paths, ["big", "big/sub", "mixed", "mixed/limit", "small"]);
+}
+
+#[test]
+fn test_walk_reachable_inodes() {
+    let dir = make_tree(&["a", "sub/b", "sub/c"]);
+    std::fs::hard_link(dir.path().join("a"), dir.path().join("sub/d")).unwrap();
+    std::os::unix::fs::symlink("a", dir.path().join("l1")).unwrap();
+    std::os::unix::fs::symlink("a", dir.path().join("sub/l2")).unwrap();
+    let opts = WriteOptions {
+        dedup_symlinks: true,
+        ..Default::default()
+    };
+    let mut out = Cursor::new(Vec::new());
+    write_image_with(dir.path(), &mut out, None, EncryptionType::None, &opts)
+        .unwrap();
+    let fs = FS::open(out, None).unwrap();
+    let inodes = fs
+        .walk_reachable_inodes()
+        .unwrap()
+        .collect::<crate::Result<Vec<_>>>()
+        .unwrap();
+    // The root, sub, a, b, c and one symlink
+    assert_eq!(inodes.len(), 6);
+    assert_eq!(inodes[0].0, fs.header().root_inode());
+    assert!(inodes[0].1.is_dir());
+    let mut offsets: Vec<u64> = inodes.iter().map(|i| i.0).collect();
+    offsets.sort();
+    offsets.dedup();
+    assert_eq!(offsets.len(), 6);
+    assert_eq!(inodes.iter().filter(|i| i.1.is_dir()).count(), 2);
+    assert_eq!(inodes.iter().filter(|i| i.1.is_symlink()).count(), 1);
+    let files: Vec<u64> = inodes
+        .iter()
+        .filter(|i| i.1.is_file())
+        .map(|i| i.2)
+        .collect();
+    assert_eq!(files, [1, 5, 5]);
+}
+
+#[test]
+fn test_special_files() {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    let dir = make_tree(&["file"]);
+    let fifo = dir.path().join("fifo");
+    let c = std::ffi::CString::new(fifo.as_os_str().as_bytes()).unwrap();
+    // SAFETY: c is a valid NUL terminated string
+    assert_eq!(unsafe { libc::mkfifo(c.as_ptr(), 0o640) }, 0);
+    let _sock = std::os::unix::net::UnixListener::bind(dir.path().join("sock"))
+        .unwrap();
+    let opts = WriteOptions {
+        merkle: true,
+        ..Default::default()
+    };
+    let mut out = Cursor::new(Vec::new());
+    let summary = write_image_with(
+        dir.path(),
+        &mut out,
+        None,
+        EncryptionType::None,
+        &opts,
+    )
+    .unwrap();
+    assert_eq!(summary.files, 1);
+    assert_eq!(summary.specials, 2);
+    let fs = FS::open(out, None).unwrap();
+    match fs.resolve("fifo").unwrap() {
+        Some(FSItem::Special(s)) => {
+            assert!(s.file_type().is_fifo());
+            assert_eq!(s.rdev(), None);
+            let md = s.metadata().unwrap();
+            assert_eq!(md.size(), 0);
+            assert_eq!(md.mode(), Some(0o640));
+        }
+        _ => panic!("fifo is not a special file"),
+    }
+    match fs.resolve("sock").unwrap() {
+        Some(FSItem::Special(s)) => assert!(s.file_type().is_socket()),
+        _ => panic!("sock is not a special file"),
+    }
+    assert!(crate::verify(&fs).unwrap().is_ok());
+
+    let target = tempfile::tempdir().unwrap();
+    let extracted =
+        extract_fs(&fs, &target.path(), &ExtractOptions::default()).unwrap();
+    assert_eq!(extracted.specials, 2);
+    let md = std::fs::symlink_metadata(target.path().join("fifo")).unwrap();
+    assert!(md.file_type().is_fifo());
+    assert_eq!(md.permissions().mode() & 0o7777, 0o640);
+    let md = std::fs::symlink_metadata(target.path().join("sock")).unwrap();
+    assert!(md.file_type().is_socket());
+}
+
+#[test]
+fn test_xattrs() {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    let dir = make_tree(&["file", "sub/other"]);
+    let set = |path: &Path, name: &str, value: &[u8]| {
+        let p = CString::new(path.as_os_str().as_bytes()).unwrap();
+        let n = CString::new(name).unwrap();
+        // SAFETY: the strings are NUL terminated and value has the
+        // given length
+        let res = unsafe {
+            libc::setxattr(
+                p.as_ptr(),
+                n.as_ptr(),
+                value.as_ptr() as *const libc::c_void,
+                value.len(),
+                0,
+            )
+        };
+        res == 0
+    };
+    if !set(&dir.path().join("file"), "user.test", b"value") {
+        // The filesystem of the temporary directory doesn't have them
+        return;
+    }
+    assert!(set(&dir.path().join("sub"), "user.dir", b""));
+    let build = |xattrs: bool| {
+        let opts = WriteOptions {
+            xattrs,
+            ..Default::default()
+        };
+        let mut out = Cursor::new(Vec::new());
+        write_image_with(
+            dir.path(),
+            &mut out,
+            None,
+            EncryptionType::None,
+            &opts,
+        )
+        .unwrap();
+        FS::open(out, None).unwrap()
+    };
+    let test = CString::new("user.test").unwrap();
+    assert_eq!(get_file(&build(false), "file").xattrs().unwrap(), []);
+    let fs = build(true);
+    assert_eq!(
+        fs.header().compat_features() & crate::COMPAT_XATTR,
+        crate::COMPAT_XATTR
+    );
+    assert_eq!(
+        get_file(&fs, "file").xattrs().unwrap(),
+        [(test.clone(), b"value".to_vec())]
+    );
+    assert_eq!(get_file(&fs, "sub/other").xattrs().unwrap(), []);
+    match fs.resolve("sub").unwrap() {
+        Some(FSItem::Directory(d)) => assert_eq!(
+            d.xattrs().unwrap(),
+            [(CString::new("user.dir").unwrap(), Vec::new())]
+        ),
+        _ => panic!("sub is not a directory"),
+    }
+
+    let target = tempfile::tempdir().unwrap();
+    let extracted =
+        extract_fs(&fs, &target.path(), &ExtractOptions::default()).unwrap();
+    assert_eq!(extracted.unset_xattrs, 0);
+    let p = CString::new(target.path().join("file").as_os_str().as_bytes())
+        .unwrap();
+    let mut buf = [0u8; 16];
+    // SAFETY: as above
+    let len = unsafe {
+        libc::getxattr(
+            p.as_ptr(),
+            test.as_ptr(),
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len(),
+        )
+    };
+    assert_eq!(&buf[..len as usize], b"value");
+}
+
+#[test]
+fn test_image_builder() {
+    use crate::ImageBuilder;
+    // Not in order, the builder sorts them
+    let files: Vec<String> = (0..50).rev().map(|i| format!("f{}", i)).collect();
+    let mut builder = ImageBuilder::new();
+    for name in &files {
+        builder
+            .add_file(format!("dir/{}", name), name.as_bytes())
+            .unwrap();
+    }
+    builder.add_file("b/c/deep", &b"deep"[..]).unwrap();
+    builder.add_symlink("link", "dir/f7").unwrap();
+    builder.add_dir("empty").unwrap();
+    builder.add_dir("b").unwrap();
+    assert!(builder.add_file("link", &b""[..]).is_err());
+    assert!(builder.add_file("link/x", &b""[..]).is_err());
+    assert!(builder.add_dir("b/c/deep").is_err());
+    assert!(builder.add_file("a//b", &b""[..]).is_err());
+    assert!(builder.add_file("../a", &b""[..]).is_err());
+    let long = vec![b'x'; crate::NAME_MAX + 1];
+    assert!(matches!(
+        builder.add_dir(&long),
+        Err(crate::Error::Bounds("name too long"))
+    ));
+
+    let opts = WriteOptions {
+        collation: Collation::Natural,
+        merkle: true,
+        ..Default::default()
+    };
+    let mut out = Cursor::new(Vec::new());
+    let summary = builder
+        .finish(&mut out, None, EncryptionType::None, &opts)
+        .unwrap();
+    assert_eq!(summary.files, 51);
+    assert_eq!(summary.dirs, 5);
+    assert_eq!(summary.symlinks, 1);
+    let fs = FS::open(out, None).unwrap();
+    assert!(crate::verify(&fs).unwrap().is_ok());
+    for name in &files {
+        let f = get_file(&fs, &format!("dir/{}", name));
+        assert_eq!(read_all(&f), name.as_bytes());
+    }
+    assert_eq!(read_all(&get_file(&fs, "link")), b"f7");
+    assert_eq!(read_all(&get_file(&fs, "b/c/deep")), b"deep");
+    match fs.resolve("dir").unwrap() {
+        Some(FSItem::Directory(d)) => {
+            let expected: Vec<Vec<u8>> =
+                (0..50).map(|i| format!("f{}", i).into_bytes()).collect();
+            assert_eq!(names(&d), expected);
+        }
+        _ => panic!("dir is not a directory"),
+    }
+    match fs.resolve("empty").unwrap() {
+        Some(FSItem::Directory(d)) => assert!(d.is_empty()),
+        _ => panic!("empty is not a directory"),
+    }
+}
+
+#[cfg(feature = "fuse")]
+#[test]
+fn test_mount_ops() {
+    use crate::mount::ImageFs;
+    use fuser::{FileType, FUSE_ROOT_ID};
+    use std::ffi::OsStr;
+
+    let fs = open_dir("test_data/small");
+    let mut img = ImageFs::new(&fs).unwrap();
+    let root = img.getattr(FUSE_ROOT_ID).unwrap();
+    assert_eq!(root.kind, FileType::Directory);
+
+    let dir = img.lookup(FUSE_ROOT_ID, OsStr::new("dir")).unwrap();
+    assert_eq!(dir.kind, FileType::Directory);
+    let nested = img.lookup(dir.ino, OsStr::new("nested.txt")).unwrap();
+    assert_eq!((nested.kind, nested.size), (FileType::RegularFile, 7));
+    assert_eq!(img.read(nested.ino, 0, 100).unwrap(), b"nested\n");
+    assert_eq!(img.read(nested.ino, 3, 2).unwrap(), b"te");
+    assert_eq!(img.read(dir.ino, 0, 100), Err(libc::EISDIR));
+    let link = img.lookup(FUSE_ROOT_ID, OsStr::new("link")).unwrap();
+    assert_eq!(img.readlink(link.ino).unwrap(), b"hello.txt");
+    assert_eq!(
+        img.lookup(FUSE_ROOT_ID, OsStr::new("missing")),
+        Err(libc::ENOENT)
+    );
+
+    let list = |img: &ImageFs, ino, offset, max| {
+        let mut res = Vec::new();
+        img.readdir(ino, offset, |ino, next, _, name| {
+            res.push((ino, next, name.to_owned()));
+            res.len() == max
+        })
+        .unwrap();
+        res
+    };
+    let names: Vec<_> = list(&img, FUSE_ROOT_ID, 0, usize::MAX)
+        .into_iter()
+        .map(|e| e.2)
+        .collect();
+    assert_eq!(names, [".", "..", "dir", "hello.txt", "link"]);
+    // Resuming from the offset of the last entry returned
+    let first = list(&img, dir.ino, 0, 3);
+    assert_eq!(first[1].0, FUSE_ROOT_ID);
+    assert_eq!(first[2].0, nested.ino);
+    let rest = list(&img, dir.ino, first[2].1, usize::MAX);
+    assert_eq!(rest.len(), 1);
+    assert_eq!(rest[0].2, "sub");
+
+    img.forget(nested.ino, 1);
+    assert_eq!(img.getattr(nested.ino), Err(libc::ENOENT));
+    assert!(img.getattr(dir.ino).is_ok());
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn test_mmap_image() {
+    let dir = make_tree(&["src/a", "src/sub/b"]);
+    let image = dir.path().join("image.sqh");
+    let key = [3; crate::CHACHA20_KEY_LEN];
+    for (enc, key) in [
+        (EncryptionType::None, None),
+        (EncryptionType::ChaCha20, Some(&key[..])),
+    ] {
+        crate::write_image_file(&dir.path().join("src"), &image, key, enc)
+            .unwrap();
+        let fs =
+            FS::open(crate::MmapImage::open(&image).unwrap(), key).unwrap();
+        let f = get_file(&fs, "sub/b");
+        let mut buf = vec![0; 16];
+        let n = f.read_at(&mut buf, 0).unwrap();
+        assert_eq!(&buf[..n], b"src/sub/b");
+        assert!(crate::verify(&fs).unwrap().is_ok());
+    }
+    let len = std::fs::metadata(&image).unwrap().len();
+    let map = crate::MmapImage::open(&image).unwrap();
+    assert_eq!(crate::ReadAt::stream_len(&map), Some(len));
+    assert_eq!(crate::ReadAt::read_at(&map, &mut [0; 4], len).unwrap(), 0);
+}
@@ -0,0 +1,185 @@
+// Checking that a whole image can be read
+
+use crate::disk::merkle::{self, DirHasher, Hash};
+use crate::error::Error;
+use crate::fs;
+
+use std::collections::HashSet;
+use std::io;
+use std::ops::Range;
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Result of `verify`.
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    /// Number of entries that were read without error
+    pub entries: u64,
+    /// Paths that could not be read with the error they gave, in the
+    /// order they were met. Entries whose name can't be read are named
+    /// after their position in their directory, as `#<pos>`.
+    pub failures: Vec<(Vec<u8>, Error)>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+fn join(dir: &[u8], name: &[u8]) -> Vec<u8> {
+    let mut path = dir.to_vec();
+    if !path.is_empty() {
+        path.push(b'/');
+    }
+    path.extend_from_slice(name);
+    path
+}
+
+enum Checked {
+    // With its hash if the image has hash trees
+    Leaf(Option<Hash>),
+    // With where its contents are too
+    File(Option<Hash>, Range<u64>),
+    Directory(fs::Directory),
+}
+
+fn verify_entry(ent: &fs::DirEntry) -> Result<Checked> {
+    Ok(match ent.item()? {
+        fs::FSItem::File(f) => {
+            // This checks the contents against the tree if there is one
+            if f.checksum().is_none() {
+                f.read_range(0..f.size(), &mut io::sink(), None)?;
+            } else if !f.verify()? {
+                return Err(Error::Format("file checksum mismatch"));
+            }
+            Checked::File(f.root_hash()?, f.data_extent()?)
+        }
+        fs::FSItem::Symlink(s) => {
+            Checked::Leaf(Some(merkle::symlink_hash(&s.get_link()?)))
+        }
+        fs::FSItem::Special(s) => Checked::Leaf(Some(s.hash())),
+        fs::FSItem::Directory(d) => Checked::Directory(d),
+    })
+}
+
+// Returns the hash of the directory, None if something failed or the
+// image has no hash trees.
+fn verify_dir(
+    dir: &fs::Directory,
+    path: &[u8],
+    seen: &mut HashSet<u64>,
+    extents: &mut Vec<(Range<u64>, Vec<u8>)>,
+    report: &mut VerifyReport,
+) -> Option<Hash> {
+    let mut hasher = Some(DirHasher::new());
+    for (pos, e) in dir.iter().enumerate() {
+        let ent = match e {
+            Ok(ent) => ent,
+            Err(e) => {
+                let name = format!("#{}", pos);
+                report.failures.push((join(path, name.as_bytes()), e));
+                hasher = None;
+                continue;
+            }
+        };
+        let name = match ent.file_name() {
+            Ok(name) => name,
+            Err(e) => {
+                let name = format!("#{}", pos);
+                report.failures.push((join(path, name.as_bytes()), e));
+                hasher = None;
+                continue;
+            }
+        };
+        let sub = join(path, name.as_bytes());
+        let hash = match verify_entry(&ent) {
+            Ok(Checked::Directory(d)) => {
+                report.entries += 1;
+                // A corrupt image can make a directory contain itself
+                if seen.insert(ent.ino()) {
+                    verify_dir(&d, &sub, seen, extents, report)
+                } else {
+                    report.failures.push((
+                        sub,
+                        Error::Format("directory appears more than once"),
+                    ));
+                    None
+                }
+            }
+            Ok(Checked::Leaf(hash)) => {
+                report.entries += 1;
+                hash
+            }
+            Ok(Checked::File(hash, extent)) => {
+                report.entries += 1;
+                if !extent.is_empty() {
+                    extents.push((extent, sub));
+                }
+                hash
+            }
+            Err(e) => {
+                report.failures.push((sub, e));
+                None
+            }
+        };
+        match (hasher.as_mut(), hash) {
+            (Some(h), Some(hash)) => h.add(name.as_bytes(), &hash),
+            _ => hasher = None,
+        }
+    }
+    hasher.map(|h| h.finish())
+}
+
+// Report files whose contents partly overlap others. Files can share
+// the exact same contents, but a corrupt size could make a file read
+// the contents of its neighbours without any error.
+fn check_overlaps(
+    mut extents: Vec<(Range<u64>, Vec<u8>)>,
+    report: &mut VerifyReport,
+) {
+    extents.sort_by_key(|(e, _)| (e.start, e.end));
+    let mut prev: Option<Range<u64>> = None;
+    for (extent, path) in extents {
+        match prev {
+            Some(ref p) if *p == extent => continue,
+            Some(ref p) if extent.start < p.end => {
+                report
+                    .failures
+                    .push((path, Error::Format("overlapping inode data")));
+                // Keep the one that reaches further
+                if extent.end <= p.end {
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        prev = Some(extent);
+    }
+}
+
+/// Read every entry of the image, including the whole contents of
+/// files, and report the ones that fail. This includes files whose
+/// contents overlap the contents of other files. For images with hash
+/// trees, this also checks the root hash, which then shows as a
+/// failure of the empty path.
+///
+/// This keeps going after errors so that the report covers the whole
+/// image. Only failing to read the root is an error.
+pub fn verify(fs: &fs::FS) -> Result<VerifyReport> {
+    let mut report = VerifyReport::default();
+    let root = fs.get_root()?;
+    let mut seen = HashSet::from([fs.header().root_inode()]);
+    let mut extents = Vec::new();
+    let hash = verify_dir(&root, b"", &mut seen, &mut extents, &mut report);
+    check_overlaps(extents, &mut report);
+    // Only meaningful if everything else could be read
+    if let (Some(expected), true) = (fs.root_hash(), report.is_ok()) {
+        if hash != Some(expected) {
+            report
+                .failures
+                .push((Vec::new(), Error::Crypto("root hash mismatch")));
+        }
+    }
+    Ok(report)
+}
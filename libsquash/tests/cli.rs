@@ -0,0 +1,119 @@
+// Output of the command line tool
+
+use std::process::Command;
+
+use libsquash::{write_image, EncryptionType};
+
+fn run(args: &[&str]) -> String {
+    let out = Command::new(env!("CARGO_BIN_EXE_squashfile"))
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(
+        out.status.success(),
+        "{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+    String::from_utf8(out.stdout).unwrap()
+}
+
+#[test]
+fn test_info_json() {
+    let out = run(&["info", "-i", "test_data/small.sqh", "--format", "json"]);
+    assert_eq!(
+        out,
+        "{\"magic\":\"SQUASHFL\",\"magic_valid\":true,\
+         \"version\":{\"major\":0,\"minor\":0},\"compression\":\"none\",\
+         \"encryption\":\"none\",\"root_inode\":390,\
+         \"incompatible_features\":0,\"compatible_features\":0,\
+         \"uuid\":null,\"root_hash\":null,\"digest\":null,\
+         \"key_salt\":null}\n"
+    );
+}
+
+#[test]
+fn test_list_json() {
+    let out = run(&["--format", "json", "list", "-i", "test_data/small.sqh"]);
+    assert_eq!(
+        out,
+        "[\n\
+         {\"path\":\"dir\",\"type\":\"dir\"},\n\
+         {\"path\":\"dir/nested.txt\",\"type\":\"file\",\"size\":7},\n\
+         {\"path\":\"dir/sub\",\"type\":\"dir\"},\n\
+         {\"path\":\"dir/sub/.keep\",\"type\":\"file\",\"size\":0},\n\
+         {\"path\":\"hello.txt\",\"type\":\"file\",\"size\":14},\n\
+         {\"path\":\"link\",\"type\":\"symlink\",\"target\":\"hello.txt\"}\n\
+         ]\n"
+    );
+}
+
+#[test]
+fn test_list_json_non_utf8() {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    let dir = tempfile::tempdir().unwrap();
+    let src = dir.path().join("src");
+    std::fs::create_dir(&src).unwrap();
+    std::fs::write(src.join(OsStr::from_bytes(b"a\xff")), "").unwrap();
+    let image = dir.path().join("image.sqh");
+    let mut f = std::fs::File::create(&image).unwrap();
+    write_image(&src, &mut f, None, EncryptionType::None).unwrap();
+    let out = run(&["list", "--format", "json", "-i", image.to_str().unwrap()]);
+    assert_eq!(
+        out,
+        "[\n{\"path\":\"Yf8=\",\"path_base64\":true,\"type\":\"file\",\
+         \"size\":0}\n]\n"
+    );
+}
+
+#[test]
+fn test_verify_json() {
+    let out = run(&["verify", "-i", "test_data/small.sqh", "--format", "json"]);
+    assert_eq!(out, "{\"ok\":true,\"entries\":6,\"failures\":[]}\n");
+}
+
+#[test]
+fn test_verify_path() {
+    let hash = hex::encode(blake3::hash(b"Hello, world!\n").as_bytes());
+    let args = ["verify", "-i", "test_data/small.sqh", "--path", "hello.txt"];
+    let out = run(&[&args[..], &["--hash", &hash]].concat());
+    assert_eq!(out, "hello.txt: OK\n");
+    let out =
+        run(&[&args[..], &["--hash", &hash, "--format", "json"]].concat());
+    assert_eq!(out, "{\"path\":\"hello.txt\",\"ok\":true}\n");
+
+    let other = hex::encode(blake3::hash(b"other").as_bytes());
+    let status = Command::new(env!("CARGO_BIN_EXE_squashfile"))
+        .args(args)
+        .args(["--hash", &other])
+        .output()
+        .unwrap()
+        .status;
+    assert!(!status.success());
+}
+
+#[test]
+fn test_passphrase() {
+    let dir = tempfile::tempdir().unwrap();
+    let image = dir.path().join("image.sqh");
+    let image = image.to_str().unwrap();
+    let pass = ["--passphrase", "open sesame"];
+    let create = ["create", "-s", "test_data/small", "-i", image, "-e"];
+    run(&[&create[..], &["chacha20"], &pass].concat());
+    let out = run(&["cat", "-i", image, "-p", "hello.txt", pass[0], pass[1]]);
+    assert_eq!(out, "Hello, world!\n");
+    let out = run(&["info", "-i", image]);
+    assert!(!out.contains("key salt: none"));
+}
+
+#[test]
+fn test_extract_path() {
+    let dir = tempfile::tempdir().unwrap();
+    let image = "test_data/small.sqh";
+    let target = dir.path().to_str().unwrap();
+    run(&["extract", "-i", image, "-t", target, "--path", "dir"]);
+    assert!(dir.path().join("nested.txt").exists());
+    assert!(!dir.path().join("hello.txt").exists());
+    run(&["extract", "-i", image, "-t", target, "--path", "hello.txt"]);
+    assert!(dir.path().join("hello.txt").exists());
+}
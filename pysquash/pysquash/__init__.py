@@ -1,5 +1,6 @@
 from .pysquash import (
     SquashError,
+    SquashLinkLoopError,
     SquashCursor as _SquashCursor,
 )
 from io import BufferedReader, TextIOWrapper, DEFAULT_BUFFER_SIZE
@@ -15,6 +16,9 @@ class SquashCursor:
     def cd(self, path):
         return SquashCursor(self._cur.cd(bytes(path)))
 
+    def stat(self, path):
+        return self._cur.stat(bytes(path))
+
     def open(
         self, path, binary=False, buffering=-1, encoding=None, errors=None, newline=None
     ):
@@ -37,5 +41,19 @@ class SquashCursor:
             line_buffering=line_buffering,
         )
 
+    def walk(self, top=b""):
+        """Like os.walk, top-down: yields (dirpath, dirnames, filenames)
+        for this directory then for each of its subdirectories, with
+        bytes paths relative to it starting with `top`. Removing names
+        from dirnames skips those directories. Symlinks are listed in
+        filenames and never followed."""
+        dirnames, filenames = [], []
+        for entry in self._cur.scandir():
+            (dirnames if entry.is_dir() else filenames).append(entry.name)
+        yield top, dirnames, filenames
+        for name in dirnames:
+            path = top + b"/" + name if top else name
+            yield from SquashCursor(self._cur.cd(name)).walk(path)
+
     def __iter__(self):
         return self._cur.scandir()
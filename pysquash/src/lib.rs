@@ -5,12 +5,13 @@ use libsquash::error::Error;
 
 use pyo3::create_exception;
 use pyo3::prelude::*;
-use pyo3::exceptions::{PyIsADirectoryError, PyFileNotFoundError, PyNotADirectoryError};
+use pyo3::exceptions::{PyIsADirectoryError, PyFileNotFoundError, PyNotADirectoryError, PyValueError};
 use pyo3::types::PyBytes;
 use pyo3::types::PyUnicode;
 use std::path::PathBuf;
 
 create_exception!(pysquash, SquashError, pyo3::exceptions::PyException);
+create_exception!(pysquash, SquashLinkLoopError, SquashError);
 
 #[pyclass(module="pysquash.pysquash", unsendable)]
 struct SquashCursor {
@@ -23,21 +24,63 @@ struct SquashFile {
     pos: u64,
 }
 
+/// What `SquashCursor.stat` returns.
+#[pyclass(module="pysquash.pysquash")]
+struct SquashStat {
+    #[pyo3(get)]
+    is_dir: bool,
+    #[pyo3(get)]
+    is_file: bool,
+    #[pyo3(get)]
+    is_symlink: bool,
+    #[pyo3(get)]
+    size: u64,
+}
+
+/// What `SquashDirIter` yields, like os.DirEntry.
+#[pyclass(module="pysquash.pysquash")]
+struct SquashDirEntry {
+    name: Vec<u8>,
+    ty: fs::FileType,
+}
+
 #[pyclass(module="pysquash.pysquash", unsendable)]
 struct SquashDirIter {
     rd: fs::ReadDir,
 }
 
 
-fn convert_err(e: Error) -> PyErr {
+// Where the error happened, as a suffix for messages
+fn context(e: &Error) -> String {
     match e {
-        Error::IO(e) => e.into(),
+        Error::Context { offset, path, source } => {
+            let mut res = context(source);
+            if let Some(path) = path {
+                res += &format!(" in {:?}", String::from_utf8_lossy(path));
+            }
+            if let Some(offset) = offset {
+                res += &format!(" at offset {offset}");
+            }
+            res
+        }
+        _ => String::new(),
+    }
+}
+
+fn convert_err(e: Error) -> PyErr {
+    let ctx = context(&e);
+    match e.into_inner() {
+        Error::IO(e) if ctx.is_empty() => e.into(),
+        Error::IO(e) => std::io::Error::new(e.kind(), format!("{e}{ctx}")).into(),
         Error::Hex(_) => SquashError::new_err(format!("Error decoding hex")),
-        Error::Format(m) => SquashError::new_err(format!("Invalid value: {m}")),
-        Error::Bounds(m) => SquashError::new_err(format!("Value out of bounds: {m}")),
-        Error::Crypto(m) => SquashError::new_err(format!("Crypto error: {m}")),
-        Error::Compression(m) => SquashError::new_err(format!("Decompression error: {m}")),
-        Error::InvalidOperation(m) => SquashError::new_err(format!("Invalid operation: {m}")),
+        Error::Format(m) => SquashError::new_err(format!("Invalid value: {m}{ctx}")),
+        e @ Error::LinkLoop => SquashLinkLoopError::new_err(format!("{e}{ctx}")),
+        Error::Bounds(m) => SquashError::new_err(format!("Value out of bounds: {m}{ctx}")),
+        Error::Crypto(m) => SquashError::new_err(format!("Crypto error: {m}{ctx}")),
+        e @ (Error::KeyRequired | Error::InvalidKeyLength { .. } | Error::UnsupportedVersion { .. }) => SquashError::new_err(e.to_string()),
+        Error::Compression(m) => SquashError::new_err(format!("Decompression error: {m}{ctx}")),
+        Error::InvalidOperation(m) => SquashError::new_err(format!("Invalid operation: {m}{ctx}")),
+        Error::Context { .. } => unreachable!("into_inner removes the context"),
     }
 }
 
@@ -72,6 +115,25 @@ impl SquashCursor {
         }
     }
 
+    // Like os.stat, this follows symlinks
+    fn stat(&self, path: &PyBytes) -> PyResult<SquashStat> {
+        let p: &[u8] = path.extract()?;
+        let meta = match self.dir.resolve(p).map_err(convert_err)? {
+            Some(fs::FSItem::File(f)) => f.metadata(),
+            Some(fs::FSItem::Directory(d)) => d.metadata(),
+            Some(fs::FSItem::Symlink(l)) => l.metadata(),
+            Some(fs::FSItem::Special(s)) => s.metadata(),
+            None => return Err(PyFileNotFoundError::new_err(p.to_owned())),
+        }
+        .map_err(convert_err)?;
+        Ok(SquashStat {
+            is_dir: meta.is_dir(),
+            is_file: meta.is_file(),
+            is_symlink: meta.is_symlink(),
+            size: meta.size(),
+        })
+    }
+
     // Iterator
     fn scandir(&self) -> SquashDirIter {
         SquashDirIter { rd: self.dir.iter() }
@@ -85,15 +147,42 @@ impl SquashCursor {
 #[pymethods]
 impl SquashFile {
     fn read<'py>(&mut self, py: Python<'py>, size: usize) -> PyResult<&'py PyBytes> {
-        let sz: usize = std::cmp::min(self.f.size() - self.pos, size as u64).try_into()?;
+        let sz: usize = std::cmp::min(self.f.size().saturating_sub(self.pos), size as u64).try_into()?;
+        // Reading past the end gives nothing rather than an error
+        if sz == 0 {
+            return Ok(PyBytes::new(py, b""));
+        }
         let res = PyBytes::new_with(py, sz,
                                     |buf| self.f.read_exact_at(buf, self.pos).map_err(convert_err));
         self.pos += sz as u64;
         res
     }
 
+    /// Like io.RawIOBase.seek, except that the position is kept
+    /// between the start and the end of the file.
+    #[args(whence = "0")]
+    fn seek(&mut self, offset: i64, whence: i32) -> PyResult<u64> {
+        let base = match whence {
+            0 => 0,
+            1 => self.pos as i128,
+            2 => self.f.size() as i128,
+            _ => return Err(PyValueError::new_err(format!("invalid whence ({whence})"))),
+        };
+        let pos = (base + offset as i128).clamp(0, self.f.size() as i128);
+        self.pos = pos as u64;
+        Ok(self.pos)
+    }
+
+    fn tell(&self) -> u64 {
+        self.pos
+    }
+
+    fn seekable(&self) -> bool {
+        true
+    }
+
     fn readall<'py>(&mut self, py: Python<'py>) -> PyResult<&'py PyBytes> {
-        self.read(py, self.size() as usize)
+        self.read(py, self.size().try_into()?)
     }
 
     fn size(&self) -> u64 {
@@ -107,21 +196,62 @@ impl SquashDirIter {
         slf
     }
 
-    fn __next__<'py>(&mut self, py: Python<'py>) -> PyResult<Option<&'py PyBytes>> {
+    fn __next__(&mut self) -> PyResult<Option<SquashDirEntry>> {
        match self.rd.next() {
             None => Ok(None),
             Some(Err(e)) => Err(convert_err(e)),
-            Some(Ok(v)) => Ok(Some(PyBytes::new(py, v.file_name().map_err(convert_err)?.as_bytes())))
+            Some(Ok(v)) => Ok(Some(SquashDirEntry {
+                name: v.file_name().map_err(convert_err)?.into_bytes(),
+                ty: v.file_type().map_err(convert_err)?,
+            })),
         }
     }
 }
 
+#[pymethods]
+impl SquashDirEntry {
+    #[getter]
+    fn name<'py>(&self, py: Python<'py>) -> &'py PyBytes {
+        PyBytes::new(py, &self.name)
+    }
+
+    fn is_dir(&self) -> bool {
+        self.ty.is_dir()
+    }
+
+    fn is_file(&self) -> bool {
+        self.ty.is_file()
+    }
+
+    fn is_symlink(&self) -> bool {
+        self.ty.is_symlink()
+    }
+
+    // Entries used to be plain names, these keep most of that working.
+    // Methods without arguments that take `py` go through argument
+    // parsing, which doesn't handle being called without any.
+    fn __bytes__(&self) -> Py<PyBytes> {
+        Python::with_gil(|py| self.name(py).into())
+    }
+
+    fn __fspath__(&self) -> Py<PyBytes> {
+        self.__bytes__()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("<SquashDirEntry {:?}>", String::from_utf8_lossy(&self.name))
+    }
+}
+
 /// A Python module implemented in Rust.
 #[pymodule]
 fn pysquash(py: Python, m: &PyModule) -> PyResult<()> {
     m.add_class::<SquashCursor>()?;
     m.add_class::<SquashFile>()?;
     m.add_class::<SquashDirIter>()?;
+    m.add_class::<SquashStat>()?;
+    m.add_class::<SquashDirEntry>()?;
     m.add("SquashError", py.get_type::<SquashError>())?;
+    m.add("SquashLinkLoopError", py.get_type::<SquashLinkLoopError>())?;
     Ok(())
 }
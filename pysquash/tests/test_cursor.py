@@ -0,0 +1,57 @@
+import os
+import unittest
+
+from pysquash import SquashCursor
+
+SMALL = os.path.join(
+    os.path.dirname(__file__), "..", "..", "libsquash", "test_data", "small.sqh"
+)
+
+
+class TestCursor(unittest.TestCase):
+    def test_stat(self):
+        cur = SquashCursor(SMALL)
+        st = cur.stat(b"hello.txt")
+        self.assertTrue(st.is_file)
+        self.assertFalse(st.is_dir)
+        self.assertEqual(st.size, 14)
+        st = cur.stat(b"dir")
+        self.assertTrue(st.is_dir)
+        self.assertFalse(st.is_file)
+        # Symlinks are followed
+        st = cur.stat(b"link")
+        self.assertTrue(st.is_file)
+        self.assertFalse(st.is_symlink)
+        self.assertEqual(st.size, 14)
+        with self.assertRaises(FileNotFoundError):
+            cur.stat(b"missing")
+
+    def test_scandir(self):
+        entries = {bytes(e): e for e in SquashCursor(SMALL)}
+        self.assertEqual(set(entries), {b"dir", b"hello.txt", b"link"})
+        self.assertTrue(entries[b"dir"].is_dir())
+        self.assertTrue(entries[b"hello.txt"].is_file())
+        # Entries are not followed
+        self.assertTrue(entries[b"link"].is_symlink())
+        self.assertFalse(entries[b"link"].is_file())
+        self.assertEqual(entries[b"link"].name, b"link")
+        self.assertEqual(os.fspath(entries[b"link"]), b"link")
+
+    def test_walk(self):
+        cur = SquashCursor(SMALL)
+        self.assertEqual(
+            list(cur.walk()),
+            [
+                (b"", [b"dir"], [b"hello.txt", b"link"]),
+                (b"dir", [b"sub"], [b"nested.txt"]),
+                (b"dir/sub", [], [b".keep"]),
+            ],
+        )
+        walk = cur.walk()
+        path, dirnames, filenames = next(walk)
+        dirnames.clear()
+        self.assertEqual(list(walk), [])
+
+
+if __name__ == "__main__":
+    unittest.main()
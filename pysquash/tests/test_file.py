@@ -0,0 +1,38 @@
+import os
+import unittest
+
+from pysquash import SquashCursor
+
+SMALL = os.path.join(
+    os.path.dirname(__file__), "..", "..", "libsquash", "test_data", "small.sqh"
+)
+
+
+class TestFile(unittest.TestCase):
+    def test_read_past_end(self):
+        f = SquashCursor(SMALL)._cur.open(b"hello.txt")
+        self.assertEqual(f.read(5), b"Hello")
+        f.seek(f.size())
+        self.assertEqual(f.read(10), b"")
+        self.assertEqual(f.read(1), b"")
+        f.seek(0)
+        self.assertEqual(len(f.read(100)), f.size())
+
+    def test_seek(self):
+        f = SquashCursor(SMALL)._cur.open(b"hello.txt")
+        self.assertTrue(f.seekable())
+        self.assertEqual(f.seek(7), 7)
+        self.assertEqual(f.tell(), 7)
+        self.assertEqual(f.read(5), b"world")
+        self.assertEqual(f.seek(-6, 1), 6)
+        self.assertEqual(f.seek(-2, 2), f.size() - 2)
+        self.assertEqual(f.read(10), b"!\n")
+        # The position stays within the file
+        self.assertEqual(f.seek(100), f.size())
+        self.assertEqual(f.seek(-100, 1), 0)
+        with self.assertRaises(ValueError):
+            f.seek(0, 3)
+
+
+if __name__ == "__main__":
+    unittest.main()
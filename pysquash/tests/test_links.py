@@ -0,0 +1,49 @@
+import os
+import unittest
+
+from pysquash import SquashCursor, SquashError, SquashLinkLoopError
+
+# good -> sub, dangling -> missing, loop -> loop, ping -> pong -> ping
+LINKS = os.path.join(
+    os.path.dirname(__file__), "..", "..", "libsquash", "test_data", "links.sqh"
+)
+
+
+class TestLinks(unittest.TestCase):
+    def test_good(self):
+        cur = SquashCursor(LINKS)
+        self.assertTrue(cur.stat(b"good").is_dir)
+        f = cur._cur.open(b"good/file.txt")
+        self.assertEqual(f.read(100), b"in sub\n")
+        self.assertEqual([bytes(e) for e in cur.cd(b"good")], [b"file.txt"])
+
+    def test_dangling(self):
+        cur = SquashCursor(LINKS)
+        with self.assertRaises(FileNotFoundError):
+            cur.stat(b"dangling")
+        with self.assertRaises(FileNotFoundError):
+            cur._cur.open(b"dangling")
+        with self.assertRaises(FileNotFoundError):
+            cur.cd(b"dangling")
+        # The link itself is still listed
+        entries = {bytes(e): e for e in cur}
+        self.assertTrue(entries[b"dangling"].is_symlink())
+
+    def test_loop(self):
+        cur = SquashCursor(LINKS)
+        for path in [b"loop", b"ping", b"pong", b"loop/file.txt"]:
+            with self.assertRaises(SquashLinkLoopError):
+                cur.stat(path)
+        with self.assertRaises(SquashLinkLoopError):
+            cur._cur.open(b"ping")
+        with self.assertRaises(SquashLinkLoopError):
+            cur.cd(b"loop")
+        # Still a SquashError for callers that don't tell them apart
+        with self.assertRaises(SquashError):
+            cur.stat(b"loop")
+        entries = {bytes(e): e for e in cur}
+        self.assertTrue(entries[b"loop"].is_symlink())
+
+
+if __name__ == "__main__":
+    unittest.main()